@@ -0,0 +1,244 @@
+use std::pin::pin;
+use std::time::{Duration, Instant};
+use async_channel::{bounded, Receiver, Sender};
+use async_lock::Mutex;
+use futures::future::{pending, select};
+use serde::Serialize;
+use strum::{Display, EnumString};
+use zbus::{Connection, Result as zbus_Result};
+use zbus::zvariant::OwnedFd;
+use crate::connect::with_method_timeout;
+use crate::output::Writer;
+use crate::rt::sleep;
+
+/// The marker written when an action is scheduled. The action, the path of the device whose alert
+/// scheduled it and the delay before it is taken (in seconds) are appended to the marker,
+/// separated by spaces.
+pub(crate) const ACTION_PENDING_MARKER: &str = "ActionPending";
+
+/// The marker written when a pending action is cancelled because a device started charging. The
+/// action is appended to the marker, separated by a space.
+pub(crate) const ACTION_CANCELLED_MARKER: &str = "ActionCancelled";
+
+/// The marker written immediately before an action is taken. The action is appended to the
+/// marker, separated by a space.
+pub(crate) const ACTION_MARKER: &str = "Action";
+
+/// The bus name, object path and interface of logind's manager object.
+const LOGIND_SERVICE: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+
+/// Actions which can be taken by logind when an alert fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, EnumString, Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Suspend the system to RAM.
+    Suspend,
+    /// Hibernate the system to disk.
+    Hibernate,
+    /// Power off the system.
+    PowerOff
+}
+
+impl Action {
+    /// The method of `org.freedesktop.login1.Manager` which takes the action.
+    fn method(&self) -> &'static str {
+        match self {
+            Action::Suspend => "Suspend",
+            Action::Hibernate => "Hibernate",
+            Action::PowerOff => "PowerOff"
+        }
+    }
+
+    /// The kind of inhibitor lock which delays the action.
+    fn inhibits(&self) -> &'static str {
+        match self {
+            Action::Suspend | Action::Hibernate => "sleep",
+            Action::PowerOff => "shutdown"
+        }
+    }
+
+    /// Ask logind to take the action.
+    pub(crate) async fn take(&self, conn: &Connection) -> zbus_Result<()> {
+        with_method_timeout(self.method(), conn.call_method(
+            Some(LOGIND_SERVICE),
+            LOGIND_PATH,
+            Some(LOGIND_MANAGER_INTERFACE),
+            self.method(),
+            // Whether to ask the user for authorisation if required.
+            &(false,)
+        )).await?;
+        Ok(())
+    }
+
+    /// Take a delay inhibitor lock from logind, so that the system does not sleep (or shut down)
+    /// on its own while the action is pending. The lock is released when the returned file
+    /// descriptor is closed.
+    async fn inhibit(&self, conn: &Connection) -> zbus_Result<OwnedFd> {
+        with_method_timeout("Inhibit", conn.call_method(
+            Some(LOGIND_SERVICE),
+            LOGIND_PATH,
+            Some(LOGIND_MANAGER_INTERFACE),
+            "Inhibit",
+            &(self.inhibits(), "upmon", format!("Waiting to {self}"), "delay")
+        )).await?.body()
+    }
+}
+
+/// An action which has been scheduled but not yet taken.
+#[derive(Debug)]
+struct PendingAction {
+    action: Action,
+    /// When the action is due to be taken.
+    due: Instant
+}
+
+/// Schedules actions to be taken after a confirmation delay, during which they can be cancelled
+/// (for example, because AC power has returned). At most one action is pending at a time.
+#[derive(Debug)]
+pub struct ActionScheduler {
+    /// The pending action, if any.
+    pending: Mutex<Option<PendingAction>>,
+    /// Used to wake [`ActionScheduler::run`] when an action is scheduled or cancelled.
+    changed_sender: Sender<()>,
+    /// Used to wait for an action to be scheduled or cancelled.
+    changed_receiver: Receiver<()>
+}
+
+impl Default for ActionScheduler {
+    fn default() -> Self {
+        let (changed_sender, changed_receiver) = bounded(1);
+        Self { pending: Mutex::new(None), changed_sender, changed_receiver }
+    }
+}
+
+impl ActionScheduler {
+    /// Schedule `action` to be taken after `delay`, unless an action is already pending. Returns
+    /// whether the action was scheduled.
+    pub(crate) async fn schedule(&self, action: Action, delay: Duration) -> bool {
+        let mut pending = self.pending.lock().await;
+        if pending.is_some() {
+            return false
+        }
+        *pending = Some(PendingAction { action, due: Instant::now() + delay });
+        // If a wake-up is already waiting, the runner will see this action anyway.
+        let _ = self.changed_sender.try_send(());
+        true
+    }
+
+    /// Cancel the pending action, returning it if there was one.
+    pub(crate) async fn cancel(&self) -> Option<Action> {
+        let cancelled = self.pending.lock().await.take().map(|p| p.action);
+        let _ = self.changed_sender.try_send(());
+        cancelled
+    }
+
+    /// Return the pending action and when it is due, if any.
+    async fn pending(&self) -> Option<(Action, Instant)> {
+        self.pending.lock().await.as_ref().map(|p| (p.action, p.due))
+    }
+
+    /// Remove and return the pending action if it is due at `now`.
+    async fn take_due(&self, now: Instant) -> Option<Action> {
+        let mut pending = self.pending.lock().await;
+        match &*pending {
+            Some(p) if p.due <= now => pending.take().map(|p| p.action),
+            _ => None
+        }
+    }
+
+    /// Take each action when it becomes due, writing an [`ACTION_MARKER`] to `writer` first.
+    /// While an action is pending, a delay inhibitor lock is held so that the system does not
+    /// sleep or shut down before the action is taken or cancelled; it is released immediately
+    /// before the action is taken. Failures to write the marker or take the action are logged,
+    /// and later actions are still taken. This never completes.
+    pub(crate) async fn run(&self, conn: &Connection, writer: &impl Writer) {
+        // The action for which a lock has been requested, and the lock if it was taken.
+        let mut lock: Option<(Action, Option<OwnedFd>)> = None;
+        loop {
+            if let Some(action) = self.take_due(Instant::now()).await {
+                lock = None;
+                if let Err(e) = writer.write_marker(&format!("{ACTION_MARKER} {action}")).await {
+                    eprintln!("Error writing output: {e}");
+                }
+                if let Err(e) = action.take(conn).await {
+                    eprintln!("Error when taking action {action}: {e}");
+                }
+                continue
+            }
+            let pending_action = self.pending().await;
+            match pending_action {
+                Some((action, _)) if lock.as_ref().map(|(a, _)| *a) != Some(action) => {
+                    let fd = action.inhibit(conn).await.map(Some).unwrap_or_else(|e| {
+                        eprintln!("Could not take inhibitor lock: {e}");
+                        None
+                    });
+                    lock = Some((action, fd));
+                },
+                Some(_) => {},
+                None => lock = None
+            }
+            // Wait until the pending action is due, or until an action is scheduled or cancelled.
+            let until_due = async {
+                match pending_action {
+                    Some((_, due)) => sleep(due.saturating_duration_since(Instant::now())).await,
+                    None => pending().await
+                }
+            };
+            select(pin!(self.changed_receiver.recv()), pin!(until_due)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::{Duration, Instant};
+    use crate::action::{Action, ActionScheduler};
+    use crate::output::LineWriter;
+    use crate::rt::block_on;
+    use crate::testing::{MockUPower, run_until, SharedBuffer};
+
+    /// Test that only one action is pending at a time, and that it is only taken once due unless
+    /// cancelled.
+    #[test]
+    fn scheduler() {
+        assert_eq!("poweroff".parse::<Action>(), Ok(Action::PowerOff));
+        assert!("reboot".parse::<Action>().is_err());
+        assert_eq!(Action::Hibernate.inhibits(), "sleep");
+        assert_eq!(Action::PowerOff.inhibits(), "shutdown");
+        let scheduler = ActionScheduler::default();
+        block_on(async {
+            assert!(scheduler.schedule(Action::Suspend, Duration::from_secs(60)).await);
+            assert!(!scheduler.schedule(Action::PowerOff, Duration::ZERO).await);
+            assert_eq!(scheduler.take_due(Instant::now()).await, None);
+            assert_eq!(
+                scheduler.take_due(Instant::now() + Duration::from_secs(61)).await,
+                Some(Action::Suspend)
+            );
+            assert_eq!(scheduler.cancel().await, None);
+            assert!(scheduler.schedule(Action::Hibernate, Duration::ZERO).await);
+            assert_eq!(scheduler.cancel().await, Some(Action::Hibernate));
+            assert_eq!(scheduler.take_due(Instant::now()).await, None);
+        });
+    }
+
+    /// Test that the scheduler keeps taking actions after one fails (here, because the mock's
+    /// connection has no logind), and that it is woken when an action is scheduled.
+    #[test]
+    fn run_after_failure() {
+        let scheduler = ActionScheduler::default();
+        let buf = SharedBuffer::default();
+        let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            run_until(scheduler.run(&upower.client, &writer), async {
+                scheduler.schedule(Action::Suspend, Duration::ZERO).await;
+                buf.wait_for("Action suspend\n").await;
+                scheduler.schedule(Action::Hibernate, Duration::from_millis(50)).await;
+                buf.wait_for("Action suspend\nAction hibernate\n").await;
+            }).await;
+        });
+    }
+}
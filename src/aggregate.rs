@@ -0,0 +1,199 @@
+use std::time::Duration;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use futures::future::pending;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::rt::sleep;
+use crate::throttle::Throttle;
+use crate::upower::{Property, PropertyKind};
+
+/// The minimum, maximum and total of the values of a numeric property seen during a window.
+#[derive(Debug)]
+struct Stats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32
+}
+
+impl Stats {
+    /// Create statistics for a window in which `value` is the first value seen.
+    fn new(value: f64) -> Self {
+        Self { min: value, max: value, sum: value, count: 1 }
+    }
+
+    /// Add a value to the statistics.
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The changes to a single device which have been received during the current window.
+#[derive(Debug)]
+struct Window {
+    /// The latest event received for the device, with the latest value of every property changed
+    /// during the window, in the order in which they first changed.
+    event: DeviceEvent,
+    /// Statistics for each measured property changed during the window.
+    stats: Vec<(PropertyKind, Stats)>
+}
+
+/// Whether minimum, maximum and average values are reported for the given property. Only
+/// measurements which vary continuously are included.
+fn is_measurement(value: &Property) -> bool {
+    matches!(
+        value,
+        Property::Percentage(_) | Property::EnergyRate(_) | Property::TimeToEmpty(_)
+            | Property::TimeToFull(_) | Property::EnergyFull(_) | Property::Capacity(_)
+    )
+}
+
+/// A [`Writer`] which accumulates the changes to each device over a fixed window, and passes on
+/// at most one event per device per window to an inner [`Writer`], containing the latest value of
+/// every property which changed. If requested, the minimum, maximum and average value of each
+/// measured property during the window are added as the `<Property>Min`, `<Property>Max` and
+/// `<Property>Avg` pseudo-properties. Changes are written by [`AggregateWriter::run`]; markers are
+/// passed on immediately.
+pub struct AggregateWriter<W: Writer> {
+    /// The writer to which aggregated changes are passed.
+    inner: W,
+    /// The length of each window, or `None` if changes should be passed on as they are received.
+    window: Option<Duration>,
+    /// Whether to add the minimum, maximum and average values of measured properties.
+    stats: bool,
+    /// The changes received for each device during the current window, in the order in which each
+    /// device first changed.
+    pending: Mutex<Vec<(String, Window)>>
+}
+
+impl<W: Writer> AggregateWriter<W> {
+    /// Create a new [`AggregateWriter`] which passes changes to `inner` once per `window`, adding
+    /// statistics if `stats` is true.
+    pub(crate) fn new(inner: W, window: Option<Duration>, stats: bool) -> Self {
+        Self {
+            inner,
+            window,
+            stats,
+            pending: Mutex::new(vec!())
+        }
+    }
+
+    /// Write the changes received for each device since the last window ended, and start a new
+    /// window.
+    pub(crate) async fn flush(&self) -> Result<(), std::io::Error> {
+        let pending = std::mem::take(&mut *self.pending.lock().await);
+        for (_, Window { mut event, stats }) in pending {
+            if self.stats {
+                for (kind, s) in stats {
+                    let name = kind.as_str();
+                    for (suffix, value) in [
+                        ("Min", s.min),
+                        ("Max", s.max),
+                        ("Avg", s.sum / f64::from(s.count))
+                    ] {
+                        event.insert(
+                            PropertyKind::Other(format!("{name}{suffix}")),
+                            Property::Other(Value::from(value).into())
+                        );
+                    }
+                }
+            }
+            self.inner.write(&event).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the accumulated changes at the end of each window, lengthened by `throttle` while on
+    /// battery. If no window is set, this never completes.
+    pub(crate) async fn run(&self, throttle: &Throttle) -> Result<(), std::io::Error> {
+        let Some(window) = self.window else {
+            return pending().await
+        };
+        loop {
+            sleep(throttle.interval(window)).await;
+            self.flush().await?;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for AggregateWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        if self.window.is_none() {
+            return self.inner.write(event).await
+        }
+        let mut pending = self.pending.lock().await;
+        let i = match pending.iter().position(|(d, _)| *d == event.device) {
+            Some(i) => {
+                let window = &mut pending[i].1;
+                let mut merged = event.with_changes(std::mem::take(&mut window.event.changes));
+                for (k, v) in event.iter() {
+                    merged.insert(k.clone(), v.clone());
+                }
+                window.event = merged;
+                i
+            },
+            None => {
+                pending.push((
+                    event.device.clone(),
+                    Window { event: event.clone(), stats: vec!() }
+                ));
+                pending.len() - 1
+            }
+        };
+        let window = &mut pending[i].1;
+        for (k, v) in event.iter().filter(|(_, v)| is_measurement(v)) {
+            let Some(value) = v.as_f64() else { continue };
+            match window.stats.iter_mut().find(|(kind, _)| kind == k) {
+                Some((_, s)) => s.add(value),
+                None => window.stats.push((k.clone(), Stats::new(value)))
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+    use crate::aggregate::AggregateWriter;
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that changes to each device are merged until the window ends, keeping the latest
+    /// values, and that statistics are added for measured properties.
+    #[test]
+    fn aggregate_writer() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = AggregateWriter::new(inner, Some(Duration::from_secs(60)), true);
+        block_on(async {
+            for (device, p) in [("/a", 50.0), ("/b", 20.0), ("/a", 48.0), ("/a", 49.0)] {
+                writer.write(&DeviceEvent::new(device, vec!(
+                    (PropertyKind::Percentage, Percentage(p))
+                ))).await.unwrap();
+            }
+            writer.write(&DeviceEvent::new("/a", vec!((PropertyKind::State, State(2)))))
+                .await.unwrap();
+            writer.write_marker("Resumed").await.unwrap();
+            writer.flush().await.unwrap();
+            writer.flush().await.unwrap();
+        });
+        assert_eq!(buf.contents(), "Resumed\n\
+            /a Percentage=49 State=Discharging PercentageMin=48 PercentageMax=50 PercentageAvg=49\n\
+            /b Percentage=20 PercentageMin=20 PercentageMax=20 PercentageAvg=20\n");
+    }
+}
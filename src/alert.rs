@@ -0,0 +1,680 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use async_channel::{bounded, Receiver, Sender, TrySendError};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::{Local, NaiveTime};
+use clap::ValueEnum;
+use futures::future::{join_all, pending};
+use serde::Serialize;
+use zbus::Connection;
+use crate::action::{Action, ActionScheduler, ACTION_CANCELLED_MARKER, ACTION_PENDING_MARKER};
+use crate::event::DeviceEvent;
+use crate::expr::{Expr, Op};
+use crate::notify::Notifier;
+use crate::output::Writer;
+use crate::sound::{Sound, SoundPlayer};
+use crate::upower::{Property, PropertyKind, STATE_CHARGING};
+
+/// The marker written when an alert rule fires. The device path and the rule's firing condition
+/// are appended to the marker, separated by spaces.
+pub(crate) const ALERT_MARKER: &str = "Alert";
+
+/// The marker written instead of an [`ALERT_MARKER`] when an alert rule fires during its quiet
+/// hours. The device path and the rule's firing condition are appended to the marker, separated by
+/// spaces.
+pub(crate) const QUIET_ALERT_MARKER: &str = "QuietAlert";
+
+/// The number of notifications which may wait to be shown or closed for each rule while an earlier
+/// one is still being handled. Further notifications for the rule are dropped.
+const NOTIFICATION_QUEUE: usize = 16;
+
+/// A range of local times of day, such as `22:00-07:00`, which may span midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeRange {
+    /// The time at which the range starts.
+    start: NaiveTime,
+    /// The time at which the range ends (exclusive).
+    end: NaiveTime
+}
+
+impl TimeRange {
+    /// Parse a range in the form `HH:MM-HH:MM`.
+    fn parse(s: &str) -> Option<Self> {
+        let (start, end) = s.split_once('-')?;
+        let time = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").ok();
+        Some(Self { start: time(start)?, end: time(end)? })
+    }
+
+    /// Whether `time` is within the range.
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// Which of the alert rules whose firing conditions hold at the same time may fire.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertMatch {
+    /// Every rule may fire, except those after a rule with `stop` whose condition holds.
+    All,
+    /// Only the first rule whose condition holds may fire.
+    First
+}
+
+/// A rule which fires an alert when a device's property values meet a condition.
+#[derive(Debug, PartialEq)]
+pub struct AlertRule {
+    /// The firing condition as given by the user (without spaces), used to identify the rule in
+    /// output.
+    spec: String,
+    /// The condition under which the alert fires.
+    fire: Expr,
+    /// The condition under which the alert is reset, so that it can fire again. If not given, the
+    /// alert is reset as soon as the firing condition no longer holds.
+    reset: Option<Expr>,
+    /// The minimum time between successive firings of the alert.
+    cooldown: Duration,
+    /// The action to take when the alert fires, if any.
+    action: Option<Action>,
+    /// The delay before the action is taken, during which it is cancelled if a device starts
+    /// charging.
+    delay: Duration,
+    /// The sound to play when the alert fires, if any.
+    sound: Option<Sound>,
+    /// The priority of the rule. Rules with higher priorities are checked first; rules with the
+    /// same priority are checked in the order given.
+    priority: i32,
+    /// Whether later rules are prevented from firing while this rule's firing condition holds.
+    stop: bool,
+    /// The times of day during which the rule's sound, action and notification are suppressed.
+    quiet: Vec<TimeRange>,
+    /// Whether a desktop notification is shown when the alert fires.
+    notify: bool
+}
+
+impl AlertRule {
+    /// Parse an alert rule from a string such as `Percentage<=15,reset>=20,cooldown=300`. The
+    /// first comma-separated element is an [`Expr`] giving the firing condition, and the optional
+    /// `reset` and `cooldown` elements give the reset condition and cooldown period (in seconds).
+    /// If the firing condition is a single comparison, the reset condition may omit the property
+    /// name (as in `reset>=20`). The optional `action` element gives an [`Action`] to take when
+    /// the alert fires, and `delay` the number of seconds to wait before taking it. The optional
+    /// `sound` element rings the terminal bell when the alert fires, or plays a sound file if
+    /// given as `sound=PATH`. The optional `priority` element gives the rule's priority, and `stop`
+    /// prevents rules checked after this one from firing while its firing condition holds. Each
+    /// `quiet` element gives a [`TimeRange`] (such as `quiet=22:00-07:00`) during which the rule
+    /// fires quietly. The optional `notify` element shows a desktop notification when the alert
+    /// fires.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = s.split(',');
+        let first = parts.next().unwrap_or_default();
+        let mut rule = Self {
+            spec: first.replace(' ', ""),
+            fire: Expr::parse(first)?,
+            reset: None,
+            cooldown: Duration::ZERO,
+            action: None,
+            delay: Duration::ZERO,
+            sound: None,
+            priority: 0,
+            stop: false,
+            quiet: vec!(),
+            notify: false
+        };
+        for part in parts {
+            let part = part.trim();
+            if let Some(r) = part.strip_prefix("reset") {
+                let r = r.trim();
+                rule.reset = Some(match &rule.fire {
+                    Expr::Compare(p, _, _) if Op::ALL.iter().any(|(o, _)| r.starts_with(o)) =>
+                        Expr::parse(&format!("{p}{r}"))?,
+                    _ => Expr::parse(r)?
+                });
+            } else if let Some(c) = part.strip_prefix("cooldown=") {
+                rule.cooldown = Duration::from_secs(c.trim().parse()
+                    .map_err(|_| format!("Invalid cooldown in alert rule: {s}"))?);
+            } else if let Some(a) = part.strip_prefix("action=") {
+                rule.action = Some(a.trim().parse()
+                    .map_err(|_| format!("Invalid action in alert rule: {s}"))?);
+            } else if let Some(d) = part.strip_prefix("delay=") {
+                rule.delay = Duration::from_secs(d.trim().parse()
+                    .map_err(|_| format!("Invalid delay in alert rule: {s}"))?);
+            } else if let Some(f) = part.strip_prefix("sound=") {
+                rule.sound = Some(Sound::File(String::from(f.trim())));
+            } else if part == "sound" {
+                rule.sound = Some(Sound::Bell);
+            } else if let Some(p) = part.strip_prefix("priority=") {
+                rule.priority = p.trim().parse()
+                    .map_err(|_| format!("Invalid priority in alert rule: {s}"))?;
+            } else if part == "stop" {
+                rule.stop = true;
+            } else if part == "notify" {
+                rule.notify = true;
+            } else if let Some(q) = part.strip_prefix("quiet=") {
+                rule.quiet.push(TimeRange::parse(q)
+                    .ok_or_else(|| format!("Invalid quiet hours in alert rule: {s}"))?);
+            } else {
+                return Err(format!("Unexpected element \"{part}\" in alert rule: {s}"))
+            }
+        }
+        if rule.action.is_none() && rule.delay > Duration::ZERO {
+            return Err(format!("Delay given without an action in alert rule: {s}"))
+        }
+        Ok(rule)
+    }
+
+    /// Whether the rule is in its quiet hours at the local time of day `time`.
+    fn is_quiet(&self, time: NaiveTime) -> bool {
+        self.quiet.iter().any(|r| r.contains(time))
+    }
+
+    /// Whether the rule takes an action when it fires.
+    pub(crate) fn has_action(&self) -> bool {
+        self.action.is_some()
+    }
+
+    /// Return the names of all properties referred to by the rule.
+    pub(crate) fn properties(&self) -> Vec<&PropertyKind> {
+        let mut props = self.fire.properties();
+        if let Some(r) = &self.reset {
+            props.extend(r.properties());
+        }
+        props
+    }
+}
+
+/// The state of a single alert rule for a single device.
+#[derive(Debug, Default)]
+struct AlertState {
+    /// Whether the alert has fired and not yet been reset.
+    fired: bool,
+    /// When the alert last fired.
+    last_fired: Option<Instant>
+}
+
+impl AlertState {
+    /// Update the state of the given rule with the latest property values of its device at time
+    /// `now`, returning whether the alert should fire.
+    fn update(&mut self, rule: &AlertRule, values: &HashMap<PropertyKind, Property>, now: Instant)
+        -> bool {
+        if self.fired {
+            let reset = match &rule.reset {
+                Some(r) => r.eval(values),
+                None => !rule.fire.eval(values)
+            };
+            if reset {
+                self.fired = false;
+            }
+            return false
+        }
+        let cooled_down = self.last_fired
+            .map(|t| now.duration_since(t) >= rule.cooldown)
+            .unwrap_or(true);
+        if rule.fire.eval(values) && cooled_down {
+            self.fired = true;
+            self.last_fired = Some(now);
+            return true
+        }
+        false
+    }
+}
+
+/// The state of all alert rules for a single device.
+#[derive(Debug)]
+struct DeviceAlerts {
+    /// The latest value of each of the device's properties.
+    values: HashMap<PropertyKind, Property>,
+    /// The state of each rule (by index).
+    states: Vec<AlertState>
+}
+
+/// A change to the desktop notification of an alert rule for a device, waiting to be made.
+#[derive(Debug)]
+enum NotificationJob {
+    /// Show a notification with the given summary and body.
+    Show { device: String, summary: String, body: String },
+    /// Close the notification.
+    Close { device: String }
+}
+
+/// A [`Writer`] which passes all changes on to an inner [`Writer`], and additionally writes an
+/// [`ALERT_MARKER`] whenever one of its alert rules fires. If the rule has an action, it is
+/// scheduled (see [`AlertWriter::run_actions`]) and an [`ACTION_PENDING_MARKER`] is written; it is
+/// cancelled, with an [`ACTION_CANCELLED_MARKER`], if any device goes online or starts charging.
+/// If the rule has a sound, it is played. During the rule's quiet hours, a [`QUIET_ALERT_MARKER`]
+/// is written instead and no sound, action or notification is played, taken or shown. If the rule
+/// shows notifications, each replaces the last one for the same rule and device, and is closed
+/// once the rule's firing condition no longer holds for the device. Notifications are shown and
+/// closed by [`AlertWriter::run_notifications`], one at a time for each rule, so that a
+/// notification service which is slow to respond does not delay changes to any device, or the
+/// notifications of other rules. Rules are checked
+/// in order of priority; a rule which is prevented from firing by an earlier rule (see
+/// [`AlertMatch`]) is treated as having fired, so that it does not fire until it has been reset.
+pub struct AlertWriter<W: Writer> {
+    /// The writer to which changes and alerts are passed.
+    inner: W,
+    /// The alert rules to check, in order of priority.
+    rules: Vec<AlertRule>,
+    /// Which of the rules whose firing conditions hold may fire.
+    matching: AlertMatch,
+    /// The alert state of each device.
+    states: Mutex<HashMap<String, DeviceAlerts>>,
+    /// The actions scheduled by alerts which have fired.
+    actions: ActionScheduler,
+    /// The player used for the sounds of alerts which have fired.
+    player: SoundPlayer,
+    /// Shows the notifications of alerts which have fired.
+    notifier: Notifier,
+    /// The queue of notifications waiting to be shown or closed for each rule (by index).
+    notifications: Vec<(Sender<NotificationJob>, Receiver<NotificationJob>)>
+}
+
+impl<W: Writer> AlertWriter<W> {
+    /// Create a new [`AlertWriter`] which passes changes to `inner` and checks the given rules.
+    /// Every rule whose firing condition holds may fire, unless an earlier rule stops it.
+    pub(crate) fn new(inner: W, mut rules: Vec<AlertRule>) -> Self {
+        rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+        let notifications = rules.iter().map(|_| bounded(NOTIFICATION_QUEUE)).collect();
+        Self {
+            inner,
+            rules,
+            matching: AlertMatch::All,
+            states: Mutex::new(HashMap::new()),
+            actions: ActionScheduler::default(),
+            player: SoundPlayer::default(),
+            notifier: Notifier::default(),
+            notifications
+        }
+    }
+
+    /// Decide which of the rules whose firing conditions hold may fire according to `matching`.
+    pub(crate) fn with_match(self, matching: AlertMatch) -> Self {
+        Self { matching, ..self }
+    }
+
+    /// Play sound files using `command` rather than the default player.
+    pub(crate) fn with_sound_player(self, command: &str) -> Self {
+        Self { player: SoundPlayer::new(command), ..self }
+    }
+
+    /// Take each scheduled action when it becomes due, using logind over `conn`. This never
+    /// completes.
+    pub(crate) async fn run_actions(&self, conn: &Connection) {
+        if !self.rules.iter().any(AlertRule::has_action) {
+            return pending().await
+        }
+        self.actions.run(conn, &self.inner).await
+    }
+
+    /// Queue a change to the notification of the rule at index `rule`, dropping it (with a
+    /// warning) if too many are already waiting.
+    fn queue_notification(&self, rule: usize, job: NotificationJob) {
+        if let Err(TrySendError::Full(job)) = self.notifications[rule].0.try_send(job) {
+            eprintln!("Dropping alert notification, as too many are waiting: {job:?}");
+        }
+    }
+
+    /// Show and close the notifications of alerts as they are queued, handling each rule's
+    /// notifications in order, independently of other rules. If no rule shows notifications, this
+    /// never completes.
+    pub(crate) async fn run_notifications(&self) {
+        let rules = self.rules.iter()
+            .zip(&self.notifications)
+            .enumerate()
+            .filter(|(_, (rule, _))| rule.notify)
+            .map(|(i, (_, (_, jobs)))| async move {
+                while let Ok(job) = jobs.recv().await {
+                    match job {
+                        NotificationJob::Show { device, summary, body } => {
+                            if let Err(e) = self.notifier.show(i, &device, &summary, &body).await {
+                                eprintln!("Error showing alert notification: {e}");
+                            }
+                        },
+                        NotificationJob::Close { device } => {
+                            if let Err(e) = self.notifier.close(i, &device).await {
+                                eprintln!("Error closing alert notification: {e}");
+                            }
+                        }
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            return pending().await
+        }
+        join_all(rules).await;
+    }
+}
+
+/// Whether the given event shows that AC power has returned: that is, that a line power device
+/// has gone online or a battery has started charging.
+fn power_returned(event: &DeviceEvent) -> bool {
+    matches!(event.get(&PropertyKind::Online), Some(Property::Online(true)))
+        || matches!(event.get(&PropertyKind::State), Some(Property::State(STATE_CHARGING)))
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for AlertWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        self.inner.write(event).await?;
+        if self.rules.is_empty() {
+            return Ok(())
+        }
+        if power_returned(event) {
+            if let Some(action) = self.actions.cancel().await {
+                self.inner.write_marker(&format!("{ACTION_CANCELLED_MARKER} {action}")).await?;
+            }
+        }
+        let now = Instant::now();
+        let mut fired = vec!();
+        // The rules with notifications whose firing conditions no longer hold.
+        let mut cleared = vec!();
+        {
+            let mut states = self.states.lock().await;
+            let device = states.entry(event.device.clone())
+                .or_insert_with(|| DeviceAlerts {
+                    values: HashMap::new(),
+                    states: self.rules.iter().map(|_| AlertState::default()).collect()
+                });
+            for (k, v) in event.iter() {
+                device.values.insert(k.clone(), v.clone());
+            }
+            // Whether an earlier rule whose firing condition holds prevents later rules firing.
+            let mut stopped = false;
+            for (i, (rule, state)) in self.rules.iter().zip(device.states.iter_mut()).enumerate() {
+                if state.update(rule, &device.values, now) && !stopped {
+                    fired.push((i, rule));
+                }
+                let holds = rule.fire.eval(&device.values);
+                if (rule.stop || self.matching == AlertMatch::First) && holds {
+                    stopped = true;
+                }
+                if rule.notify && !holds {
+                    cleared.push(i);
+                }
+            }
+        }
+        for i in cleared {
+            self.queue_notification(i, NotificationJob::Close { device: event.device.clone() });
+        }
+        let time = Local::now().time();
+        for (i, rule) in fired {
+            if rule.is_quiet(time) {
+                self.inner
+                    .write_marker(&format!("{QUIET_ALERT_MARKER} {} {}", event.device, rule.spec))
+                    .await?;
+                continue
+            }
+            self.inner.write_marker(&format!("{ALERT_MARKER} {} {}", event.device, rule.spec))
+                .await?;
+            if rule.notify {
+                self.queue_notification(i, NotificationJob::Show {
+                    device: event.device.clone(),
+                    summary: rule.spec.clone(),
+                    body: event.alias.clone().unwrap_or_else(|| event.device.clone())
+                });
+            }
+            if let Some(sound) = &rule.sound {
+                if let Err(e) = self.player.play(sound) {
+                    eprintln!("Error playing alert sound: {e}");
+                }
+            }
+            if let Some(action) = rule.action {
+                if self.actions.schedule(action, rule.delay).await {
+                    self.inner.write_marker(&format!(
+                        "{ACTION_PENDING_MARKER} {action} {} {}",
+                        event.device,
+                        rule.delay.as_secs()
+                    )).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use chrono::NaiveTime;
+    use crate::action::Action;
+    use crate::alert::{
+        AlertMatch, AlertRule, AlertState, AlertWriter, NotificationJob, NOTIFICATION_QUEUE
+    };
+    use crate::event::DeviceEvent;
+    use crate::expr::Expr;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::sound::Sound;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{self, Online, Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Build a map of property values containing only the given percentage.
+    fn percentage(p: f64) -> HashMap<PropertyKind, Property> {
+        let mut values = HashMap::new();
+        values.insert(PropertyKind::Percentage, Percentage(p));
+        values
+    }
+
+    /// Test parsing of alert rules.
+    #[test]
+    fn parse_rules() {
+        let rule = AlertRule::parse("Percentage <= 15, reset >= 20, cooldown=300").unwrap();
+        assert_eq!(rule, AlertRule {
+            spec: String::from("Percentage<=15"),
+            fire: Expr::parse("Percentage <= 15").unwrap(),
+            reset: Some(Expr::parse("Percentage >= 20").unwrap()),
+            cooldown: Duration::from_secs(300),
+            action: None,
+            delay: Duration::ZERO,
+            sound: None,
+            priority: 0,
+            stop: false,
+            quiet: vec!(),
+            notify: false
+        });
+        let action = AlertRule::parse("Percentage <= 5, action=suspend, delay=60").unwrap();
+        assert_eq!(action.action, Some(Action::Suspend));
+        assert_eq!(action.delay, Duration::from_secs(60));
+        let bell = AlertRule::parse("Percentage <= 10, sound").unwrap();
+        assert_eq!(bell.sound, Some(Sound::Bell));
+        let file = AlertRule::parse("Percentage <= 10, sound=/usr/share/sounds/low.oga").unwrap();
+        assert_eq!(file.sound, Some(Sound::File(String::from("/usr/share/sounds/low.oga"))));
+        let stop = AlertRule::parse("Percentage <= 5, priority=10, stop").unwrap();
+        assert_eq!((stop.priority, stop.stop), (10, true));
+        assert!(AlertRule::parse("Percentage <= 15, notify").unwrap().notify);
+        let quiet = AlertRule::parse("Percentage <= 15, quiet=22:00-07:00, quiet=12:00-13:00")
+            .unwrap();
+        let time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+        assert!(quiet.is_quiet(time("03:00")));
+        assert!(quiet.is_quiet(time("22:00")));
+        assert!(!quiet.is_quiet(time("07:00")));
+        assert!(quiet.is_quiet(time("12:30")));
+        assert!(!quiet.is_quiet(time("18:00")));
+        let simple = AlertRule::parse("State==2").unwrap();
+        assert_eq!(simple.fire, Expr::parse("State == 2").unwrap());
+        assert_eq!(simple.reset, None);
+        assert_eq!(simple.cooldown, Duration::ZERO);
+        let compound = AlertRule::parse(
+            "State == Discharging && Percentage < 10, reset State == Charging"
+        ).unwrap();
+        assert_eq!(compound.spec, "State==Discharging&&Percentage<10");
+        assert_eq!(compound.reset, Some(Expr::parse("State == Charging").unwrap()));
+        assert_eq!(
+            compound.properties(),
+            vec!(&PropertyKind::State, &PropertyKind::Percentage, &PropertyKind::State)
+        );
+
+        assert!(AlertRule::parse("<=15").is_err());
+        assert!(AlertRule::parse("State == Discharging && Percentage < 10,reset>=20").is_err());
+        assert!(AlertRule::parse("Percentage<=low").is_err());
+        assert!(AlertRule::parse("Percentage<=15,bogus").is_err());
+        assert!(AlertRule::parse("Percentage<=15,cooldown=soon").is_err());
+        assert!(AlertRule::parse("Percentage<=15,action=reboot").is_err());
+        assert!(AlertRule::parse("Percentage<=15,delay=60").is_err());
+        assert!(AlertRule::parse("Percentage<=15,priority=high").is_err());
+        assert!(AlertRule::parse("Percentage<=15,quiet=22:00").is_err());
+        assert!(AlertRule::parse("Percentage<=15,quiet=25:00-07:00").is_err());
+    }
+
+    /// Test that alerts with hysteresis do not fire again until reset.
+    #[test]
+    fn hysteresis() {
+        let rule = AlertRule::parse("Percentage<=15,reset>=20").unwrap();
+        let mut state = AlertState::default();
+        let now = Instant::now();
+        let fired: Vec<bool> = [16.0, 15.0, 14.0, 16.0, 15.0, 19.0, 20.0, 15.0].iter()
+            .map(|v| state.update(&rule, &percentage(*v), now))
+            .collect();
+        assert_eq!(fired, vec!(false, true, false, false, false, false, false, true));
+    }
+
+    /// Test that alerts do not fire again within the cooldown period.
+    #[test]
+    fn cooldown() {
+        let rule = AlertRule::parse("Percentage<=15,cooldown=60").unwrap();
+        let mut state = AlertState::default();
+        let start = Instant::now();
+        assert!(state.update(&rule, &percentage(15.0), start));
+        assert!(!state.update(&rule, &percentage(16.0), start + Duration::from_secs(10)));
+        assert!(!state.update(&rule, &percentage(15.0), start + Duration::from_secs(20)));
+        assert!(state.update(&rule, &percentage(14.0), start + Duration::from_secs(61)));
+    }
+
+    /// Test that alert markers are written after the changes which caused them.
+    #[test]
+    fn alert_writer() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = AlertWriter::new(inner, vec!(AlertRule::parse("Percentage<15").unwrap()));
+        for p in [15.0, 14.0, 13.0] {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(p));
+            block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        }
+        assert_eq!(
+            buf.contents(),
+            "/dev Percentage=15\n/dev Percentage=14\nAlert /dev Percentage<15\n/dev Percentage=13\n"
+        );
+    }
+
+    /// Test that notifications are queued rather than shown while changes are written, and that
+    /// they are dropped once too many are waiting.
+    #[test]
+    fn queued_notifications() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = AlertWriter::new(
+            inner,
+            vec!(AlertRule::parse("Percentage<15, notify").unwrap())
+        );
+        for p in [10.0, 50.0].repeat(NOTIFICATION_QUEUE) {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(p));
+            block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        }
+        assert_eq!(buf.contents().matches("Alert /dev").count(), NOTIFICATION_QUEUE);
+        let jobs = &writer.notifications[0].1;
+        assert_eq!(jobs.len(), NOTIFICATION_QUEUE);
+        assert!(matches!(
+            block_on(jobs.recv()).unwrap(),
+            NotificationJob::Show { device, .. } if device == "/dev"
+        ));
+    }
+
+    /// Test that alerts whose conditions refer to several properties use the latest value of each.
+    #[test]
+    fn compound_alert() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = AlertWriter::new(
+            inner,
+            vec!(AlertRule::parse("State==Discharging && Percentage<15").unwrap())
+        );
+        for (k, v) in [
+            ("Percentage", Percentage(10.0)),
+            ("State", State(2)),
+            ("Percentage", Percentage(9.0))
+        ] {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::from_name(k), v);
+            block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        }
+        assert_eq!(
+            buf.contents(),
+            "/dev Percentage=10\n/dev State=Discharging\n\
+             Alert /dev State==Discharging&&Percentage<15\n/dev Percentage=9\n"
+        );
+    }
+
+    /// Test that rules are checked in order of priority, and that later rules do not fire while an
+    /// earlier rule with stop holds (or, when only the first match may fire, any earlier rule).
+    #[test]
+    fn rule_chaining() {
+        let alerts = |rules: &[&str], matching: AlertMatch| {
+            let buf = SharedBuffer::default();
+            let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            let rules = rules.iter().map(|r| AlertRule::parse(r).unwrap()).collect();
+            let writer = AlertWriter::new(inner, rules).with_match(matching);
+            block_on(async {
+                for p in [16.0, 4.0, 10.0, 20.0, 14.0] {
+                    let change = (PropertyKind::Percentage, Percentage(p));
+                    writer.write(&DeviceEvent::new("/dev", vec!(change))).await.unwrap();
+                }
+            });
+            buf.contents().lines()
+                .filter_map(|l| l.strip_prefix("Alert /dev "))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            alerts(&["Percentage<=15", "Percentage<=5,priority=1,stop"], AlertMatch::All),
+            vec!("Percentage<=5", "Percentage<=15")
+        );
+        assert_eq!(
+            alerts(&["Percentage<=5", "Percentage<=15"], AlertMatch::All),
+            vec!("Percentage<=5", "Percentage<=15", "Percentage<=15")
+        );
+        assert_eq!(
+            alerts(&["Percentage<=5", "Percentage<=15"], AlertMatch::First),
+            vec!("Percentage<=5", "Percentage<=15")
+        );
+    }
+
+    /// Test that an alert's action is scheduled when it fires, and cancelled when AC power
+    /// returns.
+    #[test]
+    fn alert_action() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = AlertWriter::new(
+            inner,
+            vec!(AlertRule::parse("Percentage<=5,action=hibernate,delay=30").unwrap())
+        );
+        block_on(async {
+            for (device, change) in [
+                ("/bat", (PropertyKind::Percentage, Percentage(5.0))),
+                ("/ac", (PropertyKind::Online, Online(true))),
+                ("/ac", (PropertyKind::Online, Online(true)))
+            ] {
+                writer.write(&DeviceEvent::new(device, vec!(change))).await.unwrap();
+            }
+        });
+        assert_eq!(
+            buf.contents(),
+            "/bat Percentage=5\nAlert /bat Percentage<=5\nActionPending hibernate /bat 30\n\
+             /ac Online=true\nActionCancelled hibernate\n/ac Online=true\n"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use futures::future::ready;
+use futures::stream::{select, StreamExt};
+use zbus::{Connection, Error, Result as zbus_Result, fdo::{ManagedObjects, ObjectManagerProxy}};
+use crate::connect::with_method_timeout;
+use crate::upower::{DeviceConfig, DeviceSet};
+
+/// The well-known bus name of the BlueZ service.
+const BLUEZ_SERVICE: &str = "org.bluez";
+
+/// The BlueZ interface exposing a Bluetooth device's battery level.
+pub(crate) const BATTERY_INTERFACE: &str = "org.bluez.Battery1";
+
+/// Return the (sorted) paths of all objects implementing [`BATTERY_INTERFACE`].
+fn battery_paths(objects: &ManagedObjects) -> Vec<String> {
+    let mut paths = objects.iter()
+        .filter(|(_, ifaces)| ifaces.keys().any(|i| i.as_str() == BATTERY_INTERFACE))
+        .map(|(p, _)| p.to_string())
+        .collect::<Vec<String>>();
+    paths.sort();
+    paths
+}
+
+/// Return a [`DeviceConfig`] monitoring the `Percentage` property of the BlueZ device at `path`.
+fn battery_config(path: &str) -> zbus_Result<DeviceConfig> {
+    DeviceConfig::new(path, "Percentage", Some(BATTERY_INTERFACE))
+        .map(|c| c.with_service(BLUEZ_SERVICE))
+        .map_err(|e| Error::Failure(format!("Could not create configuration for {path}: {e}")))
+}
+
+/// Return a proxy for BlueZ's object manager.
+async fn object_manager(conn: &Connection) -> zbus_Result<ObjectManagerProxy<'_>> {
+    ObjectManagerProxy::builder(conn)
+        .destination(BLUEZ_SERVICE)?
+        .path("/")?
+        .build()
+        .await
+}
+
+/// Return the paths of all Bluetooth devices currently known to BlueZ which report their battery
+/// level.
+async fn current_battery_paths(proxy: &ObjectManagerProxy<'_>) -> zbus_Result<Vec<String>> {
+    let objects = with_method_timeout("GetManagedObjects", async {
+        Ok(proxy.get_managed_objects().await?)
+    }).await?;
+    Ok(battery_paths(&objects))
+}
+
+/// Discover all Bluetooth devices currently known to BlueZ which report their battery level, and
+/// return a [`DeviceConfig`] monitoring the `Percentage` property of each.
+pub(crate) async fn discover_batteries(conn: &Connection) -> zbus_Result<Vec<DeviceConfig>> {
+    let proxy = object_manager(conn).await?;
+    current_battery_paths(&proxy).await?.iter().map(|p| battery_config(p)).collect()
+}
+
+/// Watch for Bluetooth devices which report their battery level being added to or removed from
+/// BlueZ, and add them to or remove them from `devices`. Devices which are already in `devices`
+/// (such as those found by [`discover_batteries`]) are not added again. Each added device's
+/// configuration is set to print the signals it receives if `debug_signals` is true.
+pub(crate) async fn watch_batteries(
+    conn: &Connection,
+    devices: &DeviceSet,
+    debug_signals: bool
+) -> zbus_Result<()> {
+    let proxy = object_manager(conn).await?;
+    // Whether a device was added (`true`) or removed, and its path.
+    let added = proxy.receive_interfaces_added().await?.filter_map(|signal| ready(
+        signal.args().ok()
+            .filter(|a| a.interfaces_and_properties().contains_key(BATTERY_INTERFACE))
+            .map(|a| (true, a.object_path().to_string()))
+    ));
+    let removed = proxy.receive_interfaces_removed().await?.filter_map(|signal| ready(
+        signal.args().ok()
+            .filter(|a| a.interfaces().contains(&BATTERY_INTERFACE))
+            .map(|a| (false, a.object_path().to_string()))
+    ));
+    let mut changes = select(added, removed);
+    // Devices added between discovery and subscribing to the signals are added now.
+    for path in current_battery_paths(&proxy).await? {
+        let _ = devices.add(battery_config(&path)?.with_debug_signals(debug_signals)).await;
+    }
+    while let Some((added, path)) = changes.next().await {
+        // Devices already in the set are not added again, and devices which are not in it need
+        // not be removed, so errors are ignored.
+        let _ = match added {
+            true => devices.add(battery_config(&path)?.with_debug_signals(debug_signals)).await,
+            false => devices.remove(&path).await
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use futures::try_join;
+    use zbus::{dbus_interface, ConnectionBuilder, Guid};
+    use zbus::fdo::{ManagedObjects, ObjectManager};
+    use zbus::names::OwnedInterfaceName;
+    use zbus::zvariant::OwnedObjectPath;
+    use crate::bluez::{battery_paths, watch_batteries, BATTERY_INTERFACE};
+    use crate::rt::{block_on, bus_stream_pair, sleep, timeout};
+    use crate::testing::run_until;
+    use crate::upower::DeviceSet;
+
+    /// A mock implementation of BlueZ's battery interface.
+    struct MockBattery;
+
+    #[dbus_interface(name = "org.bluez.Battery1")]
+    impl MockBattery {
+        #[dbus_interface(property)]
+        fn percentage(&self) -> u8 {
+            50
+        }
+    }
+
+    /// Wait until the devices in `devices` are those at `expected`, failing the test if they are
+    /// not within five seconds.
+    async fn wait_for_devices(devices: &DeviceSet, expected: &[&str]) {
+        let current = || async {
+            devices.configs().await.iter().map(|c| c.device()).collect::<Vec<_>>()
+        };
+        let _ = timeout(Duration::from_secs(5), async {
+            while current().await != expected {
+                sleep(Duration::from_millis(5)).await;
+            }
+        }).await;
+        assert_eq!(current().await, expected);
+    }
+
+    /// Test that only objects implementing the battery interface are selected.
+    #[test]
+    fn find_battery_paths() {
+        let mut objects: ManagedObjects = HashMap::new();
+        let device = "org.bluez.Device1";
+        let objs = [
+            ("/org/bluez/hci0", vec!("org.bluez.Adapter1")),
+            ("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF", vec!(device, BATTERY_INTERFACE)),
+            ("/org/bluez/hci0/dev_00_11_22_33_44_55", vec!(device, BATTERY_INTERFACE)),
+            ("/org/bluez/hci0/dev_66_77_88_99_AA_BB", vec!(device))
+        ];
+        for (path, ifaces) in objs {
+            objects.insert(
+                OwnedObjectPath::try_from(path).unwrap(),
+                ifaces.into_iter()
+                    .map(|i| (OwnedInterfaceName::try_from(i).unwrap(), HashMap::new()))
+                    .collect()
+            );
+        }
+        assert_eq!(
+            battery_paths(&objects),
+            vec!("/org/bluez/hci0/dev_00_11_22_33_44_55", "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF")
+        );
+    }
+
+    /// Test that batteries which BlueZ adds and removes after discovery are added to and removed
+    /// from the set of monitored devices.
+    #[test]
+    fn watch_added_batteries() {
+        let first = "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF";
+        let second = "/org/bluez/hci0/dev_00_11_22_33_44_55";
+        block_on(async {
+            let (server_stream, client_stream) = bus_stream_pair().unwrap();
+            let guid = Guid::generate();
+            let server = ConnectionBuilder::unix_stream(server_stream)
+                .server(&guid)
+                .p2p()
+                .serve_at("/", ObjectManager).unwrap()
+                .serve_at(first, MockBattery).unwrap()
+                .build();
+            let client = ConnectionBuilder::unix_stream(client_stream).p2p().build();
+            let (server, client) = try_join!(server, client).unwrap();
+            let devices = DeviceSet::default();
+            run_until(watch_batteries(&client, &devices, false), async {
+                wait_for_devices(&devices, &[first]).await;
+                server.object_server().at(second, MockBattery).await.unwrap();
+                wait_for_devices(&devices, &[first, second]).await;
+                server.object_server().remove::<MockBattery, _>(first).await.unwrap();
+                wait_for_devices(&devices, &[second]).await;
+            }).await;
+        });
+    }
+}
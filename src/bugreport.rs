@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use serde::Serialize;
+use zbus::{Connection, Result as zbus_Result};
+use zbus::zvariant::OwnedObjectPath;
+use crate::connect::with_method_timeout;
+use crate::record::{read_events, RecordedEvent};
+use crate::redact::REDACTED;
+use crate::upower::{daemon_version, DeviceConfig, UPOWER_PATH, UPOWER_SERVICE};
+
+/// The number of the most recently recorded events included in a bug report.
+const RECENT_EVENTS: usize = 100;
+
+/// Everything needed to reproduce a problem with upmon, written as a single JSON file to be
+/// attached to an issue.
+#[derive(Debug, Serialize)]
+pub struct BugReport {
+    /// The version of upmon.
+    upmon_version: &'static str,
+    /// The fully resolved configuration, as printed by `--dry-run`.
+    config: serde_json::Value,
+    /// The version of the UPower daemon, if it could be fetched.
+    daemon_version: Option<String>,
+    /// Every property of each monitored device and each device known to UPower, by device path.
+    devices: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+    /// The most recent events in the file given to `--record`, if any.
+    events: Vec<RecordedEvent>
+}
+
+impl BugReport {
+    /// Create a new [`BugReport`] for the given configuration, without any information from DBus.
+    pub(crate) fn new(config: serde_json::Value) -> Self {
+        Self {
+            upmon_version: env!("CARGO_PKG_VERSION"),
+            config,
+            daemon_version: None,
+            devices: BTreeMap::new(),
+            events: vec!()
+        }
+    }
+
+    /// Collect the UPower daemon's version and the properties of the given devices and of every
+    /// device known to UPower. Anything which cannot be fetched is left out, with a warning.
+    pub(crate) async fn collect(&mut self, conn: &Connection, monitored: &[DeviceConfig]) {
+        match daemon_version(conn).await {
+            Ok(version) => self.daemon_version = version,
+            Err(e) => eprintln!("Warning: could not fetch the UPower daemon's version: {e}")
+        }
+        let mut devices = monitored.to_vec();
+        match enumerate_devices(conn).await {
+            Ok(paths) => devices.extend(paths.iter()
+                .filter(|p| !monitored.iter().any(|c| c.is_for(p.as_str())))
+                .filter_map(|p| DeviceConfig::new(p.as_str(), "*", None).ok())),
+            Err(e) => eprintln!("Warning: could not enumerate UPower devices: {e}")
+        }
+        for device in devices {
+            match device.discover(conn).await {
+                Ok(Some(properties)) => {
+                    self.devices.insert(
+                        String::from(device.path()),
+                        properties.into_iter().map(|p| (p.name, p.value.to_json())).collect()
+                    );
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    eprintln!("Warning: could not fetch properties of {}: {e}", device.path())
+                }
+            }
+        }
+    }
+
+    /// Include the most recent events recorded in the file at `path`.
+    pub(crate) fn add_recorded(&mut self, path: &str) {
+        match read_events(path) {
+            Ok(mut events) => {
+                self.events = events.split_off(events.len().saturating_sub(RECENT_EVENTS))
+            },
+            Err(e) => eprintln!("Warning: could not read recorded events: {e}")
+        }
+    }
+
+    /// Replace the value of each of the named properties of each device with [`REDACTED`], and
+    /// remove them from recorded events.
+    pub(crate) fn redact(&mut self, names: &[&str]) {
+        for properties in self.devices.values_mut() {
+            for (name, value) in properties.iter_mut() {
+                if names.contains(&name.as_str()) {
+                    *value = serde_json::Value::from(REDACTED);
+                }
+            }
+        }
+        for event in &mut self.events {
+            event.changed.retain(|name, _| !names.contains(&name.as_str()));
+        }
+    }
+
+    /// Write the bug report as pretty-printed JSON to the file at `path`.
+    pub(crate) fn write(&self, path: &str) -> Result<(), std::io::Error> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+}
+
+/// Ask UPower for the paths of all the devices it knows about.
+async fn enumerate_devices(conn: &Connection) -> zbus_Result<Vec<OwnedObjectPath>> {
+    with_method_timeout("EnumerateDevices", conn.call_method(
+        Some(UPOWER_SERVICE),
+        UPOWER_PATH,
+        Some("org.freedesktop.UPower"),
+        "EnumerateDevices",
+        &()
+    )).await?.body()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::bugreport::BugReport;
+    use crate::rt::block_on;
+    use crate::testing::{MockUPower, MOCK_DEVICE_PATH};
+    use crate::upower::DeviceConfig;
+
+    /// Test that the properties of monitored devices are collected, and that properties are
+    /// redacted when asked.
+    #[test]
+    fn bug_report() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let device = DeviceConfig::new(MOCK_DEVICE_PATH, "Percentage", None).unwrap();
+            let mut report = BugReport::new(serde_json::json!({ "devices": [] }));
+            report.collect(&upower.client, &[device]).await;
+            report.devices.get_mut(MOCK_DEVICE_PATH).unwrap()
+                .insert(String::from("Serial"), serde_json::Value::from("1234"));
+            report.redact(&["Serial"]);
+            let json = serde_json::to_value(&report).unwrap();
+            assert_eq!(json["upmon_version"], env!("CARGO_PKG_VERSION"));
+            assert_eq!(json["config"], serde_json::json!({ "devices": [] }));
+            assert_eq!(json["devices"][MOCK_DEVICE_PATH]["NativePath"], "MOCK");
+            assert_eq!(json["devices"][MOCK_DEVICE_PATH]["Serial"], "<redacted>");
+            assert_eq!(json["events"], serde_json::json!([]));
+        });
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use serde_json::{Map, Value};
+use crate::event::DeviceEvent;
+use crate::upower::{Property, PropertyKind};
+
+/// The latest value of each property of each device, updated from each [`DeviceEvent`] written.
+/// Devices are kept in the order in which they were first seen, so that writers which describe
+/// every device at once (such as [`crate::summary::SummaryWriter`]) do so in a stable order.
+#[derive(Debug, Default)]
+pub struct ValueCache {
+    /// Each device's path and the latest value of each of its properties.
+    devices: Vec<(String, HashMap<PropertyKind, Property>)>
+}
+
+impl ValueCache {
+    /// Update the cache with the changes in `event`, returning the latest values of the event's
+    /// device.
+    pub(crate) fn update(&mut self, event: &DeviceEvent) -> &HashMap<PropertyKind, Property> {
+        let i = match self.devices.iter().position(|(d, _)| *d == event.device) {
+            Some(i) => i,
+            None => {
+                self.devices.push((event.device.clone(), HashMap::new()));
+                self.devices.len() - 1
+            }
+        };
+        let values = &mut self.devices[i].1;
+        for (k, v) in event.iter() {
+            values.insert(k.clone(), v.clone());
+        }
+        values
+    }
+
+    /// Iterate over each device and the latest values of its properties, in the order in which the
+    /// devices were first seen.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &HashMap<PropertyKind, Property>)> {
+        self.devices.iter().map(|(d, v)| (d.as_str(), v))
+    }
+
+    /// Return the latest value of each property of each device, as a JSON object keyed by device
+    /// path and then by property name.
+    pub(crate) fn to_json(&self) -> Value {
+        Value::Object(self.devices.iter()
+            .map(|(d, values)| (
+                d.clone(),
+                Value::Object(values.iter()
+                    .map(|(k, v)| (String::from(k.as_str()), v.to_json()))
+                    .collect::<Map<_, _>>())
+            ))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+    use crate::cache::ValueCache;
+    use crate::event::DeviceEvent;
+    use crate::upower::Property::{Online, Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that the latest value of each property is kept, and that devices are kept in the order
+    /// in which they were first seen.
+    #[test]
+    fn value_cache() {
+        let mut cache = ValueCache::default();
+        cache.update(&DeviceEvent::new("/bat", vec!(
+            (PropertyKind::Percentage, Percentage(80.0)),
+            (PropertyKind::State, State(2))
+        )));
+        cache.update(&DeviceEvent::new("/ac", vec!((PropertyKind::Online, Online(false)))));
+        let latest = cache.update(&DeviceEvent::new("/bat", vec!(
+            (PropertyKind::Percentage, Percentage(79.0))
+        )));
+        assert_eq!(latest.get(&PropertyKind::Percentage), Some(&Percentage(79.0)));
+        assert_eq!(latest.get(&PropertyKind::State), Some(&State(2)));
+        assert_eq!(cache.iter().map(|(d, _)| d).collect::<Vec<_>>(), vec!("/bat", "/ac"));
+        assert_eq!(
+            cache.to_json(),
+            json!({"/bat": {"Percentage": 79.0, "State": "Discharging"}, "/ac": {"Online": false}})
+        );
+    }
+}
@@ -0,0 +1,1990 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::pin::pin;
+use std::slice;
+use std::time::Duration;
+use futures::future::{pending, select, Either};
+use futures::join;
+use clap::{crate_version, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use zbus::Connection;
+use crate::aggregate::AggregateWriter;
+use crate::alert::{AlertMatch, AlertRule, AlertWriter};
+use crate::daemon::Daemon;
+use crate::diff::{diff, read_snapshot};
+use crate::exit::ExitStatus;
+use crate::connect::{connect_system, set_method_timeout, Bus, DEFAULT_METHOD_TIMEOUT_MS};
+use crate::control::{bind_control_socket, ControlCommand, ControlWriter, serve_control};
+#[cfg(feature = "compress")]
+use crate::compress::Compression;
+use crate::exec::{DEFAULT_EXEC_JOBS, ExecWriter};
+use crate::names::{DeviceName, DeviceNames, DeviceNameWriter};
+use crate::redact::{Redacted, RedactWriter};
+use crate::host::{HostField, HostInfo, HostWriter};
+use crate::ratelimit::{RateLimit, RateLimitOverflow, RateLimitWriter};
+use crate::expr::Expr;
+use crate::event::{EVENT_SCHEMA, SCHEMA_VERSION};
+use crate::filter::{FilteredWriter, PropertyFilterWriter, Sink, SinkFilter};
+use crate::metrics::{MetricProtocol, MetricsWriter, Transport};
+use crate::http::HttpWriter;
+use crate::instance::{ExistingInstance, InstanceLock, PidFile, terminated};
+use crate::glyph::{GlyphWriter, Glyphs, NERD_RAMP};
+use crate::latency::LatencyWriter;
+use crate::lifecycle::{write_lifecycle, Lifecycle};
+use crate::locale::Locale;
+use crate::numeric::NumericEnumWriter;
+use crate::osd::OsdWriter;
+use crate::output::{FileMode, FileOptions, FormatWriter, open_output, TeeWriter, Writer};
+use crate::plugin::PluginWriter;
+use crate::queue::{Overflow, QueueWriter};
+use crate::registry::{OutputSpec, WriterOptions, WriterRegistry};
+use crate::bluez::discover_batteries;
+use crate::record::{read_events, replay, Recorder};
+use crate::bugreport::BugReport;
+use crate::report::ReportWriter;
+use crate::retry::RetryPolicy;
+use crate::rt::TcpListener;
+use crate::service::{DEFAULT_SERVICE_NAME, ServiceWriter};
+use crate::session::SessionWriter;
+use crate::sound::DEFAULT_SOUND_PLAYER;
+use crate::stale::StaleWriter;
+use crate::stats::StatsWriter;
+use crate::threshold::ThresholdWriter;
+use crate::throttle::Throttle;
+use crate::smooth::SmoothingWriter;
+use crate::quantize::QuantizeWriter;
+use crate::severity::{
+    RouteTarget, SeverityBands, SeverityColors, SeverityRoute, SeverityRouteWriter, SeverityWriter
+};
+use crate::until::UntilWriter;
+use crate::zabbix::ZabbixWriter;
+use crate::{bluez, critical, health, i3bar, info, lifecycle, logind, rt, stats, udev};
+#[cfg(feature = "otel")]
+use crate::otel;
+#[cfg(feature = "sqlite")]
+use crate::sqlite;
+#[cfg(feature = "tui")]
+use crate::tui;
+#[cfg(feature = "wasm")]
+use crate::wasm;
+use crate::upower::{
+    ConfigError, daemon_version, DeviceConfig, DeviceSet, DISPLAY_DEVICE_PATH, expand_device_path,
+    Property, PropertyKind, required_version, RulesFormat, UpdateTimeFormat, upower_available
+};
+
+/// Formats in which informational output (such as the list of supported properties) can be
+/// printed.
+#[derive(Clone, Copy, ValueEnum)]
+enum InfoFormat {
+    /// Plain text, one item per line.
+    Text,
+    /// A JSON document containing more detailed information.
+    Json
+}
+
+/// Sources from which upmon can receive changes to device properties.
+#[derive(Clone, Copy, ValueEnum)]
+enum Backend {
+    /// Listen for PropertiesChanged signals sent over DBus by UPower.
+    Dbus,
+    /// Listen for uevents sent over netlink by the kernel for power_supply devices. This does not
+    /// require UPower (or DBus) to be running, but only the Percentage, State, Online and IsPresent
+    /// properties are available.
+    Udev
+}
+
+/// Formats in which changes can be output.
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// All changed properties of a device on a single line, as described by --separator and
+    /// --delimiter.
+    Line,
+    /// One JSON object per line for each change or marker, in the same form as the events served
+    /// over HTTP.
+    Json,
+    /// A single line summarising the latest state of every device (such as "AC=online BAT0=85%↓
+    /// 2:10"), written again on each change.
+    Summary,
+    /// The i3bar protocol, with one block per device, so that upmon can be used as the
+    /// status_command of i3bar or swaybar.
+    I3bar,
+    /// Like summary, but with each device escaped and coloured by its Severity using polybar's
+    /// formatting tags.
+    Polybar,
+    /// Like summary, but with each device escaped and coloured by its Severity using lemonbar's
+    /// formatting tags.
+    Lemonbar,
+    /// One line per changed property in the input format of zabbix_sender ("host key timestamp
+    /// value"), or sent directly to a Zabbix server if --zabbix-server is given.
+    Zabbix,
+    /// StatsD gauges, one per changed numeric property, sent to --metrics-address if given.
+    Statsd,
+    /// Graphite plaintext metrics, one per changed numeric property, sent to --metrics-address if
+    /// given.
+    Graphite,
+    /// OpenTelemetry gauges for the percentage, energy rate and time estimates of each device,
+    /// pushed to the collector at --otel-endpoint.
+    #[cfg(feature = "otel")]
+    Otel,
+    /// Rows appended to the SQLite database at --output-file (which is created if it does not
+    /// exist), one per changed property.
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    /// Each change or marker as formatted by the format function of the module given by
+    /// --wasm-module.
+    #[cfg(feature = "wasm")]
+    Wasm
+}
+
+/// Subcommands which do something other than monitor devices for changes.
+#[derive(Subcommand)]
+enum Command {
+    /// Replay events previously recorded with --record, writing any changes to the properties
+    /// configured with --path as if they had just been received.
+    Replay {
+        /// Path to the file containing the recorded events.
+        file: String,
+        /// Factor by which to speed up replay relative to the original timing. A value of 0 replays
+        /// all events without delay.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64
+    },
+    /// Compare two snapshots of device state (saved from GET /state or the dump-state control
+    /// command) and write the properties whose values differ as changes, as if they had just been
+    /// received. Only the devices configured with --path are compared, if any are.
+    Diff {
+        /// Path to the earlier snapshot.
+        old: String,
+        /// Path to the later snapshot.
+        new: String
+    },
+    /// Print the JSON Schema describing events and markers as written in the JSON output format,
+    /// served over HTTP and passed to plugins, and exit.
+    Schema
+}
+
+/// Command line app to monitor UPower devices over DBus for changes to certain properties, and
+/// output a summary of those changes in an easily parsable format.
+#[derive(Parser)]
+#[command(
+    about,
+    version = crate_version!(),
+    after_help = "Signals: SIGUSR1 writes upmon's counters to standard error, and SIGUSR2 pauses \
+        or resumes output (without interrupting monitoring)."
+)]
+struct CliArgs {
+    /// Specify a single device path to monitor. This can be specified multiple times. The path must
+    /// be to a device that implements the org.freedesktop.UPower.Device interface. The first
+    /// parameter is the path to the device and the second is a comma-delimited list of properties
+    /// to monitor, "*" to monitor every property the device exposes, or "default" to monitor the
+    /// default properties for the type of device (such as Online for line power). The path and
+    /// properties can also be given as a single parameter, separated by a colon (as in
+    /// "PATH:PROPERTIES"); a path given alone monitors the default properties, and properties
+    /// given alone are monitored on the display device. Instead of a full path, the name of a
+    /// UPower device (such as battery_BAT0 or DisplayDevice) can be given.
+    #[arg(short, long, num_args = 1..=2, value_names = ["PATH", "PROPERTIES"])]
+    path: Vec<String>,
+    /// The arguments given to each occurrence of --path, which clap flattens into `path`.
+    #[arg(skip)]
+    path_args: Vec<Vec<String>>,
+    /// Monitor the properties of the given DBus interface (such as org.bluez.Battery1) on the
+    /// device paths, rather than those of org.freedesktop.UPower.Device. Any property names may
+    /// then be given, and values are output without any special formatting.
+    #[arg(short, long)]
+    interface: Option<String>,
+    /// Also monitor the battery level (the Percentage property of org.bluez.Battery1) of every
+    /// Bluetooth device known to BlueZ that reports one, including devices which BlueZ finds
+    /// later.
+    #[arg(short, long)]
+    bluez: bool,
+    /// Print the list of properties that upmon can monitor and exit. If "json" is given, each
+    /// property's DBus type, possible values, units and description are also printed.
+    #[arg(short, long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "text")]
+    list_properties: Option<InfoFormat>,
+    /// Print every property exposed by the device at the given path (on the interface given by
+    /// --interface, if any) with its DBus type and current value, and exit. This can be specified
+    /// multiple times.
+    #[arg(long, value_name = "PATH")]
+    discover: Vec<String>,
+    /// Print the exit codes used by upmon and their meanings, and exit.
+    #[arg(long)]
+    help_exit_codes: bool,
+    /// Path to file to write output to. If not provided, output is written to standard output.
+    #[arg(short, long)]
+    output_file: Option<String>,
+    /// Compress the output file as it is written. Flush points are written every few seconds (if
+    /// anything has been written) and after each marker, so that the file can be read while upmon
+    /// is still running; the stream is finished when upmon exits.
+    #[cfg(feature = "compress")]
+    #[arg(long, value_enum, requires = "output_file")]
+    compress: Option<Compression>,
+    /// Truncate the output file when upmon starts, rather than appending to it.
+    #[arg(long, requires = "output_file")]
+    truncate: bool,
+    /// Permissions, in octal (such as 600), with which the output file is created if it does not
+    /// exist. The umask still applies. The permissions of an existing file are not changed.
+    #[arg(long, value_name = "OCTAL", requires = "output_file")]
+    output_mode: Option<FileMode>,
+    /// Format in which to output changes.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Line)]
+    format: OutputFormat,
+    /// Version of the JSON format in which to write events, for consumers which have not been
+    /// updated for the latest version. Version 1 has no schema_version, seq or alias fields (nor
+    /// those added by --annotate). This applies to JSON output only; events served over HTTP are
+    /// always in the latest version.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..=SCHEMA_VERSION as i64),
+        default_value_t = SCHEMA_VERSION
+    )]
+    output_version: u32,
+    /// Also write changes in the given format (line, json, summary, polybar or lemonbar) to the
+    /// file at the given path, or to standard output if no path is given, in the form FORMAT or
+    /// FORMAT=PATH. This can be specified multiple times.
+    #[arg(long, value_name = "FORMAT[=PATH]")]
+    extra_output: Vec<OutputSpec>,
+    /// Host name to report Zabbix items for, as configured in Zabbix. The default of "-" tells
+    /// zabbix_sender to use the host name from its configuration file.
+    #[arg(long, value_name = "HOST", default_value = "-")]
+    zabbix_host: String,
+    /// Prefix of each Zabbix item key. Keys are of the form PREFIX.PROPERTY[DEVICE], where DEVICE
+    /// is the last element of the device path.
+    #[arg(long, value_name = "PREFIX", default_value = "upmon")]
+    zabbix_key_prefix: String,
+    /// Send items directly to the Zabbix server or proxy at the given address (HOST:PORT), rather
+    /// than writing them to the output.
+    #[arg(long, value_name = "ADDRESS")]
+    zabbix_server: Option<String>,
+    /// Send StatsD or Graphite metrics to the server at the given address (HOST:PORT), rather than
+    /// writing them to the output.
+    #[arg(long, value_name = "ADDRESS")]
+    metrics_address: Option<String>,
+    /// Transport over which to send metrics to --metrics-address. Defaults to UDP for StatsD and
+    /// TCP for Graphite.
+    #[arg(long, value_enum)]
+    metrics_transport: Option<Transport>,
+    /// Prefix of each StatsD or Graphite metric name. Metrics are named PREFIX.DEVICE.PROPERTY,
+    /// where DEVICE is the last element of the device path.
+    #[arg(long, value_name = "PREFIX", default_value = "upmon")]
+    metrics_prefix: String,
+    /// Endpoint of the OpenTelemetry collector to push metrics to, using OTLP over HTTP.
+    #[cfg(feature = "otel")]
+    #[arg(long, value_name = "URL", default_value = "http://localhost:4318")]
+    otel_endpoint: String,
+    /// Serve events over HTTP at the given address (such as 127.0.0.1:8080), in addition to
+    /// writing them to the output. GET /events returns a stream of server-sent events, each a JSON
+    /// object describing a change or marker, and GET /state returns the latest value of each
+    /// monitored property of each device as JSON.
+    #[arg(long, value_name = "ADDRESS")]
+    listen_http: Option<String>,
+    /// Write each new Percentage (rounded to an integer) as a line to the FIFO at the given path
+    /// (which is created if it does not exist), for on-screen display programs such as xob or wob
+    /// to read, in addition to writing it to the output.
+    #[arg(long, value_name = "PATH")]
+    osd_fifo: Option<String>,
+    /// Run the given command using the shell and write each change or marker to its standard
+    /// input as a JSON object on its own line (in the same form as the events served over HTTP),
+    /// in addition to writing it to the output. The command is restarted if it exits.
+    #[arg(long, value_name = "COMMAND")]
+    plugin: Option<String>,
+    /// Expect the --plugin command to acknowledge each event by writing a line to its standard
+    /// output: "ok" if the event was handled, or anything else to report an error.
+    #[arg(long, requires = "plugin")]
+    plugin_acks: bool,
+    /// Run the given command using the shell for each change, with the path of the device in
+    /// UPMON_DEVICE and the new value of each changed property in UPMON_ followed by the name of
+    /// the property in upper case (such as UPMON_PERCENTAGE). Placeholders in the command are
+    /// replaced with the latest value of the property named in braces (such as "{Percentage}"), or
+    /// the device path for "{device}". The command's output and exit status are logged to standard
+    /// error. Only changes that pass any filters run the command.
+    #[arg(long, value_name = "COMMAND")]
+    exec: Option<String>,
+    /// Kill --exec commands which have not exited after the given number of seconds.
+    #[arg(long, value_name = "SECONDS", requires = "exec")]
+    exec_timeout: Option<u64>,
+    /// The maximum number of --exec commands which may run at once. Changes made while this many
+    /// commands are running do not run the command, and count as failures.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_EXEC_JOBS, requires = "exec")]
+    exec_jobs: usize,
+    /// Write a line containing "ExecFailed" followed by the device path and the reason (such as
+    /// "status=1" or "timeout") whenever an --exec command fails.
+    #[arg(long, requires = "exec")]
+    exec_failure_events: bool,
+    /// When --format is i3bar, run COMMAND using the shell whenever one of upmon's blocks is
+    /// clicked, with the path of the device in UPMON_DEVICE and the mouse button in UPMON_BUTTON.
+    #[arg(long, value_name = "COMMAND")]
+    i3bar_click: Option<String>,
+    /// Show a live dashboard of devices' current values, a sparkline of each device's Percentage
+    /// and a log of events in the terminal, instead of writing output. Press q to quit.
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with_all = ["format", "daemon"])]
+    tui: bool,
+    /// Claim the given name (by default, io.github.bunburya.upmon) on the session bus and re-emit
+    /// changes and markers as Changed and Marker signals of the io.github.bunburya.upmon interface
+    /// at /io/github/bunburya/upmon, in addition to writing them to the output. The interface's
+    /// GetState method returns the latest value of each monitored property of each device.
+    #[arg(
+        long,
+        value_name = "NAME",
+        num_args = 0..=1,
+        default_missing_value = DEFAULT_SERVICE_NAME
+    )]
+    dbus_service: Option<String>,
+    /// String used to separate each changed property from its new value in the output.
+    #[arg(short, long, default_value = "=")]
+    separator: String,
+    #[arg(short, long, default_value = " ")]
+    /// String used to delimit each changed property-value pair in the output.
+    delimiter: String,
+    /// Only write changes to the given comma-separated properties, and only when their value
+    /// differs from the last value seen for the same device (for example, when State changes from
+    /// Charging to Discharging).
+    #[arg(long, value_name = "PROPERTIES", value_delimiter = ',')]
+    on_transition: Option<Vec<String>>,
+    /// Only write changes for a device when the given condition holds for the latest values of its
+    /// monitored properties, such as "State == Discharging && Percentage < 20".
+    #[arg(long, value_name = "CONDITION")]
+    filter: Option<String>,
+    /// Only write changes to the given comma-separated properties to one sink (output, http,
+    /// dbus-service, osd, plugin or extra-output), in the form SINK=PROPERTIES, such as
+    /// "osd=Percentage". Can be given once for each sink; other sinks receive changes to every
+    /// monitored property.
+    #[arg(long, value_name = "SINK=PROPERTIES")]
+    sink_properties: Vec<SinkFilter>,
+    /// Write at most N changes to each device every SECONDS seconds to one sink (as for
+    /// --sink-properties), or to every sink if no sink is given, in the form [SINK=]N/SECONDS,
+    /// such as "http=10/1". Up to N changes can be written at once after a quiet period. Can be
+    /// given once for each sink and once for every sink.
+    #[arg(long, value_name = "[SINK=]N/SECONDS")]
+    rate_limit: Vec<RateLimit>,
+    /// What to do with changes which exceed the rate limit given by --rate-limit: drop them, or
+    /// hold them back and write them with the device's next change within the limit.
+    #[arg(long, value_enum, default_value_t = RateLimitOverflow::Drop)]
+    rate_limit_overflow: RateLimitOverflow,
+    /// Only write changes to devices whose Severity (see --severity) is at least warning or
+    /// critical to one sink (as for --sink-properties), or only run the --exec command for them,
+    /// in the form TARGET=SEVERITY, such as "osd=warning" or "exec=critical". Can be given once for
+    /// each target; other targets receive changes of every severity.
+    #[arg(long, value_name = "TARGET=SEVERITY", requires = "severity")]
+    route: Vec<SeverityRoute>,
+    /// Accumulate the changes to each device over windows of the given number of seconds, and
+    /// write at most one change per device per window, containing the latest value of each
+    /// property which changed during the window.
+    #[arg(long, value_name = "SECONDS")]
+    aggregate: Option<u64>,
+    /// When aggregating changes, also write the minimum, maximum and average values during each
+    /// window of the Percentage, EnergyRate, TimeToEmpty, TimeToFull, EnergyFull and Capacity
+    /// properties, as pseudo-properties such as PercentageMin, PercentageMax and PercentageAvg.
+    #[arg(long, requires = "aggregate")]
+    aggregate_stats: bool,
+    /// Load the WebAssembly module (in binary or text format) at the given path. If the module
+    /// exports a filter function, only changes and markers which it keeps are written; if it
+    /// exports a format function, it is used to format output when --format is wasm.
+    #[cfg(feature = "wasm")]
+    #[arg(long, value_name = "PATH")]
+    wasm_module: Option<String>,
+    /// Stop monitoring and exit once the given condition holds for the latest values of any
+    /// device's monitored properties, such as "Percentage >= 80".
+    #[arg(long, value_name = "CONDITION")]
+    until: Option<String>,
+    /// Write a line containing "Alert" followed by the device path and condition when the
+    /// monitored properties of a device meet a condition, such as "Percentage<=15". This can be
+    /// specified multiple times. The condition may be followed by ",reset" and a second condition
+    /// (such as "reset>=20"), in which case the alert will not fire again until the second
+    /// condition has been met, and by ",cooldown=" and a minimum number of seconds between alerts.
+    /// ",action=" followed by suspend, hibernate or poweroff asks logind to take that action when
+    /// the alert fires, after the number of seconds given by ",delay=" (if any), unless a device
+    /// goes online or starts charging in the meantime. ",sound" rings the terminal bell when the
+    /// alert fires, and ",sound=" followed by a path plays that sound file using --sound-player.
+    /// Rules are checked in the order given, unless ",priority=" gives a rule a higher (or lower)
+    /// priority than the default of 0; ",stop" prevents rules checked later from firing while the
+    /// rule's condition holds. ",quiet=" followed by a range of local times (such as
+    /// "22:00-07:00") writes "QuietAlert" instead of "Alert" during that range, without playing the
+    /// rule's sound or taking its action. ",notify" shows a desktop notification when the alert
+    /// fires, which replaces the previous one for the same rule and device and is closed once the
+    /// condition no longer holds.
+    #[arg(long, value_name = "RULE")]
+    alert: Vec<String>,
+    /// Whether every alert rule whose condition holds may fire, or only the first one checked.
+    #[arg(long, value_enum, default_value_t = AlertMatch::All)]
+    alert_match: AlertMatch,
+    /// The command used to play the sound files of alert rules. The path of the file is appended
+    /// to the command, which is run using the shell.
+    #[arg(long, value_name = "COMMAND", default_value = DEFAULT_SOUND_PLAYER)]
+    sound_player: String,
+    /// Write a line containing "Session" followed by the device path, "Charging" or "Discharging"
+    /// and a summary of the session (its start and end times, duration, starting and ending
+    /// percentage, change in energy and average rate) whenever a device stops charging or
+    /// discharging. State and Percentage must be monitored, as must EnergyFull for the energy to be
+    /// reported.
+    #[arg(long)]
+    sessions: bool,
+    /// Write a line containing "TransitionLatency" followed by a battery's path, a line power
+    /// device's path and the number of seconds between the two when the battery's State changes
+    /// within a minute of the line power device going online or offline. State and Online must be
+    /// monitored.
+    #[arg(long)]
+    transition_latency: bool,
+    /// Every given number of seconds (such as 86400 for daily reports), write a line containing
+    /// "Report" followed by the device path and statistics for the period: the lowest and highest
+    /// Percentage, the number of seconds spent discharging, the number of charge cycles observed
+    /// and the average EnergyRate while discharging.
+    #[arg(long, value_name = "SECONDS")]
+    report: Option<u64>,
+    /// While the system is on battery (as UPower's OnBattery property reports), check for stale
+    /// devices (--stale-after), write reports (--report) and write aggregated changes (--aggregate)
+    /// FACTOR times less often, so that upmon itself wakes the system less often.
+    #[arg(long, value_name = "FACTOR", value_parser = clap::value_parser!(u32).range(2..))]
+    throttle_on_battery: Option<u32>,
+    /// Add a Severity pseudo-property (ok, warning or critical) to each change, classifying the
+    /// device's state according to --severity-warning and --severity-critical. Severity can then be
+    /// used with --on-transition and --filter.
+    #[arg(long)]
+    severity: bool,
+    /// Condition under which a device's severity is at least "warning".
+    #[arg(
+        long,
+        value_name = "CONDITION",
+        default_value = "Percentage <= 20 || WarningLevel >= Low"
+    )]
+    severity_warning: String,
+    /// Condition under which a device's severity is "critical".
+    #[arg(
+        long,
+        value_name = "CONDITION",
+        default_value = "Percentage <= 5 || WarningLevel >= Critical"
+    )]
+    severity_critical: String,
+    /// The colour in which the i3bar, polybar and lemonbar formats show devices whose severity is
+    /// "warning".
+    #[arg(long, value_name = "COLOR", default_value = "#FFFF00")]
+    warning_color: String,
+    /// The colour in which the i3bar, polybar and lemonbar formats show devices whose severity is
+    /// "critical".
+    #[arg(long, value_name = "COLOR", default_value = "#FF0000")]
+    critical_color: String,
+    /// Replace each change to EnergyRate with an exponential moving average of the values received
+    /// for the device, giving each new value the given weight (between 0 and 1). Lower weights
+    /// give smoother values which are slower to respond to changes.
+    #[arg(long, value_name = "WEIGHT")]
+    smooth_energy_rate: Option<f64>,
+    /// When smoothing EnergyRate, also write the raw value as the EnergyRateRaw pseudo-property.
+    #[arg(long, requires = "smooth_energy_rate")]
+    raw_energy_rate: bool,
+    /// Round each change to Percentage to the nearest multiple of N (such as 5), and drop changes
+    /// which round to the value last written for the device. This happens before anything else,
+    /// so conditions, --on-transition and every output only see whole steps.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=100))]
+    quantize: Option<u32>,
+    /// Output enumerated properties (State, WarningLevel and BatteryLevel) as their raw numeric
+    /// values rather than their names, in all output formats.
+    #[arg(long)]
+    numeric_enums: bool,
+    /// Identify devices in output by their object path, their NativePath, Model or Serial property
+    /// (read when upmon starts), or their alias (the last element of the path, such as
+    /// battery_BAT0). Devices without the chosen property are identified by their path.
+    #[arg(long, value_enum, default_value_t = DeviceName::Path)]
+    device_name: DeviceName,
+    /// Replace the values of the given device properties with "<redacted>" in all output,
+    /// including markers and the report written by --bug-report, so that output sent off the
+    /// machine does not identify its devices.
+    #[arg(long, value_enum, value_name = "PROPERTIES", value_delimiter = ',')]
+    redact: Vec<Redacted>,
+    /// Add the given fields identifying this machine (its hostname, machine-id or boot-id) to
+    /// every change written in JSON, served over HTTP or passed to plugins, so that changes from
+    /// many machines can be aggregated. They are read once, when upmon starts.
+    #[arg(long, value_enum, value_name = "FIELDS", value_delimiter = ',')]
+    annotate: Vec<HostField>,
+    /// When upmon starts, write a DeviceInfo marker describing each monitored device by its
+    /// Vendor, Model, Serial, Type and Technology properties.
+    #[arg(long)]
+    device_info: bool,
+    /// Format values in line output for the given locale (such as "de" or "fr_FR.UTF-8"), using its
+    /// decimal separator and translations of state names and durations. If no locale is given, it
+    /// is read from the LC_ALL, LC_MESSAGES or LANG environment variable.
+    #[arg(long, value_name = "LOCALE", num_args = 0..=1, default_missing_value = "")]
+    locale: Option<String>,
+    /// Add an Icon pseudo-property to each change, giving a glyph representing the device's latest
+    /// Percentage. The glyph is chosen from the given comma-separated list of glyphs, ordered from
+    /// empty to full, or from Nerd Font battery icons if no list (or "nerd") is given.
+    #[arg(long, value_name = "GLYPHS", num_args = 0..=1, default_missing_value = NERD_RAMP)]
+    icon: Option<String>,
+    /// Comma-separated list of glyphs (or "nerd") to choose the Icon from while the device is
+    /// charging. Defaults to Nerd Font charging icons if --icon uses Nerd Font icons, and to the
+    /// glyphs given to --icon otherwise.
+    #[arg(long, value_name = "GLYPHS", requires = "icon")]
+    icon_charging: Option<String>,
+    /// Add a Bar pseudo-property to each change, giving a bar of the given width representing the
+    /// device's latest Percentage (such as [###--]), followed by "+" while it is charging.
+    #[arg(long, value_name = "WIDTH")]
+    bar: Option<usize>,
+    /// Add a TimeToThreshold pseudo-property to each change while a device is discharging, giving
+    /// the estimated number of seconds until it reaches the given percentage. The estimate is based
+    /// on the (smoothed) EnergyRate and EnergyFull if they are monitored, and on TimeToEmpty
+    /// otherwise.
+    #[arg(long, value_name = "PERCENT")]
+    time_to: Option<f64>,
+    /// Format in which to output UpdateTime in line output.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = UpdateTimeFormat::Utc)]
+    update_time_format: UpdateTimeFormat,
+    /// Add a Stale pseudo-property (true or false) to each change to UpdateTime, indicating whether
+    /// the update time is more than the given number of seconds old, and write a change to Stale
+    /// when a device's update time becomes that old without changing. UpdateTime must be
+    /// monitored.
+    #[arg(long, value_name = "SECONDS")]
+    stale_after: Option<u64>,
+    /// Log each change and marker to standard error, noting any which are not written because
+    /// output is paused. Verbose logging can be turned on and off at runtime with the verbose
+    /// command of --control-socket.
+    #[arg(short, long)]
+    verbose: bool,
+    /// Accept commands to reconfigure upmon while it is running, one per line, from clients
+    /// connecting to the Unix socket at the given path (which is replaced if it already exists).
+    /// See the README for the supported commands.
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<String>,
+    /// Ensure that only one instance of upmon runs at a time for the current user, using a lock
+    /// file in $XDG_RUNTIME_DIR. If another instance is already running, either exit with an error
+    /// ("exit", the default) or stop the other instance and take its place ("replace").
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        num_args = 0..=1,
+        default_missing_value = "exit"
+    )]
+    single_instance: Option<ExistingInstance>,
+    /// Fork into the background once devices are being monitored. Standard output and error are
+    /// closed if --output-file is given, and otherwise left open so that output can be redirected.
+    /// If upmon fails to start, the original process exits with the same status.
+    #[arg(long)]
+    daemon: bool,
+    /// Write the PID of upmon to the file at the given path, which is removed when upmon shuts
+    /// down cleanly (including on SIGINT or SIGTERM).
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<String>,
+    /// Log every PropertiesChanged signal received for the monitored devices to standard error,
+    /// with each changed property's value and DBus type and whether it was written, or why not
+    /// (because it is not targeted or its type is not the one expected).
+    #[arg(long)]
+    debug_signals: bool,
+    /// Print the DBus rules generated for the given device paths and exit. If busctl, dbus-monitor
+    /// or gdbus is given, print a command line which uses that tool to print the signals upmon
+    /// would receive for each device instead.
+    #[arg(short, long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "rule")]
+    rules: Option<RulesFormat>,
+    /// Include an ISO 8601-formatted timestamp in the output.
+    #[arg(short, long)]
+    timestamp: bool,
+    /// Print the fully resolved configuration (devices, properties, DBus rules and output
+    /// settings) as JSON and exit, without connecting to DBus.
+    #[arg(long)]
+    dry_run: bool,
+    /// Write a bug report to the given file and exit. The report is a single JSON object containing
+    /// upmon's version, the configuration printed by --dry-run, the UPower daemon's version, every
+    /// property of each monitored device and each device known to UPower, and the most recent
+    /// events in the file given to --record (if any).
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    bug_report: Option<String>,
+    /// Replace device serial numbers in the bug report written by --bug-report (as if --redact
+    /// serial were given).
+    #[arg(long, requires = "bug_report")]
+    bug_report_redact: bool,
+    /// Path to file to record all received property changes to, so that they can later be replayed
+    /// using the replay subcommand.
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+    /// When the system resumes from sleep, write the current values of all monitored properties,
+    /// as cached values and time estimates may be stale.
+    #[arg(long)]
+    refresh_on_resume: bool,
+    /// When the system resumes from sleep, write a line containing only "Resumed" (preceded by a
+    /// timestamp, if enabled).
+    #[arg(long)]
+    mark_resume: bool,
+    /// Write a line containing "Lifecycle" followed by the event (and the device, if any) when
+    /// upmon starts or stops monitoring, subscribes to or loses a device, or sees the UPower daemon
+    /// restart, so that gaps in the data can be told apart from periods without changes. In JSON,
+    /// these lines also have an event_type field naming the event.
+    #[arg(long)]
+    lifecycle: bool,
+    /// Write a line containing "CriticalAction" followed by the action UPower is configured to
+    /// take (such as HybridSleep or PowerOff) when the display device's WarningLevel indicates that
+    /// UPower is about to take that action.
+    #[arg(long)]
+    critical_action: bool,
+    /// Write a line containing "HealthWarning" followed by the device path and its capacity when
+    /// the full capacity (EnergyFull) of a monitored UPower device falls below the given
+    /// percentage of its design capacity (EnergyFullDesign), or is already below it on startup.
+    #[arg(long, value_name = "PERCENT")]
+    health_warning: Option<f64>,
+    /// Source from which to receive changes to device properties.
+    #[arg(long, value_enum, default_value_t = Backend::Dbus)]
+    backend: Backend,
+    /// If the system bus (or a bus given by --bus) is not available at startup, keep retrying the
+    /// connection (waiting exponentially longer between attempts) for up to the given number of
+    /// seconds. 0 means exit immediately if the connection fails.
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    connect_timeout: u64,
+    /// How long to wait for the reply to each DBus method call (such as fetching a device's
+    /// properties), in milliseconds, before reporting an error, so that a hung UPower daemon cannot
+    /// stall upmon indefinitely. 0 means wait indefinitely.
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_METHOD_TIMEOUT_MS)]
+    dbus_timeout: u64,
+    /// How long to wait before retrying a failed connection, in milliseconds: to the system bus
+    /// (or a bus given by --bus) at startup, to a plugin which has exited, or to the server to
+    /// which an output sends changes over the network.
+    #[arg(long, value_name = "MS", default_value_t = 100)]
+    retry_initial_delay: u64,
+    /// How many times longer to wait before each retry of a connection than before the last.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        default_value_t = 2
+    )]
+    retry_multiplier: u32,
+    /// The longest time to wait between retries of a connection, in milliseconds.
+    #[arg(long, value_name = "MS", default_value_t = 5000)]
+    retry_max_delay: u64,
+    /// The largest fraction (between 0 and 1) by which each wait between retries is randomly
+    /// shortened, so that many instances of upmon which lose a connection at once do not all retry
+    /// at once.
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.2)]
+    retry_jitter: f64,
+    /// Give up on a connection after the given number of failed attempts, rather than retrying
+    /// until --connect-timeout has elapsed (at startup) or for as long as upmon runs.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    retry_max_attempts: Option<u32>,
+    /// Also connect to the bus at the given DBus address, such as a container's system bus at
+    /// "container=unix:path=/run/container/dbus/system_bus_socket". Devices given to --path as
+    /// LABEL@PATH are monitored on that bus, and identified as LABEL@PATH in output. Can be given
+    /// more than once.
+    #[arg(long, value_name = "LABEL=ADDRESS")]
+    bus: Vec<Bus>,
+    /// Also monitor UPower on a remote host, by running systemd-stdio-bridge on it over SSH (which
+    /// must be able to log in without a password). Devices given to --path as LABEL@PATH are
+    /// monitored on the host; the label is the host name unless given. Can be given more than once.
+    #[arg(long, value_name = "[LABEL=][USER@]HOST", value_parser = Bus::remote)]
+    remote: Vec<Bus>,
+    /// The number of changes which can be queued for writing while output is slow (for example,
+    /// if a FIFO is not being read or an HTTP client is not keeping up), without holding up
+    /// monitoring.
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    queue_size: usize,
+    /// What to do with changes when the queue given by --queue-size is full.
+    #[arg(long, value_enum, default_value_t = Overflow::Block)]
+    queue_overflow: Overflow,
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+/// Run upmon as configured by its command line arguments.
+pub fn main() {
+    let matches = CliArgs::command().get_matches();
+    let mut cli = CliArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    cli.path_args = matches.get_occurrences::<String>("path")
+        .map(|o| o.map(|args| args.cloned().collect::<Vec<_>>()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    // Remote hosts are reached over buses like any other.
+    cli.bus.append(&mut cli.remote);
+    // The async runtime runs threads which would not be copied into the daemon, so fork before it
+    // is started.
+    let daemon = cli.daemon.then(|| Daemon::fork().unwrap_or_else(|e| {
+        eprintln!("Error when starting daemon: {e}");
+        ExitStatus::Error.exit()
+    }));
+    // The future is boxed because it is too large to be passed by value through the runtime's
+    // stack frames, which would overflow the main thread's stack in unoptimised builds.
+    rt::block_on(Box::pin(run(cli, daemon))).exit()
+}
+
+/// Print every property exposed by each device at the given paths, returning the status with
+/// which upmon should exit.
+async fn discover(conn: &Connection, paths: &[String], interface: Option<&str>) -> ExitStatus {
+    for path in paths {
+        let config = DeviceConfig::new(path, "*", interface).unwrap_or_else(|e| {
+            eprintln!("Error when reading device configuration: {e}");
+            ExitStatus::Config.exit()
+        });
+        match config.discover(conn).await {
+            Ok(Some(properties)) => {
+                println!("{}", config.path());
+                for p in properties {
+                    println!("  {} ({}): {}", p.name, p.dbus_type, p.value);
+                }
+            },
+            Ok(None) => {
+                eprintln!("Cannot discover properties of {path}: its DBus service is not known");
+                return ExitStatus::Config
+            },
+            Err(e) => {
+                eprintln!("Error when discovering properties of {path}: {e}");
+                return ExitStatus::DbusConnection
+            }
+        }
+    }
+    ExitStatus::Success
+}
+
+/// Monitor devices (or do whatever else was asked) as configured by `cli`, returning the status
+/// with which upmon should exit. If `daemon` is given, readiness is reported once devices are
+/// being monitored.
+async fn run(cli: CliArgs, daemon: Option<Daemon>) -> ExitStatus {
+    if cli.help_exit_codes {
+        println!("{}", ExitStatus::help());
+        return ExitStatus::Success
+    }
+
+    if let Some(Command::Schema) = cli.command {
+        print!("{EVENT_SCHEMA}");
+        return ExitStatus::Success
+    }
+
+    match cli.list_properties {
+        Some(InfoFormat::Text) => {
+            for p in Property::names() {
+                println!("{p}");
+            }
+            return ExitStatus::Success
+        },
+        Some(InfoFormat::Json) => {
+            let info = Property::names()
+                .filter_map(Property::info)
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&info).expect("Could not serialize properties.")
+            );
+            return ExitStatus::Success
+        },
+        None => {}
+    }
+
+    if !cli.discover.is_empty() && (matches!(cli.backend, Backend::Udev) || cli.command.is_some()) {
+        eprintln!("--discover can only be used when listening over DBus");
+        ExitStatus::Config.exit()
+    }
+
+    let mut path_confs = DeviceConfig::from_args(&cli.path_args, cli.interface.as_deref())
+        .unwrap_or_else(|e| {
+            eprintln!("Error when reading device configuration: {e}");
+            ExitStatus::Config.exit()
+        })
+        .into_iter()
+        .map(|c| c.with_debug_signals(cli.debug_signals))
+        .collect::<Vec<_>>();
+    for (i, bus) in cli.bus.iter().enumerate() {
+        if cli.bus[..i].iter().any(|b| b.label == bus.label) {
+            eprintln!("Bus given more than once: {}", bus.label);
+            ExitStatus::Config.exit()
+        }
+    }
+    let unknown_bus = |c: &DeviceConfig| c.bus().filter(|l| !cli.bus.iter().any(|b| b.label == *l))
+        .map(|l| format!(
+            "Unknown bus {l} for device {}; give it with --bus or --remote",
+            c.path()
+        ));
+    if let Some(e) = path_confs.iter().find_map(unknown_bus) {
+        eprintln!("{e}");
+        ExitStatus::Config.exit()
+    }
+    if !cli.bus.is_empty() && (matches!(cli.backend, Backend::Udev) || cli.command.is_some()) {
+        eprintln!("--bus and --remote can only be used when listening over DBus");
+        ExitStatus::Config.exit()
+    }
+
+    let is_property = |p: &PropertyKind| p.is_upower();
+    // The Severity, EnergyRateRaw, Stale, Icon, Bar and TimeToThreshold pseudo-properties are added
+    // before changes are filtered.
+    let is_filterable = |p: &PropertyKind| match p {
+        PropertyKind::Severity => cli.severity,
+        PropertyKind::EnergyRateRaw => cli.raw_energy_rate,
+        PropertyKind::Stale => cli.stale_after.is_some(),
+        PropertyKind::Icon => cli.icon.is_some(),
+        PropertyKind::Bar => cli.bar.is_some(),
+        PropertyKind::TimeToThreshold => cli.time_to.is_some(),
+        p => p.is_upower()
+    };
+    let transitions = cli.on_transition.as_ref()
+        .map(|props| props.iter().map(|p| PropertyKind::from_name(p)).collect::<Vec<_>>());
+
+    let glyphs = Glyphs::new(cli.icon.as_deref(), cli.icon_charging.as_deref(), cli.bar)
+        .unwrap_or_else(|e| {
+            eprintln!("Error when reading glyphs: {e}");
+            ExitStatus::Config.exit()
+        });
+
+    if let Some(alpha) = cli.smooth_energy_rate {
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            eprintln!("Smoothing weight must be greater than 0 and at most 1: {alpha}");
+            ExitStatus::Config.exit()
+        }
+    }
+
+    if !(0.0..=1.0).contains(&cli.retry_jitter) {
+        eprintln!("Retry jitter must be between 0 and 1: {}", cli.retry_jitter);
+        ExitStatus::Config.exit()
+    }
+    set_method_timeout(cli.dbus_timeout);
+    let retry = RetryPolicy {
+        initial_delay: Duration::from_millis(cli.retry_initial_delay),
+        multiplier: cli.retry_multiplier,
+        max_delay: Duration::from_millis(cli.retry_max_delay),
+        jitter: cli.retry_jitter,
+        max_attempts: cli.retry_max_attempts
+    };
+
+    if let (Some(props), None) = (&transitions, &cli.interface) {
+        if let Some(p) = props.iter().find(|p| !is_filterable(p)) {
+            eprintln!("{}", ConfigError::unknown_property(p.as_str(), "for --on-transition"));
+            ExitStatus::Config.exit()
+        }
+    }
+    for (i, f) in cli.sink_properties.iter().enumerate() {
+        if cli.sink_properties[..i].iter().any(|g| g.sink == f.sink) {
+            eprintln!("Properties given more than once for sink {}", f.sink);
+            ExitStatus::Config.exit()
+        }
+        if cli.interface.is_none() {
+            if let Some(p) = f.properties.iter().find(|p| !is_filterable(p)) {
+                let context = format!("for sink {}", f.sink);
+                eprintln!("{}", ConfigError::unknown_property(p.as_str(), &context));
+                ExitStatus::Config.exit()
+            }
+        }
+    }
+    let sink_properties = |sink: Sink| cli.sink_properties.iter()
+        .find(|f| f.sink == sink)
+        .map(|f| f.properties.clone());
+    for (i, r) in cli.rate_limit.iter().enumerate() {
+        if cli.rate_limit[..i].iter().any(|q| q.sink == r.sink) {
+            match r.sink {
+                Some(sink) => eprintln!("Rate limit given more than once for sink {sink}"),
+                None => eprintln!("Rate limit given more than once for all sinks")
+            }
+            ExitStatus::Config.exit()
+        }
+    }
+    let rate_limit = |sink: Sink| cli.rate_limit.iter()
+        .find(|r| r.sink == Some(sink))
+        .or_else(|| cli.rate_limit.iter().find(|r| r.sink.is_none()))
+        .cloned();
+    for (i, r) in cli.route.iter().enumerate() {
+        if cli.route[..i].iter().any(|q| q.target == r.target) {
+            eprintln!("Route given more than once for {}", r.target);
+            ExitStatus::Config.exit()
+        }
+    }
+    let route = |target: RouteTarget| cli.route.iter()
+        .find(|r| r.target == target)
+        .map(|r| r.severity);
+
+    let alert_rules = cli.alert.iter()
+        .map(|a| AlertRule::parse(a))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("Error when reading alert rules: {e}");
+            ExitStatus::Config.exit()
+        });
+    let actions_supported = matches!(cli.backend, Backend::Dbus) && cli.command.is_none();
+    if alert_rules.iter().any(AlertRule::has_action) && !actions_supported {
+        eprintln!("Alert actions can only be taken when listening over DBus");
+        ExitStatus::Config.exit()
+    }
+    if cli.lifecycle && !actions_supported {
+        eprintln!("--lifecycle can only be used when listening over DBus");
+        ExitStatus::Config.exit()
+    }
+    if cli.throttle_on_battery.is_some() && !actions_supported {
+        eprintln!("--throttle-on-battery can only be used when listening over DBus");
+        ExitStatus::Config.exit()
+    }
+    let parse_condition = |c: &Option<String>| c.as_deref().map(|c| Expr::parse(c)
+        .unwrap_or_else(|e| {
+            eprintln!("Error when reading condition: {e}");
+            ExitStatus::Config.exit()
+        }));
+    let filter = parse_condition(&cli.filter);
+    #[cfg(feature = "wasm")]
+    let wasm_module = cli.wasm_module.as_deref().map(|p| {
+        std::rc::Rc::new(wasm::WasmModule::load(p).unwrap_or_else(|e| {
+            eprintln!("Error when loading WASM module: {e}");
+            ExitStatus::Config.exit()
+        }))
+    });
+    #[cfg(feature = "wasm")]
+    if matches!(cli.format, OutputFormat::Wasm)
+        && !wasm_module.as_ref().is_some_and(|m| m.has_format()) {
+        eprintln!("--format wasm requires a --wasm-module which exports format");
+        ExitStatus::Config.exit()
+    }
+    let registry = WriterRegistry::default();
+    if let Some(o) = cli.extra_output.iter().find(|o| !registry.names().any(|n| n == o.format)) {
+        eprintln!(
+            "Unknown format for --extra-output: {} (expected one of: {})",
+            o.format,
+            registry.names().collect::<Vec<_>>().join(", ")
+        );
+        ExitStatus::Config.exit()
+    }
+    if let Some(threshold) = cli.time_to {
+        if !(0.0..100.0).contains(&threshold) {
+            eprintln!("Threshold must be at least 0 and less than 100: {threshold}");
+            ExitStatus::Config.exit()
+        }
+    }
+    if cli.report == Some(0) {
+        eprintln!("Reporting period must be at least one second");
+        ExitStatus::Config.exit()
+    }
+    if cli.aggregate == Some(0) {
+        eprintln!("Aggregation window must be at least one second");
+        ExitStatus::Config.exit()
+    }
+    if cli.exec_jobs == 0 {
+        eprintln!("At least one exec hook must be allowed to run at once");
+        ExitStatus::Config.exit()
+    }
+    if let Some(r) = cli.redact.iter().find(|r| cli.device_name.property() == Some(r.property())) {
+        eprintln!("Devices cannot be named by their {} when it is redacted", r.property());
+        ExitStatus::Config.exit()
+    }
+    if cli.i3bar_click.is_some() && !matches!(cli.format, OutputFormat::I3bar) {
+        eprintln!("--i3bar-click can only be used when --format is i3bar");
+        ExitStatus::Config.exit()
+    }
+    let colors = SeverityColors {
+        warning: cli.warning_color.clone(),
+        critical: cli.critical_color.clone()
+    };
+    let until = parse_condition(&cli.until);
+    let bands = cli.severity.then(|| SeverityBands::new(
+        parse_condition(&Some(cli.severity_warning.clone())).unwrap(),
+        parse_condition(&Some(cli.severity_critical.clone())).unwrap()
+    ));
+    if cli.interface.is_none() {
+        let mut referenced = alert_rules.iter().flat_map(AlertRule::properties)
+            .chain(until.iter().flat_map(Expr::properties))
+            .chain(bands.iter().flat_map(SeverityBands::properties));
+        if let Some(p) = referenced.find(|p| !is_property(p)) {
+            eprintln!("{}", ConfigError::unknown_property(p.as_str(), "in condition"));
+            ExitStatus::Config.exit()
+        }
+        if let Some(p) = filter.iter().flat_map(Expr::properties).find(|p| !is_filterable(p)) {
+            eprintln!("{}", ConfigError::unknown_property(p.as_str(), "in condition"));
+            ExitStatus::Config.exit()
+        }
+    }
+
+    if let Some(format) = cli.rules {
+        for p in path_confs {
+            println!("{}", p.rule_in(format).unwrap_or_else(|e| {
+                eprintln!("Could not create DBus rule for path: {e}");
+                ExitStatus::Config.exit()
+            }));
+        }
+        return ExitStatus::Success
+    }
+
+    let metrics_protocol = match cli.format {
+        OutputFormat::Graphite => MetricProtocol::Graphite,
+        _ => MetricProtocol::Statsd
+    };
+    let metrics_transport = cli.metrics_transport.unwrap_or(match metrics_protocol {
+        MetricProtocol::Statsd => Transport::Udp,
+        MetricProtocol::Graphite => Transport::Tcp
+    });
+
+    let resolved = (cli.dry_run || cli.bug_report.is_some()).then(|| {
+        #[cfg_attr(not(feature = "tui"), allow(unused_mut))]
+        let mut resolved = serde_json::json!({
+            "devices": path_confs,
+            "writer": match cli.format {
+                OutputFormat::Line => serde_json::json!({
+                    "type": "line",
+                    "output_file": cli.output_file,
+                    "separator": cli.separator,
+                    "delimiter": cli.delimiter,
+                    "timestamp": cli.timestamp,
+                    "update_time_format": cli.update_time_format,
+                    "locale": cli.locale
+                }),
+                OutputFormat::Json => serde_json::json!({
+                    "type": "json",
+                    "output_file": cli.output_file
+                }),
+                OutputFormat::Summary => serde_json::json!({
+                    "type": "summary",
+                    "output_file": cli.output_file,
+                    "separator": cli.separator,
+                    "delimiter": cli.delimiter
+                }),
+                OutputFormat::Polybar | OutputFormat::Lemonbar => serde_json::json!({
+                    "type": cli.format.to_possible_value().unwrap().get_name(),
+                    "output_file": cli.output_file,
+                    "separator": cli.separator,
+                    "delimiter": cli.delimiter,
+                    "warning_color": colors.warning,
+                    "critical_color": colors.critical
+                }),
+                OutputFormat::I3bar => serde_json::json!({
+                    "type": "i3bar",
+                    "output_file": cli.output_file,
+                    "click_command": cli.i3bar_click,
+                    "warning_color": colors.warning,
+                    "critical_color": colors.critical
+                }),
+                OutputFormat::Zabbix => serde_json::json!({
+                    "type": "zabbix",
+                    "output_file": cli.output_file,
+                    "server": cli.zabbix_server,
+                    "host": cli.zabbix_host,
+                    "key_prefix": cli.zabbix_key_prefix
+                }),
+                OutputFormat::Statsd | OutputFormat::Graphite => serde_json::json!({
+                    "type": metrics_protocol,
+                    "output_file": cli.output_file,
+                    "address": cli.metrics_address,
+                    "transport": cli.metrics_address.as_ref().map(|_| metrics_transport),
+                    "prefix": cli.metrics_prefix
+                }),
+                #[cfg(feature = "otel")]
+                OutputFormat::Otel => serde_json::json!({
+                    "type": "otel",
+                    "endpoint": cli.otel_endpoint
+                }),
+                #[cfg(feature = "sqlite")]
+                OutputFormat::Sqlite => serde_json::json!({
+                    "type": "sqlite",
+                    "output_file": cli.output_file
+                }),
+                #[cfg(feature = "wasm")]
+                OutputFormat::Wasm => serde_json::json!({
+                    "type": "wasm",
+                    "output_file": cli.output_file,
+                    "module": cli.wasm_module
+                })
+            },
+            "extra_outputs": cli.extra_output.iter()
+                .map(|o| serde_json::json!({
+                    "type": o.format,
+                    "output_file": o.output_file
+                }))
+                .collect::<Vec<_>>(),
+            "filters": {
+                "on_transition": cli.on_transition,
+                "condition": cli.filter,
+                "sink_properties": cli.sink_properties.iter()
+                    .map(|f| (f.sink.to_string(), serde_json::json!(f.properties.iter()
+                        .map(PropertyKind::as_str)
+                        .collect::<Vec<_>>())))
+                    .collect::<serde_json::Map<_, _>>(),
+                "rate_limits": cli.rate_limit.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "rate_limit_overflow": cli.rate_limit_overflow,
+                "routes": cli.route.iter().map(ToString::to_string).collect::<Vec<_>>()
+            },
+            "aggregate": cli.aggregate.map(|secs| serde_json::json!({
+                "window": secs,
+                "stats": cli.aggregate_stats
+            })),
+            "until": cli.until,
+            "stale_after": cli.stale_after,
+            "time_to": cli.time_to,
+            "numeric_enums": cli.numeric_enums,
+            "glyphs": serde_json::json!({
+                "icon": cli.icon,
+                "icon_charging": cli.icon_charging,
+                "bar": cli.bar
+            }),
+            "quantize": cli.quantize,
+            "smooth_energy_rate": cli.smooth_energy_rate.map(|alpha| serde_json::json!({
+                "weight": alpha,
+                "raw": cli.raw_energy_rate
+            })),
+            "listen_http": cli.listen_http,
+            "dbus_service": cli.dbus_service,
+            "connect_timeout": cli.connect_timeout,
+            "dbus_timeout": cli.dbus_timeout,
+            "retry": serde_json::json!({
+                "initial_delay_ms": cli.retry_initial_delay,
+                "multiplier": cli.retry_multiplier,
+                "max_delay_ms": cli.retry_max_delay,
+                "jitter": cli.retry_jitter,
+                "max_attempts": cli.retry_max_attempts
+            }),
+            "buses": cli.bus,
+            "queue": serde_json::json!({
+                "size": cli.queue_size,
+                "overflow": cli.queue_overflow
+            }),
+            "osd_fifo": cli.osd_fifo,
+            "plugin": cli.plugin.as_ref().map(|c| serde_json::json!({
+                "command": c,
+                "acks": cli.plugin_acks
+            })),
+            "exec": cli.exec.as_ref().map(|c| serde_json::json!({
+                "command": c,
+                "timeout": cli.exec_timeout,
+                "jobs": cli.exec_jobs,
+                "failure_events": cli.exec_failure_events
+            })),
+            "verbose": cli.verbose,
+            "debug_signals": cli.debug_signals,
+            "device_name": cli.device_name,
+            "redact": cli.redact,
+            "annotate": cli.annotate,
+            "device_info": cli.device_info,
+            "lifecycle": cli.lifecycle,
+            "output_version": cli.output_version,
+            "output_file_options": {
+                "truncate": cli.truncate,
+                "mode": cli.output_mode
+            },
+            "control_socket": cli.control_socket,
+            "single_instance": cli.single_instance,
+            "pid_file": cli.pid_file,
+            "daemon": cli.daemon,
+            "severity": cli.severity.then_some(serde_json::json!({
+                "warning": cli.severity_warning,
+                "critical": cli.severity_critical
+            })),
+            "alerts": cli.alert,
+            "alert_match": cli.alert_match,
+            "sound_player": cli.sound_player,
+            "sessions": cli.sessions,
+            "transition_latency": cli.transition_latency,
+            "report": cli.report,
+            "throttle_on_battery": cli.throttle_on_battery
+        });
+        #[cfg(feature = "tui")]
+        if cli.tui {
+            resolved["writer"] = serde_json::json!({ "type": "tui" });
+        }
+        #[cfg(feature = "compress")]
+        {
+            resolved["output_file_options"]["compress"] = serde_json::json!(cli.compress);
+        }
+        resolved
+    });
+    if let Some(resolved) = resolved.as_ref().filter(|_| cli.dry_run) {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(resolved).unwrap_or_else(|e| {
+                eprintln!("Could not serialize configuration: {e}");
+                ExitStatus::Error.exit()
+            })
+        );
+        return ExitStatus::Success
+    }
+
+    if let Some(path) = &cli.bug_report {
+        let timeout = Duration::from_secs(cli.connect_timeout);
+        let conn = connect_system(timeout, &retry).await.unwrap_or_else(|e| {
+            eprintln!("Error when connecting to the system bus: {e}");
+            ExitStatus::DbusConnection.exit()
+        });
+        let mut report = BugReport::new(resolved.unwrap_or_default());
+        let system_confs = path_confs.iter()
+            .filter(|c| c.bus().is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        report.collect(&conn, &system_confs).await;
+        if let Some(record) = &cli.record {
+            report.add_recorded(record);
+        }
+        let mut redacted = cli.redact.iter().map(Redacted::property).collect::<Vec<_>>();
+        if cli.bug_report_redact {
+            redacted.push(Redacted::Serial.property());
+        }
+        report.redact(&redacted);
+        if let Err(e) = report.write(path) {
+            eprintln!("Error writing bug report: {e}");
+            return ExitStatus::WriterIo
+        }
+        eprintln!("Bug report written to {path}");
+        return ExitStatus::Success
+    }
+
+    // Held until upmon exits.
+    let _instance_lock = cli.single_instance.map(|existing| {
+        InstanceLock::acquire(&InstanceLock::default_path(), existing).unwrap_or_else(|e| {
+            eprintln!("Error when checking for another instance: {e}");
+            ExitStatus::Error.exit()
+        })
+    });
+
+    // Removed when upmon returns from main, so from here on errors are returned rather than
+    // exiting straight away.
+    let pid_file = cli.pid_file.as_deref().map(|p| PidFile::create(Path::new(p))).transpose();
+    let _pid_file = match pid_file {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error when writing PID file: {e}");
+            return ExitStatus::Error
+        }
+    };
+
+    let http_listener = match &cli.listen_http {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(l) => Some(l),
+            Err(e) => {
+                eprintln!("Error when listening for HTTP connections: {e}");
+                return ExitStatus::Error
+            }
+        },
+        None => None
+    };
+    let http = http_listener.as_ref().map(|_| HttpWriter::default());
+    let service = match &cli.dbus_service {
+        Some(name) => match ServiceWriter::new(name).await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("Error when registering DBus service: {e}");
+                return ExitStatus::DbusConnection
+            }
+        },
+        None => None
+    };
+
+    let locale = match cli.locale.as_deref().map(|l| match l {
+        "" => Locale::from_env(),
+        l => Locale::find(l)
+    }).transpose() {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Error when reading locale: {e}");
+            return ExitStatus::Config
+        }
+    };
+
+    let control_listener = match &cli.control_socket {
+        Some(path) => match bind_control_socket(path).await {
+            Ok(l) => Some(l),
+            Err(e) => {
+                eprintln!("Error when binding control socket: {e}");
+                return ExitStatus::Error
+            }
+        },
+        None => None
+    };
+
+    let osd = match cli.osd_fifo.as_deref().map(OsdWriter::new).transpose() {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error when opening OSD FIFO: {e}");
+            return ExitStatus::WriterIo
+        }
+    };
+
+    let plugin = cli.plugin.as_deref().map(|c| PluginWriter::new(c, cli.plugin_acks)).transpose();
+    let plugin = match plugin {
+        Ok(p) => p.map(|p| p.with_retry(retry)),
+        Err(e) => {
+            eprintln!("Error when starting plugin: {e}");
+            return ExitStatus::WriterIo
+        }
+    };
+
+    #[cfg(feature = "tui")]
+    let dashboard = cli.tui.then(|| std::sync::Arc::new(tui::TuiWriter::default()));
+
+    let file_options = FileOptions {
+        #[cfg(feature = "compress")]
+        compress: cli.compress,
+        truncate: cli.truncate,
+        mode: cli.output_mode
+    };
+    let options = WriterOptions {
+        output_file: cli.output_file.as_deref(),
+        file: file_options,
+        separator: &cli.separator,
+        delimiter: &cli.delimiter,
+        timestamp: cli.timestamp,
+        update_time: cli.update_time_format,
+        locale,
+        colors: &colors,
+        output_version: cli.output_version
+    };
+    let format_writer = match cli.format {
+        OutputFormat::Line | OutputFormat::Json | OutputFormat::Summary | OutputFormat::Polybar
+            | OutputFormat::Lemonbar => registry
+                .create(cli.format.to_possible_value().unwrap().get_name(), &options)
+                .map(FormatWriter::Registered),
+        OutputFormat::I3bar => open_output(cli.output_file.as_deref(), &file_options)
+            .and_then(|out| i3bar::I3barWriter::new(
+                out,
+                cli.i3bar_click.is_some(),
+                colors.clone()
+            ))
+            .map(|w| FormatWriter::Registered(Box::new(w))),
+        OutputFormat::Zabbix => open_output(cli.output_file.as_deref(), &file_options)
+            .map(|out| FormatWriter::Zabbix(ZabbixWriter::new(
+                out,
+                cli.zabbix_server.as_deref(),
+                &cli.zabbix_host,
+                &cli.zabbix_key_prefix
+            ).with_retry(retry))),
+        OutputFormat::Statsd | OutputFormat::Graphite => match &cli.metrics_address {
+            Some(address) => MetricsWriter::connect(
+                metrics_protocol,
+                address,
+                metrics_transport,
+                &cli.metrics_prefix
+            ).await.map(|w| w.with_retry(retry)),
+            None => open_output(cli.output_file.as_deref(), &file_options).map(|out| {
+                MetricsWriter::from_writer(metrics_protocol, out, &cli.metrics_prefix)
+            })
+        }.map(FormatWriter::Metrics),
+        #[cfg(feature = "otel")]
+        OutputFormat::Otel => otel::OtelWriter::new(&cli.otel_endpoint)
+            .map(|w| FormatWriter::Otel(w.with_retry(retry)))
+            .map_err(std::io::Error::other),
+        #[cfg(feature = "sqlite")]
+        OutputFormat::Sqlite => match &cli.output_file {
+            Some(_) if file_options != FileOptions::default() => {
+                Err(std::io::Error::other(
+                    "--compress, --truncate and --output-mode cannot be used with SQLite output"
+                ))
+            },
+            Some(path) => sqlite::SqliteWriter::new(path)
+                .map(FormatWriter::Sqlite)
+                .map_err(std::io::Error::other),
+            None => Err(std::io::Error::other("--output-file is required for SQLite output"))
+        },
+        #[cfg(feature = "wasm")]
+        OutputFormat::Wasm => open_output(cli.output_file.as_deref(), &file_options)
+            .map(|out| FormatWriter::Registered(Box::new(wasm::WasmWriter::new(
+                wasm_module.clone().expect("Checked above"),
+                out
+            ))))
+    };
+    #[cfg(feature = "tui")]
+    let format_writer = match &dashboard {
+        Some(d) => Ok(FormatWriter::Tui(d.clone())),
+        None => format_writer
+    };
+    let format_writer = match format_writer {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("Error creating writer: {e}");
+            return ExitStatus::WriterIo
+        }
+    };
+    let extra_writers = cli.extra_output.iter()
+        .map(|o| registry.create(
+            &o.format,
+            &WriterOptions {
+                output_file: o.output_file.as_deref(),
+                file: FileOptions::default(),
+                ..options
+            }
+        ))
+        .collect::<Result<Vec<_>, _>>();
+    let extra_writers = match extra_writers {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("Error creating writer: {e}");
+            return ExitStatus::WriterIo
+        }
+    };
+    // Devices are named in output once their names have been resolved.
+    let device_names = DeviceNames::new(cli.device_name);
+    if let Some(command) = &cli.i3bar_click {
+        i3bar::handle_clicks(command.clone());
+    }
+    // Everything that changes are written to, once they have been filtered.
+    // Each sink only receives changes to the properties given for it by --sink-properties, at no
+    // more than the rate given for it by --rate-limit, and only to devices of the severity given
+    // for it by --route.
+    let overflow = cli.rate_limit_overflow;
+    let sink_route = |sink: Sink| route(RouteTarget::Sink(sink));
+    let output_sink = SeverityRouteWriter::new(
+        PropertyFilterWriter::new(
+            RateLimitWriter::new(
+                DeviceNameWriter::new(format_writer, &device_names),
+                rate_limit(Sink::Output),
+                overflow
+            ),
+            sink_properties(Sink::Output)
+        ),
+        sink_route(Sink::Output)
+    );
+    let http_sink = SeverityRouteWriter::new(
+        PropertyFilterWriter::new(
+            RateLimitWriter::new(http.as_ref(), rate_limit(Sink::Http), overflow),
+            sink_properties(Sink::Http)
+        ),
+        sink_route(Sink::Http)
+    );
+    let service_sink = SeverityRouteWriter::new(
+        PropertyFilterWriter::new(
+            RateLimitWriter::new(service.as_ref(), rate_limit(Sink::DbusService), overflow),
+            sink_properties(Sink::DbusService)
+        ),
+        sink_route(Sink::DbusService)
+    );
+    let osd_sink = SeverityRouteWriter::new(
+        PropertyFilterWriter::new(
+            RateLimitWriter::new(osd.as_ref(), rate_limit(Sink::Osd), overflow),
+            sink_properties(Sink::Osd)
+        ),
+        sink_route(Sink::Osd)
+    );
+    let plugin_sink = SeverityRouteWriter::new(
+        PropertyFilterWriter::new(
+            RateLimitWriter::new(plugin.as_ref(), rate_limit(Sink::Plugin), overflow),
+            sink_properties(Sink::Plugin)
+        ),
+        sink_route(Sink::Plugin)
+    );
+    let extra_sink = SeverityRouteWriter::new(
+        PropertyFilterWriter::new(
+            RateLimitWriter::new(
+                DeviceNameWriter::new(extra_writers, &device_names),
+                rate_limit(Sink::ExtraOutput),
+                overflow
+            ),
+            sink_properties(Sink::ExtraOutput)
+        ),
+        sink_route(Sink::ExtraOutput)
+    );
+    // Properties given by --redact are redacted from all of them.
+    let sinks = RedactWriter::new(
+        TeeWriter::new(
+            TeeWriter::new(
+                TeeWriter::new(
+                    TeeWriter::new(TeeWriter::new(output_sink, http_sink), service_sink),
+                    osd_sink
+                ),
+                plugin_sink
+            ),
+            extra_sink
+        ),
+        &cli.redact
+    );
+    // The fields given by --annotate are added to every change.
+    let sinks = HostWriter::new(sinks, HostInfo::read(&cli.annotate));
+    // Changes are queued so that slow output does not hold up monitoring.
+    let queue = QueueWriter::new(sinks, cli.queue_size, cli.queue_overflow);
+    // Paused output is dropped after all state has been updated, so that filters, alerts and
+    // conditions are up to date when output is resumed.
+    let control = ControlWriter::new(&queue, cli.verbose);
+    // Changes which pass the filters are aggregated before they are written.
+    let aggregate_writer = AggregateWriter::new(
+        NumericEnumWriter::new(&control, cli.numeric_enums),
+        cli.aggregate.map(Duration::from_secs),
+        cli.aggregate_stats
+    );
+    let exec_writer = ExecWriter::new(
+        &aggregate_writer,
+        cli.exec.clone(),
+        cli.exec_timeout.map(Duration::from_secs),
+        cli.exec_jobs,
+        cli.exec_failure_events
+    ).with_severity(route(RouteTarget::Exec));
+    let filtered_writer = FilteredWriter::new(&exec_writer, transitions, filter);
+    #[cfg(feature = "wasm")]
+    let filtered_writer = filtered_writer.with_module(wasm_module);
+    let severity_writer = SeverityWriter::new(GlyphWriter::new(filtered_writer, glyphs), bands);
+    let report_writer = ReportWriter::new(&severity_writer, cli.report.map(Duration::from_secs));
+    let alert_writer = AlertWriter::new(
+        LatencyWriter::new(
+            SessionWriter::new(&report_writer, cli.sessions),
+            cli.transition_latency
+        ),
+        alert_rules
+    ).with_match(cli.alert_match).with_sound_player(&cli.sound_player);
+    let until_writer = UntilWriter::new(&alert_writer, until);
+    // EnergyRate is smoothed before it is used by any conditions.
+    let stale_writer = StaleWriter::new(
+        ThresholdWriter::new(&until_writer, cli.time_to),
+        cli.stale_after.map(Duration::from_secs)
+    );
+    // Percentage is quantized before anything else sees it.
+    let writer = StatsWriter::new(QuantizeWriter::new(
+        SmoothingWriter::new(&stale_writer, cli.smooth_energy_rate, cli.raw_energy_rate),
+        cli.quantize
+    ));
+    let serve_http = async {
+        if let (Some(http), Some(listener)) = (&http, &http_listener) {
+            if let Err(e) = http.serve(listener).await {
+                eprintln!("Error when serving HTTP connections: {e}");
+            }
+        }
+        pending::<()>().await
+    };
+    // Lengthens the intervals below while on battery, once UPower is being watched (over DBus).
+    let throttle = Throttle::new(cli.throttle_on_battery);
+    let watch_stale = async {
+        if let Err(e) = stale_writer.watch(&throttle).await {
+            eprintln!("Error when checking for stale devices: {e}");
+        }
+        pending::<()>().await
+    };
+    let write_aggregated = async {
+        if let Err(e) = aggregate_writer.run(&throttle).await {
+            eprintln!("Error writing aggregated changes: {e}");
+        }
+        pending::<()>().await
+    };
+    let write_reports = async {
+        if let Err(e) = report_writer.run(&throttle).await {
+            eprintln!("Error writing reports: {e}");
+        }
+        pending::<()>().await
+    };
+    let report_hooks = async {
+        if let Err(e) = exec_writer.run().await {
+            eprintln!("Error reporting on exec hooks: {e}");
+        }
+        pending::<()>().await
+    };
+    let show_notifications = alert_writer.run_notifications();
+    let handle_signals = async {
+        if let Err(e) = control.handle_signals().await {
+            eprintln!("Error when handling signals: {e}");
+        }
+        pending::<()>().await
+    };
+    // The devices being monitored, which can be changed over the control socket when listening
+    // over DBus.
+    let devices = DeviceSet::default().with_lifecycle(cli.lifecycle);
+    devices.extend(path_confs.iter().cloned()).await;
+    let dynamic_devices = matches!(cli.backend, Backend::Dbus) && cli.command.is_none();
+    let handle_command = async |command| -> Result<Option<String>, String> {
+        match command {
+            ControlCommand::Pause | ControlCommand::Resume => control
+                .set_paused(command == ControlCommand::Pause).await
+                .map_err(|e| e.to_string())?,
+            ControlCommand::Verbose(v) => control.set_verbose(v),
+            ControlCommand::AddDevice { .. } | ControlCommand::RemoveDevice(_)
+                if !dynamic_devices => {
+                return Err(String::from("Devices can only be changed when listening over DBus"))
+            },
+            ControlCommand::AddDevice { path, properties } => {
+                let config = DeviceConfig::new(&path, &properties, cli.interface.as_deref())?
+                    .with_debug_signals(cli.debug_signals);
+                if let Some(e) = unknown_bus(&config) {
+                    return Err(e)
+                }
+                devices.add(config).await?
+            },
+            ControlCommand::RemoveDevice(path) => {
+                devices.remove(&expand_device_path(&path)?).await?
+            },
+            ControlCommand::SetThreshold(severity, condition) => {
+                if cli.interface.is_none() {
+                    if let Some(p) = condition.properties().into_iter().find(|p| !is_property(p)) {
+                        return Err(ConfigError::unknown_property(p.as_str(), "in condition").into())
+                    }
+                }
+                severity_writer.set_condition(severity, condition).await?
+            },
+            ControlCommand::Stats => return Ok(Some(stats::to_json().to_string())),
+            ControlCommand::DumpState => {
+                let mut state = control.state().await;
+                state["devices"] = serde_json::json!(
+                    devices.configs().await.iter().map(|c| c.as_ref()).collect::<Vec<_>>()
+                );
+                state["dropped_changes"] = serde_json::json!(queue.dropped());
+                return Ok(Some(state.to_string()))
+            }
+        }
+        Ok(None)
+    };
+    let serve_commands = async {
+        if let Some(listener) = &control_listener {
+            if let Err(e) = serve_control(listener, &handle_command).await {
+                eprintln!("Error when serving control commands: {e}");
+            }
+        }
+        pending::<()>().await
+    };
+    let background = async {
+        join!(
+            serve_http,
+            watch_stale,
+            write_aggregated,
+            write_reports,
+            report_hooks,
+            show_notifications,
+            handle_signals,
+            serve_commands
+        );
+    };
+    // Completes when the user quits the dashboard, if shown.
+    let run_dashboard = async {
+        #[cfg(feature = "tui")]
+        if let Some(d) = &dashboard {
+            if let Err(e) = d.run().await {
+                eprintln!("Error when showing dashboard: {e}");
+            }
+            return
+        }
+        pending::<()>().await
+    };
+    let shutdown = async {
+        if let Err(e) = terminated().await {
+            eprintln!("Error when waiting for termination signals: {e}");
+            pending::<()>().await
+        }
+    };
+    // Writes queued changes, completing with an exit status if writing fails.
+    let write_queued = async {
+        match queue.run().await {
+            Ok(()) => None,
+            Err(e) => {
+                eprintln!("Error writing output: {e}");
+                Some(ExitStatus::WriterIo)
+            }
+        }
+    };
+    // Writes the lifecycle marker for monitoring stopping, if enabled, after any changes which are
+    // still queued.
+    let write_stopping = async || if cli.lifecycle {
+        if let Err(e) = write_lifecycle(&queue, Lifecycle::MonitorStopping, None).await {
+            eprintln!("Error writing output: {e}");
+        }
+    };
+    // Completes when monitoring should stop because the condition given by --until holds, the
+    // user has quit the dashboard, upmon has been asked to terminate or output has failed (or
+    // finished, once the queue is closed).
+    let stopped = async {
+        // Completes with whether the condition given by --until holds.
+        let reasons = async {
+            matches!(
+                select(
+                    pin!(background),
+                    select(pin!(until_writer.met()), select(pin!(run_dashboard), pin!(shutdown)))
+                ).await,
+                Either::Right((Either::Left(_), _))
+            )
+        };
+        let status = match select(pin!(write_queued), pin!(reasons)).await {
+            Either::Left((Some(status), _)) => status,
+            Either::Left((None, _)) if until_writer.is_met() => ExitStatus::ConditionMet,
+            Either::Left((None, _)) => ExitStatus::Success,
+            // The change which met the condition is still written.
+            Either::Right((true, write_queued)) => {
+                if let Err(e) = aggregate_writer.flush().await {
+                    eprintln!("Error writing aggregated changes: {e}");
+                }
+                write_stopping().await;
+                queue.close();
+                write_queued.await.unwrap_or(ExitStatus::ConditionMet)
+            },
+            // Otherwise, queued changes are only written if the lifecycle marker must be.
+            Either::Right((false, write_queued)) if cli.lifecycle => {
+                write_stopping().await;
+                queue.close();
+                write_queued.await.unwrap_or(ExitStatus::Success)
+            },
+            Either::Right(_) => ExitStatus::Success
+        };
+        status
+    };
+
+    let notify_ready = || match &daemon {
+        Some(d) => {
+            let closes_stdout = cli.output_file.is_some()
+                && cli.extra_output.iter().all(|o| o.output_file.is_some());
+            d.ready(closes_stdout).map_err(|e| {
+                eprintln!("Error when starting daemon: {e}");
+                ExitStatus::Error
+            })
+        },
+        None => Ok(())
+    };
+
+    if let Some(Command::Replay { file, speed }) = &cli.command {
+        let events = match read_events(file) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Error when reading recorded events: {e}");
+                return ExitStatus::Config
+            }
+        };
+        if let Err(status) = notify_ready() {
+            return status
+        }
+        let replayed = pin!(replay(&events, &path_confs, &writer, *speed));
+        let status = match select(replayed, pin!(stopped)).await {
+            Either::Left((Err(e), _)) => {
+                eprintln!("Error when replaying events: {e}");
+                ExitStatus::from(&e)
+            },
+            // Changes still queued are written before exiting. The condition may have held after
+            // the last event.
+            Either::Left((Ok(()), stopped)) => {
+                if let Err(e) = aggregate_writer.flush().await {
+                    eprintln!("Error writing aggregated changes: {e}");
+                }
+                queue.close();
+                stopped.await
+            },
+            Either::Right((status, _)) => status
+        };
+        return status
+    }
+
+    if let Some(Command::Diff { old, new }) = &cli.command {
+        let include = |d: &str| path_confs.is_empty() || path_confs.iter().any(|p| p.is_for(d));
+        let events = read_snapshot(old)
+            .and_then(|old| Ok((old, read_snapshot(new)?)))
+            .and_then(|(old, new)| diff(&old, &new, include));
+        let events = match events {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Error when comparing snapshots: {e}");
+                return ExitStatus::Config
+            }
+        };
+        if let Err(status) = notify_ready() {
+            return status
+        }
+        let written = async {
+            for event in &events {
+                writer.write(event).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let status = match select(pin!(written), pin!(stopped)).await {
+            Either::Left((Err(e), _)) => {
+                eprintln!("Error when writing differences: {e}");
+                ExitStatus::WriterIo
+            },
+            Either::Left((Ok(()), stopped)) => {
+                if let Err(e) = aggregate_writer.flush().await {
+                    eprintln!("Error writing aggregated changes: {e}");
+                }
+                queue.close();
+                stopped.await
+            },
+            Either::Right((status, _)) => status
+        };
+        return status
+    }
+
+    let recorder = match cli.record.as_deref().map(Recorder::new).transpose() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error creating recorder: {e}");
+            return ExitStatus::WriterIo
+        }
+    };
+
+    if let Backend::Udev = cli.backend {
+        if let Err(status) = notify_ready() {
+            return status
+        }
+        let listened = pin!(udev::listen_all(&path_confs, &writer, recorder.as_ref()));
+        return match select(listened, pin!(stopped)).await {
+            Either::Left((Err(e), _)) => {
+                eprintln!("Error when listening for uevents: {e}");
+                ExitStatus::from(&e)
+            },
+            Either::Left((Ok(()), _)) => ExitStatus::Success,
+            Either::Right((status, _)) => status
+        }
+    }
+
+    let timeout = Duration::from_secs(cli.connect_timeout);
+    let conn = match connect_system(timeout, &retry).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error when connecting to the system bus: {e}");
+            return ExitStatus::DbusConnection
+        }
+    };
+
+    let mut buses = HashMap::new();
+    for bus in &cli.bus {
+        match bus.connect(timeout, &retry).await {
+            Ok(bus_conn) => buses.insert(bus.label.clone(), bus_conn),
+            Err(e) => {
+                eprintln!("Error when connecting to bus {}: {e}", bus.label);
+                return ExitStatus::DbusConnection
+            }
+        };
+    }
+    // The connection to the bus each device is on.
+    let conn_for = |c: &DeviceConfig| c.bus().and_then(|b| buses.get(b)).unwrap_or(&conn);
+
+    let all_buses = [(None, &conn)].into_iter().chain(buses.iter().map(|(l, c)| (Some(l), c)));
+    for (label, bus_conn) in all_buses {
+        if !path_confs.iter().any(|c| c.is_upower() && c.bus() == label.map(String::as_str)) {
+            continue
+        }
+        let bus = label.map_or(String::from("the system bus"), |l| format!("bus {l}"));
+        match upower_available(bus_conn).await {
+            Ok(true) => {},
+            Ok(false) => {
+                eprintln!("UPower is not available on {bus}");
+                return ExitStatus::UpowerMissing
+            },
+            Err(e) => {
+                eprintln!("Error when checking for UPower on {bus}: {e}");
+                return ExitStatus::DbusConnection
+            }
+        }
+    }
+
+    if !cli.discover.is_empty() {
+        return discover(&conn, &cli.discover, cli.interface.as_deref()).await
+    }
+
+    if cli.bluez {
+        let discovered = match discover_batteries(&conn).await {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("Error when discovering BlueZ devices: {e}");
+                return ExitStatus::Error
+            }
+        };
+        let discovered = discovered.into_iter()
+            .map(|c| c.with_debug_signals(cli.debug_signals))
+            .collect::<Vec<_>>();
+        devices.extend(discovered.iter().cloned()).await;
+        path_confs.extend(discovered);
+    }
+    for conf in &path_confs {
+        device_names.resolve(conn_for(conf), slice::from_ref(conf)).await;
+    }
+    if cli.lifecycle {
+        if let Err(e) = write_lifecycle(&writer, Lifecycle::MonitorStarted, None).await {
+            eprintln!("Error writing output: {e}");
+            return ExitStatus::WriterIo
+        }
+    }
+    if cli.device_info {
+        for conf in &path_confs {
+            if let Err(e) = info::write_device_info(conn_for(conf), slice::from_ref(conf), &writer)
+                .await {
+                eprintln!("Error writing output: {e}");
+                return ExitStatus::WriterIo
+            }
+        }
+    }
+
+    // Properties added in a later version of UPower than the daemon's are explained as such.
+    let daemon_version = if path_confs.iter().any(|c| c.is_upower() && c.bus().is_none()) {
+        daemon_version(&conn).await.unwrap_or_else(|e| {
+            eprintln!("Warning: could not fetch the UPower daemon's version: {e}");
+            None
+        })
+    } else {
+        None
+    };
+    for conf in &path_confs {
+        match conf.unsupported_targets(conn_for(conf)).await {
+            Ok(unsupported) => for kind in unsupported {
+                let required = daemon_version.as_deref()
+                    .filter(|_| conf.is_upower() && conf.bus().is_none())
+                    .and_then(|v| Some((v, required_version(kind, v)?)));
+                match required {
+                    Some((version, required)) => eprintln!(
+                        "Warning: {kind} requires UPower {required} or later, but the daemon is \
+                        version {version}, so it will never change for {}",
+                        conf.device()
+                    ),
+                    None => eprintln!(
+                        "Warning: {kind} is not available for {}, so it will never change",
+                        conf.device()
+                    )
+                }
+            },
+            Err(e) => eprintln!("Could not check properties of {}: {e}", conf.device())
+        }
+    }
+    // Resume from sleep and battery health are only followed for devices on the system bus.
+    let system_confs = path_confs.iter()
+        .filter(|c| c.bus().is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let listen_devices = async {
+        if let Err(e) = devices.listen(&conn, &buses, &writer, recorder.as_ref()).await {
+            eprintln!("Error writing output: {e}");
+            return ExitStatus::WriterIo
+        }
+        pending().await
+    };
+    let listen_sleep = async {
+        if !(cli.refresh_on_resume || cli.mark_resume) {
+            return
+        }
+        if let Err(e) = logind::listen_resume(
+            &conn,
+            &system_confs,
+            &writer,
+            cli.refresh_on_resume,
+            cli.mark_resume
+        ).await {
+            eprintln!("Error when listening for resume from sleep: {e}");
+        }
+    };
+    let listen_critical = async {
+        if !cli.critical_action {
+            return
+        }
+        if let Err(e) = critical::listen_critical(&conn, DISPLAY_DEVICE_PATH, &writer).await {
+            eprintln!("Error when listening for critical action: {e}");
+        }
+    };
+    let listen_health = async {
+        if let Some(threshold) = cli.health_warning {
+            health::listen_health_all(&conn, &system_confs, threshold, &writer).await
+        }
+    };
+    let listen_upower = async {
+        if !cli.lifecycle {
+            return
+        }
+        if let Err(e) = lifecycle::listen_upower(&conn, &system_confs, &writer).await {
+            eprintln!("Error when listening for UPower restarts: {e}");
+        }
+    };
+    let watch_bluez = async {
+        if !cli.bluez {
+            return
+        }
+        if let Err(e) = bluez::watch_batteries(&conn, &devices, cli.debug_signals).await {
+            eprintln!("Error when watching for BlueZ devices: {e}");
+        }
+    };
+    let watch_on_battery = async {
+        if let Err(e) = throttle.watch(&conn).await {
+            eprintln!("Error when watching whether the system is on battery: {e}");
+        }
+    };
+    let take_actions = alert_writer.run_actions(&conn);
+    // Completes only if the daemon could not report that it is ready.
+    let listen_ready = async {
+        devices.subscribed().await;
+        if let Err(status) = notify_ready() {
+            return status
+        }
+        pending().await
+    };
+    let listen_others = async {
+        join!(
+            listen_sleep,
+            listen_critical,
+            listen_health,
+            listen_upower,
+            watch_bluez,
+            watch_on_battery,
+            take_actions
+        );
+        pending().await
+    };
+    let listen = async {
+        let others = async {
+            select(pin!(listen_others), pin!(listen_ready)).await.factor_first().0
+        };
+        select(pin!(listen_devices), pin!(others)).await.factor_first().0
+    };
+    let (status, _) = select(pin!(listen), pin!(stopped)).await.factor_first();
+    status
+}
@@ -0,0 +1,168 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+
+/// How often compressed output is flushed, so that everything written before a flush point can be
+/// decompressed even while upmon is still writing.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The compression applied to the output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// gzip (readable with zcat or zless).
+    Gzip,
+    /// Zstandard (readable with zstdcat or zstdless).
+    Zstd
+}
+
+impl Compression {
+    /// Return a writer which compresses everything written to it before writing it to `out`.
+    pub(crate) fn wrap(
+        self,
+        out: impl Write + Send + 'static
+    ) -> Result<Box<dyn Write>, std::io::Error> {
+        let encoder: Box<dyn Write + Send> = match self {
+            Compression::Gzip => Box::new(GzEncoder::new(out, flate2::Compression::default())),
+            Compression::Zstd => Box::new(zstd::Encoder::new(out, 0)?.auto_finish())
+        };
+        Ok(Box::new(CompressedOutput::new(encoder, FLUSH_INTERVAL)))
+    }
+}
+
+/// An encoder shared between a [`CompressedOutput`] and the thread which flushes it.
+struct SharedEncoder {
+    /// The encoder to which output is written.
+    encoder: Box<dyn Write + Send>,
+    /// Whether anything has been written since the encoder was last flushed.
+    unflushed: bool
+}
+
+/// A compressed stream. Writing to the stream does not flush it, as flushing after every line would
+/// defeat the compression; instead, a separate thread writes a flush point once per interval if
+/// anything has been written since the last, and flushing the stream (as writers do after writing a
+/// marker) writes one immediately. The stream is finished when it is dropped; if upmon is killed,
+/// everything written before the last flush point can still be recovered.
+struct CompressedOutput {
+    /// The encoder, which is taken (and so finished) when the stream is dropped.
+    shared: Arc<Mutex<Option<SharedEncoder>>>
+}
+
+impl CompressedOutput {
+    /// Create a new [`CompressedOutput`] which flushes `encoder` once per `interval` if anything
+    /// has been written to it.
+    fn new(encoder: Box<dyn Write + Send>, interval: Duration) -> Self {
+        let shared = Arc::new(Mutex::new(Some(SharedEncoder { encoder, unflushed: false })));
+        let flushed = Arc::clone(&shared);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match lock(&flushed).as_mut() {
+                Some(s) if s.unflushed => {
+                    s.unflushed = false;
+                    if let Err(e) = s.encoder.flush() {
+                        eprintln!("Error when flushing compressed output: {e}");
+                    }
+                },
+                Some(_) => {},
+                None => return
+            }
+        });
+        Self { shared }
+    }
+}
+
+/// Lock `shared`, ignoring poisoning: a panic while writing leaves the encoder no less usable than
+/// any other write error would.
+fn lock(shared: &Mutex<Option<SharedEncoder>>) -> MutexGuard<'_, Option<SharedEncoder>> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Write for CompressedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut shared = lock(&self.shared);
+        let s = shared.as_mut().expect("Only taken when dropped");
+        s.unflushed = true;
+        s.encoder.write(buf)
+    }
+
+    /// Flush the encoder, writing a flush point.
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut shared = lock(&self.shared);
+        let s = shared.as_mut().expect("Only taken when dropped");
+        s.unflushed = false;
+        s.encoder.flush()
+    }
+}
+
+impl Drop for CompressedOutput {
+    /// Finish the stream here, rather than on the flushing thread, so that it is finished before
+    /// upmon exits.
+    fn drop(&mut self) {
+        drop(lock(&self.shared).take());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Read, Write};
+    use std::time::Duration;
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use crate::compress::{CompressedOutput, Compression};
+    use crate::rt::block_on;
+    use crate::testing::{eventually, SharedBuffer};
+
+    /// Test that output compressed in each format can be decompressed once the stream is finished.
+    #[test]
+    fn compressed_output() {
+        let lines = "/bat Percentage=80\n/bat Percentage=79\n".repeat(100);
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let buf = SharedBuffer::default();
+            let mut out = compression.wrap(buf.clone()).unwrap();
+            for line in lines.lines() {
+                writeln!(out, "{line}").unwrap();
+            }
+            drop(out);
+            let compressed = buf.bytes();
+            assert!(compressed.len() < lines.len() / 10);
+            let decompressed = match compression {
+                Compression::Gzip => {
+                    let mut s = String::new();
+                    GzDecoder::new(compressed.as_slice()).read_to_string(&mut s).unwrap();
+                    s
+                },
+                Compression::Zstd => {
+                    String::from_utf8(zstd::decode_all(compressed.as_slice()).unwrap()).unwrap()
+                }
+            };
+            assert_eq!(decompressed, lines);
+        }
+    }
+
+    /// Test that output is flushed once the interval has passed, and immediately when the stream
+    /// is flushed, so that it can be decompressed before the stream is finished.
+    #[test]
+    fn flush_points() {
+        let decompressed = |buf: &SharedBuffer| {
+            // The stream is unfinished, so decoding stops with an error after the last flush point.
+            let mut s = String::new();
+            let _ = GzDecoder::new(buf.bytes().as_slice()).read_to_string(&mut s);
+            s
+        };
+        for (interval, flush) in [(Duration::from_millis(50), false), (Duration::MAX, true)] {
+            let buf = SharedBuffer::default();
+            let encoder = GzEncoder::new(buf.clone(), flate2::Compression::default());
+            let mut out = CompressedOutput::new(Box::new(encoder), interval);
+            writeln!(out, "/bat Percentage=80").unwrap();
+            if flush {
+                out.flush().unwrap();
+                assert_eq!(decompressed(&buf), "/bat Percentage=80\n");
+            } else {
+                assert!(block_on(eventually(|| decompressed(&buf) == "/bat Percentage=80\n")));
+            }
+        }
+    }
+}
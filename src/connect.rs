@@ -0,0 +1,296 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Serialize;
+use zbus::{Connection, ConnectionBuilder, Error as zbus_Error, Result as zbus_Result};
+use crate::retry::RetryPolicy;
+use crate::rt::{bus_stream, timeout};
+use crate::stats::{increment, Counter};
+
+/// The default for how long to wait for the reply to a DBus method call, in milliseconds. This is
+/// the same as libdbus's default.
+pub(crate) const DEFAULT_METHOD_TIMEOUT_MS: u64 = 25_000;
+
+/// How long to wait for the reply to a DBus method call, in milliseconds, or 0 to wait
+/// indefinitely. This is set from `--dbus-timeout` when upmon starts.
+static METHOD_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_METHOD_TIMEOUT_MS);
+
+/// Set how long to wait for the reply to each DBus method call, in milliseconds (0 meaning
+/// indefinitely).
+pub(crate) fn set_method_timeout(ms: u64) {
+    METHOD_TIMEOUT_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Wait for `call` (a call of the DBus method `method`, or a series of calls made for it) to
+/// finish, for up to `limit`. If it does not finish in time, a [`TimedOut`] error naming the
+/// method is returned, so that a hung service cannot stall upmon indefinitely.
+///
+/// [`TimedOut`]: std::io::ErrorKind::TimedOut
+async fn with_timeout<T>(
+    limit: Option<Duration>,
+    method: &str,
+    call: impl Future<Output = zbus_Result<T>>
+) -> zbus_Result<T> {
+    let Some(limit) = limit else {
+        return call.await
+    };
+    timeout(limit, call).await.unwrap_or_else(|_| Err(zbus_Error::InputOutput(Arc::new(
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("no reply to {method} within {}ms", limit.as_millis())
+        )
+    ))))
+}
+
+/// Wait for `call` to finish, for up to the time set by [`set_method_timeout`]. See
+/// [`with_timeout`].
+pub(crate) async fn with_method_timeout<T>(
+    method: &str,
+    call: impl Future<Output = zbus_Result<T>>
+) -> zbus_Result<T> {
+    let ms = METHOD_TIMEOUT_MS.load(Ordering::Relaxed);
+    with_timeout((ms > 0).then(|| Duration::from_millis(ms)), method, call).await
+}
+
+/// Connect to the system bus, retrying for up to `timeout` if it is not yet available (for
+/// example, if upmon is started before dbus-daemon), waiting between attempts as set out by
+/// `policy`.
+pub(crate) async fn connect_system(
+    timeout: Duration,
+    policy: &RetryPolicy
+) -> zbus_Result<Connection> {
+    let retrying = Cell::new(false);
+    policy.retry(timeout, async || {
+        if retrying.replace(true) {
+            increment(Counter::Reconnects);
+        }
+        Connection::system().await
+    }).await
+}
+
+/// The command run on a remote host to bridge its standard input and output to the host's system
+/// bus.
+const REMOTE_BRIDGE: &str = "systemd-stdio-bridge";
+
+/// Return an error if `label` is not a valid label for a bus.
+fn check_label(label: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if label.is_empty() || !label.chars().all(valid) {
+        return Err(format!(
+            "Invalid bus label: {label} (expected letters, digits, dots, hyphens or underscores)"
+        ))
+    }
+    Ok(())
+}
+
+/// Escape `value` for use in a DBus address, in which only a few characters may appear unescaped.
+fn escape_address_value(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'-' | b'_' | b'/' | b'.' | b'\\' | b'*' => char::from(b).to_string(),
+            b if b.is_ascii_alphanumeric() => char::from(b).to_string(),
+            b => format!("%{b:02x}")
+        })
+        .collect()
+}
+
+/// Unescape a value in a DBus address, in which any byte may be given as `%` and two hex digits.
+fn unescape_address_value(value: &str) -> Result<String, String> {
+    let mut bytes = vec!();
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2)
+                .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok())
+                .ok_or_else(|| format!("Invalid escape in address value: {value}"))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("Invalid address value: {value}"))
+}
+
+/// Return the program and arguments of a `unixexec:` address, whose `path` is the program to run
+/// and whose `argv1`, `argv2` and so on are its arguments (`argv0` is ignored, as it cannot be set
+/// portably). Returns `None` if the address is of some other kind, which zbus handles itself.
+fn exec_command(address: &str) -> Option<Result<(String, Vec<String>), String>> {
+    let params = address.strip_prefix("unixexec:")?;
+    let parse = || {
+        let mut path = None;
+        let mut args = vec!();
+        for param in params.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = param.split_once('=')
+                .ok_or_else(|| format!("Expected KEY=VALUE in address: {param}"))?;
+            let value = unescape_address_value(value)?;
+            match key.strip_prefix("argv").map(str::parse::<usize>) {
+                Some(Ok(0)) => {},
+                Some(Ok(n)) => args.push((n, value)),
+                _ if key == "path" => path = Some(value),
+                _ => return Err(format!("Unknown key in unixexec address: {key}"))
+            }
+        }
+        args.sort();
+        if args.iter().enumerate().any(|(i, (n, _))| *n != i + 1) {
+            return Err(String::from("Arguments of unixexec address must be numbered from argv1"))
+        }
+        let path = path.ok_or_else(|| String::from("No path given in unixexec address"))?;
+        Ok((path, args.into_iter().map(|(_, a)| a).collect()))
+    };
+    Some(parse())
+}
+
+/// Connect to a bus through a program which relays the bus over its standard input and output,
+/// such as `systemd-stdio-bridge` run over SSH.
+async fn connect_exec(program: &str, args: &[String]) -> zbus_Result<Connection> {
+    let (ours, theirs) = UnixStream::pair()?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::from(OwnedFd::from(theirs.try_clone()?)))
+        .stdout(Stdio::from(OwnedFd::from(theirs)))
+        .spawn()?;
+    // The program exits when the connection is closed; wait for it so that it is not left behind.
+    std::thread::spawn(move || child.wait());
+    ConnectionBuilder::unix_stream(bus_stream(ours)?).build().await
+}
+
+/// A bus other than the system bus on which devices are monitored, such as a container's system
+/// bus, given on the command line in the form `LABEL=ADDRESS`. Devices on the bus are given and
+/// identified as `LABEL@PATH`. As well as the addresses zbus supports, `unixexec:` addresses are
+/// supported, so that a bus can be reached through a program such as `ssh`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Bus {
+    /// The label identifying the bus.
+    pub label: String,
+    /// The DBus address of the bus, such as `unix:path=/run/dbus/system_bus_socket`.
+    pub address: String
+}
+
+impl FromStr for Bus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, address) = s.split_once('=')
+            .ok_or_else(|| format!("Expected LABEL=ADDRESS: {s}"))?;
+        check_label(label)?;
+        if address.is_empty() {
+            return Err(format!("No address given for bus {label}"))
+        }
+        if let Some(Err(e)) = exec_command(address) {
+            return Err(e)
+        }
+        Ok(Self { label: String::from(label), address: String::from(address) })
+    }
+}
+
+impl Bus {
+    /// Create a [`Bus`] for the system bus of a remote host, given in the form
+    /// `[LABEL=][USER@]HOST`, which is reached by running `systemd-stdio-bridge` on the host over
+    /// SSH. If no label is given, the host name is used.
+    pub(crate) fn remote(s: &str) -> Result<Self, String> {
+        let (label, destination) = match s.split_once('=') {
+            Some((label, destination)) => (label, destination),
+            None => (s.rsplit('@').next().unwrap_or(s), s)
+        };
+        check_label(label)?;
+        if destination.is_empty() || destination.starts_with('-') {
+            return Err(format!("Invalid host: {destination}"))
+        }
+        Ok(Self {
+            label: String::from(label),
+            address: format!(
+                "unixexec:path=ssh,argv1=-xT,argv2={},argv3={REMOTE_BRIDGE}",
+                escape_address_value(destination)
+            )
+        })
+    }
+
+    /// Connect to the bus, retrying for up to `timeout` (as set out by `policy`) if it is not yet
+    /// available.
+    pub(crate) async fn connect(
+        &self,
+        timeout: Duration,
+        policy: &RetryPolicy
+    ) -> zbus_Result<Connection> {
+        let retrying = Cell::new(false);
+        policy.retry(timeout, async || {
+            if retrying.replace(true) {
+                increment(Counter::Reconnects);
+            }
+            match exec_command(&self.address) {
+                Some(Ok((program, args))) => connect_exec(&program, &args).await,
+                Some(Err(e)) => Err(zbus_Error::Address(e)),
+                None => ConnectionBuilder::address(self.address.as_str())?.build().await
+            }
+        }).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::future::pending;
+    use std::time::Duration;
+    use crate::connect::{exec_command, with_timeout, Bus};
+    use crate::rt::block_on;
+
+    /// Test that calls which take too long fail with an error naming the method, and that calls
+    /// are waited for indefinitely if there is no timeout.
+    #[test]
+    fn method_timeout() {
+        block_on(async {
+            let limit = Some(Duration::from_millis(20));
+            let err = with_timeout(limit, "GetAll", pending::<zbus::Result<()>>()).await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "I/O error: no reply to GetAll within 20ms");
+            assert_eq!(with_timeout(limit, "Get", async { Ok(1) }).await, Ok(1));
+            assert_eq!(with_timeout(None, "Get", async { Ok(2) }).await, Ok(2));
+        })
+    }
+
+    /// Test that buses are parsed, and that invalid ones are rejected.
+    #[test]
+    fn parse_bus() {
+        assert_eq!(
+            "container=unix:path=/run/container/dbus.sock".parse::<Bus>(),
+            Ok(Bus {
+                label: String::from("container"),
+                address: String::from("unix:path=/run/container/dbus.sock")
+            })
+        );
+        assert!("unix:path=/run/dbus.sock".parse::<Bus>().is_err());
+        assert!("a@b=unix:path=/run/dbus.sock".parse::<Bus>().is_err());
+        assert!("container=".parse::<Bus>().is_err());
+        assert!("remote=unixexec:argv1=host".parse::<Bus>().is_err());
+    }
+
+    /// Test that remote hosts are reached over SSH, and that unixexec addresses are parsed into
+    /// the program and arguments to run.
+    #[test]
+    fn remote_bus() {
+        let bus = Bus::remote("admin@ups-1.example.com").unwrap();
+        assert_eq!(bus.label, "ups-1.example.com");
+        assert_eq!(
+            bus.address,
+            "unixexec:path=ssh,argv1=-xT,argv2=admin%40ups-1.example.com,argv3=systemd-stdio-bridge"
+        );
+        assert_eq!(
+            exec_command(&bus.address),
+            Some(Ok((String::from("ssh"), vec!(
+                String::from("-xT"),
+                String::from("admin@ups-1.example.com"),
+                String::from("systemd-stdio-bridge")
+            ))))
+        );
+        assert_eq!(Bus::remote("ups=ups-1").unwrap().label, "ups");
+        assert!(Bus::remote("ups=-oProxyCommand").is_err());
+        assert_eq!(exec_command("unix:path=/run/dbus.sock"), None);
+    }
+}
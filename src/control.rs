@@ -0,0 +1,366 @@
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use async_signal::{Signal, Signals};
+use chrono::{SecondsFormat, Utc};
+use futures::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use crate::cache::ValueCache;
+use crate::event::DeviceEvent;
+use crate::expr::Expr;
+use crate::output::Writer;
+use crate::rt::{UnixListener, UnixStream};
+use crate::severity::Severity;
+use crate::stats;
+
+/// The marker written when output is paused.
+const PAUSED_MARKER: &str = "OutputPaused";
+
+/// The marker written when output is resumed.
+const RESUMED_MARKER: &str = "OutputResumed";
+
+/// A command sent to a running upmon over its control socket, one per line.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ControlCommand {
+    /// `pause`: pause output.
+    Pause,
+    /// `resume`: resume output.
+    Resume,
+    /// `verbose on` or `verbose off`: enable or disable verbose logging.
+    Verbose(bool),
+    /// `add-device PATH PROPERTIES`: start monitoring the given comma-separated properties of the
+    /// device at the given path.
+    AddDevice {
+        path: String,
+        properties: String
+    },
+    /// `remove-device PATH`: stop monitoring the device at the given path.
+    RemoveDevice(String),
+    /// `set-threshold warning|critical CONDITION`: replace the condition under which a device's
+    /// severity is at least the given severity.
+    SetThreshold(Severity, Expr),
+    /// `dump-state`: return the current configuration and the latest value of each property of
+    /// each device, as JSON.
+    DumpState,
+    /// `stats`: return the counters of what upmon itself has done (see [`crate::stats`]), as JSON.
+    Stats
+}
+
+impl ControlCommand {
+    /// Parse a command from a line sent over the control socket.
+    pub(crate) fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+        let mut words = args.split_whitespace();
+        let command = match (command, words.next(), words.next(), words.next()) {
+            ("pause", None, _, _) => Self::Pause,
+            ("resume", None, _, _) => Self::Resume,
+            ("verbose", Some("on"), None, _) => Self::Verbose(true),
+            ("verbose", Some("off"), None, _) => Self::Verbose(false),
+            ("add-device", Some(path), Some(properties), None) => Self::AddDevice {
+                path: String::from(path),
+                properties: String::from(properties)
+            },
+            ("remove-device", Some(path), None, _) => Self::RemoveDevice(String::from(path)),
+            ("set-threshold", Some(severity), Some(_), _) => {
+                let severity = match severity {
+                    "warning" => Severity::Warning,
+                    "critical" => Severity::Critical,
+                    s => return Err(format!("Expected warning or critical: {s}"))
+                };
+                // The condition is everything after the severity, and may contain whitespace.
+                let condition = args[args.find(char::is_whitespace).unwrap_or_default()..].trim();
+                Self::SetThreshold(severity, Expr::parse(condition)?)
+            },
+            ("dump-state", None, _, _) => Self::DumpState,
+            ("stats", None, _, _) => Self::Stats,
+            _ => return Err(format!("Invalid command: {line}"))
+        };
+        Ok(command)
+    }
+}
+
+/// Bind a listener for control commands to the Unix socket at `path`, replacing any socket left
+/// there by a previous instance.
+pub(crate) async fn bind_control_socket(path: &str) -> Result<UnixListener, Error> {
+    if let Ok(m) = Path::new(path).symlink_metadata() {
+        if !m.file_type().is_socket() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("Not a socket: {path}")))
+        }
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path).await
+}
+
+/// Read commands from a single control socket client, one per line, and write the response to each
+/// command as a line: the output of `handler` (or "ok" if it returns `None`) if it succeeds, or
+/// "error: " followed by the error if it does not.
+async fn handle_client(
+    stream: UnixStream,
+    handler: &impl AsyncFn(ControlCommand) -> Result<Option<String>, String>
+) -> Result<(), Error> {
+    let (reader, mut stream) = stream.split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let response = match ControlCommand::parse(&line) {
+            Ok(command) => handler(command).await,
+            Err(e) => Err(e)
+        };
+        let response = match response {
+            Ok(output) => output.unwrap_or_else(|| String::from("ok")),
+            Err(e) => format!("error: {e}")
+        };
+        stream.write_all(format!("{response}\n").as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Serve control commands to clients connecting to `listener`, passing each command to `handler`.
+pub(crate) async fn serve_control(
+    listener: &UnixListener,
+    handler: &impl AsyncFn(ControlCommand) -> Result<Option<String>, String>
+) -> Result<(), Error> {
+    listener.incoming()
+        .for_each_concurrent(None, |stream| async move {
+            if let Ok(stream) = stream {
+                let _ = handle_client(stream, handler).await;
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// A [`Writer`] which passes changes and markers on to an inner [`Writer`] unless output has been
+/// paused, and which logs each change and marker to standard error if verbose logging is enabled.
+/// Pausing output does not affect monitoring, so changes are written again as soon as output is
+/// resumed. [`ControlWriter::handle_signals`] pauses and resumes output when upmon receives
+/// SIGUSR2; verbose logging is toggled over the control socket. The latest value of each
+/// property of each device is kept, whether or not output is paused, for `dump-state`.
+pub struct ControlWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// Whether output is paused.
+    paused: AtomicBool,
+    /// Whether verbose logging is enabled.
+    verbose: AtomicBool,
+    /// The latest value of each property of each device.
+    values: Mutex<ValueCache>
+}
+
+impl<W: Writer> ControlWriter<W> {
+    /// Create a new [`ControlWriter`] which passes changes to `inner`, with verbose logging enabled
+    /// if `verbose` is true.
+    pub(crate) fn new(inner: W, verbose: bool) -> Self {
+        Self {
+            inner,
+            paused: AtomicBool::new(false),
+            verbose: AtomicBool::new(verbose),
+            values: Mutex::new(ValueCache::default())
+        }
+    }
+
+    /// Return whether output is paused, whether verbose logging is enabled and the latest value of
+    /// each property of each device, as a JSON object.
+    pub(crate) async fn state(&self) -> Value {
+        json!({
+            "paused": self.is_paused(),
+            "verbose": self.verbose.load(Ordering::SeqCst),
+            "values": self.values.lock().await.to_json()
+        })
+    }
+
+    /// Whether output is paused.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Pause or resume output. A marker is written when output is paused (before pausing) and when
+    /// it is resumed (after resuming), so that consumers know that changes may have been missed.
+    pub(crate) async fn set_paused(&self, paused: bool) -> Result<(), Error> {
+        if self.is_paused() == paused {
+            return Ok(())
+        }
+        if paused {
+            self.write_marker(PAUSED_MARKER).await?;
+            self.paused.store(true, Ordering::SeqCst);
+        } else {
+            self.paused.store(false, Ordering::SeqCst);
+            self.write_marker(RESUMED_MARKER).await?;
+        }
+        Ok(())
+    }
+
+    /// Enable or disable verbose logging.
+    pub(crate) fn set_verbose(&self, verbose: bool) {
+        self.verbose.store(verbose, Ordering::SeqCst);
+        eprintln!("Verbose logging {}", if verbose { "enabled" } else { "disabled" });
+    }
+
+    /// Log a line to standard error if verbose logging is enabled, noting if it will not be written
+    /// because output is paused.
+    fn log(&self, line: &str) {
+        if self.verbose.load(Ordering::SeqCst) {
+            eprintln!(
+                "{} {line}{}",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                if self.is_paused() { " (paused)" } else { "" }
+            );
+        }
+    }
+
+    /// Pause or resume output whenever upmon receives SIGUSR2, and write upmon's counters (see
+    /// [`crate::stats`]) to standard error whenever it receives SIGUSR1.
+    pub(crate) async fn handle_signals(&self) -> Result<(), Error> {
+        let mut signals = Signals::new([Signal::Usr1, Signal::Usr2])?;
+        while let Some(signal) = signals.next().await {
+            match signal? {
+                Signal::Usr2 => self.set_paused(!self.is_paused()).await?,
+                _ => eprintln!("Counters: {}", stats::to_line())
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for ControlWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        self.values.lock().await.update(event);
+        if self.verbose.load(Ordering::SeqCst) {
+            let mut changed = event.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>();
+            changed.sort();
+            self.log(&format!("{} {}", event.device, changed.join(" ")));
+        }
+        if self.is_paused() {
+            return Ok(())
+        }
+        self.inner.write(event).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        self.log(marker);
+        if self.is_paused() {
+            return Ok(())
+        }
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use futures::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use futures::StreamExt;
+    use crate::control::{bind_control_socket, ControlCommand, ControlWriter, serve_control};
+    use crate::event::DeviceEvent;
+    use crate::expr::Expr;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::{block_on, UnixStream};
+    use crate::severity::Severity;
+    use crate::testing::{run_until, SharedBuffer};
+    use crate::upower::Property::Percentage;
+    use crate::upower::PropertyKind;
+
+    /// Test that nothing is written while output is paused, and that markers are written when
+    /// output is paused and resumed.
+    #[test]
+    fn pause() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = ControlWriter::new(inner, false);
+        let write = |p| {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(p));
+            block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        };
+        write(50.0);
+        block_on(writer.set_paused(true)).unwrap();
+        block_on(writer.set_paused(true)).unwrap();
+        write(49.0);
+        block_on(writer.write_marker("Resumed")).unwrap();
+        block_on(writer.set_paused(false)).unwrap();
+        write(48.0);
+        assert_eq!(
+            buf.contents(),
+            "/dev Percentage=50\nOutputPaused\nOutputResumed\n/dev Percentage=48\n"
+        );
+        let state = block_on(writer.state());
+        assert_eq!(state["paused"], false);
+        assert_eq!(state["values"]["/dev"]["Percentage"], 48.0);
+    }
+
+    /// Test parsing of control commands.
+    #[test]
+    fn parse_commands() {
+        assert_eq!(ControlCommand::parse(" pause ").unwrap(), ControlCommand::Pause);
+        assert_eq!(ControlCommand::parse("verbose off").unwrap(), ControlCommand::Verbose(false));
+        assert_eq!(
+            ControlCommand::parse("add-device /dev Percentage,State").unwrap(),
+            ControlCommand::AddDevice {
+                path: String::from("/dev"),
+                properties: String::from("Percentage,State")
+            }
+        );
+        assert_eq!(
+            ControlCommand::parse("remove-device /dev").unwrap(),
+            ControlCommand::RemoveDevice(String::from("/dev"))
+        );
+        assert_eq!(
+            ControlCommand::parse("set-threshold critical Percentage <= 10").unwrap(),
+            ControlCommand::SetThreshold(
+                Severity::Critical,
+                Expr::parse("Percentage <= 10").unwrap()
+            )
+        );
+        assert_eq!(ControlCommand::parse("dump-state").unwrap(), ControlCommand::DumpState);
+        assert_eq!(ControlCommand::parse("stats").unwrap(), ControlCommand::Stats);
+        for invalid in [
+            "unpause", "pause now", "verbose", "add-device /dev", "set-threshold ok Online",
+            "set-threshold warning Percentage <"
+        ] {
+            assert!(ControlCommand::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    /// Test that commands received over the control socket are handled and answered.
+    #[test]
+    fn control_socket() {
+        let path = std::env::temp_dir().join(format!("upmon-control-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        block_on(async {
+            let listener = bind_control_socket(path).await.unwrap();
+            let handler = async |command| match command {
+                ControlCommand::DumpState => Ok(Some(String::from("{}"))),
+                ControlCommand::Pause => Ok(None),
+                _ => Err(String::from("Unsupported"))
+            };
+            let mut responses = vec!();
+            run_until(serve_control(&listener, &handler), async {
+                let (reader, mut writer) = UnixStream::connect(path).await.unwrap().split();
+                writer.write_all(b"pause\n\ndump-state\nresume\nbogus\n").await.unwrap();
+                let mut lines = BufReader::new(reader).lines();
+                for _ in 0..4 {
+                    responses.push(lines.next().await.unwrap().unwrap());
+                }
+            }).await;
+            assert_eq!(
+                responses,
+                vec!("ok", "{}", "error: Unsupported", "error: Invalid command: bogus")
+            );
+            // A socket left by a previous instance is replaced.
+            drop(listener);
+            bind_control_socket(path).await.unwrap();
+        });
+        std::fs::remove_file(path).unwrap();
+        assert!(block_on(bind_control_socket("/dev/null")).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use zbus::{
+    Connection, MessageStream, Result as zbus_Result,
+    export::futures_util::TryStreamExt,
+    fdo::PropertiesChanged,
+    zvariant::Value::U32
+};
+use crate::connect::with_method_timeout;
+use crate::output::Writer;
+use crate::upower::{
+    properties_changed_rule, UPOWER_DEVICE_INTERFACE, UPOWER_PATH, UPOWER_SERVICE
+};
+
+/// The value of the `WarningLevel` property indicating that the critical action is imminent.
+const WARNING_LEVEL_ACTION: u32 = 5;
+
+/// The marker written when UPower is about to take its critical action. The action itself is
+/// appended to the marker, separated by a space.
+pub(crate) const CRITICAL_ACTION_MARKER: &str = "CriticalAction";
+
+/// Ask UPower which action it will take when the battery level becomes critical (such as
+/// `HybridSleep` or `PowerOff`).
+pub(crate) async fn critical_action(conn: &Connection) -> zbus_Result<String> {
+    with_method_timeout("GetCriticalAction", conn.call_method(
+        Some(UPOWER_SERVICE),
+        UPOWER_PATH,
+        Some("org.freedesktop.UPower"),
+        "GetCriticalAction",
+        &()
+    )).await?.body()
+}
+
+/// Listen for changes to the `WarningLevel` of the device at `path` (which should usually be the
+/// display device), and write a [`CRITICAL_ACTION_MARKER`] followed by the configured critical
+/// action when it reaches the level at which UPower takes that action.
+pub(crate) async fn listen_critical(
+    conn: &Connection,
+    path: &str,
+    writer: &impl Writer
+) -> zbus_Result<()> {
+    let mut stream = MessageStream::for_match_rule(
+        properties_changed_rule(path, UPOWER_DEVICE_INTERFACE)?,
+        conn,
+        None
+    ).await?;
+    let mut reached = false;
+    loop {
+        let Some(msg) = stream.try_next().await? else {
+            return Ok(())
+        };
+        // Signals which cannot be parsed are skipped.
+        let Some(signal) = PropertiesChanged::from_message(msg) else {
+            continue
+        };
+        let Ok(args) = signal.args() else {
+            continue
+        };
+        if let Some(U32(level)) = args.changed_properties.get("WarningLevel") {
+            let now_reached = *level == WARNING_LEVEL_ACTION;
+            if now_reached && !reached {
+                let action = critical_action(conn).await?;
+                writer.write_marker(&format!("{CRITICAL_ACTION_MARKER} {action}")).await?;
+            }
+            reached = now_reached;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use zbus::zvariant::Value::U32;
+    use crate::critical::{critical_action, listen_critical};
+    use crate::output::LineWriter;
+    use crate::rt::block_on;
+    use crate::testing::{MOCK_DEVICE_PATH, MockUPower, run_until, SharedBuffer};
+
+    /// Test querying the critical action.
+    #[test]
+    fn query_critical_action() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            assert_eq!(critical_action(&upower.client).await.unwrap(), "HybridSleep");
+        })
+    }
+
+    /// Test that a marker is written each time the warning level reaches the action level.
+    #[test]
+    fn critical_action_marker() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            run_until(listen_critical(&upower.client, MOCK_DEVICE_PATH, &writer), async {
+                upower.emit_malformed_change(MOCK_DEVICE_PATH).await.unwrap();
+                for level in [3, 4, 5, 5, 4, 5] {
+                    upower.set_properties(&[("WarningLevel", U32(level))]).await.unwrap();
+                }
+                buf.wait_for("CriticalAction HybridSleep\nCriticalAction HybridSleep\n").await;
+            }).await;
+        })
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::process::exit;
+use std::sync::Mutex;
+use nix::fcntl::OFlag;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{dup2, fork, pipe2, setsid, ForkResult};
+
+/// The byte sent by the daemon to the original process once it is ready.
+const READY: u8 = b'r';
+
+/// A daemon process, forked from the original process by [`Daemon::fork`]. The original process
+/// waits until the daemon reports that it is ready (and then exits successfully), or until it
+/// exits (and then exits with the same status), so that errors during startup are still reported
+/// to whatever started upmon.
+#[derive(Debug)]
+pub struct Daemon {
+    /// The pipe over which readiness is reported to the original process, until it has been.
+    ready: Mutex<Option<File>>
+}
+
+impl Daemon {
+    /// Fork into the background, returning the [`Daemon`] in the child process. The original
+    /// process does not return from this function.
+    ///
+    /// This must be called while the process has a single thread (that is, before the async
+    /// runtime is started), as only the calling thread is copied into the child.
+    pub(crate) fn fork() -> Result<Self, String> {
+        // The pipe is closed on exec, so that processes started by the daemon (such as plugins)
+        // do not keep it open after the daemon exits.
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC)
+            .map_err(|e| format!("Could not create pipe: {e}"))?;
+        // SAFETY: both ends were just returned by `pipe2` and are not owned by anything else.
+        let (mut reader, writer) = unsafe {
+            (File::from_raw_fd(reader), File::from_raw_fd(writer))
+        };
+        // SAFETY: the process has a single thread, so the child's state is consistent.
+        match unsafe { fork() }.map_err(|e| format!("Could not fork: {e}"))? {
+            ForkResult::Parent { child } => {
+                drop(writer);
+                let mut byte = [0];
+                if reader.read(&mut byte).is_ok_and(|n| n == 1) && byte[0] == READY {
+                    exit(0)
+                }
+                // The daemon exited (or closed the pipe) without becoming ready.
+                match waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, code)) => exit(code),
+                    _ => exit(1)
+                }
+            },
+            ForkResult::Child => {
+                drop(reader);
+                setsid().map_err(|e| format!("Could not create session: {e}"))?;
+                Ok(Self { ready: Mutex::new(Some(writer)) })
+            }
+        }
+    }
+
+    /// Report to the original process that the daemon is ready, so that it can exit. Standard
+    /// input is redirected from /dev/null, as are standard output and error if `close_stdio` is
+    /// true (so that the daemon does not hold on to the terminal). Only the first call has any
+    /// effect.
+    pub(crate) fn ready(&self, close_stdio: bool) -> Result<(), String> {
+        let Some(mut writer) = self.ready.lock().unwrap().take() else {
+            return Ok(())
+        };
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .map_err(|e| format!("Could not open /dev/null: {e}"))?;
+        let redirected: &[i32] = if close_stdio { &[0, 1, 2] } else { &[0] };
+        for fd in redirected {
+            dup2(null.as_raw_fd(), *fd).map_err(|e| format!("Could not redirect stdio: {e}"))?;
+        }
+        writer.write_all(&[READY]).map_err(|e| format!("Could not report readiness: {e}"))
+    }
+}
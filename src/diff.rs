@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io::BufReader;
+use serde_json::{Map, Value};
+use crate::event::DeviceEvent;
+use crate::upower::{Property, PropertyKind};
+
+/// Read a snapshot of the latest value of each property of each device from the JSON file at
+/// `path`. Both the output of `GET /state` (an object keyed by device path and then by property
+/// name) and the output of the `dump-state` control command (which holds such an object under
+/// `values`) are accepted.
+pub(crate) fn read_snapshot(path: &str) -> Result<Map<String, Value>, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open {path}: {e}"))?;
+    let snapshot: Value = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("Invalid snapshot in {path}: {e}"))?;
+    let values = match snapshot.get("values") {
+        Some(v @ Value::Object(_)) => v.clone(),
+        _ => snapshot
+    };
+    let Value::Object(devices) = values else {
+        return Err(format!("Invalid snapshot in {path}: expected an object"))
+    };
+    if let Some((device, _)) = devices.iter().find(|(_, v)| !v.is_object()) {
+        return Err(format!("Invalid snapshot in {path}: no properties for {device}"))
+    }
+    Ok(devices)
+}
+
+/// Compare two snapshots read by [`read_snapshot`], returning an event for each device in `new`
+/// describing the properties whose values differ from (or are missing from) `old`. Devices
+/// accepted by `include` are compared, in the order in which they appear in `new`; properties
+/// which are only in `old` are ignored.
+pub(crate) fn diff(
+    old: &Map<String, Value>,
+    new: &Map<String, Value>,
+    include: impl Fn(&str) -> bool
+) -> Result<Vec<DeviceEvent>, String> {
+    let mut events = vec!();
+    for (device, values) in new.iter().filter(|(d, _)| include(d)) {
+        let old_values = old.get(device);
+        let mut changes = vec!();
+        for (name, value) in values.as_object().into_iter().flatten() {
+            if old_values.and_then(|v| v.get(name)) == Some(value) {
+                continue
+            }
+            let kind = PropertyKind::from_name(name);
+            let property = Property::from_json(&kind, value)?;
+            changes.push((kind, property));
+        }
+        if !changes.is_empty() {
+            events.push(DeviceEvent::new(device, changes));
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::{json, Value};
+    use crate::diff::diff;
+    use crate::upower::Property::{Online, Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that only properties whose values differ are included, for the devices included.
+    #[test]
+    fn snapshot_diff() {
+        let old = json!({
+            "/bat": { "Percentage": 80.0, "State": "Discharging", "IsPresent": true },
+            "/ac": { "Online": false }
+        });
+        let new = json!({
+            "/bat": { "Percentage": 100.0, "State": "Discharging", "IsPresent": true },
+            "/ac": { "Online": true },
+            "/mouse": { "Percentage": 50.0, "State": "Charging" }
+        });
+        let (Value::Object(old), Value::Object(new)) = (old, new) else {
+            unreachable!()
+        };
+        let events = diff(&old, &new, |_| true).unwrap();
+        let changes = events.iter()
+            .map(|e| (e.device.as_str(), e.changes.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, vec!(
+            ("/ac", vec!((PropertyKind::Online, Online(true)))),
+            ("/bat", vec!((PropertyKind::Percentage, Percentage(100.0)))),
+            ("/mouse", vec!(
+                (PropertyKind::Percentage, Percentage(50.0)),
+                (PropertyKind::State, State(1))
+            ))
+        ));
+        assert_eq!(diff(&old, &new, |d| d == "/ac").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,352 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{Error, MapAccess, Visitor};
+use serde::ser::SerializeStruct;
+use crate::host::HostInfo;
+use crate::upower::{Property, PropertyKind};
+
+/// The version of the JSON format of events, which is written as `schema_version` in every event
+/// and marker. It is increased whenever a change is made to the format which existing consumers
+/// may not handle, such as removing or renaming a field. Version 1 had no `schema_version`, `seq`,
+/// `alias`, `hostname`, `machine_id` or `boot_id`.
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+/// The fields added to JSON events and markers in each version of the format after the first.
+/// Optional fields added since then, which consumers of the latest version can ignore, are listed
+/// with it.
+const ADDED_FIELDS: [(u32, &[&str]); 1] = [
+    (2, &["schema_version", "seq", "alias", "hostname", "machine_id", "boot_id", "event_type"])
+];
+
+/// Remove the fields which were added after `version` of the format from `json`, an event or
+/// marker serialized in the latest version, so that it can be read by consumers of that version.
+pub(crate) fn downgrade(json: &mut serde_json::Value, version: u32) {
+    let Some(fields) = json.as_object_mut() else {
+        return
+    };
+    for (_, added) in ADDED_FIELDS.iter().filter(|(v, _)| *v > version) {
+        for field in *added {
+            fields.remove(*field);
+        }
+    }
+}
+
+/// The JSON Schema describing events and markers as written in JSON.
+pub(crate) const EVENT_SCHEMA: &str = include_str!("../schema/event.schema.json");
+
+/// The sequence number of the next [`DeviceEvent`] to be created.
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Changes to the properties of a single device, as detected at a single point in time. Events are
+/// created by the listener and passed through every [`crate::output::Writer`], each of which may
+/// pass on a modified copy (for example, with some changes filtered out or pseudo-properties
+/// added).
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceEvent {
+    /// The DBus object path of the device.
+    pub device: String,
+    /// A user-friendly name for the device, if one is known.
+    pub alias: Option<String>,
+    /// The fields identifying the machine on which the changes were detected, if they are added
+    /// to events (see [`crate::host::HostWriter`]).
+    pub host: Option<Arc<HostInfo>>,
+    /// When the changes were detected.
+    pub timestamp: DateTime<Utc>,
+    /// The event's sequence number, which is unique to the event (and any copies of it) and
+    /// increases with each event created by this process.
+    pub seq: u64,
+    /// The changed properties and their new values, in the order in which they were detected.
+    /// Each property appears at most once.
+    pub changes: Vec<(PropertyKind, Property)>
+}
+
+impl DeviceEvent {
+    /// Create a new event describing the given changes to the device at `device`, detected now.
+    pub fn new(device: &str, changes: impl IntoIterator<Item = (PropertyKind, Property)>) -> Self {
+        Self {
+            device: String::from(device),
+            alias: None,
+            host: None,
+            timestamp: Utc::now(),
+            seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+            changes: changes.into_iter().collect()
+        }
+    }
+
+    /// Return a copy of this event with the given changes in place of its own.
+    pub fn with_changes(&self, changes: impl IntoIterator<Item = (PropertyKind, Property)>)
+        -> Self {
+        Self {
+            device: self.device.clone(),
+            alias: self.alias.clone(),
+            host: self.host.clone(),
+            timestamp: self.timestamp,
+            seq: self.seq,
+            changes: changes.into_iter().collect()
+        }
+    }
+
+    /// Return the new value of the given property, if it has changed.
+    pub fn get(&self, kind: &PropertyKind) -> Option<&Property> {
+        self.changes.iter().find(|(k, _)| k == kind).map(|(_, v)| v)
+    }
+
+    /// Set the new value of the given property, replacing any value it already has in this event.
+    pub fn insert(&mut self, kind: PropertyKind, value: Property) {
+        match self.changes.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, v)) => *v = value,
+            None => self.changes.push((kind, value))
+        }
+    }
+
+    /// Iterate over the changed properties and their new values.
+    pub fn iter(&self) -> impl Iterator<Item = (&PropertyKind, &Property)> {
+        self.changes.iter().map(|(k, v)| (k, v))
+    }
+
+    /// Whether the event contains no changes.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl Serialize for DeviceEvent {
+    /// Serialize the event as a struct with `schema_version` (see [`SCHEMA_VERSION`]),
+    /// `timestamp` (in RFC 3339 format, with milliseconds), `device`, `alias` (omitted if
+    /// unknown), `hostname`, `machine_id` and `boot_id` (each omitted if not added), `seq` and
+    /// `changes`, which maps the name of each changed property to its new value.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("DeviceEvent", 9)?;
+        state.serialize_field("schema_version", &SCHEMA_VERSION)?;
+        state.serialize_field(
+            "timestamp",
+            &self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+        )?;
+        state.serialize_field("device", &self.device)?;
+        match &self.alias {
+            Some(alias) => state.serialize_field("alias", alias)?,
+            None => state.skip_field("alias")?
+        }
+        let host = self.host.as_deref();
+        for (name, value) in [
+            ("hostname", host.and_then(|h| h.hostname.as_ref())),
+            ("machine_id", host.and_then(|h| h.machine_id.as_ref())),
+            ("boot_id", host.and_then(|h| h.boot_id.as_ref()))
+        ] {
+            match value {
+                Some(value) => state.serialize_field(name, value)?,
+                None => state.skip_field(name)?
+            }
+        }
+        state.serialize_field("seq", &self.seq)?;
+        state.serialize_field("changes", &Changes(&self.changes))?;
+        state.end()
+    }
+}
+
+/// The changes in a [`DeviceEvent`], serialized as a map.
+struct Changes<'a>(&'a [(PropertyKind, Property)]);
+
+impl Serialize for Changes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+    }
+}
+
+/// A [`DeviceEvent`] as serialized, before its timestamp and changes are parsed.
+#[derive(Deserialize)]
+struct RawEvent {
+    /// When the changes were detected, in RFC 3339 format.
+    timestamp: String,
+    /// The DBus object path of the device.
+    device: String,
+    /// A user-friendly name for the device, if one is known.
+    #[serde(default)]
+    alias: Option<String>,
+    /// The machine's host name, if it was added.
+    #[serde(default)]
+    hostname: Option<String>,
+    /// The machine's ID, if it was added.
+    #[serde(default)]
+    machine_id: Option<String>,
+    /// The ID of the machine's boot, if it was added.
+    #[serde(default)]
+    boot_id: Option<String>,
+    /// The event's sequence number (zero if not given).
+    #[serde(default)]
+    seq: u64,
+    /// The name of each changed property and its new value.
+    changes: RawChanges
+}
+
+/// The changes in a serialized [`DeviceEvent`], in the order in which they were serialized.
+struct RawChanges(Vec<(String, serde_json::Value)>);
+
+impl<'de> Deserialize<'de> for RawChanges {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// Visitor which collects the entries of a map in order.
+        struct ChangesVisitor;
+
+        impl<'de> Visitor<'de> for ChangesVisitor {
+            type Value = RawChanges;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of property names to values")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RawChanges, A::Error> {
+                let mut changes = vec!();
+                while let Some(entry) = map.next_entry()? {
+                    changes.push(entry);
+                }
+                Ok(RawChanges(changes))
+            }
+        }
+
+        deserializer.deserialize_map(ChangesVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceEvent {
+    /// Deserialize an event in the form produced by the [`Serialize`] implementation.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = RawEvent::deserialize(deserializer)?;
+        let timestamp = DateTime::parse_from_rfc3339(&raw.timestamp)
+            .map_err(D::Error::custom)?
+            .with_timezone(&Utc);
+        let changes = raw.changes.0.iter()
+            .map(|(name, value)| {
+                let kind = PropertyKind::from_name(name);
+                Property::from_json(&kind, value).map(|v| (kind, v))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(D::Error::custom)?;
+        let host = HostInfo {
+            hostname: raw.hostname,
+            machine_id: raw.machine_id,
+            boot_id: raw.boot_id
+        };
+        Ok(Self {
+            device: raw.device,
+            alias: raw.alias,
+            host: (!host.is_empty()).then(|| Arc::new(host)),
+            timestamp,
+            seq: raw.seq,
+            changes
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+    use serde_json::{json, Value};
+    use crate::event::{downgrade, DeviceEvent, EVENT_SCHEMA, SCHEMA_VERSION};
+    use crate::host::HostInfo;
+    use crate::upower::Property::{Online, Percentage, State, UpdateTime};
+    use crate::upower::PropertyKind;
+
+    /// Test that changes keep their order, that inserting a property replaces its value, and
+    /// that copies keep the original event's metadata.
+    #[test]
+    fn device_event() {
+        let mut event = DeviceEvent::new("/dev", vec!(
+            (PropertyKind::State, State(2)),
+            (PropertyKind::Percentage, Percentage(50.0))
+        ));
+        assert_eq!(event.get(&PropertyKind::Percentage), Some(&Percentage(50.0)));
+        assert_eq!(event.get(&PropertyKind::Online), None);
+        event.insert(PropertyKind::Percentage, Percentage(49.0));
+        event.insert(PropertyKind::Online, Online(true));
+        assert_eq!(
+            event.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(),
+            vec!("State", "Percentage", "Online")
+        );
+        assert_eq!(event.get(&PropertyKind::Percentage), Some(&Percentage(49.0)));
+
+        let copy = event.with_changes(vec!());
+        assert!(copy.is_empty());
+        assert_eq!(
+            (copy.seq, copy.timestamp, &copy.device),
+            (event.seq, event.timestamp, &event.device)
+        );
+        assert!(DeviceEvent::new("/dev", vec!()).seq > event.seq);
+    }
+
+    /// Test that events are serialized with their metadata and formatted values, and can be
+    /// deserialized again.
+    #[test]
+    fn device_event_serde() {
+        let mut event = DeviceEvent::new("/dev", vec!(
+            (PropertyKind::State, State(2)),
+            (PropertyKind::Percentage, Percentage(50.5)),
+            (PropertyKind::UpdateTime, UpdateTime(1707684062))
+        ));
+        event.timestamp = "2024-02-11T20:41:02.113Z".parse().unwrap();
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["changes"], json!({
+            "State": "Discharging",
+            "Percentage": 50.5,
+            "UpdateTime": "2024-02-11T20:41:02Z"
+        }));
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["timestamp"], "2024-02-11T20:41:02.113Z");
+        assert_eq!(json["seq"], event.seq);
+        assert!(json.get("alias").is_none());
+        let text = serde_json::to_string(&event).unwrap();
+        assert_eq!(serde_json::from_str::<DeviceEvent>(&text).unwrap(), event);
+
+        event.alias = Some(String::from("laptop"));
+        event.host = Some(Arc::new(HostInfo {
+            hostname: Some(String::from("desk")),
+            ..HostInfo::default()
+        }));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["alias"], "laptop");
+        assert_eq!(json["hostname"], "desk");
+        assert!(json.get("boot_id").is_none());
+        let text = serde_json::to_string(&event).unwrap();
+        assert_eq!(serde_json::from_str::<DeviceEvent>(&text).unwrap(), event);
+
+        let mut old = json.clone();
+        downgrade(&mut old, 1);
+        assert_eq!(
+            old.as_object().unwrap().keys().collect::<Vec<_>>(),
+            vec!("changes", "device", "timestamp")
+        );
+        assert_eq!(
+            serde_json::from_value::<DeviceEvent>(old).unwrap().changes,
+            serde_json::from_value::<DeviceEvent>(json.clone()).unwrap().changes
+        );
+        let mut latest = json.clone();
+        downgrade(&mut latest, SCHEMA_VERSION);
+        assert_eq!(latest, json);
+
+        let json = json!({
+            "timestamp": "2024-02-11T20:41:02.113Z",
+            "device": "/dev",
+            "changes": {"State": 2, "Percentage": "full"}
+        });
+        assert!(serde_json::from_value::<DeviceEvent>(json).is_err());
+    }
+
+    /// Test that the embedded schema is valid JSON describing the current version of the format,
+    /// and that it requires every field which is always written.
+    #[test]
+    fn event_schema() {
+        let schema = serde_json::from_str::<Value>(EVENT_SCHEMA).unwrap();
+        assert_eq!(schema["properties"]["schema_version"]["const"], SCHEMA_VERSION);
+        let event = serde_json::to_value(DeviceEvent::new("/dev", vec!())).unwrap();
+        let required = schema["required"].as_array().unwrap().iter()
+            .chain(schema["oneOf"][0]["required"].as_array().unwrap())
+            .map(|f| f.as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert!(required.iter().all(|f| event.get(f).is_some()));
+        let fields = schema["properties"].as_object().unwrap().keys()
+            .chain(schema["oneOf"][0]["properties"].as_object().unwrap().keys())
+            .collect::<Vec<_>>();
+        assert!(event.as_object().unwrap().keys().all(|k| fields.contains(&k)));
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Read;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use async_channel::{unbounded, Receiver, Sender};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use futures::future::pending;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::severity::Severity;
+use crate::upower::{Property, PropertyKind};
+
+/// The marker written when a hook fails, if failure events are enabled. The device path and the
+/// reason for the failure (such as `status=1`, `signal=9`, `timeout` or `error`) are appended to
+/// the marker, separated by spaces.
+pub(crate) const EXEC_FAILED_MARKER: &str = "ExecFailed";
+
+/// The number of hooks which may run at once if no limit is given.
+pub(crate) const DEFAULT_EXEC_JOBS: usize = 4;
+
+/// How often a running hook is checked to see whether it has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How a hook finished.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// The hook exited with the given status.
+    Exited(ExitStatus),
+    /// The hook did not exit within the timeout, and was killed.
+    TimedOut,
+    /// The hook could not be run.
+    Error(String)
+}
+
+/// The result of running a hook for a single change.
+#[derive(Debug)]
+struct HookResult {
+    /// The path of the device whose change the hook was run for.
+    device: String,
+    /// How the hook finished.
+    outcome: Outcome,
+    /// Everything the hook wrote to standard output.
+    stdout: String,
+    /// Everything the hook wrote to standard error.
+    stderr: String
+}
+
+impl HookResult {
+    /// Describe why the hook failed, or return `None` if it succeeded.
+    fn failure(&self) -> Option<String> {
+        match &self.outcome {
+            Outcome::Exited(s) if s.success() => None,
+            Outcome::Exited(s) => Some(match (s.code(), s.signal()) {
+                (Some(code), _) => format!("status={code}"),
+                (None, Some(signal)) => format!("signal={signal}"),
+                (None, None) => String::from("status=unknown")
+            }),
+            Outcome::TimedOut => Some(String::from("timeout")),
+            Outcome::Error(_) => Some(String::from("error"))
+        }
+    }
+
+    /// Write the hook's output, and the reason it failed (if it did), to standard error, each line
+    /// prefixed with the device path.
+    fn log(&self) {
+        let prefix = format!("exec {}:", self.device);
+        for line in self.stdout.lines() {
+            eprintln!("{prefix} stdout: {line}");
+        }
+        for line in self.stderr.lines() {
+            eprintln!("{prefix} stderr: {line}");
+        }
+        match &self.outcome {
+            Outcome::Exited(_) => if let Some(failure) = self.failure() {
+                eprintln!("{prefix} hook failed with {failure}");
+            },
+            Outcome::TimedOut => eprintln!("{prefix} hook timed out and was killed"),
+            Outcome::Error(e) => eprintln!("{prefix} hook could not be run: {e}")
+        }
+    }
+}
+
+/// Read everything from `source` on a separate thread, so that a hook never blocks writing to a
+/// full pipe.
+fn read_all(source: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(mut s) = source {
+            // Output which is not valid UTF-8 is dropped.
+            let _ = s.read_to_string(&mut output);
+        }
+        output
+    })
+}
+
+/// Wait for `child` to exit, killing it (and any processes it started) if it runs for longer than
+/// `timeout`.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<Outcome, std::io::Error> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Outcome::Exited(status))
+        }
+        if timeout.is_some_and(|t| start.elapsed() >= t) {
+            // The hook is the leader of its own process group.
+            let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+            child.wait()?;
+            return Ok(Outcome::TimedOut)
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Run `command` using the shell for a change to `device`, with the given positional parameters
+/// and environment, capturing its output and exit status.
+fn run_hook(
+    command: &str,
+    args: Vec<String>,
+    device: String,
+    env: Vec<(String, String)>,
+    timeout: Option<Duration>
+) -> HookResult {
+    let spawned = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .arg("upmon")
+        .args(args)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn();
+    let mut child = match spawned {
+        Ok(c) => c,
+        Err(e) => return HookResult {
+            device,
+            outcome: Outcome::Error(e.to_string()),
+            stdout: String::new(),
+            stderr: String::new()
+        }
+    };
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    let outcome = wait(&mut child, timeout).unwrap_or_else(|e| Outcome::Error(e.to_string()));
+    HookResult {
+        device,
+        outcome,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default()
+    }
+}
+
+/// Return the environment in which a hook is run for `event`: `UPMON_DEVICE` gives the path of the
+/// device, and `UPMON_` followed by the name of each changed property in upper case (such as
+/// `UPMON_PERCENTAGE`) gives its new value, formatted as in line output.
+fn hook_env(event: &DeviceEvent) -> Vec<(String, String)> {
+    let mut env = vec!((String::from("UPMON_DEVICE"), event.device.clone()));
+    env.extend(event.iter().map(|(k, v)| {
+        (format!("UPMON_{}", k.as_str().to_uppercase()), v.to_string())
+    }));
+    env
+}
+
+/// The quotes within which some part of a shell command appears.
+#[derive(Clone, Copy, PartialEq)]
+enum Quoting {
+    Unquoted,
+    Single,
+    Double
+}
+
+/// Expand the placeholders in `template` for a change to `device`, whose latest property values
+/// are `values`, returning the command and its positional parameters. `{device}` stands for the
+/// path of the device, and the name of a property in braces (such as `{Percentage}`) for its latest
+/// value, formatted as in line output, or for nothing if the property is a UPower property whose
+/// value is not yet known. Each placeholder is replaced with a reference to a positional parameter
+/// holding its value (such as `"${1}"`), quoted to suit the quotes it appears in, rather than with
+/// the value itself, as values such as a device's model come from the device and must not be run
+/// as commands. Anything else in braces (such as `${HOME}`) is left as it is, so braces meant for
+/// the shell need no escaping.
+fn expand(
+    template: &str,
+    device: &str,
+    values: &HashMap<PropertyKind, Property>
+) -> (String, Vec<String>) {
+    let mut expanded = String::with_capacity(template.len());
+    let mut args = Vec::new();
+    let mut quoting = Quoting::Unquoted;
+    let mut rest = template;
+    while let Some(c) = rest.chars().next() {
+        if c != '{' {
+            // An escaped character is copied as it is, whatever it is.
+            let len = match (quoting, c) {
+                (Quoting::Unquoted | Quoting::Double, '\\') => {
+                    rest.chars().nth(1).map_or(1, |e| 1 + e.len_utf8())
+                },
+                (Quoting::Unquoted, '\'') => {
+                    quoting = Quoting::Single;
+                    1
+                },
+                (Quoting::Unquoted, '"') => {
+                    quoting = Quoting::Double;
+                    1
+                },
+                (Quoting::Single, '\'') | (Quoting::Double, '"') => {
+                    quoting = Quoting::Unquoted;
+                    1
+                },
+                _ => c.len_utf8()
+            };
+            expanded.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue
+        }
+        let name = rest[1..].find('}')
+            .map(|end| &rest[1..=end])
+            .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        let value = name.and_then(|n| match n {
+            "device" => Some(String::from(device)),
+            n => match values.get(&PropertyKind::from_name(n)) {
+                Some(v) => Some(v.to_string()),
+                None => Property::names().any(|p| p == n).then(String::new)
+            }
+        });
+        match (name, value) {
+            (Some(n), Some(v)) => {
+                args.push(v);
+                let reference = format!("${{{}}}", args.len());
+                match quoting {
+                    Quoting::Unquoted => write!(expanded, "\"{reference}\""),
+                    Quoting::Single => write!(expanded, "'\"{reference}\"'"),
+                    Quoting::Double => write!(expanded, "{reference}")
+                }.expect("Writing to a String cannot fail");
+                rest = &rest[n.len() + 2..];
+            },
+            _ => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    (expanded, args)
+}
+
+/// A [`Writer`] which passes all changes on to an inner [`Writer`], and additionally runs a hook
+/// command for each change on a separate thread, after expanding any placeholders in it (see
+/// [`expand`]). Each hook's output and exit status are logged to
+/// standard error by [`ExecWriter::run`], which can also write an [`EXEC_FAILED_MARKER`] for each
+/// hook which fails. Hooks which run for longer than the timeout are killed, and hooks for changes
+/// made while too many hooks are already running are skipped (and count as failed).
+pub struct ExecWriter<W: Writer> {
+    /// The writer to which changes and failures are passed.
+    inner: W,
+    /// The hook command, or `None` if no hooks should be run.
+    command: Option<String>,
+    /// How long a hook may run before it is killed, if there is a limit.
+    timeout: Option<Duration>,
+    /// The maximum number of hooks which may run at once.
+    jobs: usize,
+    /// Whether an [`EXEC_FAILED_MARKER`] is written for each hook which fails.
+    failure_markers: bool,
+    /// The number of hooks currently running.
+    running: Arc<AtomicUsize>,
+    /// The sending and receiving ends of the channel over which hooks' results are returned.
+    results: (Sender<HookResult>, Receiver<HookResult>),
+    /// The latest value of each property of each device, from which placeholders are expanded.
+    values: Mutex<HashMap<String, HashMap<PropertyKind, Property>>>,
+    /// If set, hooks are only run for changes to devices of at least this severity.
+    severity: Option<Severity>
+}
+
+impl<W: Writer> ExecWriter<W> {
+    /// Create a new [`ExecWriter`] which passes changes to `inner`, running `command` (if given)
+    /// for each change. At most `jobs` hooks run at once, each for no longer than `timeout`.
+    pub(crate) fn new(
+        inner: W,
+        command: Option<String>,
+        timeout: Option<Duration>,
+        jobs: usize,
+        failure_markers: bool
+    ) -> Self {
+        Self {
+            inner,
+            command,
+            timeout,
+            jobs,
+            failure_markers,
+            running: Arc::new(AtomicUsize::new(0)),
+            results: unbounded(),
+            values: Mutex::new(HashMap::new()),
+            severity: None
+        }
+    }
+
+    /// Only run hooks for changes to devices whose severity is at least `severity`, if given.
+    /// Changes without a severity are treated as ok.
+    pub(crate) fn with_severity(mut self, severity: Option<Severity>) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    /// Log the result of a hook, writing an [`EXEC_FAILED_MARKER`] if it failed and failure
+    /// events are enabled.
+    async fn report(&self, result: HookResult) -> Result<(), std::io::Error> {
+        result.log();
+        match result.failure() {
+            Some(failure) if self.failure_markers => self.inner
+                .write_marker(&format!("{EXEC_FAILED_MARKER} {} {failure}", result.device))
+                .await,
+            _ => Ok(())
+        }
+    }
+
+    /// Log the result of each hook as it finishes. If no hook command is set, this never
+    /// completes.
+    pub(crate) async fn run(&self) -> Result<(), std::io::Error> {
+        if self.command.is_none() {
+            return pending().await
+        }
+        while let Ok(result) = self.results.1.recv().await {
+            self.report(result).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for ExecWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        self.inner.write(event).await?;
+        let Some(command) = &self.command else {
+            return Ok(())
+        };
+        let (command, args) = {
+            let mut values = self.values.lock().await;
+            let device = values.entry(event.device.clone()).or_default();
+            for (k, v) in event.iter() {
+                device.insert(k.clone(), v.clone());
+            }
+            expand(command, &event.device, device)
+        };
+        if self.severity.is_some_and(|s| Severity::of(event).unwrap_or(Severity::Ok) < s) {
+            return Ok(())
+        }
+        if self.running.fetch_add(1, Ordering::SeqCst) >= self.jobs {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            return self.report(HookResult {
+                device: event.device.clone(),
+                outcome: Outcome::Error(format!("{} hooks already running", self.jobs)),
+                stdout: String::new(),
+                stderr: String::new()
+            }).await
+        }
+        let device = event.device.clone();
+        let env = hook_env(event);
+        let timeout = self.timeout;
+        let running = Arc::clone(&self.running);
+        let sender = self.results.0.clone();
+        std::thread::spawn(move || {
+            let result = run_hook(&command, args, device, env, timeout);
+            running.fetch_sub(1, Ordering::SeqCst);
+            // The receiver is only closed once upmon is exiting.
+            let _ = sender.send_blocking(result);
+        });
+        Ok(())
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+    use crate::event::DeviceEvent;
+    use std::collections::HashMap;
+    use crate::exec::{expand, hook_env, run_hook, ExecWriter, Outcome};
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that hooks are given the changed values, and that their output and exit status are
+    /// captured.
+    #[test]
+    fn hooks() {
+        let event = DeviceEvent::new("/dev", vec!((PropertyKind::Percentage, Percentage(42.0))));
+        let env = hook_env(&event);
+        let result = run_hook(
+            "echo \"$UPMON_DEVICE $UPMON_PERCENTAGE\"; echo oops >&2; exit 3",
+            vec!(),
+            String::from("/dev"),
+            env.clone(),
+            None
+        );
+        assert_eq!(result.stdout, "/dev 42\n");
+        assert_eq!(result.stderr, "oops\n");
+        assert_eq!(result.failure(), Some(String::from("status=3")));
+        let result = run_hook("sleep 5", vec!(), String::from("/dev"), env, Some(Duration::ZERO));
+        assert_eq!(result.outcome, Outcome::TimedOut);
+    }
+
+    /// Test that placeholders are expanded from the latest values, whether or not they are in
+    /// quotes, and that anything else in braces is left as it is.
+    #[test]
+    fn placeholders() {
+        let mut values = HashMap::new();
+        values.insert(PropertyKind::Percentage, Percentage(12.0));
+        values.insert(PropertyKind::State, State(2));
+        let run = |template: &str, device: &str| {
+            let (command, args) = expand(template, device, &values);
+            run_hook(&command, args, String::from(device), vec!(), None).stdout
+        };
+        assert_eq!(
+            run("echo \"Battery {Percentage}% ({State})\"", "/dev"),
+            "Battery 12% (Discharging)\n"
+        );
+        assert_eq!(
+            run("echo Battery {Percentage}% '{State} at' \\\"{device}\\\"", "/dev"),
+            "Battery 12% Discharging at \"/dev\"\n"
+        );
+        assert_eq!(
+            expand("echo {TimeToEmpty}|${HOME}|{Bogus}|{}|{x y}|{", "/dev", &values),
+            (String::from("echo \"${1}\"|${HOME}|{Bogus}|{}|{x y}|{"), vec!(String::new()))
+        );
+        // Values are passed to the command as they are, rather than run or split into words.
+        let device = "/dev/x; echo injected $(echo run) it's  *";
+        assert_eq!(run("printf %s {device}", device), device);
+        assert_eq!(run("printf %s \"{device}\"", device), device);
+        assert_eq!(run("printf %s '{device}'", device), device);
+    }
+
+    /// Test that a failure marker is written for a hook which fails, and for a change made while
+    /// too many hooks are running.
+    #[test]
+    fn failure_markers() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = ExecWriter::new(inner, Some(String::from("sleep 0.2; false")), None, 1, true);
+        block_on(async {
+            for p in [50.0, 49.0] {
+                let change = (PropertyKind::Percentage, Percentage(p));
+                writer.write(&DeviceEvent::new("/dev", vec!(change))).await.unwrap();
+            }
+            let result = writer.results.1.recv().await.unwrap();
+            writer.report(result).await.unwrap();
+        });
+        assert_eq!(
+            buf.contents(),
+            "/dev Percentage=50\n/dev Percentage=49\nExecFailed /dev error\n\
+             ExecFailed /dev status=1\n"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::process::exit;
+use strum::{EnumIter, IntoEnumIterator};
+use crate::upower::ListenError;
+
+/// The statuses with which upmon exits, so that scripts can tell why it stopped.
+#[derive(Clone, Copy, Debug, EnumIter, PartialEq)]
+pub enum ExitStatus {
+    /// upmon stopped normally.
+    Success = 0,
+    /// upmon stopped because of an error not covered by any other status.
+    Error = 1,
+    /// The command line arguments or configuration were invalid.
+    Config = 2,
+    /// The condition given by --until held.
+    ConditionMet = 3,
+    /// upmon could not connect to (or register on) DBus.
+    DbusConnection = 4,
+    /// UPower is not available on the system bus.
+    UpowerMissing = 5,
+    /// Output could not be opened or written to.
+    WriterIo = 6
+}
+
+impl ExitStatus {
+    /// Return the numeric exit code.
+    pub(crate) fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Return a description of when upmon exits with this status.
+    fn description(self) -> &'static str {
+        match self {
+            Self::Success => "Stopped normally (including on SIGINT or SIGTERM)",
+            Self::Error => "An error not covered by any other status",
+            Self::Config => "Invalid command line arguments or configuration",
+            Self::ConditionMet => "The condition given by --until held",
+            Self::DbusConnection => "Could not connect to (or register on) DBus",
+            Self::UpowerMissing => "UPower is not available on the system bus",
+            Self::WriterIo => "Output could not be opened or written to"
+        }
+    }
+
+    /// Return a table of all exit codes and their descriptions, one per line, as printed by
+    /// --help-exit-codes.
+    pub(crate) fn help() -> String {
+        Self::iter()
+            .map(|s| format!("{:<4}{}", s.code(), s.description()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Exit the process with this status.
+    pub(crate) fn exit(self) -> ! {
+        exit(self.code())
+    }
+}
+
+impl From<&ListenError> for ExitStatus {
+    fn from(e: &ListenError) -> Self {
+        match e {
+            ListenError::Receive(_) => Self::Error,
+            ListenError::Write(_) => Self::WriterIo
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use strum::IntoEnumIterator;
+    use crate::exit::ExitStatus;
+
+    /// Test that every status has a distinct code and is included in the help.
+    #[test]
+    fn exit_codes() {
+        let codes = ExitStatus::iter().map(ExitStatus::code).collect::<Vec<_>>();
+        assert_eq!(codes, (0..codes.len() as i32).collect::<Vec<_>>());
+        let help = ExitStatus::help();
+        assert_eq!(help.lines().count(), codes.len());
+        assert!(help.lines().any(|l| l == "3   The condition given by --until held"));
+        // Usage errors reported by clap also use exit code 2.
+        assert_eq!(ExitStatus::Config.code(), 2);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::upower::{Property, PropertyKind};
+
+/// Comparison operators which can be used in expressions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne
+}
+
+impl Op {
+    /// Operators and their string representations, in the order in which they should be matched
+    /// when parsing (so that, for example, `<=` is matched before `<`).
+    pub(crate) const ALL: [(&'static str, Op); 6] = [
+        ("<=", Op::Le),
+        (">=", Op::Ge),
+        ("==", Op::Eq),
+        ("!=", Op::Ne),
+        ("<", Op::Lt),
+        (">", Op::Gt)
+    ];
+
+    /// Compare two values using the operator.
+    fn compare<T: PartialOrd>(&self, a: T, b: T) -> bool {
+        match self {
+            Op::Lt => a < b,
+            Op::Le => a <= b,
+            Op::Gt => a > b,
+            Op::Ge => a >= b,
+            Op::Eq => a == b,
+            Op::Ne => a != b
+        }
+    }
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (s, _) = Op::ALL.iter().find(|(_, o)| o == self).unwrap();
+        write!(f, "{s}")
+    }
+}
+
+/// A value against which a property can be compared.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Literal {
+    /// A number, compared against the numeric value of the property (see [`Property::as_f64`]).
+    Number(f64),
+    /// A name, such as `Discharging` or `true`, compared against the formatted value of the
+    /// property.
+    Name(String)
+}
+
+impl Display for Literal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Literal::Number(n) => write!(f, "{n}"),
+            Literal::Name(s) => write!(f, "{s}")
+        }
+    }
+}
+
+/// A boolean expression over the values of a device's properties, such as
+/// `State == Discharging && (Percentage < 20 || TimeToEmpty < 900)`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// True if both sub-expressions are true.
+    And(Box<Expr>, Box<Expr>),
+    /// True if either sub-expression is true.
+    Or(Box<Expr>, Box<Expr>),
+    /// True if the sub-expression is false.
+    Not(Box<Expr>),
+    /// True if the property's value compares as given against the literal.
+    Compare(PropertyKind, Op, Literal),
+    /// True if the property's value is true (or non-zero).
+    Truthy(PropertyKind)
+}
+
+/// A token in an expression.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen
+}
+
+/// Split an expression into tokens.
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec!();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let len = if let Some((op_str, op)) = Op::ALL.iter().find(|(o, _)| rest.starts_with(o)) {
+            tokens.push(Token::Op(*op));
+            op_str.len()
+        } else if rest.starts_with("&&") {
+            tokens.push(Token::And);
+            2
+        } else if rest.starts_with("||") {
+            tokens.push(Token::Or);
+            2
+        } else if c == '!' {
+            tokens.push(Token::Not);
+            1
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            1
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            1
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let len = rest[1..].find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .map(|i| i + 1)
+                .unwrap_or(rest.len());
+            let n = rest[..len].parse()
+                .map_err(|_| format!("Invalid number in expression: {}", &rest[..len]))?;
+            tokens.push(Token::Number(n));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(String::from(&rest[..len])));
+            len
+        } else {
+            return Err(format!("Unexpected character in expression: {c}"))
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser for expressions.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl Parser {
+    /// Return the next token without consuming it.
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Consume and return the next token.
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Parse a disjunction of conjunctions.
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    /// Parse a conjunction of unary expressions.
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    /// Parse a negation, a parenthesised expression or a comparison.
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(String::from("Expected \")\" in expression"))
+                }
+            },
+            Some(Token::Ident(name)) => {
+                let property = PropertyKind::from_name(&name);
+                let Some(Token::Op(op)) = self.peek().cloned() else {
+                    return Ok(Expr::Truthy(property))
+                };
+                self.next();
+                let literal = match self.next() {
+                    Some(Token::Number(n)) => Literal::Number(n),
+                    Some(Token::Ident(s)) => Literal::Name(s),
+                    _ => return Err(format!("Expected value after \"{property} {op}\""))
+                };
+                let info = Property::info(property.as_str());
+                if let (Literal::Name(name), Some(info)) = (&literal, info) {
+                    let valid = match info.dbus_type {
+                        "b" => name == "true" || name == "false",
+                        _ => info.values.contains(&name.as_str())
+                    };
+                    if !valid {
+                        return Err(format!("Unexpected value for {property}: {name}"))
+                    }
+                }
+                Ok(Expr::Compare(property, op, literal))
+            },
+            Some(t) => Err(format!("Unexpected token in expression: {t:?}")),
+            None => Err(String::from("Unexpected end of expression"))
+        }
+    }
+}
+
+impl Expr {
+    /// Parse an expression from a string.
+    pub(crate) fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(s)?, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(t) = parser.peek() {
+            return Err(format!("Unexpected token in expression: {t:?}"))
+        }
+        Ok(expr)
+    }
+
+    /// Return the names of all properties referred to in the expression.
+    pub(crate) fn properties(&self) -> Vec<&PropertyKind> {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => {
+                let mut props = a.properties();
+                props.extend(b.properties());
+                props
+            },
+            Expr::Not(e) => e.properties(),
+            Expr::Compare(p, _, _) | Expr::Truthy(p) => vec!(p)
+        }
+    }
+
+    /// Evaluate the expression against the given property values. Comparisons involving
+    /// properties without a known value are false.
+    pub(crate) fn eval(&self, values: &HashMap<PropertyKind, Property>) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(values) && b.eval(values),
+            Expr::Or(a, b) => a.eval(values) || b.eval(values),
+            Expr::Not(e) => !e.eval(values),
+            Expr::Truthy(p) => values.get(p)
+                .and_then(Property::as_f64)
+                .is_some_and(|x| x != 0.0),
+            Expr::Compare(p, op, literal) => {
+                let Some(value) = values.get(p) else { return false };
+                match literal {
+                    Literal::Number(n) => value.as_f64().is_some_and(|x| op.compare(x, *n)),
+                    // Enumerated values are ordered by their numeric value, so (for example)
+                    // "WarningLevel >= Low" can be used.
+                    Literal::Name(name) => match Property::info(p.as_str())
+                        .and_then(|i| i.values.iter().position(|v| v == name)) {
+                        Some(n) => value.as_f64().is_some_and(|x| op.compare(x, n as f64)),
+                        None => op.compare(value.to_string().as_str(), name.as_str())
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::And(a, b) => write!(f, "({a} && {b})"),
+            Expr::Or(a, b) => write!(f, "({a} || {b})"),
+            Expr::Not(e) => write!(f, "!{e}"),
+            Expr::Compare(p, op, literal) => write!(f, "{p} {op} {literal}"),
+            Expr::Truthy(p) => write!(f, "{p}")
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::expr::{Expr, Literal, Op};
+    use crate::upower::Property::{self, Online, Percentage, State, TimeToEmpty, WarningLevel};
+    use crate::upower::PropertyKind;
+
+    /// Build a map of property values from the given properties.
+    fn values(props: Vec<(&str, Property)>) -> HashMap<PropertyKind, Property> {
+        props.into_iter().map(|(k, v)| (PropertyKind::from_name(k), v)).collect()
+    }
+
+    /// Test parsing of expressions.
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Expr::parse("Percentage<=15").unwrap(),
+            Expr::Compare(PropertyKind::Percentage, Op::Le, Literal::Number(15.0))
+        );
+        let expr = Expr::parse(
+            "State == Discharging && (Percentage < 20 || TimeToEmpty < 900) && !Online"
+        ).unwrap();
+        assert_eq!(
+            expr.to_string(),
+            "((State == Discharging && (Percentage < 20 || TimeToEmpty < 900)) && !Online)"
+        );
+        assert_eq!(
+            expr.properties(),
+            vec!(
+                &PropertyKind::State,
+                &PropertyKind::Percentage,
+                &PropertyKind::TimeToEmpty,
+                &PropertyKind::Online
+            )
+        );
+        assert_eq!(
+            Expr::parse("a || b && c").unwrap().to_string(),
+            "(a || (b && c))"
+        );
+
+        assert!(Expr::parse("").is_err());
+        assert!(Expr::parse("Percentage <").is_err());
+        assert!(Expr::parse("(Percentage < 5").is_err());
+        assert!(Expr::parse("Percentage < 5)").is_err());
+        assert!(Expr::parse("Percentage < 5 Online").is_err());
+        assert!(Expr::parse("Percentage # 5").is_err());
+        assert!(Expr::parse("State == Discharing").is_err());
+    }
+
+    /// Test evaluation of expressions.
+    #[test]
+    fn eval() {
+        let expr = Expr::parse("State == Discharging && (Percentage < 20 || TimeToEmpty < 900)")
+            .unwrap();
+        assert!(expr.eval(&values(vec!(("State", State(2)), ("Percentage", Percentage(19.0))))));
+        assert!(!expr.eval(&values(vec!(("State", State(1)), ("Percentage", Percentage(19.0))))));
+        assert!(!expr.eval(&values(vec!(("State", State(2)), ("Percentage", Percentage(20.0))))));
+        assert!(expr.eval(&values(vec!(
+            ("State", State(2)),
+            ("Percentage", Percentage(50.0)),
+            ("TimeToEmpty", TimeToEmpty(600))
+        ))));
+        assert!(!expr.eval(&values(vec!(("State", State(2))))));
+
+        let level = Expr::parse("WarningLevel >= Low").unwrap();
+        assert!(level.eval(&values(vec!(("WarningLevel", WarningLevel(4))))));
+        assert!(!level.eval(&values(vec!(("WarningLevel", WarningLevel(1))))));
+
+        assert!(Expr::parse("!Online").unwrap().eval(&values(vec!(("Online", Online(false))))));
+        assert!(Expr::parse("Online == true").unwrap().eval(&values(vec!(("Online", Online(true))))));
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+use crate::stats::{increment, Counter};
+
+/// Return whether the file at `path` exists and is a FIFO (named pipe).
+pub(crate) fn is_fifo(path: &str) -> bool {
+    Path::new(path).metadata().is_ok_and(|m| m.file_type().is_fifo())
+}
+
+/// A FIFO which is written to a line at a time, such as a status bar's named pipe. Lines are
+/// dropped while no program is reading from the FIFO (or the reader is not keeping up), so that
+/// monitoring is never blocked, and the FIFO is reopened when a reader returns. Rust ignores
+/// SIGPIPE, so a reader going away is seen as a failed write rather than killing upmon.
+pub(crate) struct Fifo {
+    /// The path to the FIFO.
+    path: String,
+    /// The FIFO, if it is currently open.
+    file: Option<File>,
+    /// The part of the current line which has been written so far.
+    line: Vec<u8>,
+    /// Whether the reader has gone away since the FIFO was last opened.
+    lost: bool
+}
+
+impl Fifo {
+    /// Create a new [`Fifo`] which writes to the FIFO at `path`. The FIFO is not opened until a
+    /// line is written.
+    pub(crate) fn new(path: &str) -> Self {
+        Self { path: String::from(path), file: None, line: vec!(), lost: false }
+    }
+
+    /// Write a line to the FIFO, opening it if necessary. The line is dropped if no program is
+    /// reading from the FIFO, or the reader is not keeping up.
+    fn write_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        if self.file.is_none() {
+            // Opening a FIFO for writing without O_NONBLOCK would block until there is a reader.
+            match OpenOptions::new()
+                .write(true)
+                .custom_flags(nix::libc::O_NONBLOCK)
+                .open(&self.path) {
+                Ok(f) => {
+                    if self.lost {
+                        increment(Counter::Reconnects);
+                        self.lost = false;
+                    }
+                    self.file = Some(f)
+                },
+                Err(e) if e.raw_os_error() == Some(nix::libc::ENXIO) => return Ok(()),
+                Err(e) => return Err(e)
+            }
+        }
+        let Some(f) = self.file.as_mut() else {
+            return Ok(())
+        };
+        match f.write_all(line) {
+            Err(e) if matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::WouldBlock) => {
+                // The reader has gone away or is not keeping up; reopen on the next write.
+                self.file = None;
+                self.lost = true;
+                Ok(())
+            },
+            result => result
+        }
+    }
+}
+
+impl Write for Fifo {
+    /// Add `buf` to the current line, writing each line which is completed.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line = self.line.drain(..=end).collect::<Vec<_>>();
+            self.write_line(&line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs::{OpenOptions, remove_file};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use crate::fifo::{is_fifo, Fifo};
+
+    /// Test that only whole lines are written, and that the FIFO is reopened when a reader returns
+    /// after going away.
+    #[test]
+    fn fifo_reopened() {
+        let path = std::env::temp_dir().join(format!("upmon-fifo-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        mkfifo(path, Mode::from_bits_truncate(0o600)).unwrap();
+        assert!(is_fifo(path));
+        assert!(!is_fifo("/dev/null"));
+        let open_reader = || OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NONBLOCK)
+            .open(path)
+            .unwrap();
+        let mut fifo = Fifo::new(path);
+        // There is no reader yet, so this is dropped.
+        fifo.write_all(b"dropped\n").unwrap();
+        let mut buf = [0; 64];
+        for _ in 0..2 {
+            let mut reader = open_reader();
+            fifo.write_all(b"/bat Perc").unwrap();
+            fifo.write_all(b"entage=80\n/bat").unwrap();
+            fifo.write_all(b" Percentage=79\n").unwrap();
+            let n = reader.read(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"/bat Percentage=80\n/bat Percentage=79\n");
+            drop(reader);
+            // The reader has gone away, so this is dropped.
+            fifo.write_all(b"/bat Percentage=78\n").unwrap();
+        }
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+#[cfg(feature = "wasm")]
+use std::rc::Rc;
+use std::str::FromStr;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use strum::{Display, EnumString, VariantNames};
+use crate::event::DeviceEvent;
+use crate::expr::Expr;
+#[cfg(feature = "wasm")]
+use crate::http::{change_event, marker_event};
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+#[cfg(feature = "wasm")]
+use crate::wasm::WasmModule;
+
+/// A [`Writer`] which filters changes before passing them on to an inner [`Writer`].
+pub struct FilteredWriter<W: Writer> {
+    /// The writer to which filtered changes are passed.
+    inner: W,
+    /// If set, only changes to these properties are written, and only when their value differs
+    /// from the last value seen for the same device.
+    transitions: Option<Vec<PropertyKind>>,
+    /// If set, changes for a device are only written when this condition holds for the latest
+    /// values of its properties.
+    condition: Option<Expr>,
+    /// The last value seen for each property of each device.
+    last: Mutex<HashMap<String, HashMap<PropertyKind, Property>>>,
+    /// If set, changes and markers are only written if this module's `filter` function keeps
+    /// them (after all other filters have been applied).
+    #[cfg(feature = "wasm")]
+    module: Option<Rc<WasmModule>>
+}
+
+impl<W: Writer> FilteredWriter<W> {
+    /// Create a new [`FilteredWriter`] which passes changes to `inner`, applying the given
+    /// filters.
+    pub(crate) fn new(inner: W, transitions: Option<Vec<PropertyKind>>, condition: Option<Expr>)
+        -> Self {
+        Self {
+            inner,
+            transitions,
+            condition,
+            last: Mutex::new(HashMap::new()),
+            #[cfg(feature = "wasm")]
+            module: None
+        }
+    }
+
+    /// Also filter changes and markers using the given WASM module's `filter` function.
+    #[cfg(feature = "wasm")]
+    pub(crate) fn with_module(mut self, module: Option<Rc<WasmModule>>) -> Self {
+        self.module = module.filter(|m| m.has_filter());
+        self
+    }
+
+    /// Apply all configured filters to the given event, returning a copy of the event containing
+    /// only those changes which should be written.
+    async fn filter(&self, event: &DeviceEvent) -> DeviceEvent {
+        let mut last = self.last.lock().await;
+        let last = last.entry(event.device.clone()).or_default();
+        let mut filtered = vec!();
+        for (k, v) in event.iter() {
+            if let Some(t) = &self.transitions {
+                if !t.contains(k) || last.get(k) == Some(v) {
+                    continue
+                }
+            }
+            filtered.push((k.clone(), v.clone()));
+        }
+        for (k, v) in event.iter() {
+            last.insert(k.clone(), v.clone());
+        }
+        if self.condition.as_ref().is_some_and(|c| !c.eval(last)) {
+            filtered.clear();
+        }
+        event.with_changes(filtered)
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for FilteredWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let filtered = self.filter(event).await;
+        if filtered.is_empty() {
+            return Ok(())
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(m) = &self.module {
+            if !m.filter(&change_event(&filtered)).await? {
+                return Ok(())
+            }
+        }
+        self.inner.write(&filtered).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        #[cfg(feature = "wasm")]
+        if let Some(m) = &self.module {
+            if !m.filter(&marker_event(marker)).await? {
+                return Ok(())
+            }
+        }
+        self.inner.write_marker(marker).await
+    }
+}
+
+/// The destinations to which changes are written, each of which can be given its own
+/// [`SinkFilter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, EnumString, VariantNames)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Sink {
+    /// The writer for the selected output format.
+    Output,
+    /// The HTTP server's state and event stream.
+    Http,
+    /// The DBus service's signals and state.
+    DbusService,
+    /// The on-screen display FIFO.
+    Osd,
+    /// The plugin command.
+    Plugin,
+    /// Every output given by `--extra-output`.
+    ExtraOutput
+}
+
+/// The properties which are written to a single [`Sink`], given on the command line in the form
+/// `SINK=PROPERTY,PROPERTY`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkFilter {
+    /// The sink whose changes are filtered.
+    pub sink: Sink,
+    /// The only properties whose changes are written to the sink.
+    pub properties: Vec<PropertyKind>
+}
+
+impl FromStr for SinkFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sink, properties) = s.split_once('=')
+            .ok_or_else(|| format!("Expected SINK=PROPERTIES: {s}"))?;
+        let sink = sink.trim().parse::<Sink>().map_err(|_| format!(
+            "Unknown sink: {sink} (expected one of: {})",
+            Sink::VARIANTS.join(", ")
+        ))?;
+        let properties = properties.split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(PropertyKind::from_name)
+            .collect::<Vec<_>>();
+        if properties.is_empty() {
+            return Err(format!("No properties given for sink {sink}"))
+        }
+        Ok(Self { sink, properties })
+    }
+}
+
+/// A [`Writer`] which only passes changes to the given properties on to an inner [`Writer`], so
+/// that each sink can be given its own subset of the monitored properties. Events with no
+/// remaining changes are dropped; markers are always passed on.
+pub struct PropertyFilterWriter<W: Writer> {
+    /// The writer to which filtered changes are passed.
+    inner: W,
+    /// If set, only changes to these properties are written.
+    properties: Option<Vec<PropertyKind>>
+}
+
+impl<W: Writer> PropertyFilterWriter<W> {
+    /// Create a new [`PropertyFilterWriter`] which passes changes to `properties` (or all changes,
+    /// if `None`) to `inner`.
+    pub(crate) fn new(inner: W, properties: Option<Vec<PropertyKind>>) -> Self {
+        Self { inner, properties }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for PropertyFilterWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let Some(properties) = &self.properties else {
+            return self.inner.write(event).await
+        };
+        let filtered = event.with_changes(event.iter()
+            .filter(|(k, _)| properties.contains(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>());
+        if filtered.is_empty() {
+            return Ok(())
+        }
+        self.inner.write(&filtered).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::event::DeviceEvent;
+    use crate::expr::Expr;
+    use crate::filter::{FilteredWriter, PropertyFilterWriter, Sink, SinkFilter};
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{self, Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Write each of the given changes for a single device, one at a time.
+    fn write_all(writer: &FilteredWriter<LineWriter>, changes: Vec<(&str, Property)>) {
+        for (k, v) in changes {
+            let mut hm = HashMap::new();
+            hm.insert(PropertyKind::from_name(k), v);
+            block_on(writer.write(&DeviceEvent::new("/dev", hm))).unwrap();
+        }
+    }
+
+    /// Test that no changes are filtered when no filters are configured.
+    #[test]
+    fn no_filters() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = FilteredWriter::new(inner, None, None);
+        write_all(&writer, vec!(
+            ("State", State(2)),
+            ("State", State(2)),
+            ("Percentage", Percentage(50.0))
+        ));
+        assert_eq!(
+            buf.contents(),
+            "/dev State=Discharging\n/dev State=Discharging\n/dev Percentage=50\n"
+        );
+    }
+
+    /// Test that only transitions of the specified properties are written.
+    #[test]
+    fn transitions() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = FilteredWriter::new(inner, Some(vec!(PropertyKind::State)), None);
+        write_all(&writer, vec!(
+            ("State", State(2)),
+            ("Percentage", Percentage(50.0)),
+            ("State", State(2)),
+            ("State", State(1)),
+            ("State", State(1)),
+            ("State", State(2))
+        ));
+        assert_eq!(
+            buf.contents(),
+            "/dev State=Discharging\n/dev State=Charging\n/dev State=Discharging\n"
+        );
+    }
+
+    /// Test that changes are only written while the condition holds.
+    #[test]
+    fn condition() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let condition = Expr::parse("State == Discharging").unwrap();
+        let writer = FilteredWriter::new(inner, None, Some(condition));
+        write_all(&writer, vec!(
+            ("Percentage", Percentage(50.0)),
+            ("State", State(2)),
+            ("Percentage", Percentage(49.0)),
+            ("State", State(1)),
+            ("Percentage", Percentage(50.0))
+        ));
+        assert_eq!(buf.contents(), "/dev State=Discharging\n/dev Percentage=49\n");
+    }
+
+    /// Test that sink filters are parsed, and that invalid ones are rejected.
+    #[test]
+    fn sink_filters() {
+        assert_eq!(
+            "dbus-service=State, Percentage".parse::<SinkFilter>(),
+            Ok(SinkFilter {
+                sink: Sink::DbusService,
+                properties: vec!(PropertyKind::State, PropertyKind::Percentage)
+            })
+        );
+        assert!("output".parse::<SinkFilter>().is_err());
+        assert!("stdout=State".parse::<SinkFilter>().is_err());
+        assert!("http=".parse::<SinkFilter>().is_err());
+    }
+
+    /// Test that only changes to the given properties are written, and that markers are kept.
+    #[test]
+    fn property_filter() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = PropertyFilterWriter::new(inner, Some(vec!(PropertyKind::State)));
+        block_on(async {
+            writer.write(&DeviceEvent::new("/dev", vec!(
+                (PropertyKind::Percentage, Percentage(50.0)),
+                (PropertyKind::State, State(2))
+            ))).await.unwrap();
+            writer.write(&DeviceEvent::new("/dev", vec!(
+                (PropertyKind::Percentage, Percentage(49.0))
+            ))).await.unwrap();
+            writer.write_marker("Resumed").await.unwrap();
+        });
+        assert_eq!(buf.contents(), "/dev State=Discharging\nResumed\n");
+    }
+}
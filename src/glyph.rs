@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+
+/// The name of the built-in ramp of Nerd Font battery icons.
+pub(crate) const NERD_RAMP: &str = "nerd";
+
+/// Nerd Font (Material Design) battery icons, from empty to full.
+const NERD_ICONS: [&str; 11] = [
+    "\u{f008e}", "\u{f007a}", "\u{f007b}", "\u{f007c}", "\u{f007d}", "\u{f007e}", "\u{f007f}",
+    "\u{f0080}", "\u{f0081}", "\u{f0082}", "\u{f0079}"
+];
+
+/// Nerd Font (Material Design) charging battery icons, from empty to full.
+const NERD_CHARGING_ICONS: [&str; 11] = [
+    "\u{f089f}", "\u{f089c}", "\u{f0086}", "\u{f0087}", "\u{f0088}", "\u{f089d}", "\u{f0089}",
+    "\u{f089e}", "\u{f008a}", "\u{f008b}", "\u{f0085}"
+];
+
+/// The numeric value of the `Charging` state.
+const CHARGING: u32 = 1;
+
+/// Parse a ramp of glyphs, given either as the name of a built-in ramp or as a comma-separated list
+/// of glyphs from empty to full.
+fn parse_ramp(ramp: &str, nerd: &[&str]) -> Result<Vec<String>, String> {
+    if ramp == NERD_RAMP {
+        return Ok(nerd.iter().map(|g| String::from(*g)).collect())
+    }
+    let glyphs = ramp.split(',').map(String::from).collect::<Vec<_>>();
+    if glyphs.len() < 2 {
+        return Err(format!("Expected at least two comma-separated glyphs: {ramp}"))
+    }
+    Ok(glyphs)
+}
+
+/// The glyphs used to represent devices' charge levels.
+#[derive(Debug, Default)]
+pub struct Glyphs {
+    /// The icons to use, from empty to full, if any.
+    icons: Option<Vec<String>>,
+    /// The icons to use while charging, from empty to full, if different from `icons`.
+    charging_icons: Option<Vec<String>>,
+    /// The width of the bar, if any.
+    bar_width: Option<usize>
+}
+
+impl Glyphs {
+    /// Create a new [`Glyphs`] from the given ramps (see [`parse_ramp`]) and bar width. If no
+    /// charging ramp is given, the Nerd Font charging icons are used while charging if `icons` is
+    /// the Nerd Font ramp, and `icons` are used otherwise.
+    pub(crate) fn new(
+        icons: Option<&str>,
+        charging_icons: Option<&str>,
+        bar_width: Option<usize>
+    ) -> Result<Self, String> {
+        let charging_icons = match (icons, charging_icons) {
+            (_, Some(r)) => Some(parse_ramp(r, &NERD_CHARGING_ICONS)?),
+            (Some(NERD_RAMP), None) => Some(parse_ramp(NERD_RAMP, &NERD_CHARGING_ICONS)?),
+            _ => None
+        };
+        if bar_width == Some(0) {
+            return Err(String::from("Bar width must be at least 1"))
+        }
+        Ok(Self {
+            icons: icons.map(|r| parse_ramp(r, &NERD_ICONS)).transpose()?,
+            charging_icons,
+            bar_width
+        })
+    }
+
+    /// Whether any glyphs are configured.
+    fn is_empty(&self) -> bool {
+        self.icons.is_none() && self.bar_width.is_none()
+    }
+
+    /// Return the icon for the given percentage and state.
+    fn icon(&self, percentage: f64, state: Option<u32>) -> Option<&str> {
+        let icons = match (&self.charging_icons, state) {
+            (Some(c), Some(CHARGING)) => c,
+            _ => self.icons.as_ref()?
+        };
+        let i = (percentage.clamp(0.0, 100.0) / 100.0 * (icons.len() - 1) as f64).round();
+        Some(&icons[i as usize])
+    }
+
+    /// Return the bar for the given percentage and state, with "+" appended while charging.
+    fn bar(&self, percentage: f64, state: Option<u32>) -> Option<String> {
+        let width = self.bar_width?;
+        let filled = (percentage.clamp(0.0, 100.0) / 100.0 * width as f64).round() as usize;
+        Some(format!(
+            "[{}{}]{}",
+            "#".repeat(filled),
+            "-".repeat(width - filled),
+            if state == Some(CHARGING) { "+" } else { "" }
+        ))
+    }
+}
+
+/// The latest charge level of a device.
+#[derive(Clone, Copy, Debug, Default)]
+struct Level {
+    percentage: Option<f64>,
+    state: Option<u32>
+}
+
+/// A [`Writer`] which adds `Icon` and `Bar` pseudo-properties, representing a device's latest
+/// `Percentage` and `State`, to each change before passing it on to an inner [`Writer`].
+pub struct GlyphWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// The glyphs to add.
+    glyphs: Glyphs,
+    /// The latest percentage and state of each device.
+    levels: Mutex<HashMap<String, Level>>
+}
+
+impl<W: Writer> GlyphWriter<W> {
+    /// Create a new [`GlyphWriter`] which passes changes to `inner`, adding the given glyphs.
+    pub(crate) fn new(inner: W, glyphs: Glyphs) -> Self {
+        Self {
+            inner,
+            glyphs,
+            levels: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for GlyphWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        if self.glyphs.is_empty() {
+            return self.inner.write(event).await
+        }
+        let Level { percentage, state } = {
+            let mut levels = self.levels.lock().await;
+            let level = levels.entry(event.device.clone()).or_default();
+            if let Some(Property::Percentage(p)) = event.get(&PropertyKind::Percentage) {
+                level.percentage = Some(*p);
+            }
+            if let Some(Property::State(s)) = event.get(&PropertyKind::State) {
+                level.state = Some(*s);
+            }
+            *level
+        };
+        let Some(percentage) = percentage else {
+            return self.inner.write(event).await
+        };
+        let mut decorated = event.clone();
+        if let Some(icon) = self.glyphs.icon(percentage, state) {
+            decorated.insert(PropertyKind::Icon, Property::Other(Value::from(icon).into()));
+        }
+        if let Some(bar) = self.glyphs.bar(percentage, state) {
+            decorated.insert(PropertyKind::Bar, Property::Other(Value::from(bar).into()));
+        }
+        self.inner.write(&decorated).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::event::DeviceEvent;
+    use crate::glyph::{GlyphWriter, Glyphs, NERD_CHARGING_ICONS, NERD_ICONS};
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test selection of icons and bars.
+    #[test]
+    fn glyphs() {
+        let glyphs = Glyphs::new(Some("a,b,c"), None, Some(5)).unwrap();
+        assert_eq!(glyphs.icon(0.0, None), Some("a"));
+        assert_eq!(glyphs.icon(40.0, None), Some("b"));
+        assert_eq!(glyphs.icon(80.0, Some(1)), Some("c"));
+        assert_eq!(glyphs.bar(60.0, Some(2)).unwrap(), "[###--]");
+        assert_eq!(glyphs.bar(100.0, Some(1)).unwrap(), "[#####]+");
+
+        let nerd = Glyphs::new(Some("nerd"), None, None).unwrap();
+        assert_eq!(nerd.icon(52.0, Some(2)), Some(NERD_ICONS[5]));
+        assert_eq!(nerd.icon(52.0, Some(1)), Some(NERD_CHARGING_ICONS[5]));
+        assert_eq!(nerd.bar(52.0, None), None);
+
+        assert!(Glyphs::new(Some("a"), None, None).is_err());
+        assert!(Glyphs::new(None, None, Some(0)).is_err());
+    }
+
+    /// Test that icons and bars are added to changes once the device's percentage is known.
+    #[test]
+    fn glyph_writer() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", "\t", false);
+        let writer = GlyphWriter::new(inner, Glyphs::new(Some("E,H,F"), None, Some(4)).unwrap());
+        for (k, v) in [("State", State(1)), ("Percentage", Percentage(50.0)), ("State", State(2))] {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::from_name(k), v);
+            block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        }
+        let lines = buf.contents().lines()
+            .map(|l| {
+                let mut fields = l.split(['\t', ' ']).collect::<Vec<_>>();
+                fields.sort();
+                fields.join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!(
+            "/dev State=Charging",
+            "/dev Bar=[##--]+ Icon=H Percentage=50",
+            "/dev Bar=[##--] Icon=H State=Discharging"
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use futures::future::join_all;
+use zbus::{
+    Connection, MessageStream, Result as zbus_Result,
+    export::futures_util::TryStreamExt,
+    fdo::PropertiesChanged,
+    zvariant::Value::{self, F64}
+};
+use crate::output::Writer;
+use crate::upower::DeviceConfig;
+
+/// The marker written when a battery's capacity falls below the configured threshold. The device
+/// path and capacity (as a percentage of design capacity) are appended to the marker, separated by
+/// spaces.
+pub(crate) const HEALTH_WARNING_MARKER: &str = "HealthWarning";
+
+/// Tracks a battery's full capacity relative to its design capacity, to detect when it falls below
+/// a threshold.
+struct HealthTracker {
+    /// The capacity, as a percentage of design capacity, below which a warning is given.
+    threshold: f64,
+    /// The most recently seen value of `EnergyFull`.
+    energy_full: Option<f64>,
+    /// The most recently seen value of `EnergyFullDesign`.
+    energy_full_design: Option<f64>,
+    /// Whether the capacity was below the threshold when last checked.
+    below: bool
+}
+
+impl HealthTracker {
+    /// Create a new [`HealthTracker`] with the given threshold.
+    fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            energy_full: None,
+            energy_full_design: None,
+            below: false
+        }
+    }
+
+    /// Update the tracker with the given changed properties. If this causes the capacity to fall
+    /// below the threshold (having previously been above it, or unknown), return the capacity as a
+    /// percentage of design capacity.
+    fn update(&mut self, properties: &HashMap<&str, Value>) -> Option<f64> {
+        if let Some(F64(e)) = properties.get("EnergyFull") {
+            self.energy_full = Some(*e);
+        }
+        if let Some(F64(e)) = properties.get("EnergyFullDesign") {
+            self.energy_full_design = Some(*e);
+        }
+        let capacity = match (self.energy_full, self.energy_full_design) {
+            // Devices which do not report energy (such as line power or the display device)
+            // report a design capacity of zero.
+            (Some(full), Some(design)) if design > 0.0 => full / design * 100.0,
+            _ => return None
+        };
+        let was_below = self.below;
+        self.below = capacity < self.threshold;
+        if self.below && !was_below {
+            Some(capacity)
+        } else {
+            None
+        }
+    }
+}
+
+/// Monitor the capacity of the given device, writing a [`HEALTH_WARNING_MARKER`] when it falls
+/// below `threshold` percent of its design capacity (including when it is already below the
+/// threshold on startup).
+async fn listen_health(
+    conn: &Connection,
+    device: &DeviceConfig,
+    threshold: f64,
+    writer: &impl Writer
+) -> zbus_Result<()> {
+    let mut tracker = HealthTracker::new(threshold);
+    let mut stream = MessageStream::for_match_rule(
+        device.rule()?,
+        conn,
+        None
+    ).await?;
+    let Some(initial) = device.fetch_properties(conn).await? else {
+        return Ok(())
+    };
+    let initial: HashMap<&str, Value> = initial.iter()
+        .map(|(k, v)| (k.as_str(), Value::clone(v)))
+        .collect();
+    let warn = |capacity: f64| format!("{HEALTH_WARNING_MARKER} {} {capacity:.1}", device.path());
+    if let Some(capacity) = tracker.update(&initial) {
+        writer.write_marker(&warn(capacity)).await?;
+    }
+    loop {
+        let Some(msg) = stream.try_next().await? else {
+            return Ok(())
+        };
+        // Signals which cannot be parsed are skipped.
+        let Some(signal) = PropertiesChanged::from_message(msg) else {
+            continue
+        };
+        let Ok(args) = signal.args() else {
+            continue
+        };
+        if let Some(capacity) = tracker.update(&args.changed_properties) {
+            writer.write_marker(&warn(capacity)).await?;
+        }
+    }
+}
+
+/// Monitor the capacity of all given UPower devices, as described in [`listen_health`].
+pub(crate) async fn listen_health_all(
+    conn: &Connection,
+    devices: &[DeviceConfig],
+    threshold: f64,
+    writer: &impl Writer
+) {
+    let futures = devices.iter()
+        .filter(|d| d.is_upower())
+        .map(|d| async move {
+            if let Err(e) = listen_health(conn, d, threshold, writer).await {
+                eprintln!("Error when monitoring health of {}: {e}", d.path());
+            }
+        });
+    join_all(futures).await;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use zbus::zvariant::Value::F64;
+    use crate::health::{HealthTracker, listen_health};
+    use crate::output::LineWriter;
+    use crate::rt::block_on;
+    use crate::testing::{MOCK_DEVICE_PATH, MockUPower, run_until, SharedBuffer};
+    use crate::upower::DeviceConfig;
+
+    /// Test that the tracker reports the capacity only when it crosses below the threshold.
+    #[test]
+    fn health_tracker() {
+        let mut tracker = HealthTracker::new(80.0);
+        let mut props = HashMap::new();
+        props.insert("EnergyFull", F64(45.0));
+        assert_eq!(tracker.update(&props), None);
+        props.insert("EnergyFullDesign", F64(50.0));
+        assert_eq!(tracker.update(&props), None);
+
+        let mut changed = HashMap::new();
+        changed.insert("EnergyFull", F64(39.0));
+        assert_eq!(tracker.update(&changed), Some(78.0));
+        changed.insert("EnergyFull", F64(38.0));
+        assert_eq!(tracker.update(&changed), None);
+        changed.insert("EnergyFull", F64(41.0));
+        assert_eq!(tracker.update(&changed), None);
+        changed.insert("EnergyFull", F64(35.0));
+        assert_eq!(tracker.update(&changed), Some(70.0));
+
+        let mut no_design = HealthTracker::new(80.0);
+        props.insert("EnergyFullDesign", F64(0.0));
+        assert_eq!(no_design.update(&props), None);
+    }
+
+    /// Test that warnings are written for a device on startup and when its capacity falls.
+    #[test]
+    fn health_warning() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let device = DeviceConfig::new(MOCK_DEVICE_PATH, "Percentage", None).unwrap();
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            run_until(listen_health(&upower.client, &device, 95.0, &writer), async {
+                buf.wait_for(&format!("HealthWarning {MOCK_DEVICE_PATH} 90.0\n")).await;
+                upower.set_properties(&[("EnergyFull", F64(48.0))]).await.unwrap();
+                upower.set_properties(&[("EnergyFull", F64(46.0))]).await.unwrap();
+                buf.wait_for(&format!(
+                    "HealthWarning {MOCK_DEVICE_PATH} 90.0\n\
+                     HealthWarning {MOCK_DEVICE_PATH} 92.0\n"
+                )).await;
+            }).await;
+        })
+    }
+}
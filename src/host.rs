@@ -0,0 +1,138 @@
+use std::fs::read_to_string;
+use std::sync::Arc;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+
+/// A field identifying the machine (or boot) on which upmon is running, which can be added to
+/// every event so that events from many machines can be aggregated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostField {
+    /// The machine's host name.
+    Hostname,
+    /// The machine's ID, as found in /etc/machine-id.
+    MachineId,
+    /// The ID of the machine's current boot, which changes each time it boots.
+    BootId
+}
+
+impl HostField {
+    /// The file from which the field's value is read.
+    fn source(&self) -> &'static str {
+        match self {
+            HostField::Hostname => "/proc/sys/kernel/hostname",
+            HostField::MachineId => "/etc/machine-id",
+            HostField::BootId => "/proc/sys/kernel/random/boot_id"
+        }
+    }
+}
+
+/// The fields identifying the machine on which upmon is running, as added to events. Fields which
+/// were not asked for, or could not be read, are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HostInfo {
+    /// The machine's host name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// The machine's ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    /// The ID of the machine's current boot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_id: Option<String>
+}
+
+impl HostInfo {
+    /// Read the given fields. They are read once, when upmon starts, as none of them change while
+    /// it is running (a change of host name aside, which is not worth watching for). Any which
+    /// cannot be read are left out, with a warning.
+    pub(crate) fn read(fields: &[HostField]) -> Self {
+        let mut info = Self::default();
+        for field in fields {
+            let value = match read_to_string(field.source()) {
+                Ok(s) => Some(String::from(s.trim())),
+                Err(e) => {
+                    eprintln!("Warning: could not read {}: {e}", field.source());
+                    None
+                }
+            };
+            match field {
+                HostField::Hostname => info.hostname = value,
+                HostField::MachineId => info.machine_id = value,
+                HostField::BootId => info.boot_id = value
+            }
+        }
+        info
+    }
+
+    /// Whether none of the fields are known.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hostname.is_none() && self.machine_id.is_none() && self.boot_id.is_none()
+    }
+}
+
+/// A [`Writer`] which adds the fields identifying the machine to each change before passing it to
+/// an inner [`Writer`].
+pub struct HostWriter<W: Writer> {
+    /// The writer to which annotated changes are passed.
+    inner: W,
+    /// The fields added to each change, or `None` if there are none.
+    host: Option<Arc<HostInfo>>
+}
+
+impl<W: Writer> HostWriter<W> {
+    /// Create a new [`HostWriter`] which adds the fields in `host` to each change.
+    pub(crate) fn new(inner: W, host: HostInfo) -> Self {
+        Self { inner, host: (!host.is_empty()).then(|| Arc::new(host)) }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for HostWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let Some(host) = &self.host else {
+            return self.inner.write(event).await
+        };
+        let mut annotated = event.clone();
+        annotated.host = Some(Arc::clone(host));
+        self.inner.write(&annotated).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::event::DeviceEvent;
+    use crate::host::{HostField, HostInfo, HostWriter};
+    use crate::output::{JsonWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::Percentage;
+    use crate::upower::PropertyKind;
+
+    /// Test that only the fields asked for are read, and that they are added to each change.
+    #[test]
+    fn host_fields() {
+        let info = HostInfo::read(&[HostField::BootId]);
+        assert!(info.boot_id.as_ref().is_some_and(|id| !id.is_empty()));
+        assert_eq!((&info.hostname, &info.machine_id), (&None, &None));
+
+        let buf = SharedBuffer::default();
+        let writer = HostWriter::new(
+            JsonWriter::from_writer(Box::new(buf.clone())),
+            HostInfo { hostname: Some(String::from("laptop")), ..HostInfo::default() }
+        );
+        block_on(writer.write(&DeviceEvent::new("/bat", [
+            (PropertyKind::Percentage, Percentage(50.0))
+        ]))).unwrap();
+        let json = serde_json::from_str::<serde_json::Value>(&buf.contents()).unwrap();
+        assert_eq!(json["hostname"], "laptop");
+        assert!(json.get("machine_id").is_none());
+    }
+}
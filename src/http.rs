@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::pin::pin;
+use std::time::Duration;
+use async_channel::{bounded, Receiver, Sender};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::{SecondsFormat, Utc};
+use futures::future::select;
+use futures::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1_smol::Sha1;
+use crate::cache::ValueCache;
+use crate::event::{DeviceEvent, SCHEMA_VERSION};
+use crate::lifecycle::lifecycle_event;
+use crate::output::Writer;
+use crate::rt::{timeout, TcpListener, TcpStream};
+use crate::stats;
+
+/// The number of events which can be queued for a client before it is disconnected.
+const CLIENT_QUEUE_SIZE: usize = 256;
+
+/// The GUID appended to a client's key to compute the `Sec-WebSocket-Accept` header.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// WebSocket frame opcodes.
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// The largest WebSocket frame payload accepted from a client.
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+/// The largest request line and headers (together) accepted from a client.
+const MAX_HEAD_SIZE: u64 = 8 * 1024;
+
+/// How long a client may take to send the request line and headers of its request.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The response sent to a client whose request cannot be understood.
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// The response sent to a client whose request line and headers are longer than
+/// [`MAX_HEAD_SIZE`].
+const HEAD_TOO_LARGE: &[u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\
+    Content-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// The request line and headers of a request.
+struct Head {
+    /// The request line, such as `GET /state HTTP/1.1`.
+    request_line: String,
+    /// The value of each header, by its name in lowercase.
+    headers: HashMap<String, String>
+}
+
+/// Read the request line and headers of a request from `reader`. Returns `None` if together they
+/// are longer than [`MAX_HEAD_SIZE`], or an [`ErrorKind::InvalidData`] error if they are not
+/// valid UTF-8.
+async fn read_head(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Result<Option<Head>, Error> {
+    let mut head = reader.take(MAX_HEAD_SIZE);
+    let mut lines = vec!();
+    loop {
+        let mut line = String::new();
+        head.read_line(&mut line).await?;
+        if !line.ends_with('\n') {
+            // Either the limit was reached or the client closed the connection mid-line.
+            return match head.limit() {
+                0 => Ok(None),
+                _ => Err(Error::from(ErrorKind::UnexpectedEof))
+            }
+        }
+        if line.trim_end().is_empty() {
+            break
+        }
+        lines.push(line);
+    }
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|l| l.split_once(':')
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), String::from(v.trim()))))
+        .collect();
+    Ok(Some(Head { request_line, headers }))
+}
+
+/// Compute the `Sec-WebSocket-Accept` header for the given `Sec-WebSocket-Key`.
+fn websocket_accept(key: &str) -> String {
+    BASE64.encode(Sha1::from(format!("{key}{WEBSOCKET_GUID}")).digest().bytes())
+}
+
+/// Encode an unmasked WebSocket frame (as sent by a server) with the given opcode and payload.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec!(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend((n as u16).to_be_bytes());
+        },
+        n => {
+            frame.push(127);
+            frame.extend((n as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+    frame
+}
+
+/// Read a single WebSocket frame sent by a client, returning its opcode and (unmasked) payload.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<(u8, Vec<u8>), Error> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).await?;
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len).await?;
+            u16::from_be_bytes(len) as u64
+        },
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len).await?;
+            u64::from_be_bytes(len)
+        },
+        n => n as u64
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket frame too large"))
+    }
+    let mut mask = [0; 4];
+    if header[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec!(0; len as usize);
+    reader.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((header[0] & 0x0F, payload))
+}
+
+/// A selection of the events a WebSocket client wishes to receive, sent by the client as a JSON
+/// text message such as `{"devices": ["/org/freedesktop/UPower/devices/battery_BAT0"],
+/// "properties": ["State"]}`. Either field may be omitted to select all devices or properties.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+struct Subscription {
+    /// The device paths whose changes should be sent.
+    devices: Option<Vec<String>>,
+    /// The properties whose changes should be sent.
+    properties: Option<Vec<String>>
+}
+
+impl Subscription {
+    /// Apply the subscription to the given event, returning `None` if the event should not be
+    /// sent, or the event with any unselected properties removed. Markers are always sent.
+    fn apply(&self, event: &Value) -> Option<Value> {
+        let Some(device) = event["device"].as_str() else {
+            return Some(event.clone())
+        };
+        if self.devices.as_ref().is_some_and(|d| !d.iter().any(|d| d == device)) {
+            return None
+        }
+        let Some(properties) = &self.properties else {
+            return Some(event.clone())
+        };
+        let mut event = event.clone();
+        let changes = event["changes"].as_object_mut()?;
+        changes.retain(|k, _| properties.contains(k));
+        if changes.is_empty() {
+            return None
+        }
+        Some(event)
+    }
+}
+
+/// Build a JSON event describing the changes to a device in `event`.
+pub(crate) fn change_event(event: &DeviceEvent) -> Value {
+    serde_json::to_value(event).expect("Events can always be serialized")
+}
+
+/// Build a JSON event describing a marker. Lifecycle markers also have an `event_type` field
+/// naming the lifecycle event, so that they can be told apart without parsing the marker.
+pub(crate) fn marker_event(marker: &str) -> Value {
+    let mut event = json!({
+        "schema_version": SCHEMA_VERSION,
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "marker": marker
+    });
+    if let Some(lifecycle) = lifecycle_event(marker) {
+        event["event_type"] = Value::from(<&str>::from(lifecycle));
+    }
+    event
+}
+
+/// A [`Writer`] which caches the latest value of each property of each device and broadcasts each
+/// change as a JSON event to all subscribed clients. Clients are served over HTTP by
+/// [`HttpWriter::serve`].
+#[derive(Default)]
+pub struct HttpWriter {
+    /// The latest value of each property of each device.
+    state: Mutex<ValueCache>,
+    /// Senders for the events of each subscribed client.
+    clients: Mutex<Vec<Sender<Value>>>
+}
+
+impl HttpWriter {
+    /// Subscribe to events, returning a receiver for all events written after subscription.
+    pub(crate) async fn subscribe(&self) -> Receiver<Value> {
+        let (sender, receiver) = bounded(CLIENT_QUEUE_SIZE);
+        self.clients.lock().await.push(sender);
+        receiver
+    }
+
+    /// Return the latest value of each property of each device, as a JSON object keyed by device
+    /// path.
+    pub(crate) async fn state(&self) -> Value {
+        self.state.lock().await.to_json()
+    }
+
+    /// Send the given event to all subscribed clients, dropping any clients which have
+    /// disconnected or are not keeping up with events.
+    async fn broadcast(&self, event: Value) {
+        self.clients.lock().await.retain(|c| c.try_send(event.clone()).is_ok());
+    }
+
+    /// Handle a single HTTP request. `GET /events` streams events as server-sent events,
+    /// `GET /ws` streams events over a WebSocket, `GET /state` returns the current state and
+    /// `GET /metrics` returns upmon's counters (see [`crate::stats`]) for Prometheus. Clients
+    /// which do not send the request line and headers within [`HEAD_TIMEOUT`] are disconnected.
+    async fn handle(&self, stream: TcpStream) -> Result<(), Error> {
+        let (reader, mut stream) = stream.split();
+        let mut reader = BufReader::new(reader);
+        let head = timeout(HEAD_TIMEOUT, read_head(&mut reader)).await
+            .unwrap_or_else(|_| Err(Error::from(ErrorKind::TimedOut)));
+        let Head { request_line, headers } = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => return stream.write_all(HEAD_TOO_LARGE).await,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                return stream.write_all(BAD_REQUEST).await
+            },
+            Err(e) => return Err(e)
+        };
+        let mut parts = request_line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (None, _) | (_, None) => stream.write_all(BAD_REQUEST).await,
+            (Some("GET"), Some("/events")) => {
+                let mut events = self.subscribe().await;
+                stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                      Cache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n"
+                ).await?;
+                while let Some(event) = events.next().await {
+                    stream.write_all(format!("data: {event}\n\n").as_bytes()).await?;
+                }
+                Ok(())
+            },
+            (Some("GET"), Some("/ws")) => match headers.get("sec-websocket-key") {
+                Some(key) => self.handle_websocket(stream, reader, key).await,
+                None => stream.write_all(BAD_REQUEST).await
+            },
+            (Some("GET"), Some("/state")) => {
+                let body = self.state().await.to_string();
+                stream.write_all(format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                ).as_bytes()).await
+            },
+            (Some("GET"), Some("/metrics")) => {
+                let body = stats::to_prometheus();
+                stream.write_all(format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                ).as_bytes()).await
+            },
+            _ => stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            ).await
+        }
+    }
+
+    /// Complete the WebSocket handshake for a client which sent the given key, and then send it
+    /// events as JSON text messages, restricted by any [`Subscription`] the client sends, until it
+    /// closes the connection.
+    async fn handle_websocket(
+        &self,
+        mut writer: WriteHalf<TcpStream>,
+        mut reader: BufReader<ReadHalf<TcpStream>>,
+        key: &str
+    ) -> Result<(), Error> {
+        let mut events = self.subscribe().await;
+        writer.write_all(format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            websocket_accept(key)
+        ).as_bytes()).await?;
+        let subscription = Mutex::new(Subscription::default());
+        // Frames may be sent both when events are received and in response to the client.
+        let writer = Mutex::new(writer);
+        let receive = async {
+            loop {
+                let (opcode, payload) = read_frame(&mut reader).await?;
+                match opcode {
+                    OP_TEXT => match serde_json::from_slice(&payload) {
+                        Ok(s) => *subscription.lock().await = s,
+                        Err(e) => {
+                            let error = json!({ "error": e.to_string() }).to_string();
+                            writer.lock().await
+                                .write_all(&encode_frame(OP_TEXT, error.as_bytes())).await?;
+                        }
+                    },
+                    OP_PING => writer.lock().await
+                        .write_all(&encode_frame(OP_PONG, &payload)).await?,
+                    OP_CLOSE => {
+                        writer.lock().await.write_all(&encode_frame(OP_CLOSE, &[])).await?;
+                        return Ok::<_, Error>(())
+                    },
+                    _ => {}
+                }
+            }
+        };
+        let send = async {
+            while let Some(event) = events.next().await {
+                if let Some(event) = subscription.lock().await.apply(&event) {
+                    writer.lock().await
+                        .write_all(&encode_frame(OP_TEXT, event.to_string().as_bytes())).await?;
+                }
+            }
+            Ok::<_, Error>(())
+        };
+        let result = select(pin!(receive), pin!(send)).await.factor_first().0;
+        result
+    }
+
+    /// Serve clients connecting to `listener` until an error occurs while accepting connections.
+    /// Errors affecting individual clients simply cause them to be disconnected.
+    pub(crate) async fn serve(&self, listener: &TcpListener) -> Result<(), Error> {
+        listener.incoming()
+            .for_each_concurrent(None, |stream| async move {
+                if let Ok(stream) = stream {
+                    let _ = self.handle(stream).await;
+                }
+            })
+            .await;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for HttpWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        self.state.lock().await.update(event);
+        self.broadcast(change_event(event)).await;
+        Ok(())
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        self.broadcast(marker_event(marker)).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use futures::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use serde_json::json;
+    use crate::event::DeviceEvent;
+    use crate::http::{encode_frame, HttpWriter, MAX_HEAD_SIZE, OP_TEXT, read_frame, Subscription,
+                      websocket_accept};
+    use crate::output::Writer;
+    use crate::rt::{block_on, TcpListener, TcpStream};
+    use crate::testing::run_until;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Return some changes to write.
+    fn changes() -> HashMap<PropertyKind, crate::upower::Property> {
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(80.0));
+        changes.insert(PropertyKind::State, State(2));
+        changes
+    }
+
+    /// Test that the state is updated with each change.
+    #[test]
+    fn state() {
+        let writer = HttpWriter::default();
+        block_on(writer.write(&DeviceEvent::new("/dev/battery", changes()))).unwrap();
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(79.0));
+        block_on(writer.write(&DeviceEvent::new("/dev/battery", changes))).unwrap();
+        assert_eq!(
+            block_on(writer.state()),
+            json!({"/dev/battery": {"Percentage": 79.0, "State": "Discharging"}})
+        );
+    }
+
+    /// Test the /state and /events endpoints.
+    #[test]
+    fn endpoints() {
+        block_on(async {
+            let writer = HttpWriter::default();
+            writer.write(&DeviceEvent::new("/dev/battery", changes())).await.unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut state = String::new();
+            let mut event = String::new();
+            run_until(writer.serve(&listener), async {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(b"GET /state HTTP/1.1\r\n\r\n").await.unwrap();
+                stream.read_to_string(&mut state).await.unwrap();
+
+                let (response, mut request) = TcpStream::connect(addr).await.unwrap().split();
+                request.write_all(b"GET /events HTTP/1.1\r\n\r\n").await.unwrap();
+                let mut reader = BufReader::new(response);
+                let mut line = String::new();
+                while reader.read_line(&mut line).await.unwrap() > 2 {
+                    line.clear();
+                }
+                writer.write_marker("Resumed").await.unwrap();
+                reader.read_line(&mut event).await.unwrap();
+            }).await;
+            assert!(state.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(state.ends_with(
+                "{\"/dev/battery\":{\"Percentage\":80.0,\"State\":\"Discharging\"}}"
+            ));
+            assert!(event.starts_with("data: {\"marker\":\"Resumed\""));
+        })
+    }
+
+    /// Test that requests whose request line and headers are too long or cannot be understood
+    /// are rejected.
+    #[test]
+    fn bad_requests() {
+        block_on(async {
+            let writer = HttpWriter::default();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            // Exactly as much as is read, so that the connection is not reset by closing it with
+            // some of the request unread.
+            let mut too_long = Vec::from(&b"GET /state HTTP/1.1\r\nX-Padding: "[..]);
+            too_long.resize(MAX_HEAD_SIZE as usize, b'a');
+            let requests = [
+                (too_long, "HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+                (Vec::from(&b"GET /\xff HTTP/1.1\r\n\r\n"[..]), "HTTP/1.1 400 Bad Request\r\n"),
+                (Vec::from(&b"\r\n"[..]), "HTTP/1.1 400 Bad Request\r\n")
+            ];
+            run_until(writer.serve(&listener), async {
+                for (request, expected) in requests {
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    stream.write_all(&request).await.unwrap();
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).await.unwrap();
+                    assert!(response.starts_with(expected), "{response}");
+                }
+            }).await;
+        })
+    }
+
+    /// Test the WebSocket handshake and framing.
+    #[test]
+    fn websocket_framing() {
+        // The example from RFC 6455.
+        assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(encode_frame(OP_TEXT, b"Hi"), vec!(0x81, 2, b'H', b'i'));
+        assert_eq!(&encode_frame(OP_TEXT, &[0; 300])[..4], &[0x81, 126, 1, 44]);
+        let masked = [0x81, 0x82, 1, 2, 3, 4, b'H' ^ 1, b'i' ^ 2];
+        assert_eq!(
+            block_on(read_frame(&mut &masked[..])).unwrap(),
+            (OP_TEXT, Vec::from(&b"Hi"[..]))
+        );
+    }
+
+    /// Test that subscriptions select devices and properties.
+    #[test]
+    fn subscriptions() {
+        let event = json!({
+            "device": "/dev/a",
+            "changes": {"Percentage": 80.0, "State": "Charging"}
+        });
+        let marker = json!({"marker": "Resumed"});
+        let all = Subscription::default();
+        assert_eq!(all.apply(&event), Some(event.clone()));
+        let devices: Subscription = serde_json::from_str(r#"{"devices": ["/dev/b"]}"#).unwrap();
+        assert_eq!(devices.apply(&event), None);
+        assert_eq!(devices.apply(&marker), Some(marker.clone()));
+        let props: Subscription = serde_json::from_str(r#"{"properties": ["State"]}"#).unwrap();
+        assert_eq!(
+            props.apply(&event),
+            Some(json!({"device": "/dev/a", "changes": {"State": "Charging"}}))
+        );
+        let none: Subscription = serde_json::from_str(r#"{"properties": ["Online"]}"#).unwrap();
+        assert_eq!(none.apply(&event), None);
+    }
+
+    /// Test streaming events to a WebSocket client with a subscription.
+    #[test]
+    fn websocket() {
+        block_on(async {
+            let writer = HttpWriter::default();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut received = vec!();
+            run_until(writer.serve(&listener), async {
+                let (reader, mut stream) = TcpStream::connect(addr).await.unwrap().split();
+                stream.write_all(
+                    b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+                ).await.unwrap();
+                let mut reader = BufReader::new(reader);
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                assert_eq!(line, "HTTP/1.1 101 Switching Protocols\r\n");
+                while reader.read_line(&mut line).await.unwrap() > 2 {
+                    line.clear();
+                }
+                let subscription = br#"{"properties": ["State"]}"#;
+                let mut frame = vec!(0x81, 0x80 | subscription.len() as u8, 0, 0, 0, 0);
+                frame.extend(subscription);
+                stream.write_all(&frame).await.unwrap();
+                crate::rt::sleep(std::time::Duration::from_millis(50)).await;
+                writer.write(&DeviceEvent::new("/dev/battery", changes())).await.unwrap();
+                let mut only_percentage = HashMap::new();
+                only_percentage.insert(PropertyKind::Percentage, Percentage(79.0));
+                writer.write(&DeviceEvent::new("/dev/battery", only_percentage)).await.unwrap();
+                writer.write_marker("Resumed").await.unwrap();
+                for _ in 0..2 {
+                    let (_, payload) = read_frame(&mut reader).await.unwrap();
+                    let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+                    received.push(event);
+                }
+            }).await;
+            assert_eq!(received[0]["changes"], json!({"State": "Discharging"}));
+            assert_eq!(received[1]["marker"], "Resumed");
+        })
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Error, Write};
+use std::process::Command;
+use std::thread::JoinHandle;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::cache::ValueCache;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::severity::SeverityColors;
+use crate::summary::{short_name, summarize};
+use crate::upower::{Property, PropertyKind};
+
+/// The name given to every block written by upmon, so that click events can be recognised.
+const BLOCK_NAME: &str = "upmon";
+
+/// A [`Writer`] which implements the [i3bar protocol](https://i3wm.org/docs/i3bar-protocol.html),
+/// so that upmon can be used as the `status_command` of i3bar (or a compatible bar such as
+/// swaybar). The protocol's header and the opening of its infinite array are written when the
+/// writer is created; then, on each change, the whole status line is written again, with one block
+/// per device (in the order in which devices were first seen) summarised as by [`summarize`].
+/// Blocks are coloured according to the device's `Severity`, if it is added, and critical blocks
+/// are marked as urgent. Markers are not written.
+pub struct I3barWriter {
+    /// File (or other struct implementing Write) to write to.
+    out: Mutex<Box<dyn Write>>,
+    /// The latest value of each property of each device.
+    cache: Mutex<ValueCache>,
+    /// The colours of the blocks of devices whose severity is warning or critical.
+    colors: SeverityColors
+}
+
+impl I3barWriter {
+    /// Create a new [`I3barWriter`] which writes to `out`, writing the protocol header
+    /// immediately. If `click_events` is true, the header asks the bar to send click events
+    /// (which can be handled by [`handle_clicks`]).
+    pub(crate) fn new(mut out: Box<dyn Write>, click_events: bool, colors: SeverityColors)
+        -> Result<Self, Error> {
+        writeln!(out, "{}", json!({"version": 1, "click_events": click_events}))?;
+        // The status lines form an infinite array. Writing an empty line first means that every
+        // subsequent line can be preceded by a comma.
+        writeln!(out, "[\n[]")?;
+        out.flush()?;
+        Ok(Self { out: Mutex::new(out), cache: Mutex::new(ValueCache::default()), colors })
+    }
+
+    /// Build the block describing a device with the given latest values.
+    fn block(&self, device: &str, values: &HashMap<PropertyKind, Property>) -> Value {
+        let mut block = json!({
+            "name": BLOCK_NAME,
+            "instance": device,
+            "full_text": format!("{} {}", short_name(device), summarize(values, "="))
+        });
+        if let Some(color) = self.colors.color(values) {
+            block["color"] = json!(color);
+        }
+        if values.get(&PropertyKind::Severity).is_some_and(|s| s.to_string() == "critical") {
+            block["urgent"] = json!(true);
+        }
+        block
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for I3barWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        let line = {
+            let mut cache = self.cache.lock().await;
+            cache.update(event);
+            Value::Array(cache.iter().map(|(device, values)| self.block(device, values)).collect())
+        };
+        let mut out = self.out.lock().await;
+        writeln!(out, ",{line}")?;
+        out.flush()
+    }
+
+    async fn write_marker(&self, _marker: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A click on a block, as sent by the bar when click events are enabled.
+#[derive(Debug, Deserialize, PartialEq)]
+struct Click {
+    /// The name of the block clicked.
+    #[serde(default)]
+    name: Option<String>,
+    /// The instance of the block clicked, which is the path of the device.
+    #[serde(default)]
+    instance: Option<String>,
+    /// The mouse button used (1 for left, 2 for middle, 3 for right).
+    button: u32
+}
+
+/// Parse a single line of the click events sent by the bar, which form an infinite JSON array
+/// with one event per line. Returns `None` for the opening of the array and anything that is not a
+/// click on one of upmon's blocks.
+fn parse_click(line: &str) -> Option<Click> {
+    let line = line.trim().trim_start_matches(['[', ',']).trim();
+    serde_json::from_str::<Click>(line).ok()
+        .filter(|c| c.name.as_deref() == Some(BLOCK_NAME))
+}
+
+/// Read click events from standard input until it is closed, running `command` using the shell
+/// for each click on one of upmon's blocks. The path of the device clicked and the mouse button
+/// used are given to the command in the `UPMON_DEVICE` and `UPMON_BUTTON` environment variables.
+/// Clicks are read on a separate thread, as reading standard input blocks.
+pub(crate) fn handle_clicks(command: String) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                return
+            };
+            let Some(click) = parse_click(&line) else {
+                continue
+            };
+            let status = Command::new("/bin/sh")
+                .arg("-c")
+                .arg(&command)
+                .env("UPMON_DEVICE", click.instance.unwrap_or_default())
+                .env("UPMON_BUTTON", click.button.to_string())
+                .status();
+            if let Err(e) = status {
+                eprintln!("Error running click command: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::{json, Value};
+    use zbus::zvariant::Value as DbusValue;
+    use crate::event::DeviceEvent;
+    use crate::i3bar::{Click, I3barWriter, parse_click};
+    use crate::output::Writer;
+    use crate::rt::block_on;
+    use crate::severity::SeverityColors;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Online, Other, Percentage};
+    use crate::upower::PropertyKind;
+
+    /// Test that the header is written, followed by the whole status line on each change.
+    #[test]
+    fn i3bar_writer() {
+        let buf = SharedBuffer::default();
+        let writer = I3barWriter::new(Box::new(buf.clone()), true, SeverityColors::default())
+            .unwrap();
+        let ac = "/org/freedesktop/UPower/devices/line_power_AC";
+        let bat = "/org/freedesktop/UPower/devices/battery_BAT0";
+        block_on(async {
+            writer.write(&DeviceEvent::new(ac, vec!((PropertyKind::Online, Online(true)))))
+                .await.unwrap();
+            writer.write_marker("Resumed").await.unwrap();
+            writer.write(&DeviceEvent::new(bat, vec!(
+                (PropertyKind::Percentage, Percentage(4.0)),
+                (PropertyKind::Severity, Other(DbusValue::from("critical").into()))
+            ))).await.unwrap();
+        });
+        let contents = buf.contents();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            serde_json::from_str::<Value>(lines[0]).unwrap(),
+            json!({"version": 1, "click_events": true})
+        );
+        assert_eq!(&lines[1..3], ["[", "[]"]);
+        let line = serde_json::from_str::<Value>(lines[4].strip_prefix(',').unwrap()).unwrap();
+        assert_eq!(line, json!([
+            {"name": "upmon", "instance": ac, "full_text": "AC online"},
+            {
+                "name": "upmon",
+                "instance": bat,
+                "full_text": "BAT0 4% Severity=critical",
+                "color": "#FF0000",
+                "urgent": true
+            }
+        ]));
+    }
+
+    /// Test that click events on upmon's blocks are parsed, and everything else is ignored.
+    #[test]
+    fn i3bar_clicks() {
+        assert_eq!(parse_click("["), None);
+        assert_eq!(
+            parse_click(r#",{"name":"upmon","instance":"/dev","button":3,"x":10}"#),
+            Some(Click {
+                name: Some(String::from("upmon")),
+                instance: Some(String::from("/dev")),
+                button: 3
+            })
+        );
+        assert!(parse_click(r#"[{"name":"upmon","button":1}"#).is_some());
+        assert_eq!(parse_click(r#"{"name":"clock","button":1}"#), None);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use zbus::Connection;
+use zbus::zvariant::{OwnedValue, Value};
+use crate::output::Writer;
+use crate::upower::DeviceConfig;
+
+/// The marker written for each monitored device when upmon starts, if requested. The device path
+/// and its static properties (see [`device_info`]) are appended to the marker, separated by
+/// spaces.
+pub(crate) const DEVICE_INFO_MARKER: &str = "DeviceInfo";
+
+/// Names of the possible values of the `Type` property, indexed by their numeric value.
+const TYPE_NAMES: [&str; 29] = [
+    "Unknown",
+    "LinePower",
+    "Battery",
+    "Ups",
+    "Monitor",
+    "Mouse",
+    "Keyboard",
+    "Pda",
+    "Phone",
+    "MediaPlayer",
+    "Tablet",
+    "Computer",
+    "GamingInput",
+    "Pen",
+    "Touchpad",
+    "Modem",
+    "Network",
+    "Headset",
+    "Speakers",
+    "Headphones",
+    "Video",
+    "OtherAudio",
+    "RemoteControl",
+    "Printer",
+    "Scanner",
+    "Camera",
+    "Wearable",
+    "Toy",
+    "BluetoothGeneric"
+];
+
+/// Names of the possible values of the `Technology` property, indexed by their numeric value.
+const TECHNOLOGY_NAMES: [&str; 7] = [
+    "Unknown",
+    "LithiumIon",
+    "LithiumPolymer",
+    "LithiumIronPhosphate",
+    "LeadAcid",
+    "NickelCadmium",
+    "NickelMetalHydride"
+];
+
+/// The static properties of a UPower device which describe it, in the order they are written.
+const INFO_PROPERTIES: [&str; 5] = ["Vendor", "Model", "Serial", "Type", "Technology"];
+
+/// Describe a device from the values of its properties, as `NAME=VALUE` pairs separated by spaces.
+/// Strings which are empty or contain whitespace are quoted as JSON strings, and enumerated
+/// properties are given by name. Returns `None` if the device has none of the properties.
+pub(crate) fn device_info(properties: &HashMap<String, OwnedValue>) -> Option<String> {
+    let pairs = INFO_PROPERTIES.iter()
+        .filter_map(|name| {
+            let value = match (*name, &**properties.get(*name)?) {
+                ("Type", Value::U32(t)) => TYPE_NAMES.get(*t as usize)?.to_string(),
+                ("Technology", Value::U32(t)) => TECHNOLOGY_NAMES.get(*t as usize)?.to_string(),
+                (_, Value::Str(s)) if s.is_empty() || s.contains(char::is_whitespace) => {
+                    serde_json::to_string(s.as_str()).ok()?
+                },
+                (_, Value::Str(s)) => s.to_string(),
+                _ => return None
+            };
+            Some(format!("{name}={value}"))
+        })
+        .collect::<Vec<_>>();
+    (!pairs.is_empty()).then(|| pairs.join(" "))
+}
+
+/// Write a [`DEVICE_INFO_MARKER`] describing each of the given devices to `writer`. Devices whose
+/// properties cannot be fetched, or which have none of the properties describing them (such as
+/// devices whose interface is not UPower's), are skipped.
+pub(crate) async fn write_device_info(
+    conn: &Connection,
+    devices: &[DeviceConfig],
+    writer: &impl Writer
+) -> Result<(), std::io::Error> {
+    for device in devices {
+        let info = match device.fetch_properties(conn).await {
+            Ok(properties) => properties.as_ref().and_then(device_info),
+            Err(e) => {
+                eprintln!("Could not fetch properties of {}: {e}", device.path());
+                continue
+            }
+        };
+        if let Some(info) = info {
+            writer.write_marker(&format!("{DEVICE_INFO_MARKER} {} {info}", device.device())).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use zbus::zvariant::{OwnedValue, Value};
+    use crate::info::device_info;
+
+    /// Test that devices are described by their static properties, with enumerated properties
+    /// named and strings quoted where necessary.
+    #[test]
+    fn describe_device() {
+        let properties = |p: &[(&str, Value)]| p.iter()
+            .map(|(k, v)| (String::from(*k), OwnedValue::from(v.clone())))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            device_info(&properties(&[
+                ("Technology", Value::U32(1)),
+                ("Model", Value::from("5B10W13930")),
+                ("Vendor", Value::from("Sunwoda Electronics")),
+                ("Serial", Value::from("")),
+                ("Type", Value::U32(2)),
+                ("Percentage", Value::F64(80.0))
+            ])),
+            Some(String::from(concat!(
+                r#"Vendor="Sunwoda Electronics" Model=5B10W13930 Serial="" "#,
+                "Type=Battery Technology=LithiumIon"
+            )))
+        );
+        assert_eq!(
+            device_info(&properties(&[("Type", Value::U32(99)), ("Level", Value::U32(3))])),
+            None
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::env;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{Error, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+use async_signal::{Signal as AsyncSignal, Signals};
+use clap::ValueEnum;
+use futures::StreamExt;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{getuid, Pid};
+use serde::{Deserialize, Serialize};
+
+/// How long to wait for an existing instance to exit when replacing it.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The interval at which the lock is retried while waiting for an existing instance to exit.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What to do if another instance of upmon is already running.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExistingInstance {
+    /// Exit with an error, leaving the existing instance running.
+    Exit,
+    /// Stop the existing instance (by sending it SIGTERM) and continue once it has exited.
+    Replace
+}
+
+/// An exclusive lock, held until the process exits, indicating that an instance of upmon is
+/// running. The lock file contains the PID of the instance holding the lock.
+#[derive(Debug)]
+pub struct InstanceLock {
+    /// The lock file, which is locked for as long as it is open.
+    _file: File
+}
+
+impl InstanceLock {
+    /// Return the path of the lock file for the current user: `upmon.lock` in `$XDG_RUNTIME_DIR`,
+    /// or `upmon-UID.lock` in the temporary directory if that is not set.
+    pub(crate) fn default_path() -> PathBuf {
+        match env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("upmon.lock"),
+            _ => env::temp_dir().join(format!("upmon-{}.lock", getuid()))
+        }
+    }
+
+    /// Try to take an exclusive lock on `file` without waiting, returning whether it was taken.
+    fn try_lock(file: &File) -> bool {
+        flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_ok()
+    }
+
+    /// Read the PID of the instance holding the lock from the lock file.
+    fn read_pid(file: &mut File) -> Option<i32> {
+        let mut contents = String::new();
+        file.rewind().ok()?;
+        file.read_to_string(&mut contents).ok()?;
+        contents.trim().parse().ok()
+    }
+
+    /// Take the lock at `path`. If another instance already holds it, either return an error or
+    /// stop that instance and wait for the lock, depending on `existing`.
+    pub(crate) fn acquire(path: &Path, existing: ExistingInstance) -> Result<Self, String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("Could not open lock file {}: {e}", path.display()))?;
+        if !Self::try_lock(&file) {
+            let pid = Self::read_pid(&mut file);
+            let describe = || pid.map(|p| format!(" (PID {p})")).unwrap_or_default();
+            match (existing, pid) {
+                (ExistingInstance::Exit, _) => return Err(
+                    format!("Another instance of upmon is already running{}", describe())
+                ),
+                (ExistingInstance::Replace, Some(p)) => {
+                    kill(Pid::from_raw(p), Signal::SIGTERM).map_err(|e| {
+                        format!("Could not stop the existing instance{}: {e}", describe())
+                    })?;
+                },
+                (ExistingInstance::Replace, None) => {
+                    return Err(String::from("Could not read the PID of the existing instance"))
+                }
+            }
+            let mut waited = Duration::ZERO;
+            while !Self::try_lock(&file) {
+                if waited >= REPLACE_TIMEOUT {
+                    return Err(format!("The existing instance{} did not exit", describe()))
+                }
+                sleep(RETRY_INTERVAL);
+                waited += RETRY_INTERVAL;
+            }
+        }
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .map_err(|e| format!("Could not write lock file {}: {e}", path.display()))?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// A file containing the PID of upmon, which is removed when dropped (that is, when upmon shuts
+/// down cleanly).
+#[derive(Debug)]
+pub struct PidFile {
+    /// The path to the file.
+    path: PathBuf
+}
+
+impl PidFile {
+    /// Write the PID of the current process to the file at `path`, replacing any existing file.
+    pub(crate) fn create(path: &Path) -> Result<Self, Error> {
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Wait until upmon receives SIGINT or SIGTERM, so that it can shut down cleanly.
+pub(crate) async fn terminated() -> Result<(), Error> {
+    let mut signals = Signals::new([AsyncSignal::Int, AsyncSignal::Term])?;
+    signals.next().await.transpose()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs::{read_to_string, remove_file};
+    use crate::instance::{ExistingInstance, InstanceLock, PidFile};
+
+    /// Test that only one lock can be held at a time, and that the lock file contains the PID of
+    /// its holder.
+    #[test]
+    fn instance_lock() {
+        let path = std::env::temp_dir().join(format!("upmon-lock-test-{}", std::process::id()));
+        let lock = InstanceLock::acquire(&path, ExistingInstance::Exit).unwrap();
+        let pid = std::process::id();
+        assert_eq!(read_to_string(&path).unwrap(), format!("{pid}\n"));
+        let err = InstanceLock::acquire(&path, ExistingInstance::Exit).unwrap_err();
+        assert!(err.ends_with(&format!("(PID {pid})")), "{err}");
+        drop(lock);
+        InstanceLock::acquire(&path, ExistingInstance::Exit).unwrap();
+        remove_file(path).unwrap();
+    }
+
+    /// Test that the PID file contains the PID of the process, and is removed when dropped.
+    #[test]
+    fn pid_file() {
+        let path = std::env::temp_dir().join(format!("upmon-pid-test-{}", std::process::id()));
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
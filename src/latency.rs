@@ -0,0 +1,138 @@
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+
+/// The marker written when a battery's `State` changes after a line power device's `Online`
+/// property has changed. The battery's path, the line power device's path and the time between
+/// the two changes (in seconds) are appended to the marker, separated by spaces.
+pub(crate) const LATENCY_MARKER: &str = "TransitionLatency";
+
+/// The longest time after a line power device goes online or offline within which a change to a
+/// battery's `State` is taken to be a response to it, in seconds.
+const MAX_LATENCY: i64 = 60;
+
+/// The latest change to a line power device's `Online` property.
+#[derive(Debug)]
+struct Transition {
+    /// The path of the line power device.
+    line_power: String,
+    /// When `Online` changed.
+    time: DateTime<Utc>,
+    /// The batteries whose `State` has already changed since `Online` changed.
+    responded: Vec<String>
+}
+
+/// A [`Writer`] which passes all changes on to an inner [`Writer`], and additionally measures the
+/// time it takes each battery's `State` to change after a line power device goes online or offline,
+/// writing a [`LATENCY_MARKER`] for each battery which responds. Long or inconsistent delays point
+/// to unreliable ACPI or firmware behaviour.
+pub struct LatencyWriter<W: Writer> {
+    /// The writer to which changes and latencies are passed.
+    inner: W,
+    /// Whether latencies should be measured.
+    enabled: bool,
+    /// The latest transition of any line power device.
+    transition: Mutex<Option<Transition>>
+}
+
+impl<W: Writer> LatencyWriter<W> {
+    /// Create a new [`LatencyWriter`] which passes changes to `inner`, measuring latencies if
+    /// `enabled` is true.
+    pub(crate) fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            transition: Mutex::new(None)
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for LatencyWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        self.inner.write(event).await?;
+        if !self.enabled {
+            return Ok(())
+        }
+        let latency = {
+            let mut transition = self.transition.lock().await;
+            if let Some(Property::Online(_)) = event.get(&PropertyKind::Online) {
+                *transition = Some(Transition {
+                    line_power: event.device.clone(),
+                    time: event.timestamp,
+                    responded: vec!()
+                });
+                None
+            } else if let (Some(Property::State(_)), Some(t)) =
+                (event.get(&PropertyKind::State), transition.as_mut()) {
+                let latency = event.timestamp - t.time;
+                if latency.num_seconds() < MAX_LATENCY && !t.responded.contains(&event.device) {
+                    t.responded.push(event.device.clone());
+                    Some((t.line_power.clone(), latency))
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        };
+        match latency {
+            Some((line_power, latency)) => self.inner.write_marker(&format!(
+                "{LATENCY_MARKER} {} {line_power} {:.3}",
+                event.device,
+                latency.num_milliseconds() as f64 / 1000.0
+            )).await,
+            None => Ok(())
+        }
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chrono::{DateTime, Utc};
+    use crate::event::DeviceEvent;
+    use crate::latency::LatencyWriter;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{self, Online, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that the latency of each battery's first change to State after a line power device
+    /// goes online is written, and that later or unrelated changes are ignored.
+    #[test]
+    fn latency_writer() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = LatencyWriter::new(inner, true);
+        let event = |device: &str, time: &str, change: (PropertyKind, Property)| {
+            let mut event = DeviceEvent::new(device, vec!(change));
+            event.timestamp = time.parse::<DateTime<Utc>>().unwrap();
+            event
+        };
+        block_on(async {
+            for e in [
+                event("/bat", "2024-02-11T09:00:00Z", (PropertyKind::State, State(2))),
+                event("/ac", "2024-02-11T09:10:00Z", (PropertyKind::Online, Online(true))),
+                event("/bat", "2024-02-11T09:10:01.250Z", (PropertyKind::State, State(1))),
+                event("/bat", "2024-02-11T09:10:05Z", (PropertyKind::State, State(4))),
+                event("/ac", "2024-02-11T09:20:00Z", (PropertyKind::Online, Online(false))),
+                event("/bat", "2024-02-11T09:25:00Z", (PropertyKind::State, State(2)))
+            ] {
+                writer.write(&e).await.unwrap();
+            }
+        });
+        let markers = buf.contents().lines()
+            .filter(|l| l.starts_with("TransitionLatency"))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert_eq!(markers, vec!("TransitionLatency /bat /ac 1.250"));
+    }
+}
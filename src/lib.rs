@@ -0,0 +1,92 @@
+//! upmon monitors UPower devices and writes changes to their properties in a choice of formats.
+//! The command line tool is built on this library, which also allows other crates to write
+//! [`DeviceEvent`]s using the provided [`Writer`]s or their own, selected by name from a
+//! [`WriterRegistry`].
+
+// The configuration printed by --dry-run is built with one large `json!` invocation.
+#![recursion_limit = "256"]
+
+mod upower;
+mod event;
+mod cache;
+mod output;
+mod queue;
+mod registry;
+mod summary;
+mod i3bar;
+mod record;
+mod diff;
+mod rt;
+mod bluez;
+mod logind;
+mod critical;
+mod health;
+mod filter;
+mod aggregate;
+mod alert;
+mod action;
+mod sound;
+mod notify;
+mod session;
+mod report;
+mod latency;
+mod lifecycle;
+mod expr;
+mod until;
+mod severity;
+mod smooth;
+mod quantize;
+mod stale;
+mod threshold;
+mod throttle;
+mod numeric;
+mod locale;
+mod glyph;
+mod osd;
+mod fifo;
+mod plugin;
+mod exec;
+#[cfg(feature = "compress")]
+mod compress;
+mod names;
+mod info;
+mod connect;
+mod retry;
+mod control;
+mod daemon;
+mod exit;
+mod instance;
+mod stats;
+mod bugreport;
+mod redact;
+mod host;
+mod ratelimit;
+mod zabbix;
+mod metrics;
+mod http;
+mod service;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod udev;
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod testing;
+mod cli;
+
+#[cfg(feature = "compress")]
+pub use crate::compress::Compression;
+pub use crate::event::DeviceEvent;
+pub use crate::locale::Locale;
+pub use crate::output::{FileMode, FileOptions, JsonWriter, LineWriter, Writer};
+pub use crate::registry::{OutputSpec, WriterFactory, WriterOptions, WriterRegistry};
+pub use crate::severity::SeverityColors;
+pub use crate::upower::{Property, PropertyKind, UpdateTimeFormat};
+
+#[doc(hidden)]
+pub use crate::cli::main;
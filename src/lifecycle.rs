@@ -0,0 +1,154 @@
+use futures::stream::select;
+use strum::{EnumString, IntoStaticStr};
+use zbus::{
+    Connection, MatchRule, MessageStream, MessageType, Result as zbus_Result,
+    export::futures_util::TryStreamExt
+};
+use zbus::zvariant::OwnedObjectPath;
+use crate::output::Writer;
+use crate::upower::{DeviceConfig, UPOWER_PATH, UPOWER_SERVICE};
+
+/// The marker written for each [`Lifecycle`] event, followed by the event and, for events
+/// concerning a single device, the device.
+pub(crate) const LIFECYCLE_MARKER: &str = "Lifecycle";
+
+/// Something that happens to upmon itself or to what it is monitoring, which is written as a
+/// [`LIFECYCLE_MARKER`] so that consumers can tell gaps in the data apart from periods in which
+/// nothing changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumString, IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
+pub(crate) enum Lifecycle {
+    /// upmon has started monitoring.
+    MonitorStarted,
+    /// upmon has subscribed to changes to a device.
+    DeviceSubscribed,
+    /// Changes to a device can no longer be received, or UPower has removed it.
+    DeviceLost,
+    /// The UPower daemon has been restarted.
+    UpowerRestarted,
+    /// upmon is about to stop monitoring.
+    MonitorStopping
+}
+
+/// Return the lifecycle event described by `marker`, if it is a [`LIFECYCLE_MARKER`].
+pub(crate) fn lifecycle_event(marker: &str) -> Option<Lifecycle> {
+    marker.strip_prefix(LIFECYCLE_MARKER)?
+        .strip_prefix(' ')?
+        .split(' ')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Write a [`LIFECYCLE_MARKER`] for `event`, concerning `device` if given.
+pub(crate) async fn write_lifecycle(
+    writer: &impl Writer,
+    event: Lifecycle,
+    device: Option<&str>
+) -> Result<(), std::io::Error> {
+    let event = <&str>::from(event);
+    match device {
+        Some(device) => writer.write_marker(&format!("{LIFECYCLE_MARKER} {event} {device}")).await,
+        None => writer.write_marker(&format!("{LIFECYCLE_MARKER} {event}")).await
+    }
+}
+
+/// Build and return a `MatchRule` for changes to the owner of UPower's bus name.
+fn upower_owner_rule() -> zbus_Result<MatchRule<'static>> {
+    Ok(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg(0, UPOWER_SERVICE)?
+        .build())
+}
+
+/// Build and return a `MatchRule` for UPower's `DeviceRemoved` signal.
+fn device_removed_rule() -> zbus_Result<MatchRule<'static>> {
+    Ok(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .interface("org.freedesktop.UPower")?
+        .member("DeviceRemoved")?
+        .path(UPOWER_PATH)?
+        .build())
+}
+
+/// Listen for the UPower daemon being restarted (its bus name being taken by a new owner) and for
+/// any of the given devices being removed by UPower, and write a [`LIFECYCLE_MARKER`] for each.
+pub(crate) async fn listen_upower(
+    conn: &Connection,
+    paths: &[DeviceConfig],
+    writer: &impl Writer
+) -> zbus_Result<()> {
+    let mut stream = select(
+        MessageStream::for_match_rule(upower_owner_rule()?, conn, None).await?,
+        MessageStream::for_match_rule(device_removed_rule()?, conn, None).await?
+    );
+    loop {
+        let Some(msg) = stream.try_next().await? else {
+            return Ok(())
+        };
+        // Signals which cannot be parsed are skipped.
+        if msg.member().is_some_and(|m| m == "NameOwnerChanged") {
+            let Ok((_, _, new_owner)) = msg.body::<(String, String, String)>() else {
+                continue
+            };
+            // The name loses its owner when the daemon stops, and gains one when it starts again.
+            if !new_owner.is_empty() {
+                write_lifecycle(writer, Lifecycle::UpowerRestarted, None).await?;
+            }
+        } else {
+            let Ok(path) = msg.body::<OwnedObjectPath>() else {
+                continue
+            };
+            if let Some(conf) = paths.iter().find(|c| c.is_for(path.as_str())) {
+                write_lifecycle(writer, Lifecycle::DeviceLost, Some(&conf.device())).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::lifecycle::{lifecycle_event, listen_upower, Lifecycle};
+    use crate::output::LineWriter;
+    use crate::rt::block_on;
+    use crate::testing::{MOCK_DEVICE_PATH, MockUPower, run_until, SharedBuffer};
+    use crate::upower::{DeviceConfig, DeviceSet};
+
+    /// Test that lifecycle markers are written when a device is subscribed to and when UPower
+    /// removes it, and that they can be recognised.
+    #[test]
+    fn lifecycle_markers() {
+        assert_eq!(lifecycle_event("Lifecycle monitor-started"), Some(Lifecycle::MonitorStarted));
+        assert_eq!(lifecycle_event("Lifecycle device-lost /bat"), Some(Lifecycle::DeviceLost));
+        assert_eq!(lifecycle_event("Lifecycle"), None);
+        assert_eq!(lifecycle_event("Resumed"), None);
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let conf = DeviceConfig::new(MOCK_DEVICE_PATH, "Percentage", None).unwrap();
+            let devices = DeviceSet::default().with_lifecycle(true);
+            devices.extend([conf.clone()]).await;
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            let (buses, confs) = (HashMap::new(), [conf]);
+            let listen = async {
+                futures::join!(
+                    devices.listen(&upower.client, &buses, &writer, None),
+                    listen_upower(&upower.client, &confs, &writer)
+                )
+            };
+            run_until(listen, async {
+                devices.subscribed().await;
+                upower.remove_device("/org/freedesktop/UPower/devices/other").await.unwrap();
+                upower.remove_device(MOCK_DEVICE_PATH).await.unwrap();
+                buf.wait_for(&format!(
+                    "Lifecycle device-subscribed {MOCK_DEVICE_PATH}\n\
+                     Lifecycle device-lost {MOCK_DEVICE_PATH}\n"
+                )).await;
+            }).await;
+        });
+    }
+}
@@ -0,0 +1,191 @@
+use std::env;
+use crate::upower::Property;
+
+/// The words used for a unit of time, in the singular and plural.
+type Unit = (&'static str, &'static str);
+
+/// The conventions and translations used to format values for a language.
+#[derive(Debug, PartialEq)]
+pub struct Locale {
+    /// The language code, such as "de".
+    code: &'static str,
+    /// The character separating the integer and fractional parts of a number.
+    decimal_separator: char,
+    /// The names of the possible values of the `State` property, indexed by their numeric value.
+    states: [&'static str; 7],
+    /// The names of the possible values of the `WarningLevel` property, indexed by their numeric
+    /// value.
+    warning_levels: [&'static str; 6],
+    /// The names of the possible values of the `BatteryLevel` property, indexed by their numeric
+    /// value.
+    battery_levels: [&'static str; 9],
+    /// The words for hours, minutes and seconds.
+    units: [Unit; 3]
+}
+
+/// The supported locales. The first is used for the "C" and "POSIX" locales.
+const LOCALES: [Locale; 4] = [
+    Locale {
+        code: "en",
+        decimal_separator: '.',
+        states: [
+            "Unknown", "Charging", "Discharging", "Empty", "Fully charged", "Pending charge",
+            "Pending discharge"
+        ],
+        warning_levels: ["Unknown", "None", "Discharging", "Low", "Critical", "Action"],
+        battery_levels: [
+            "Unknown", "None", "Discharging", "Low", "Critical", "Action", "Normal", "High", "Full"
+        ],
+        units: [("hour", "hours"), ("minute", "minutes"), ("second", "seconds")]
+    },
+    Locale {
+        code: "de",
+        decimal_separator: ',',
+        states: [
+            "Unbekannt", "Lädt", "Entlädt", "Leer", "Vollständig geladen", "Laden ausstehend",
+            "Entladen ausstehend"
+        ],
+        warning_levels: ["Unbekannt", "Keine", "Entlädt", "Niedrig", "Kritisch", "Aktion"],
+        battery_levels: [
+            "Unbekannt", "Keine", "Entlädt", "Niedrig", "Kritisch", "Aktion", "Normal", "Hoch",
+            "Voll"
+        ],
+        units: [("Stunde", "Stunden"), ("Minute", "Minuten"), ("Sekunde", "Sekunden")]
+    },
+    Locale {
+        code: "es",
+        decimal_separator: ',',
+        states: [
+            "Desconocido", "Cargando", "Descargando", "Vacía", "Carga completa",
+            "Carga pendiente", "Descarga pendiente"
+        ],
+        warning_levels: ["Desconocido", "Ninguno", "Descargando", "Bajo", "Crítico", "Acción"],
+        battery_levels: [
+            "Desconocido", "Ninguno", "Descargando", "Bajo", "Crítico", "Acción", "Normal", "Alto",
+            "Lleno"
+        ],
+        units: [("hora", "horas"), ("minuto", "minutos"), ("segundo", "segundos")]
+    },
+    Locale {
+        code: "fr",
+        decimal_separator: ',',
+        states: [
+            "Inconnu", "En charge", "En décharge", "Vide", "Chargée", "Charge en attente",
+            "Décharge en attente"
+        ],
+        warning_levels: ["Inconnu", "Aucun", "En décharge", "Faible", "Critique", "Action"],
+        battery_levels: [
+            "Inconnu", "Aucun", "En décharge", "Faible", "Critique", "Action", "Normal", "Élevé",
+            "Plein"
+        ],
+        units: [("heure", "heures"), ("minute", "minutes"), ("seconde", "secondes")]
+    }
+];
+
+impl Locale {
+    /// Return the codes of the supported locales.
+    pub(crate) fn codes() -> impl Iterator<Item = &'static str> {
+        LOCALES.iter().map(|l| l.code)
+    }
+
+    /// Find the locale with the given name, which may be a POSIX locale name such as
+    /// "de_DE.UTF-8" (in which case only the language is considered).
+    pub(crate) fn find(name: &str) -> Result<&'static Locale, String> {
+        let language = name.split(['_', '-', '.', '@']).next().unwrap_or_default().to_lowercase();
+        if language == "c" || language == "posix" {
+            return Ok(&LOCALES[0])
+        }
+        LOCALES.iter()
+            .find(|l| l.code == language)
+            .ok_or_else(|| format!(
+                "Unsupported locale: {name} (supported: {})",
+                Self::codes().collect::<Vec<_>>().join(", ")
+            ))
+    }
+
+    /// Find the locale given by the environment (the `LC_ALL`, `LC_MESSAGES` or `LANG` variable).
+    pub(crate) fn from_env() -> Result<&'static Locale, String> {
+        let name = ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+            .filter_map(|v| env::var(v).ok())
+            .find(|v| !v.is_empty())
+            .ok_or_else(|| String::from("No locale set in the environment"))?;
+        Self::find(&name)
+    }
+
+    /// Format a number using the locale's decimal separator.
+    fn format_number(&self, n: f64) -> String {
+        n.to_string().replace('.', &self.decimal_separator.to_string())
+    }
+
+    /// Format a number of seconds as words, such as "2 hours 5 minutes". Seconds are only included
+    /// if the duration is less than a minute.
+    fn format_duration(&self, secs: i64) -> String {
+        let secs = secs.max(0);
+        let word = |n: i64, (singular, plural): Unit| {
+            format!("{n} {}", if n == 1 { singular } else { plural })
+        };
+        let (h, m) = (secs / 3600, secs % 3600 / 60);
+        let mut parts = vec!();
+        if h > 0 {
+            parts.push(word(h, self.units[0]));
+        }
+        if m > 0 {
+            parts.push(word(m, self.units[1]));
+        }
+        if parts.is_empty() {
+            parts.push(word(secs, self.units[2]));
+        }
+        parts.join(" ")
+    }
+
+    /// Format the value of the given property for the locale, or return `None` if the value is not
+    /// affected by the locale.
+    pub(crate) fn format(&self, property: &Property) -> Option<String> {
+        match property {
+            Property::State(n) => self.states.get(*n as usize).map(|s| String::from(*s)),
+            Property::WarningLevel(n) =>
+                self.warning_levels.get(*n as usize).map(|s| String::from(*s)),
+            Property::BatteryLevel(n) =>
+                self.battery_levels.get(*n as usize).map(|s| String::from(*s)),
+            Property::TimeToEmpty(t) | Property::TimeToFull(t) => Some(self.format_duration(*t)),
+            Property::Percentage(n) | Property::EnergyFull(n) | Property::EnergyFullDesign(n)
+            | Property::Capacity(n) | Property::EnergyRate(n) => Some(self.format_number(*n)),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::locale::Locale;
+    use crate::upower::Property::{
+        BatteryLevel, Online, Percentage, State, TimeToEmpty, WarningLevel
+    };
+
+    /// Test finding locales by name.
+    #[test]
+    fn find() {
+        assert_eq!(Locale::find("de").unwrap().code, "de");
+        assert_eq!(Locale::find("de_DE.UTF-8").unwrap().code, "de");
+        assert_eq!(Locale::find("fr-CA").unwrap().code, "fr");
+        assert_eq!(Locale::find("C").unwrap().code, "en");
+        assert!(Locale::find("xx_XX").is_err());
+    }
+
+    /// Test formatting of values.
+    #[test]
+    fn format() {
+        let de = Locale::find("de").unwrap();
+        assert_eq!(de.format(&Percentage(54.5)).unwrap(), "54,5");
+        assert_eq!(de.format(&State(1)).unwrap(), "Lädt");
+        assert_eq!(de.format(&WarningLevel(3)).unwrap(), "Niedrig");
+        assert_eq!(de.format(&BatteryLevel(8)).unwrap(), "Voll");
+        assert_eq!(de.format(&TimeToEmpty(7500)).unwrap(), "2 Stunden 5 Minuten");
+        assert_eq!(de.format(&TimeToEmpty(3600)).unwrap(), "1 Stunde");
+        assert_eq!(de.format(&TimeToEmpty(0)).unwrap(), "0 Sekunden");
+        assert_eq!(de.format(&Online(true)), None);
+        let en = Locale::find("en").unwrap();
+        assert_eq!(en.format(&Percentage(54.5)).unwrap(), "54.5");
+        assert_eq!(en.format(&TimeToEmpty(61)).unwrap(), "1 minute");
+    }
+}
@@ -0,0 +1,105 @@
+use zbus::{
+    Connection, MatchRule, MessageStream, MessageType, Result as zbus_Result,
+    export::futures_util::TryStreamExt
+};
+use crate::output::Writer;
+use crate::upower::DeviceConfig;
+
+/// The marker written when the system resumes from sleep.
+pub(crate) const RESUMED_MARKER: &str = "Resumed";
+
+/// Build and return a `MatchRule` for logind's `PrepareForSleep` signal.
+fn prepare_for_sleep_rule() -> zbus_Result<MatchRule<'static>> {
+    Ok(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.freedesktop.login1")?
+        .interface("org.freedesktop.login1.Manager")?
+        .member("PrepareForSleep")?
+        .path("/org/freedesktop/login1")?
+        .build())
+}
+
+/// Handle the system resuming from sleep: write a [`RESUMED_MARKER`] if `mark` is true, and then
+/// write the current values of all targeted properties of the given devices if `refresh` is true.
+pub(crate) async fn handle_resume(
+    conn: &Connection,
+    paths: &[DeviceConfig],
+    writer: &impl Writer,
+    refresh: bool,
+    mark: bool
+) -> zbus_Result<()> {
+    if mark {
+        writer.write_marker(RESUMED_MARKER).await?;
+    }
+    if refresh {
+        for p in paths {
+            p.refresh(conn, writer).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Listen for the system resuming from sleep (as signalled by logind) and handle each resume as
+/// described in [`handle_resume`].
+pub(crate) async fn listen_resume(
+    conn: &Connection,
+    paths: &[DeviceConfig],
+    writer: &impl Writer,
+    refresh: bool,
+    mark: bool
+) -> zbus_Result<()> {
+    let mut stream = MessageStream::for_match_rule(
+        prepare_for_sleep_rule()?,
+        conn,
+        None
+    ).await?;
+    loop {
+        let Some(msg) = stream.try_next().await? else {
+            return Ok(())
+        };
+        // The signal's argument is true before sleeping and false after resuming. Signals which
+        // cannot be parsed are skipped.
+        let Ok(sleeping) = msg.body::<bool>() else {
+            continue
+        };
+        if !sleeping {
+            handle_resume(conn, paths, writer, refresh, mark).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::logind::{handle_resume, prepare_for_sleep_rule};
+    use crate::output::LineWriter;
+    use crate::rt::block_on;
+    use crate::testing::{MOCK_DEVICE_PATH, MockUPower, SharedBuffer};
+    use crate::upower::DeviceConfig;
+
+    /// Test creation of the `PrepareForSleep` match rule.
+    #[test]
+    fn rule() {
+        assert_eq!(
+            prepare_for_sleep_rule().unwrap().to_string(),
+            "type='signal',sender='org.freedesktop.login1',\
+             interface='org.freedesktop.login1.Manager',member='PrepareForSleep',\
+             path='/org/freedesktop/login1'"
+        );
+    }
+
+    /// Test that the marker and current property values are written on resume.
+    #[test]
+    fn resume() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let conf = DeviceConfig::from_args(&[vec!(
+                String::from(MOCK_DEVICE_PATH),
+                String::from("Percentage")
+            )], None).unwrap();
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            handle_resume(&upower.client, &conf, &writer, true, true).await.unwrap();
+            assert_eq!(buf.contents(), format!("Resumed\n{MOCK_DEVICE_PATH} Percentage=80\n"));
+        })
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::{Error, Write};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::io::AsyncWriteExt;
+use serde::{Deserialize, Serialize};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::retry::{tolerate, within, Backoff, RetryPolicy, NETWORK_TIMEOUT};
+use crate::rt::{TcpStream, UdpSocket};
+
+/// Protocols in which numeric property changes can be emitted as metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetricProtocol {
+    /// StatsD gauges (`name:value|g`).
+    Statsd,
+    /// Graphite plaintext metrics (`name value timestamp`).
+    Graphite
+}
+
+/// Transports over which metrics can be sent to a server.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    Udp,
+    Tcp
+}
+
+/// Where metrics are sent.
+enum Sink {
+    /// A file (or other struct implementing Write).
+    Output(Box<dyn Write>),
+    /// A server, over UDP.
+    Udp(UdpSocket, String),
+    /// A server, over TCP. The connection is made when the first metric is sent, and remade after
+    /// any error, including a connection or write which takes longer than [`NETWORK_TIMEOUT`]
+    /// (once the writer's [`RetryPolicy`] allows).
+    Tcp(String, Option<TcpStream>)
+}
+
+/// Replace any characters in `name` which are not safe to use in a metric name component with
+/// underscores.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// A [`Writer`] which emits changes to numeric (including boolean and enumerated) properties as
+/// StatsD gauges or Graphite plaintext metrics, named `PREFIX.DEVICE.PROPERTY` where `DEVICE` is
+/// the last element of the device path. Changes to other properties are ignored.
+pub struct MetricsWriter {
+    /// The protocol in which metrics are emitted.
+    protocol: MetricProtocol,
+    /// Where metrics are sent.
+    sink: Mutex<Sink>,
+    /// The prefix of each metric name.
+    prefix: String,
+    /// Failed attempts to connect (or send) to a server over TCP.
+    connects: Mutex<Backoff>
+}
+
+impl MetricsWriter {
+    /// Create a new [`MetricsWriter`] which writes metrics to the given output.
+    pub(crate) fn from_writer(protocol: MetricProtocol, out: Box<dyn Write>, prefix: &str) -> Self {
+        Self {
+            protocol,
+            sink: Mutex::new(Sink::Output(out)),
+            prefix: String::from(prefix),
+            connects: Mutex::new(Backoff::new(RetryPolicy::default()))
+        }
+    }
+
+    /// Create a new [`MetricsWriter`] which sends metrics to the server at `address` (`host:port`)
+    /// using the given transport.
+    pub(crate) async fn connect(
+        protocol: MetricProtocol,
+        address: &str,
+        transport: Transport,
+        prefix: &str
+    ) -> Result<Self, Error> {
+        let sink = match transport {
+            Transport::Udp => Sink::Udp(UdpSocket::bind("0.0.0.0:0").await?, String::from(address)),
+            Transport::Tcp => Sink::Tcp(String::from(address), None)
+        };
+        Ok(Self {
+            protocol,
+            sink: Mutex::new(sink),
+            prefix: String::from(prefix),
+            connects: Mutex::new(Backoff::new(RetryPolicy::default()))
+        })
+    }
+
+    /// Reconnect to a server over TCP as set out by `policy`, rather than the default
+    /// [`RetryPolicy`].
+    pub(crate) fn with_retry(self, policy: RetryPolicy) -> Self {
+        Self { connects: Mutex::new(Backoff::new(policy)), ..self }
+    }
+
+    /// Format a single metric with the given name (excluding the prefix) and value.
+    fn format(&self, name: &str, value: f64, kind: &str, timestamp: i64) -> String {
+        match self.protocol {
+            MetricProtocol::Statsd => format!("{}.{name}:{value}|{kind}\n", self.prefix),
+            MetricProtocol::Graphite => format!("{}.{name} {value} {timestamp}\n", self.prefix)
+        }
+    }
+
+    /// Send the given formatted metrics. Failures to send them to a server are logged rather than
+    /// returned, as the server is retried.
+    async fn send(&self, metrics: &str) -> Result<(), Error> {
+        let mut sink = self.sink.lock().await;
+        match &mut *sink {
+            Sink::Output(out) => out.write_all(metrics.as_bytes()),
+            Sink::Udp(socket, address) => tolerate(
+                address,
+                socket.send_to(metrics.as_bytes(), &*address).await.map(|_| ())
+            ),
+            Sink::Tcp(address, conn) => tolerate(address, async {
+                let mut connects = self.connects.lock().await;
+                if conn.is_none() {
+                    connects.ready().map_err(|e| {
+                        Error::other(format!("Not connecting to {address}: {e}"))
+                    })?;
+                    match within(NETWORK_TIMEOUT, TcpStream::connect(&*address)).await {
+                        Ok(stream) => *conn = Some(stream),
+                        Err(e) => {
+                            connects.failed();
+                            return Err(e)
+                        }
+                    }
+                }
+                let stream = conn.as_mut().unwrap();
+                let result = within(NETWORK_TIMEOUT, stream.write_all(metrics.as_bytes())).await;
+                match result {
+                    Ok(()) => connects.succeeded(),
+                    Err(_) => {
+                        *conn = None;
+                        connects.failed()
+                    }
+                }
+                result
+            }.await)
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for MetricsWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        let device = sanitize(event.device.rsplit('/').next().unwrap_or(&event.device));
+        let timestamp = event.timestamp.timestamp();
+        let mut metrics = event.iter()
+            .filter_map(|(k, v)| v.as_f64().map(|n| {
+                self.format(&format!("{device}.{}", sanitize(k.as_str())), n, "g", timestamp)
+            }))
+            .collect::<Vec<_>>();
+        if metrics.is_empty() {
+            return Ok(())
+        }
+        metrics.sort();
+        self.send(&metrics.concat()).await
+    }
+
+    /// Emit the marker as a count of one for a metric named `PREFIX.events.MARKER`, where `MARKER`
+    /// is the first word of the marker.
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        let name = sanitize(marker.split(' ').next().unwrap_or(marker));
+        let metric = self.format(&format!("events.{name}"), 1.0, "c", Utc::now().timestamp());
+        self.send(&metric).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::event::DeviceEvent;
+    use crate::metrics::{MetricProtocol, MetricsWriter, sanitize, Transport};
+    use crate::output::Writer;
+    use crate::queue::{Overflow, QueueWriter};
+    use crate::rt::{block_on, TcpListener, UdpSocket};
+    use crate::stats::{get, Counter};
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Percentage, State, TimeToEmpty, UpdateTime};
+    use crate::upower::PropertyKind;
+
+    /// Return some changes to write.
+    fn changes() -> HashMap<PropertyKind, crate::upower::Property> {
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(80.5));
+        changes.insert(PropertyKind::State, State(2));
+        changes.insert(PropertyKind::TimeToEmpty, TimeToEmpty(3600));
+        changes
+    }
+
+    /// Test sanitization of metric name components.
+    #[test]
+    fn sanitization() {
+        assert_eq!(sanitize("battery_BAT0"), "battery_BAT0");
+        assert_eq!(sanitize("dev_00:11:22.33 x"), "dev_00_11_22_33_x");
+    }
+
+    /// Test emission of StatsD gauges.
+    #[test]
+    fn statsd() {
+        let buf = SharedBuffer::default();
+        let writer = MetricsWriter::from_writer(MetricProtocol::Statsd, Box::new(buf.clone()), "ups");
+        let event = DeviceEvent::new("/org/freedesktop/UPower/devices/battery_BAT0", changes());
+        block_on(writer.write(&event)).unwrap();
+        block_on(writer.write_marker("CriticalAction PowerOff")).unwrap();
+        assert_eq!(
+            buf.contents(),
+            "ups.battery_BAT0.Percentage:80.5|g\n\
+             ups.battery_BAT0.State:2|g\n\
+             ups.battery_BAT0.TimeToEmpty:3600|g\n\
+             ups.events.CriticalAction:1|c\n"
+        );
+    }
+
+    /// Test emission of Graphite plaintext metrics.
+    #[test]
+    fn graphite() {
+        let buf = SharedBuffer::default();
+        let writer = MetricsWriter::from_writer(
+            MetricProtocol::Graphite,
+            Box::new(buf.clone()),
+            "ups"
+        );
+        let mut changes = changes();
+        changes.insert(PropertyKind::UpdateTime, UpdateTime(1707671976));
+        block_on(writer.write(&DeviceEvent::new("/dev/battery", changes))).unwrap();
+        let lines = buf.contents().lines()
+            .map(|l| l.rsplit_once(' ').unwrap().0.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!(
+            "ups.battery.Percentage 80.5",
+            "ups.battery.State 2",
+            "ups.battery.TimeToEmpty 3600",
+            "ups.battery.UpdateTime 1707671976"
+        ));
+    }
+
+    /// Test sending metrics over UDP.
+    #[test]
+    fn udp() {
+        block_on(async {
+            let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = server.local_addr().unwrap().to_string();
+            let writer = MetricsWriter::connect(
+                MetricProtocol::Statsd,
+                &address,
+                Transport::Udp,
+                "ups"
+            ).await.unwrap();
+            writer.write_marker("Resumed").await.unwrap();
+            let mut buf = [0; 64];
+            let (n, _) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ups.events.Resumed:1|c\n");
+        })
+    }
+
+    /// Test that a server which refuses connections is counted as an error, rather than stopping
+    /// queued changes being written.
+    #[test]
+    fn refused() {
+        block_on(async {
+            // Nothing listens on the address once the listener is dropped.
+            let address = TcpListener::bind("127.0.0.1:0").await.unwrap()
+                .local_addr().unwrap()
+                .to_string();
+            let writer = MetricsWriter::connect(
+                MetricProtocol::Graphite,
+                &address,
+                Transport::Tcp,
+                "ups"
+            ).await.unwrap();
+            let queue = QueueWriter::new(writer, 10, Overflow::Block);
+            let errors = get(Counter::WriteErrors);
+            queue.write(&DeviceEvent::new("/dev/battery", changes())).await.unwrap();
+            queue.write_marker("Resumed").await.unwrap();
+            queue.close();
+            assert!(queue.run().await.is_ok());
+            assert!(get(Counter::WriteErrors) >= errors + 2);
+        })
+    }
+}
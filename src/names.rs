@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::Serialize;
+use zbus::Connection;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::{split_bus, DeviceConfig};
+
+/// How devices are named in output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceName {
+    /// The device's DBus object path.
+    Path,
+    /// The device's NativePath property (such as BAT0).
+    Native,
+    /// The device's Model property.
+    Model,
+    /// The device's Serial property.
+    Serial,
+    /// The last element of the device's object path (such as battery_BAT0), which can also be
+    /// given to --path.
+    Alias
+}
+
+impl DeviceName {
+    /// The property of the device which gives its name, if it is named by a property.
+    pub(crate) fn property(&self) -> Option<&'static str> {
+        match self {
+            DeviceName::Native => Some("NativePath"),
+            DeviceName::Model => Some("Model"),
+            DeviceName::Serial => Some("Serial"),
+            DeviceName::Path | DeviceName::Alias => None
+        }
+    }
+}
+
+/// The names by which devices are identified in output, which are resolved from the devices'
+/// properties at startup. Devices whose names have not been resolved are identified by their
+/// paths.
+#[derive(Debug)]
+pub struct DeviceNames {
+    /// How devices are named.
+    name: DeviceName,
+    /// The resolved name of each device, by path.
+    names: Mutex<HashMap<String, String>>
+}
+
+impl DeviceNames {
+    /// Create a new [`DeviceNames`] which names devices as given by `name`.
+    pub(crate) fn new(name: DeviceName) -> Self {
+        Self { name, names: Mutex::new(HashMap::new()) }
+    }
+
+    /// Resolve the name of each of the given devices from its properties, if devices are named by
+    /// a property. A warning is written for each device whose name cannot be resolved, or which
+    /// does not have the property.
+    pub(crate) async fn resolve(&self, conn: &Connection, devices: &[DeviceConfig]) {
+        let Some(property) = self.name.property() else {
+            return
+        };
+        for device in devices {
+            let name = match device.fetch_properties(conn).await {
+                Ok(properties) => properties.and_then(|p| match p.get(property).map(|v| &**v) {
+                    Some(Value::Str(s)) if !s.is_empty() => Some(s.to_string()),
+                    _ => None
+                }),
+                Err(e) => {
+                    eprintln!("Could not resolve the name of {}: {e}", device.path());
+                    continue
+                }
+            };
+            match name {
+                Some(name) => {
+                    self.names.lock().await.insert(device.device(), name);
+                },
+                None => eprintln!(
+                    "Warning: {} has no {property}, so it will be named by its path",
+                    device.path()
+                )
+            }
+        }
+    }
+
+    /// Return the name of the device at `path`, if it is not named by its path.
+    async fn get(&self, path: &str) -> Option<String> {
+        match self.name {
+            DeviceName::Path => None,
+            DeviceName::Alias => {
+                let (bus, path) = split_bus(path);
+                let name = path.rsplit_once('/').map(|(_, name)| name).filter(|n| !n.is_empty())?;
+                Some(bus.map_or_else(|| String::from(name), |b| format!("{b}@{name}")))
+            },
+            _ => self.names.lock().await.get(path).cloned()
+        }
+    }
+}
+
+/// A [`Writer`] which replaces the path of the device in each event with the device's name before
+/// passing it on to an inner [`Writer`]. This is used for output, after any writers which keep
+/// state for each device, so that state is still kept by path.
+pub struct DeviceNameWriter<'a, W: Writer> {
+    /// The writer to which renamed events are passed.
+    inner: W,
+    /// The names of the devices.
+    names: &'a DeviceNames
+}
+
+impl<'a, W: Writer> DeviceNameWriter<'a, W> {
+    /// Create a new [`DeviceNameWriter`] which names devices according to `names`.
+    pub(crate) fn new(inner: W, names: &'a DeviceNames) -> Self {
+        Self { inner, names }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for DeviceNameWriter<'_, W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        match self.names.get(&event.device).await {
+            Some(name) => {
+                let mut renamed = event.clone();
+                renamed.device = name;
+                self.inner.write(&renamed).await
+            },
+            None => self.inner.write(event).await
+        }
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::event::DeviceEvent;
+    use crate::names::{DeviceName, DeviceNames, DeviceNameWriter};
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::{MockUPower, SharedBuffer, MOCK_DEVICE_PATH};
+    use crate::upower::DeviceConfig;
+    use crate::upower::Property::Percentage;
+    use crate::upower::PropertyKind;
+
+    /// Test that devices are named by the chosen property once resolved, and by their paths
+    /// otherwise.
+    #[test]
+    fn device_names() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let device = DeviceConfig::new(MOCK_DEVICE_PATH, "Percentage", None).unwrap();
+            let names = DeviceNames::new(DeviceName::Native);
+            let buf = SharedBuffer::default();
+            let writer = DeviceNameWriter::new(
+                LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false),
+                &names
+            );
+            let event = |path| {
+                DeviceEvent::new(path, [(PropertyKind::Percentage, Percentage(50.0))])
+            };
+            writer.write(&event(MOCK_DEVICE_PATH)).await.unwrap();
+            names.resolve(&upower.client, &[device]).await;
+            writer.write(&event(MOCK_DEVICE_PATH)).await.unwrap();
+            writer.write(&event("/other")).await.unwrap();
+            assert_eq!(buf.contents(), format!(
+                "{MOCK_DEVICE_PATH} Percentage=50\nMOCK Percentage=50\n/other Percentage=50\n"
+            ));
+            let alias = DeviceNames::new(DeviceName::Alias);
+            assert_eq!(alias.get(MOCK_DEVICE_PATH).await.as_deref(), Some("battery_MOCK"));
+            assert_eq!(
+                alias.get(&format!("container@{MOCK_DEVICE_PATH}")).await.as_deref(),
+                Some("container@battery_MOCK")
+            );
+        });
+    }
+}
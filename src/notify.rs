@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use async_lock::Mutex;
+use zbus::{Connection, Result as zbus_Result};
+use zbus::zvariant::Value;
+use crate::connect::with_method_timeout;
+
+/// The bus name, object path and interface of the desktop notification service.
+const NOTIFICATIONS_SERVICE: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+const NOTIFICATIONS_INTERFACE: &str = "org.freedesktop.Notifications";
+
+/// The icon shown with notifications.
+const NOTIFICATION_ICON: &str = "battery-caution";
+
+/// A notification which has been shown for an alert rule and device.
+#[derive(Clone, Copy, Debug)]
+struct Notification {
+    /// The ID given to the notification by the notification service.
+    id: u32,
+    /// Whether the notification may still be shown.
+    open: bool
+}
+
+/// Shows desktop notifications over the session bus. Each alert rule and device has at most one
+/// notification: showing another replaces it, rather than adding another popup.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    /// The connection to the session bus, once it has been made.
+    conn: Mutex<Option<Connection>>,
+    /// The latest notification for each alert rule (by index) and device.
+    notifications: Mutex<HashMap<(usize, String), Notification>>
+}
+
+impl Notifier {
+    /// Create a new [`Notifier`] which sends notifications over `conn`, rather than connecting to
+    /// the session bus.
+    #[cfg(test)]
+    pub(crate) fn from_connection(conn: Connection) -> Self {
+        Self { conn: Mutex::new(Some(conn)), ..Self::default() }
+    }
+
+    /// Return the connection to the session bus, connecting if this has not yet been done.
+    async fn connection(&self) -> zbus_Result<Connection> {
+        let mut conn = self.conn.lock().await;
+        if let Some(c) = &*conn {
+            return Ok(c.clone())
+        }
+        Ok(conn.insert(Connection::session().await?).clone())
+    }
+
+    /// Show a notification for the alert rule at index `rule` firing for `device`, replacing the
+    /// previous notification for the same rule and device if there is one.
+    pub(crate) async fn show(&self, rule: usize, device: &str, summary: &str, body: &str)
+        -> zbus_Result<()> {
+        let conn = self.connection().await?;
+        let key = (rule, String::from(device));
+        let replaces = self.notifications.lock().await.get(&key).map(|n| n.id).unwrap_or(0);
+        let id: u32 = with_method_timeout("Notify", conn.call_method(
+            Some(NOTIFICATIONS_SERVICE),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_INTERFACE),
+            "Notify",
+            &(
+                "upmon",
+                replaces,
+                NOTIFICATION_ICON,
+                summary,
+                body,
+                Vec::<&str>::new(),
+                HashMap::<&str, Value>::new(),
+                // Let the notification service decide when the notification expires.
+                -1i32
+            )
+        )).await?.body()?;
+        self.notifications.lock().await.insert(key, Notification { id, open: true });
+        Ok(())
+    }
+
+    /// Close the notification for the alert rule at index `rule` and `device`, if it may still be
+    /// shown.
+    pub(crate) async fn close(&self, rule: usize, device: &str) -> zbus_Result<()> {
+        let id = {
+            let mut notifications = self.notifications.lock().await;
+            match notifications.get_mut(&(rule, String::from(device))) {
+                Some(n) if n.open => {
+                    n.open = false;
+                    n.id
+                },
+                _ => return Ok(())
+            }
+        };
+        with_method_timeout("CloseNotification", self.connection().await?.call_method(
+            Some(NOTIFICATIONS_SERVICE),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_INTERFACE),
+            "CloseNotification",
+            &(id,)
+        )).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use futures::try_join;
+    use zbus::{dbus_interface, ConnectionBuilder, Guid};
+    use zbus::zvariant::Value;
+    use crate::notify::{Notifier, NOTIFICATIONS_PATH};
+    use crate::rt::{block_on, bus_stream_pair};
+
+    /// A mock notification service, which records the notifications shown.
+    #[derive(Debug, Default)]
+    struct MockNotifications {
+        /// The ID which will be given to the next new notification.
+        next_id: u32,
+        /// The summary of each notification being shown, by ID.
+        shown: HashMap<u32, String>
+    }
+
+    #[dbus_interface(name = "org.freedesktop.Notifications")]
+    impl MockNotifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &mut self,
+            _app_name: &str,
+            replaces_id: u32,
+            _app_icon: &str,
+            summary: &str,
+            _body: &str,
+            _actions: Vec<&str>,
+            _hints: HashMap<&str, Value<'_>>,
+            _expire_timeout: i32
+        ) -> u32 {
+            let id = if replaces_id == 0 {
+                self.next_id += 1;
+                self.next_id
+            } else {
+                replaces_id
+            };
+            self.shown.insert(id, String::from(summary));
+            id
+        }
+
+        fn close_notification(&mut self, id: u32) {
+            self.shown.remove(&id);
+        }
+    }
+
+    /// Test that notifications for the same rule and device replace each other, and that they are
+    /// only closed while open.
+    #[test]
+    fn notifier() {
+        block_on(async {
+            let (server_stream, client_stream) = bus_stream_pair().unwrap();
+            let guid = Guid::generate();
+            let server = ConnectionBuilder::unix_stream(server_stream)
+                .server(&guid)
+                .p2p()
+                .serve_at(NOTIFICATIONS_PATH, MockNotifications::default()).unwrap()
+                .build();
+            let client = ConnectionBuilder::unix_stream(client_stream).p2p().build();
+            let (server, client) = try_join!(server, client).unwrap();
+            let notifier = Notifier::from_connection(client);
+            notifier.show(0, "/bat0", "Percentage<=15", "/bat0").await.unwrap();
+            notifier.show(0, "/bat0", "Percentage<=15", "/bat0").await.unwrap();
+            notifier.show(0, "/bat1", "Percentage<=15", "/bat1").await.unwrap();
+            notifier.show(1, "/bat0", "Percentage<=5", "/bat0").await.unwrap();
+            notifier.close(0, "/bat0").await.unwrap();
+            notifier.close(0, "/bat0").await.unwrap();
+            notifier.close(2, "/bat0").await.unwrap();
+            let iface = server.object_server()
+                .interface::<_, MockNotifications>(NOTIFICATIONS_PATH)
+                .await
+                .unwrap();
+            let mock = iface.get().await;
+            assert_eq!(mock.next_id, 3);
+            let mut shown = mock.shown.iter().collect::<Vec<_>>();
+            shown.sort();
+            assert_eq!(shown, vec!(
+                (&2, &String::from("Percentage<=15")),
+                (&3, &String::from("Percentage<=5"))
+            ));
+        });
+    }
+}
@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::Property;
+
+/// A [`Writer`] which replaces the values of enumerated properties (such as `State`) with their
+/// raw numeric values before passing changes on to an inner [`Writer`], so that every output
+/// format writes numbers rather than names.
+pub struct NumericEnumWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// Whether enumerated values should be replaced.
+    enabled: bool
+}
+
+impl<W: Writer> NumericEnumWriter<W> {
+    /// Create a new [`NumericEnumWriter`] which passes changes to `inner`, replacing enumerated
+    /// values if `enabled` is true.
+    pub(crate) fn new(inner: W, enabled: bool) -> Self {
+        Self { inner, enabled }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for NumericEnumWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        if !self.enabled {
+            return self.inner.write(event).await
+        }
+        let numeric = event.with_changes(event.iter()
+            .map(|(k, v)| (k.clone(), match v {
+                Property::State(n) | Property::WarningLevel(n) | Property::BatteryLevel(n) =>
+                    Property::Other(Value::from(*n).into()),
+                _ => v.clone()
+            })));
+        self.inner.write(&numeric).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::event::DeviceEvent;
+    use crate::http::HttpWriter;
+    use crate::numeric::NumericEnumWriter;
+    use crate::output::{LineWriter, TeeWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{BatteryLevel, Percentage, State, WarningLevel};
+    use crate::upower::PropertyKind;
+
+    /// Test that enumerated values are written as numbers in line and JSON output, and that other
+    /// values are unaffected.
+    #[test]
+    fn numeric_enums() {
+        let buf = SharedBuffer::default();
+        let line = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let http = HttpWriter::default();
+        let writer = NumericEnumWriter::new(TeeWriter::new(line, &http), true);
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::State, State(2));
+        changes.insert(PropertyKind::WarningLevel, WarningLevel(3));
+        changes.insert(PropertyKind::BatteryLevel, BatteryLevel(7));
+        changes.insert(PropertyKind::Percentage, Percentage(15.0));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        let mut fields = buf.contents().trim_end().split(' ').map(String::from).collect::<Vec<_>>();
+        fields.sort();
+        assert_eq!(
+            fields,
+            vec!("/dev", "BatteryLevel=7", "Percentage=15", "State=2", "WarningLevel=3")
+        );
+        let state = block_on(http.state());
+        assert_eq!(state["/dev"]["State"], serde_json::json!(2));
+        assert_eq!(state["/dev"]["Percentage"], serde_json::json!(15.0));
+
+        let buf = SharedBuffer::default();
+        let line = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = NumericEnumWriter::new(line, false);
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::State, State(2));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        assert_eq!(buf.contents(), "/dev State=Discharging\n");
+    }
+}
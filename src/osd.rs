@@ -0,0 +1,97 @@
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use crate::event::DeviceEvent;
+use crate::fifo::Fifo;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+
+/// A [`Writer`] which writes each new `Percentage` (rounded to an integer) as a line to a FIFO, in
+/// the format read by on-screen display programs such as xob and wob. Values are dropped if no
+/// program is reading from the FIFO, so that monitoring is never blocked. Other changes, and
+/// markers, are ignored.
+pub struct OsdWriter {
+    /// The FIFO.
+    fifo: Mutex<Fifo>
+}
+
+impl OsdWriter {
+    /// Create a new [`OsdWriter`] which writes to the FIFO at `path`, creating it if it does not
+    /// exist.
+    pub(crate) fn new(path: &str) -> Result<Self, Error> {
+        match Path::new(path).metadata() {
+            Ok(m) if !m.file_type().is_fifo() => {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Not a FIFO: {path}")))
+            },
+            Ok(_) => {},
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                mkfifo(path, Mode::from_bits_truncate(0o600))?;
+            },
+            Err(e) => return Err(e)
+        }
+        Ok(Self { fifo: Mutex::new(Fifo::new(path)) })
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for OsdWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        if let Some(Property::Percentage(p)) = event.get(&PropertyKind::Percentage) {
+            writeln!(self.fifo.lock().await, "{}", p.round() as i64)?;
+        }
+        Ok(())
+    }
+
+    async fn write_marker(&self, _marker: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::fs::{OpenOptions, remove_file};
+    use std::io::Read;
+    use std::os::unix::fs::OpenOptionsExt;
+    use crate::event::DeviceEvent;
+    use crate::osd::OsdWriter;
+    use crate::output::Writer;
+    use crate::rt::block_on;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that percentages are written to the FIFO when it is being read, and dropped otherwise.
+    #[test]
+    fn osd_writer() {
+        let path = std::env::temp_dir().join(format!("upmon-osd-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let writer = OsdWriter::new(path).unwrap();
+        let write = |p| {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(p));
+            changes.insert(PropertyKind::State, State(2));
+            block_on(writer.write(&DeviceEvent::new("/dev/battery", changes))).unwrap();
+        };
+        // There is no reader yet, so this is dropped.
+        write(10.0);
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NONBLOCK)
+            .open(path)
+            .unwrap();
+        write(79.6);
+        write(79.0);
+        let mut buf = [0; 16];
+        let n = reader.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"80\n79\n");
+        drop(reader);
+        // The reader has gone away, so this is dropped.
+        write(50.0);
+        remove_file(path).unwrap();
+        assert!(OsdWriter::new("/dev/null").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::io::Error;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use clap::crate_version;
+use futures::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use serde_json::{json, Value};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::retry::{tolerate, within, Backoff, RetryPolicy, NETWORK_TIMEOUT};
+use crate::rt::TcpStream;
+use crate::upower::PropertyKind;
+
+/// The properties exported as gauges, with the name and unit of the corresponding metric.
+const GAUGES: [(PropertyKind, &str, &str); 4] = [
+    (PropertyKind::Percentage, "upower.device.percentage", "%"),
+    (PropertyKind::EnergyRate, "upower.device.energy_rate", "W"),
+    (PropertyKind::TimeToEmpty, "upower.device.time_to_empty", "s"),
+    (PropertyKind::TimeToFull, "upower.device.time_to_full", "s")
+];
+
+/// The longest status line accepted from the collector.
+const MAX_STATUS_LINE_LENGTH: u64 = 8 * 1024;
+
+/// Build an OTLP attribute with a string value.
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// A [`Writer`] which exports changes to the percentage, energy rate and time estimates of each
+/// device as OpenTelemetry gauges, pushed to a collector using OTLP over HTTP (with JSON encoding).
+/// Changes to other properties, and markers, are ignored.
+pub struct OtelWriter {
+    /// The `host:port` of the collector.
+    address: String,
+    /// The path to which metrics are posted.
+    path: String,
+    /// Failed attempts to post metrics to the collector.
+    connects: Mutex<Backoff>
+}
+
+impl OtelWriter {
+    /// Create a new [`OtelWriter`] which pushes metrics to the collector at `endpoint`, which
+    /// should be of the form `http://host:port`. Metrics are posted to the `/v1/metrics` path under
+    /// the endpoint.
+    pub(crate) fn new(endpoint: &str) -> Result<Self, String> {
+        let rest = endpoint.strip_prefix("http://")
+            .ok_or_else(|| format!("Only http:// OTLP endpoints are supported: {endpoint}"))?;
+        let (address, base) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "")
+        };
+        if address.is_empty() {
+            return Err(format!("Expected host in OTLP endpoint: {endpoint}"))
+        }
+        Ok(Self {
+            address: String::from(address),
+            path: format!("{}/v1/metrics", base.trim_end_matches('/')),
+            connects: Mutex::new(Backoff::new(RetryPolicy::default()))
+        })
+    }
+
+    /// Put off posting metrics to the collector after a failure as set out by `policy`, rather
+    /// than the default [`RetryPolicy`].
+    pub(crate) fn with_retry(self, policy: RetryPolicy) -> Self {
+        Self { connects: Mutex::new(Backoff::new(policy)), ..self }
+    }
+
+    /// Build an OTLP `ExportMetricsServiceRequest` for the given changes, or return `None` if none
+    /// of the changes are exported.
+    fn request(&self, event: &DeviceEvent) -> Option<Value> {
+        let time = event.timestamp.timestamp_nanos_opt().unwrap_or_default().to_string();
+        let device_path = event.device.as_str();
+        let device = device_path.rsplit('/').next().unwrap_or(device_path);
+        let metrics = GAUGES.iter()
+            .filter_map(|(property, name, unit)| {
+                let value = event.get(property)?.as_f64()?;
+                Some(json!({
+                    "name": name,
+                    "unit": unit,
+                    "gauge": {
+                        "dataPoints": [{
+                            "asDouble": value,
+                            "timeUnixNano": time,
+                            "attributes": [
+                                attribute("device.path", device_path),
+                                attribute("device.name", device)
+                            ]
+                        }]
+                    }
+                }))
+            })
+            .collect::<Vec<_>>();
+        if metrics.is_empty() {
+            return None
+        }
+        Some(json!({
+            "resourceMetrics": [{
+                "resource": { "attributes": [attribute("service.name", "upmon")] },
+                "scopeMetrics": [{
+                    "scope": { "name": "upmon", "version": crate_version!() },
+                    "metrics": metrics
+                }]
+            }]
+        }))
+    }
+
+    /// Post the given request to the collector.
+    async fn post(&self, request: &Value) -> Result<(), Error> {
+        let body = request.to_string();
+        let mut connects = self.connects.lock().await;
+        connects.ready()
+            .map_err(|e| Error::other(format!("Not connecting to OTLP collector: {e}")))?;
+        let status_line = within(NETWORK_TIMEOUT, async {
+            let mut stream = TcpStream::connect(&self.address).await?;
+            stream.write_all(format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                self.path,
+                self.address,
+                body.len()
+            ).as_bytes()).await?;
+            // Only the status line is needed, so the rest of the response is not waited for.
+            let mut status_line = String::new();
+            BufReader::new(stream.take(MAX_STATUS_LINE_LENGTH))
+                .read_line(&mut status_line)
+                .await?;
+            Ok(status_line)
+        }).await;
+        match status_line {
+            Ok(_) => connects.succeeded(),
+            Err(_) => connects.failed()
+        }
+        let status_line = status_line?;
+        let status_line = status_line.trim_end();
+        let status = status_line.split(' ').nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            return Err(Error::other(format!("OTLP collector rejected metrics: {status_line}")))
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for OtelWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        match self.request(event) {
+            Some(request) => tolerate("OTLP collector", self.post(&request).await),
+            None => Ok(())
+        }
+    }
+
+    async fn write_marker(&self, _marker: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::future::join;
+    use crate::event::DeviceEvent;
+    use crate::otel::OtelWriter;
+    use crate::rt::{block_on, timeout, TcpListener};
+    use crate::upower::Property::{Percentage, State, TimeToEmpty};
+    use crate::upower::PropertyKind;
+
+    /// Test parsing of collector endpoints.
+    #[test]
+    fn endpoints() {
+        let writer = OtelWriter::new("http://localhost:4318").unwrap();
+        assert_eq!(writer.address, "localhost:4318");
+        assert_eq!(writer.path, "/v1/metrics");
+        let writer = OtelWriter::new("http://collector:4318/otlp/").unwrap();
+        assert_eq!(writer.path, "/otlp/v1/metrics");
+        assert!(OtelWriter::new("https://localhost:4318").is_err());
+        assert!(OtelWriter::new("http:///v1").is_err());
+    }
+
+    /// Test building of export requests.
+    #[test]
+    fn request() {
+        let writer = OtelWriter::new("http://localhost:4318").unwrap();
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::State, State(2));
+        assert!(writer.request(&DeviceEvent::new("/dev/battery", changes.clone())).is_none());
+        changes.insert(PropertyKind::Percentage, Percentage(80.0));
+        changes.insert(PropertyKind::TimeToEmpty, TimeToEmpty(3600));
+        let request = writer.request(&DeviceEvent::new("/dev/battery", changes.clone())).unwrap();
+        let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics.as_array().unwrap().len(), 2);
+        assert_eq!(metrics[0]["name"], "upower.device.percentage");
+        assert_eq!(metrics[0]["unit"], "%");
+        let point = &metrics[0]["gauge"]["dataPoints"][0];
+        assert_eq!(point["asDouble"], 80.0);
+        assert_eq!(point["attributes"][1]["value"]["stringValue"], "battery");
+        assert_eq!(metrics[1]["name"], "upower.device.time_to_empty");
+        assert_eq!(metrics[1]["gauge"]["dataPoints"][0]["asDouble"], 3600.0);
+    }
+
+    /// Test pushing metrics to a collector, which need not close the connection after replying,
+    /// and that errors reported by the collector are returned.
+    #[test]
+    fn push() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let writer = OtelWriter::new(&endpoint).unwrap();
+            let listener = &listener;
+            let collector = |status: &'static str| async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec!(0; 4096);
+                let n = stream.read(&mut buf).await.unwrap();
+                let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+                (String::from_utf8_lossy(&buf[..n]).into_owned(), stream)
+            };
+            let changes = vec!((PropertyKind::Percentage, Percentage(80.0)));
+            let request = writer.request(&DeviceEvent::new("/dev/battery", changes)).unwrap();
+            let pushed = join(collector("200 OK"), writer.post(&request));
+            let ((sent, _stream), result) = timeout(Duration::from_secs(5), pushed).await.unwrap();
+            result.unwrap();
+            assert!(sent.starts_with("POST /v1/metrics HTTP/1.1\r\n"));
+            assert!(sent.contains("\"upower.device.percentage\""));
+            let (_, result) = join(collector("400 Bad Request"), writer.post(&request)).await;
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "OTLP collector rejected metrics: HTTP/1.1 400 Bad Request"
+            );
+        })
+    }
+}
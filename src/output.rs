@@ -1,79 +1,422 @@
-use std::collections::HashMap;
+use std::fmt::Write as _;
 use std::fs::OpenOptions;
 use std::io::{stdout, Write};
-use async_std::sync::Mutex;
-use chrono::{SecondsFormat, Utc};
-use crate::upower::Property;
+use std::os::unix::fs::OpenOptionsExt;
+use std::str::FromStr;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+#[cfg(feature = "compress")]
+use crate::compress::Compression;
+use crate::event::{downgrade, DeviceEvent, SCHEMA_VERSION};
+use crate::fifo::{is_fifo, Fifo};
+use crate::http::{change_event, marker_event};
+use crate::locale::Locale;
+use crate::metrics::MetricsWriter;
+#[cfg(feature = "otel")]
+use crate::otel::OtelWriter;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::SqliteWriter;
+#[cfg(feature = "tui")]
+use crate::tui::TuiWriter;
+use crate::upower::{format_update_time, Property, UpdateTimeFormat};
+use crate::zabbix::ZabbixWriter;
 
-/// A trait for writing changed properties in some way.
-pub(crate) trait Writer {
-    /// Write the given changes.
-    async fn write(&self, device_path: &str, changes: &HashMap<&str, Property>)
-        -> Result<(), std::io::Error>;
+/// A trait for writing changed properties in some way. The trait is object-safe, so writers can
+/// be selected at runtime (see [`crate::registry::WriterRegistry`]) and used as
+/// `Box<dyn Writer>`.
+#[async_trait(?Send)]
+pub trait Writer {
+    /// Write the changes described by the given event.
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error>;
+
+    /// Write a marker indicating that some event not relating to a specific device (such as the
+    /// system resuming from sleep) has occurred.
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error>;
+}
+
+/// The permissions with which the output file is created, given in octal (such as `600`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileMode(pub u32);
+
+impl FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(Self(mode)),
+            _ => Err(format!("Expected permissions in octal, such as 600: {s}"))
+        }
+    }
 }
 
+impl Serialize for FileMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+/// Options given on the command line for how the output file is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileOptions {
+    /// The compression applied to the file, if any.
+    #[cfg(feature = "compress")]
+    pub compress: Option<Compression>,
+    /// Whether the file is truncated when it is opened, rather than appended to.
+    pub truncate: bool,
+    /// The permissions with which the file is created if it does not exist (subject to the umask).
+    /// If not given, the file is created with mode 666 less the umask.
+    pub mode: Option<FileMode>
+}
+
+/// Open the file at `out_path` for appending (creating it if it does not exist) and writing as
+/// given by `options`, or return standard output if no path is given. FIFOs are written to with
+/// [`Fifo`].
+pub(crate) fn open_output(out_path: Option<&str>, options: &FileOptions)
+    -> Result<Box<dyn Write>, std::io::Error> {
+    let Some(p) = out_path else {
+        return Ok(Box::new(stdout()))
+    };
+    let mut open = OpenOptions::new();
+    open.create(true);
+    if options.truncate {
+        open.write(true).truncate(true);
+    } else {
+        open.append(true);
+    }
+    if let Some(FileMode(mode)) = options.mode {
+        open.mode(mode);
+    }
+    // Writing to a FIFO (such as a status bar's named pipe) must not block or fail while it has no
+    // reader, so lines are dropped until a reader returns.
+    let out: Box<dyn Write + Send> = match is_fifo(p) {
+        true => Box::new(Fifo::new(p)),
+        false => Box::new(open.open(p)?)
+    };
+    #[cfg(feature = "compress")]
+    if let Some(c) = options.compress {
+        return c.wrap(out)
+    }
+    Ok(out)
+}
+
+/// The format of timestamps written by [`LineWriter`] (RFC 3339 in UTC, with milliseconds).
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
+
 /// A [`Writer`] that outputs details of all changed properties on a single line, per DBus message
 /// per device.
 pub struct LineWriter {
-    /// File (or other struct implementing Write) to write to.
-    out: Mutex<Box<dyn Write>>,
+    /// File (or other struct implementing Write) to write to, and the buffer in which each line is
+    /// built.
+    out: Mutex<LineOutput>,
     /// String used to separate each property name from its value in the output.
     separator: String,
     /// String used to separate property-value pairs in the output.
     delimiter: String,
     /// Whether to include a timestamp in the output.
-    timestamp: bool
+    timestamp: bool,
+    /// The format in which to render `UpdateTime`.
+    update_time: UpdateTimeFormat,
+    /// The locale in which to format values, if any.
+    locale: Option<&'static Locale>
 }
 
 impl LineWriter {
     /// Create a new [`LineWriter`] with the given configuration.
+    #[cfg(test)]
     pub(crate) fn new(
         out_path: Option<&str>,
         separator: &str,
         delimiter: &str,
         timestamp: bool
     ) -> Result<Self, std::io::Error> {
-        let out: Box<dyn Write> = match out_path {
-            Some(p) => Box::new(OpenOptions::new().create(true).append(true).open(p)?),
-            None => Box::new(stdout())
-        };
-        Ok(Self {
-            out: Mutex::new(out),
+        let out = open_output(out_path, &FileOptions::default())?;
+        Ok(Self::from_writer(out, separator, delimiter, timestamp))
+    }
+
+    /// Create a new [`LineWriter`] which writes to the given output.
+    pub(crate) fn from_writer(
+        out: Box<dyn Write>,
+        separator: &str,
+        delimiter: &str,
+        timestamp: bool
+    ) -> Self {
+        Self {
+            out: Mutex::new(LineOutput { out, line: String::new() }),
             separator: String::from(separator),
             delimiter: String::from(delimiter),
-            timestamp
-        })
+            timestamp,
+            update_time: UpdateTimeFormat::Utc,
+            locale: None
+        }
     }
+
+    /// Render `UpdateTime` in the given format.
+    pub(crate) fn with_update_time(mut self, format: UpdateTimeFormat) -> Self {
+        self.update_time = format;
+        self
+    }
+
+    /// Format values in the given locale.
+    pub(crate) fn with_locale(mut self, locale: Option<&'static Locale>) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    /// Append the formatted value of a property to `line`.
+    fn push_value(&self, line: &mut String, value: &Property, now: i64) {
+        match value {
+            Property::UpdateTime(t) =>
+                line.push_str(&format_update_time(*t, self.update_time, now)),
+            _ => match self.locale.and_then(|l| l.format(value)) {
+                Some(s) => line.push_str(&s),
+                None => write!(line, "{value}").expect("Writing to a String cannot fail")
+            }
+        }
+    }
+
+    /// Clear `line` and start it with `timestamp` (including the trailing space), if timestamps
+    /// are enabled.
+    fn start_line(&self, line: &mut String, timestamp: DateTime<Utc>) {
+        line.clear();
+        if self.timestamp {
+            write!(line, "{} ", timestamp.format(TIMESTAMP_FORMAT))
+                .expect("Writing to a String cannot fail");
+        }
+    }
+}
+
+/// The output of a [`LineWriter`]. Each line is built in a buffer which is reused for every line,
+/// rather than allocating for each change, and then written all at once.
+struct LineOutput {
+    /// File (or other struct implementing Write) to write to.
+    out: Box<dyn Write>,
+    /// The line currently being built.
+    line: String
 }
 
+#[async_trait(?Send)]
 impl Writer for LineWriter {
-    async fn write(&self, device_path: &str, changes: &HashMap<&str, Property>)
-        -> Result<(), std::io::Error> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let mut output = self.out.lock().await;
+        let LineOutput { out, line } = &mut *output;
+        let now = event.timestamp.timestamp();
+        self.start_line(line, event.timestamp);
+        line.push_str(&event.device);
+        line.push(' ');
+        for (i, (k, v)) in event.iter().enumerate() {
+            if i > 0 {
+                line.push_str(&self.delimiter);
+            }
+            line.push_str(k.as_str());
+            line.push_str(&self.separator);
+            self.push_value(line, v, now);
+        }
+        line.push('\n');
+        out.write_all(line.as_bytes())
+    }
+
+    /// Write the marker on its own line, in place of the device path.
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        let mut output = self.out.lock().await;
+        let LineOutput { out, line } = &mut *output;
+        self.start_line(line, Utc::now());
+        line.push_str(marker);
+        line.push('\n');
+        out.write_all(line.as_bytes())?;
+        // Markers are rare and often precede upmon or the system stopping, so they are flushed
+        // (writing a flush point, if the output is compressed) straight away.
+        out.flush()
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer + ?Sized> Writer for &W {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        (*self).write(event).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        (*self).write_marker(marker).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer + ?Sized> Writer for Box<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        (**self).write(event).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        (**self).write_marker(marker).await
+    }
+}
+
+/// A [`Writer`] that outputs each change or marker as a JSON object on its own line, in the same
+/// form as the events served over HTTP (or in an older version of that form, if requested).
+pub struct JsonWriter {
+    /// File (or other struct implementing Write) to write to.
+    out: Mutex<Box<dyn Write>>,
+    /// The version of the format in which events are written.
+    version: u32
+}
+
+impl JsonWriter {
+    /// Create a new [`JsonWriter`] which writes to the given output.
+    pub(crate) fn from_writer(out: Box<dyn Write>) -> Self {
+        Self { out: Mutex::new(out), version: SCHEMA_VERSION }
+    }
+
+    /// Write events in the given version of the format (see [`SCHEMA_VERSION`]).
+    pub(crate) fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for JsonWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let mut json = change_event(event);
+        downgrade(&mut json, self.version);
+        writeln!(self.out.lock().await, "{json}")
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        let mut json = marker_event(marker);
+        downgrade(&mut json, self.version);
         let mut out = self.out.lock().await;
-        let prop_string = changes.iter()
-            .map(|(k, v)| {
-                format!("{k}{}{v}", self.separator)
-            })
-            .collect::<Vec<String>>()
-            .join(&self.delimiter);
-        let mut t_str = String::new();
-        if self.timestamp {
-            t_str = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
-            t_str.push(' ');
+        writeln!(out, "{json}")?;
+        out.flush()
+    }
+}
+
+/// An optional [`Writer`], which does nothing if `None`.
+#[async_trait(?Send)]
+impl<W: Writer> Writer for Option<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        match self {
+            Some(w) => w.write(event).await,
+            None => Ok(())
+        }
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        match self {
+            Some(w) => w.write_marker(marker).await,
+            None => Ok(())
+        }
+    }
+}
+
+/// A list of [`Writer`]s, each of which is passed every change and marker in turn.
+#[async_trait(?Send)]
+impl<W: Writer> Writer for Vec<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        for w in self {
+            w.write(event).await?;
         }
-        write!(out, "{t_str}{device_path} {prop_string}\n")?;
         Ok(())
     }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        for w in self {
+            w.write_marker(marker).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A [`Writer`] which passes all changes and markers to two inner [`Writer`]s in turn.
+pub struct TeeWriter<A: Writer, B: Writer> {
+    /// The first writer.
+    first: A,
+    /// The second writer.
+    second: B
+}
+
+impl<A: Writer, B: Writer> TeeWriter<A, B> {
+    /// Create a new [`TeeWriter`] which writes to `first` and then `second`.
+    pub(crate) fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+#[async_trait(?Send)]
+impl<A: Writer, B: Writer> Writer for TeeWriter<A, B> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        self.first.write(event).await?;
+        self.second.write(event).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.first.write_marker(marker).await?;
+        self.second.write_marker(marker).await
+    }
+}
+
+/// The [`Writer`] for the output format selected by the user.
+pub enum FormatWriter {
+    /// A writer selected by name from a [`crate::registry::WriterRegistry`].
+    Registered(Box<dyn Writer>),
+    Zabbix(ZabbixWriter),
+    Metrics(MetricsWriter),
+    #[cfg(feature = "otel")]
+    Otel(OtelWriter),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteWriter),
+    /// The dashboard, which is shared with the task running it.
+    #[cfg(feature = "tui")]
+    Tui(std::sync::Arc<TuiWriter>)
+}
+
+#[async_trait(?Send)]
+impl Writer for FormatWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        match self {
+            FormatWriter::Registered(w) => w.write(event).await,
+            FormatWriter::Zabbix(w) => w.write(event).await,
+            FormatWriter::Metrics(w) => w.write(event).await,
+            #[cfg(feature = "otel")]
+            FormatWriter::Otel(w) => w.write(event).await,
+            #[cfg(feature = "sqlite")]
+            FormatWriter::Sqlite(w) => w.write(event).await,
+            #[cfg(feature = "tui")]
+            FormatWriter::Tui(w) => w.write(event).await
+        }
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        match self {
+            FormatWriter::Registered(w) => w.write_marker(marker).await,
+            FormatWriter::Zabbix(w) => w.write_marker(marker).await,
+            FormatWriter::Metrics(w) => w.write_marker(marker).await,
+            #[cfg(feature = "otel")]
+            FormatWriter::Otel(w) => w.write_marker(marker).await,
+            #[cfg(feature = "sqlite")]
+            FormatWriter::Sqlite(w) => w.write_marker(marker).await,
+            #[cfg(feature = "tui")]
+            FormatWriter::Tui(w) => w.write_marker(marker).await
+        }
+    }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
     use std::collections::HashMap;
+    use std::fs::{metadata, read_to_string, remove_file, write};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
     use std::path::Path;
-    use futures::executor::block_on;
-    use crate::output::{LineWriter, Writer};
+    use crate::event::DeviceEvent;
+    use crate::locale::Locale;
+    use crate::output::{FileMode, FileOptions, JsonWriter, LineWriter, open_output, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
     use crate::upower;
     use crate::upower::Property::*;
+    use crate::upower::PropertyKind;
 
     fn get_device_path() -> String {
         String::from("/org/freedesktop/UPower/devices/DisplayDevice")
@@ -81,18 +424,79 @@ pub(crate) mod tests {
 
     /// Return a [`HashMap`] that mocks the kind returned by
     /// [`upower::DeviceConfig::collect_changes`].
-    fn get_mock_changes<'s>() -> HashMap<&'s str, upower::Property> {
+    fn get_mock_changes() -> HashMap<PropertyKind, upower::Property> {
         let mut hm = HashMap::new();
-        hm.insert("UpdateTime", UpdateTime(1707671976));
-        hm.insert("Online", Online(true));
-        hm.insert("TimeToEmpty", TimeToEmpty(12345));
-        hm.insert("TimeToFull", TimeToFull(54321));
-        hm.insert("Percentage", Percentage(54.22));
-        hm.insert("IsPresent", IsPresent(false));
-        hm.insert("State", State(2));
+        hm.insert(PropertyKind::UpdateTime, UpdateTime(1707671976));
+        hm.insert(PropertyKind::Online, Online(true));
+        hm.insert(PropertyKind::TimeToEmpty, TimeToEmpty(12345));
+        hm.insert(PropertyKind::TimeToFull, TimeToFull(54321));
+        hm.insert(PropertyKind::Percentage, Percentage(54.22));
+        hm.insert(PropertyKind::IsPresent, IsPresent(false));
+        hm.insert(PropertyKind::State, State(2));
         hm
     }
 
+    /// Test that values are formatted in the configured locale.
+    #[test]
+    fn localized_line_writer() {
+        let buf = SharedBuffer::default();
+        let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false)
+            .with_locale(Some(Locale::find("fr").unwrap()));
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(54.5));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::TimeToEmpty, TimeToEmpty(5400));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        assert_eq!(buf.contents(), "/dev Percentage=54,5\n/dev TimeToEmpty=1 heure 30 minutes\n");
+    }
+
+    /// Test that each line is built from scratch, although the buffer in which it is built is
+    /// reused, and that timestamps are in RFC 3339 format.
+    #[test]
+    fn line_writer_lines() {
+        let buf = SharedBuffer::default();
+        let writer = LineWriter::from_writer(Box::new(buf.clone()), ":", ", ", false);
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::State, State(2));
+        changes.insert(PropertyKind::TimeToEmpty, TimeToEmpty(5400));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        block_on(writer.write_marker("Resumed")).unwrap();
+        let contents = buf.contents();
+        assert!(
+            contents == "/dev State:Discharging, TimeToEmpty:01:30:00\nResumed\n"
+                || contents == "/dev TimeToEmpty:01:30:00, State:Discharging\nResumed\n"
+        );
+
+        let buf = SharedBuffer::default();
+        let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", true);
+        block_on(writer.write_marker("Resumed")).unwrap();
+        let contents = buf.contents();
+        let (timestamp, marker) = contents.split_once(' ').unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+        assert_eq!(timestamp.len(), "2024-02-11T17:19:36.000Z".len());
+        assert_eq!(marker, "Resumed\n");
+    }
+
+    /// Test that a [`JsonWriter`] writes one JSON event per change or marker.
+    #[test]
+    fn json_writer() {
+        let buf = SharedBuffer::default();
+        let writer = JsonWriter::from_writer(Box::new(buf.clone()));
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::State, State(2));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        block_on(writer.write_marker("Resumed")).unwrap();
+        let events = buf.contents()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["device"], "/dev");
+        assert_eq!(events[0]["changes"]["State"], "Discharging");
+        assert_eq!(events[1]["marker"], "Resumed");
+    }
+
     /// Test creation and basic usage of a [`LineWriter`] struct.
     #[test]
     fn test_line_writer() {
@@ -104,15 +508,37 @@ pub(crate) mod tests {
             let null_r = LineWriter::new(Some("/dev/null"), "a", "b", false);
             assert!(null_r.is_ok());
             let null_writer = null_r.unwrap();
-            let write_result = block_on(null_writer.write(&dev_path, &changed));
+            let event = DeviceEvent::new(&dev_path, changed.clone());
+            let write_result = block_on(null_writer.write(&event));
             assert!(write_result.is_ok());
         }
         if Path::new("/dev/full").exists() {
             let full_r = LineWriter::new(Some("/dev/full"), "foo", "bar", true);
             assert!(full_r.is_ok());
             let full_writer = full_r.unwrap();
-            let write_result = block_on(full_writer.write(&dev_path, &changed));
+            let write_result = block_on(full_writer.write(&DeviceEvent::new(&dev_path, changed)));
             assert!(write_result.is_err());
         }
     }
+    /// Test that the output file is appended to or truncated as requested, and created with the
+    /// requested permissions.
+    #[test]
+    fn output_file_options() {
+        assert_eq!("640".parse::<FileMode>(), Ok(FileMode(0o640)));
+        assert!("680".parse::<FileMode>().is_err());
+        assert!("17777".parse::<FileMode>().is_err());
+        let path = std::env::temp_dir().join(format!("upmon-output-test-{}", std::process::id()));
+        let path_str = path.to_str();
+        let _ = remove_file(&path);
+        let options = FileOptions { mode: Some(FileMode(0o600)), ..FileOptions::default() };
+        writeln!(open_output(path_str, &options).unwrap(), "first").unwrap();
+        assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        writeln!(open_output(path_str, &FileOptions::default()).unwrap(), "second").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "first\nsecond\n");
+        write(&path, "a longer line which is truncated\n").unwrap();
+        let options = FileOptions { truncate: true, ..FileOptions::default() };
+        writeln!(open_output(path_str, &options).unwrap(), "third").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "third\n");
+        remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,241 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::time::Duration;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use crate::event::DeviceEvent;
+use crate::http::{change_event, marker_event};
+use crate::output::Writer;
+use crate::retry::{tolerate, Backoff, RetryPolicy};
+use crate::rt::{spawn_blocking, timeout};
+use crate::stats::{increment, Counter};
+
+/// How long a plugin may take to accept an event and, if it is expected to, acknowledge it,
+/// before it is killed (and restarted for the next event).
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running plugin process.
+struct Plugin {
+    /// The plugin process.
+    child: Child,
+    /// The plugin's standard input, to which events are written. This is only `None` while the
+    /// plugin is being stopped.
+    stdin: Option<ChildStdin>,
+    /// The plugin's standard output, from which acknowledgements are read, if the plugin is
+    /// expected to acknowledge events.
+    stdout: Option<BufReader<ChildStdout>>
+}
+
+impl Plugin {
+    /// Start `command` using the shell. If `acks` is true, the plugin's standard output is read
+    /// for acknowledgements; otherwise it is discarded.
+    fn spawn(command: &str, acks: bool) -> Result<Self, Error> {
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(if acks { Stdio::piped() } else { Stdio::null() })
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().map(BufReader::new);
+        Ok(Self { child, stdin, stdout })
+    }
+
+    /// Write a line to the plugin and, if it is expected to, wait for it to acknowledge the line.
+    fn send(&mut self, line: &str) -> Result<(), Error> {
+        let stdin = self.stdin.as_mut().ok_or(ErrorKind::BrokenPipe)?;
+        stdin.write_all(line.as_bytes())?;
+        stdin.flush()?;
+        let Some(stdout) = self.stdout.as_mut() else {
+            return Ok(())
+        };
+        let mut ack = String::new();
+        if stdout.read_line(&mut ack)? == 0 {
+            // The plugin has exited, so make sure it is restarted for the next event.
+            self.stdin = None;
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Plugin exited without acknowledging event"
+            ))
+        }
+        match ack.trim_end() {
+            "ok" => Ok(()),
+            reason => Err(Error::other(format!("Plugin rejected event: {reason}")))
+        }
+    }
+}
+
+/// Send a line to `plugin` on a thread where blocking is acceptable, as [`Plugin::send`] does,
+/// and return the plugin along with the result. The plugin is not returned (and is stopped) if it
+/// has exited, or if it does not accept and acknowledge the line within [`ACK_TIMEOUT`].
+async fn exchange(mut plugin: Plugin, line: String) -> (Option<Plugin>, Result<(), Error>) {
+    let pid = Pid::from_raw(plugin.child.id() as i32);
+    let exchanged = spawn_blocking(move || {
+        let result = plugin.send(&line);
+        match &result {
+            // Waiting for a plugin which has exited happens here too, as it can block.
+            Err(e) if e.kind() == ErrorKind::BrokenPipe || plugin.stdin.is_none() => {
+                (None, result)
+            },
+            _ => (Some(plugin), result)
+        }
+    });
+    match timeout(ACK_TIMEOUT, exchanged).await {
+        Ok(exchanged) => exchanged,
+        Err(_) => {
+            // Killing the plugin ends the exchange, which then drops the plugin.
+            let _ = kill(pid, Signal::SIGKILL);
+            (None, Err(Error::new(ErrorKind::TimedOut, "Plugin did not acknowledge event in time")))
+        }
+    }
+}
+
+impl Drop for Plugin {
+    /// Close the plugin's standard input, which tells it to exit, and wait for it to do so.
+    fn drop(&mut self) {
+        self.stdin = None;
+        let _ = self.child.wait();
+    }
+}
+
+/// A [`Writer`] which passes each change or marker to an external plugin program. The plugin is
+/// run using the shell and receives one JSON object per line on its standard input, in the same
+/// form as the events served over HTTP. If acknowledgements are enabled, the plugin must write a
+/// line to its standard output for each event: `ok` if the event was handled, or anything else to
+/// report an error. The plugin should exit when its standard input is closed, which happens when
+/// `upmon` exits. If the plugin exits early, it is restarted for the next event; if it keeps
+/// exiting (or cannot be started), restarts are put off as set out by a [`RetryPolicy`].
+pub struct PluginWriter {
+    /// The command which runs the plugin.
+    command: String,
+    /// Whether the plugin acknowledges each event.
+    acks: bool,
+    /// The plugin process, if it is running.
+    plugin: Mutex<Option<Plugin>>,
+    /// Failed attempts to restart the plugin.
+    restarts: Mutex<Backoff>
+}
+
+impl PluginWriter {
+    /// Create a new [`PluginWriter`] which starts the plugin by running `command`.
+    pub(crate) fn new(command: &str, acks: bool) -> Result<Self, Error> {
+        Ok(Self {
+            command: String::from(command),
+            acks,
+            plugin: Mutex::new(Some(Plugin::spawn(command, acks)?)),
+            restarts: Mutex::new(Backoff::new(RetryPolicy::default()))
+        })
+    }
+
+    /// Restart the plugin as set out by `policy`, rather than the default [`RetryPolicy`].
+    pub(crate) fn with_retry(self, policy: RetryPolicy) -> Self {
+        Self { restarts: Mutex::new(Backoff::new(policy)), ..self }
+    }
+
+    /// Send a line to the plugin, restarting it (and trying again) if it has exited. A restart
+    /// fails if the plugin cannot be started, or exits (or is killed) before the line is sent to it
+    /// and acknowledged.
+    async fn send(&self, line: String) -> Result<(), Error> {
+        let mut plugin = self.plugin.lock().await;
+        if let Some(p) = plugin.take() {
+            let (p, result) = exchange(p, line.clone()).await;
+            *plugin = p;
+            match result {
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {},
+                result => return result
+            }
+        }
+        let mut restarts = self.restarts.lock().await;
+        restarts.ready().map_err(|e| Error::other(format!("Not restarting plugin: {e}")))?;
+        let result = match Plugin::spawn(&self.command, self.acks) {
+            Ok(p) => {
+                increment(Counter::Reconnects);
+                let (p, result) = exchange(p, line).await;
+                *plugin = p;
+                result
+            },
+            Err(e) => Err(e)
+        };
+        match &result {
+            Err(_) if plugin.is_none() => restarts.failed(),
+            _ => restarts.succeeded()
+        }
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for PluginWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        tolerate("plugin", self.send(format!("{}\n", change_event(event))).await)
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        tolerate("plugin", self.send(format!("{}\n", marker_event(marker))).await)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::fs::{read_to_string, remove_file};
+    use crate::event::DeviceEvent;
+    use crate::http::{change_event, marker_event};
+    use crate::output::Writer;
+    use crate::plugin::PluginWriter;
+    use crate::rt::block_on;
+    use crate::stats::{get, Counter};
+    use crate::upower::Property::Percentage;
+    use crate::upower::PropertyKind;
+
+    /// Test that events are written to the plugin as JSON, and the plugin exits when the writer
+    /// is dropped.
+    #[test]
+    fn plugin_events() {
+        let path = std::env::temp_dir().join(format!("upmon-plugin-test-{}", std::process::id()));
+        let writer = PluginWriter::new(&format!("cat > {}", path.display()), false).unwrap();
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(50.0));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        block_on(writer.write_marker("Resumed")).unwrap();
+        drop(writer);
+        let events = read_to_string(&path).unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        remove_file(&path).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["device"], "/dev");
+        assert_eq!(events[0]["changes"]["Percentage"], 50.0);
+        assert_eq!(events[1]["marker"], "Resumed");
+    }
+
+    /// Test that acknowledgements are read from the plugin, and that anything other than `ok` is
+    /// counted as an error, without stopping changes being written.
+    #[test]
+    fn plugin_acks() {
+        let writer = PluginWriter::new(
+            r#"while read -r l; do case "$l" in *marker*) echo nope;; *) echo ok;; esac; done"#,
+            true
+        ).unwrap();
+        let errors = get(Counter::WriteErrors);
+        let err = block_on(writer.send(format!("{}\n", marker_event("Resumed")))).unwrap_err();
+        assert_eq!(err.to_string(), "Plugin rejected event: nope");
+        assert!(block_on(writer.write_marker("Resumed")).is_ok());
+        assert!(get(Counter::WriteErrors) > errors);
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(50.0));
+        assert!(block_on(writer.write(&DeviceEvent::new("/dev", changes.clone()))).is_ok());
+
+        let writer = PluginWriter::new("exit 0", true).unwrap();
+        let line = format!("{}\n", change_event(&DeviceEvent::new("/dev", changes)));
+        for _ in 0..2 {
+            assert!(block_on(writer.send(line.clone())).is_err());
+        }
+        // The restarted plugin exited too, so it is not restarted again straight away.
+        let err = block_on(writer.send(line)).unwrap_err();
+        assert!(err.to_string().starts_with("Not restarting plugin: retrying in "));
+    }
+}
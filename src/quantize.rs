@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+
+/// Round `percentage` to the nearest multiple of `step`, keeping it between 0 and 100.
+fn quantize(percentage: f64, step: u32) -> f64 {
+    let step = f64::from(step);
+    ((percentage / step).round() * step).clamp(0.0, 100.0)
+}
+
+/// A [`Writer`] which rounds each change to a device's `Percentage` to the nearest multiple of a
+/// step before passing it on to an inner [`Writer`]. Changes which round to the value last passed
+/// on for the device are removed, so that consumers which only show whole steps are not sent the
+/// intermediate values; events with no remaining changes are dropped.
+pub struct QuantizeWriter<W: Writer> {
+    /// The writer to which rounded changes are passed.
+    inner: W,
+    /// The step to round to, or `None` if changes should not be rounded.
+    step: Option<u32>,
+    /// The rounded percentage last passed on for each device.
+    last: Mutex<HashMap<String, f64>>
+}
+
+impl<W: Writer> QuantizeWriter<W> {
+    /// Create a new [`QuantizeWriter`] which passes changes to `inner`, with percentages rounded
+    /// to the nearest multiple of `step`.
+    pub(crate) fn new(inner: W, step: Option<u32>) -> Self {
+        Self { inner, step, last: Mutex::new(HashMap::new()) }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for QuantizeWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let (Some(step), Some(Property::Percentage(p))) =
+            (self.step, event.get(&PropertyKind::Percentage)) else {
+            return self.inner.write(event).await
+        };
+        let rounded = quantize(*p, step);
+        let unchanged = self.last.lock().await.insert(event.device.clone(), rounded)
+            == Some(rounded);
+        let quantized = event.with_changes(event.iter()
+            .filter(|(k, _)| !(unchanged && **k == PropertyKind::Percentage))
+            .map(|(k, v)| match k {
+                PropertyKind::Percentage => (k.clone(), Property::Percentage(rounded)),
+                _ => (k.clone(), v.clone())
+            })
+            .collect::<Vec<_>>());
+        if quantized.is_empty() {
+            return Ok(())
+        }
+        self.inner.write(&quantized).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::quantize::{quantize, QuantizeWriter};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that percentages are rounded to the nearest step, and that changes which round to the
+    /// value last written for the device are suppressed.
+    #[test]
+    fn quantized() {
+        assert_eq!(quantize(82.4, 5), 80.0);
+        assert_eq!(quantize(82.5, 5), 85.0);
+        assert_eq!(quantize(99.0, 3), 99.0);
+        assert_eq!(quantize(96.0, 10), 100.0);
+
+        let buf = SharedBuffer::default();
+        let writer = QuantizeWriter::new(
+            LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false),
+            Some(5)
+        );
+        block_on(async {
+            for (device, p) in [("/bat", 81.0), ("/bat", 79.0), ("/other", 79.0), ("/bat", 77.0)] {
+                let event = DeviceEvent::new(device, [(PropertyKind::Percentage, Percentage(p))]);
+                writer.write(&event).await.unwrap();
+            }
+            writer.write(&DeviceEvent::new("/bat", [
+                (PropertyKind::Percentage, Percentage(76.0)),
+                (PropertyKind::State, State(2))
+            ])).await.unwrap();
+        });
+        assert_eq!(
+            buf.contents(),
+            "/bat Percentage=80\n/other Percentage=80\n/bat Percentage=75\n/bat State=Discharging\n"
+        );
+    }
+}
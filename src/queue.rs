@@ -0,0 +1,198 @@
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_channel::{bounded, Receiver, Sender, TrySendError};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::stats::{increment, Counter};
+
+/// What to do with a change when the queue of changes waiting to be written is full.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Wait for space in the queue, holding up monitoring until output catches up.
+    Block,
+    /// Drop the oldest queued change to make space.
+    DropOldest,
+    /// Drop the new change.
+    DropNewest
+}
+
+/// A change or marker waiting to be written.
+#[derive(Debug)]
+enum Queued {
+    Change(DeviceEvent),
+    Marker(String)
+}
+
+/// A [`Writer`] which queues changes and markers to be written to an inner [`Writer`] by
+/// [`QueueWriter::run`], so that slow output (such as a full FIFO or an unresponsive server) does
+/// not hold up monitoring. If the queue is full, changes are dropped or waited for according to the
+/// [`Overflow`] policy, and dropped changes are counted.
+pub struct QueueWriter<W: Writer> {
+    /// The writer to which queued changes are written.
+    inner: W,
+    /// What to do when the queue is full.
+    overflow: Overflow,
+    /// The number of changes and markers dropped because the queue was full.
+    dropped: AtomicU64,
+    /// Used to queue changes.
+    sender: Sender<Queued>,
+    /// Used to take changes from the queue, either to write them or to drop them.
+    receiver: Receiver<Queued>
+}
+
+impl<W: Writer> QueueWriter<W> {
+    /// Create a new [`QueueWriter`] which queues up to `capacity` changes for writing to `inner`.
+    pub(crate) fn new(inner: W, capacity: usize, overflow: Overflow) -> Self {
+        let (sender, receiver) = bounded(capacity.max(1));
+        Self {
+            inner,
+            overflow,
+            dropped: AtomicU64::new(0),
+            sender,
+            receiver
+        }
+    }
+
+    /// Return the number of changes and markers dropped because the queue was full.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting changes. [`QueueWriter::run`] completes once all changes already queued
+    /// have been written.
+    pub(crate) fn close(&self) {
+        self.sender.close();
+    }
+
+    /// Add `item` to the queue, waiting for space or dropping a change if it is full.
+    async fn enqueue(&self, mut item: Queued) -> Result<(), Error> {
+        let closed = || Error::new(ErrorKind::BrokenPipe, "Output queue is closed");
+        if self.overflow == Overflow::Block {
+            return self.sender.send(item).await.map_err(|_| closed())
+        }
+        loop {
+            match self.sender.try_send(item) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(_)) => return Err(closed()),
+                Err(TrySendError::Full(i)) => {
+                    if self.overflow == Overflow::DropNewest {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        increment(Counter::EventsDropped);
+                        return Ok(())
+                    }
+                    // The writer may take the oldest change itself before it can be dropped.
+                    if self.receiver.try_recv().is_ok() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        increment(Counter::EventsDropped);
+                    }
+                    item = i;
+                }
+            }
+        }
+    }
+
+    /// Write queued changes to the inner writer as they are queued, until an error occurs or the
+    /// queue is closed and empty.
+    pub(crate) async fn run(&self) -> Result<(), Error> {
+        while let Ok(item) = self.receiver.recv().await {
+            match item {
+                Queued::Change(event) => {
+                    self.inner.write(&event).await
+                        .inspect_err(|_| increment(Counter::WriteErrors))?;
+                    increment(Counter::EventsWritten);
+                },
+                Queued::Marker(marker) => self.inner.write_marker(&marker).await
+                    .inspect_err(|_| increment(Counter::WriteErrors))?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for QueueWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        self.enqueue(Queued::Change(event.clone())).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        self.enqueue(Queued::Marker(String::from(marker))).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use futures::join;
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::queue::{Overflow, QueueWriter};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::Percentage;
+    use crate::upower::PropertyKind;
+
+    /// Write changes to percentages 1 to 3, with a queue of size 2 which is not being emptied,
+    /// and return the output once the queue has been closed and emptied, and the number of
+    /// dropped changes.
+    fn overflow(overflow: Overflow) -> (String, u64) {
+        block_on(async {
+            let buf = SharedBuffer::default();
+            let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            let writer = QueueWriter::new(inner, 2, overflow);
+            for p in 1..=3 {
+                let mut changes = HashMap::new();
+                changes.insert(PropertyKind::Percentage, Percentage(p as f64));
+                writer.write(&DeviceEvent::new("/dev", changes)).await.unwrap();
+            }
+            writer.close();
+            writer.run().await.unwrap();
+            assert!(writer.write_marker("Resumed").await.is_err());
+            (buf.contents(), writer.dropped())
+        })
+    }
+
+    /// Test that changes are dropped according to the overflow policy when the queue is full.
+    #[test]
+    fn queue_overflow() {
+        assert_eq!(
+            overflow(Overflow::DropNewest),
+            (String::from("/dev Percentage=1\n/dev Percentage=2\n"), 1)
+        );
+        assert_eq!(
+            overflow(Overflow::DropOldest),
+            (String::from("/dev Percentage=2\n/dev Percentage=3\n"), 1)
+        );
+    }
+
+    /// Test that changes and markers are written in order while the queue is being emptied, and
+    /// that blocking writes wait for space rather than dropping changes.
+    #[test]
+    fn queue_order() {
+        block_on(async {
+            let buf = SharedBuffer::default();
+            let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            let writer = QueueWriter::new(inner, 1, Overflow::Block);
+            let write = async {
+                for p in 1..=3 {
+                    let mut changes = HashMap::new();
+                    changes.insert(PropertyKind::Percentage, Percentage(p as f64));
+                    writer.write(&DeviceEvent::new("/dev", changes)).await.unwrap();
+                }
+                writer.write_marker("Resumed").await.unwrap();
+                writer.close();
+            };
+            let (result, _) = join!(writer.run(), write);
+            result.unwrap();
+            assert_eq!(
+                buf.contents(),
+                "/dev Percentage=1\n/dev Percentage=2\n/dev Percentage=3\nResumed\n"
+            );
+            assert_eq!(writer.dropped(), 0);
+        })
+    }
+}
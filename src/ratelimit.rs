@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::Serialize;
+use strum::VariantNames;
+use crate::event::DeviceEvent;
+use crate::filter::Sink;
+use crate::output::Writer;
+use crate::stats::{increment, Counter};
+
+/// What to do with a change which exceeds the rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitOverflow {
+    /// Drop the change.
+    Drop,
+    /// Hold the change back, and write it along with the device's next change which is within the
+    /// limit. Where both change the same property, the later value is written.
+    Coalesce
+}
+
+/// The maximum rate at which changes to each device are written to a sink, given on the command
+/// line in the form `[SINK=]N/SECONDS`. A limit without a sink applies to every sink which is not
+/// given its own limit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    /// The sink to which the limit applies, or `None` for every sink.
+    pub sink: Option<Sink>,
+    /// The number of changes which can be written within each period (and the number which can be
+    /// written in a single burst).
+    pub events: u32,
+    /// The period over which `events` changes can be written.
+    pub period: Duration
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sink, limit) = match s.split_once('=') {
+            Some((sink, limit)) => (Some(sink.trim().parse::<Sink>().map_err(|_| format!(
+                "Unknown sink: {sink} (expected one of: {})",
+                Sink::VARIANTS.join(", ")
+            ))?), limit),
+            None => (None, s)
+        };
+        let (events, secs) = limit.split_once('/')
+            .ok_or_else(|| format!("Expected [SINK=]N/SECONDS: {s}"))?;
+        let events = events.trim().parse::<u32>().ok().filter(|n| *n > 0)
+            .ok_or_else(|| format!("Invalid number of changes: {events}"))?;
+        let secs = secs.trim().parse::<u64>().ok().filter(|n| *n > 0)
+            .ok_or_else(|| format!("Invalid number of seconds: {secs}"))?;
+        Ok(Self { sink, events, period: Duration::from_secs(secs) })
+    }
+}
+
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(sink) = self.sink {
+            write!(f, "{sink}=")?;
+        }
+        write!(f, "{}/{}", self.events, self.period.as_secs())
+    }
+}
+
+/// A token bucket, which holds up to a limit's number of tokens and is refilled at the limit's
+/// rate. Each change written takes a token.
+#[derive(Debug)]
+struct Bucket {
+    /// The number of tokens currently in the bucket.
+    tokens: f64,
+    /// When the bucket was last refilled.
+    refilled: Instant
+}
+
+impl Bucket {
+    /// Create a new, full [`Bucket`] for `limit`.
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self { tokens: f64::from(limit.events), refilled: now }
+    }
+
+    /// Refill the bucket for the time since it was last refilled, then take a token if there is
+    /// one. Returns whether a token was taken.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        let rate = f64::from(limit.events) / limit.period.as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(limit.events));
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A [`Writer`] which limits the rate at which changes to each device are passed to an inner
+/// [`Writer`], so that a device sending a flood of changes does not flood the sink. Changes over
+/// the limit are dropped or coalesced, and counted as [`Counter::EventsRateLimited`]. Markers are
+/// never limited.
+pub struct RateLimitWriter<W: Writer> {
+    /// The writer to which changes within the limit are passed.
+    inner: W,
+    /// The limit, if any.
+    limit: Option<RateLimit>,
+    /// What to do with changes over the limit.
+    overflow: RateLimitOverflow,
+    /// The token bucket of each device, by path.
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// The changes held back for each device when coalescing, by path.
+    held: Mutex<HashMap<String, DeviceEvent>>
+}
+
+impl<W: Writer> RateLimitWriter<W> {
+    /// Create a new [`RateLimitWriter`] which passes changes to `inner` at no more than the rate
+    /// given by `limit` (or without limit, if `None`).
+    pub(crate) fn new(inner: W, limit: Option<RateLimit>, overflow: RateLimitOverflow) -> Self {
+        Self {
+            inner,
+            limit,
+            overflow,
+            buckets: Mutex::new(HashMap::new()),
+            held: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Write `event` if it is within the limit at `now`, or drop or hold it back if not.
+    async fn write_at(&self, event: &DeviceEvent, now: Instant) -> Result<(), std::io::Error> {
+        let Some(limit) = &self.limit else {
+            return self.inner.write(event).await
+        };
+        let allowed = self.buckets.lock().await
+            .entry(event.device.clone())
+            .or_insert_with(|| Bucket::new(limit, now))
+            .take(limit, now);
+        let mut held = self.held.lock().await;
+        if !allowed {
+            increment(Counter::EventsRateLimited);
+            if self.overflow == RateLimitOverflow::Coalesce {
+                match held.get_mut(&event.device) {
+                    Some(h) => event.iter().for_each(|(k, v)| h.insert(k.clone(), v.clone())),
+                    None => {
+                        held.insert(event.device.clone(), event.clone());
+                    }
+                }
+            }
+            return Ok(())
+        }
+        match held.remove(&event.device) {
+            Some(mut coalesced) => {
+                drop(held);
+                event.iter().for_each(|(k, v)| coalesced.insert(k.clone(), v.clone()));
+                coalesced.timestamp = event.timestamp;
+                self.inner.write(&coalesced).await
+            },
+            None => {
+                drop(held);
+                self.inner.write(event).await
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for RateLimitWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        self.write_at(event, Instant::now()).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::{Duration, Instant};
+    use crate::event::DeviceEvent;
+    use crate::filter::Sink;
+    use crate::output::LineWriter;
+    use crate::ratelimit::{RateLimit, RateLimitOverflow, RateLimitWriter};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that rate limits are parsed, and that invalid ones are rejected.
+    #[test]
+    fn parse_rate_limit() {
+        assert_eq!(
+            "osd=5/2".parse::<RateLimit>(),
+            Ok(RateLimit { sink: Some(Sink::Osd), events: 5, period: Duration::from_secs(2) })
+        );
+        assert_eq!("10/1".parse::<RateLimit>().unwrap().to_string(), "10/1");
+        assert!("10".parse::<RateLimit>().is_err());
+        assert!("0/1".parse::<RateLimit>().is_err());
+        assert!("stdout=1/1".parse::<RateLimit>().is_err());
+    }
+
+    /// Test that a burst of changes is limited for each device, and that changes over the limit
+    /// are dropped or coalesced into the next change within it.
+    #[test]
+    fn rate_limited() {
+        let limit = "2/10".parse::<RateLimit>().unwrap();
+        let start = Instant::now();
+        for (overflow, expected) in [
+            (RateLimitOverflow::Drop, concat!(
+                "/bat Percentage=80\n/bat Percentage=79\n/other Percentage=50\n",
+                "/bat Percentage=76\n"
+            )),
+            (RateLimitOverflow::Coalesce, concat!(
+                "/bat Percentage=80\n/bat Percentage=79\n/other Percentage=50\n",
+                "/bat Percentage=76 State=Discharging\n"
+            ))
+        ] {
+            let buf = SharedBuffer::default();
+            let writer = RateLimitWriter::new(
+                LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false),
+                Some(limit.clone()),
+                overflow
+            );
+            let event = |path, changes: Vec<_>| DeviceEvent::new(path, changes);
+            block_on(async {
+                for (p, secs) in [(80.0, 0), (79.0, 1), (78.0, 2)] {
+                    let e = event("/bat", vec!((PropertyKind::Percentage, Percentage(p))));
+                    writer.write_at(&e, start + Duration::from_secs(secs)).await.unwrap();
+                }
+                let e = event("/other", vec!((PropertyKind::Percentage, Percentage(50.0))));
+                writer.write_at(&e, start + Duration::from_secs(2)).await.unwrap();
+                let e = event("/bat", vec!((PropertyKind::State, State(2))));
+                writer.write_at(&e, start + Duration::from_secs(3)).await.unwrap();
+                // Enough of the bucket has been refilled for another change by the sixth second.
+                let e = event("/bat", vec!((PropertyKind::Percentage, Percentage(76.0))));
+                writer.write_at(&e, start + Duration::from_secs(6)).await.unwrap();
+            });
+            assert_eq!(buf.contents(), expected);
+        }
+    }
+}
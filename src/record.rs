@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::time::Duration;
+use async_lock::Mutex;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::Value::{self, Bool, F64, I16, I32, I64, Str, U16, U32, U64, U8};
+use crate::output::Writer;
+use crate::rt::sleep;
+use crate::upower::{DeviceConfig, ListenError};
+
+/// A single property value as recorded, along with its DBus type signature so that the original
+/// [`Value`] can be reconstructed on replay.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedValue {
+    /// The DBus type signature of the value.
+    #[serde(rename = "type")]
+    signature: String,
+    /// The value itself. This will be `null` for values of types that cannot be recorded.
+    value: serde_json::Value
+}
+
+impl RecordedValue {
+    /// Create a [`RecordedValue`] from a [`Value`]. Only basic types (other than object paths and
+    /// signatures) are recorded; the value of any other type is recorded as `null`.
+    fn from_value(v: &Value) -> Self {
+        let value = match v {
+            Bool(b) => serde_json::Value::from(*b),
+            U8(n) => serde_json::Value::from(*n),
+            I16(n) => serde_json::Value::from(*n),
+            U16(n) => serde_json::Value::from(*n),
+            I32(n) => serde_json::Value::from(*n),
+            U32(n) => serde_json::Value::from(*n),
+            I64(n) => serde_json::Value::from(*n),
+            U64(n) => serde_json::Value::from(*n),
+            F64(n) => serde_json::Value::from(*n),
+            Str(s) => serde_json::Value::from(s.as_str()),
+            _ => serde_json::Value::Null
+        };
+        Self {
+            signature: v.value_signature().to_string(),
+            value
+        }
+    }
+
+    /// Reconstruct the original [`Value`], or return `None` if it was not recorded or is
+    /// malformed.
+    fn to_value(&self) -> Option<Value<'static>> {
+        let v = &self.value;
+        Some(match self.signature.as_str() {
+            "b" => Bool(v.as_bool()?),
+            "y" => U8(v.as_u64()?.try_into().ok()?),
+            "n" => I16(v.as_i64()?.try_into().ok()?),
+            "q" => U16(v.as_u64()?.try_into().ok()?),
+            "i" => I32(v.as_i64()?.try_into().ok()?),
+            "u" => U32(v.as_u64()?.try_into().ok()?),
+            "x" => I64(v.as_i64()?),
+            "t" => U64(v.as_u64()?),
+            "d" => F64(v.as_f64()?),
+            "s" => Value::from(String::from(v.as_str()?)),
+            _ => return None
+        })
+    }
+}
+
+/// A single `PropertiesChanged` signal received for a device, as recorded.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// ISO 8601-formatted time at which the signal was received.
+    pub timestamp: String,
+    /// The device's DBus object path.
+    pub path: String,
+    /// All changed properties contained in the signal, whether or not they were targeted.
+    pub changed: HashMap<String, RecordedValue>
+}
+
+impl RecordedEvent {
+    /// Create a new [`RecordedEvent`] for changes received now.
+    fn new(path: &str, changed: &HashMap<&str, Value>) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            path: String::from(path),
+            changed: changed.iter()
+                .map(|(k, v)| (String::from(*k), RecordedValue::from_value(v)))
+                .collect()
+        }
+    }
+
+    /// Parse the time at which the event was received.
+    pub(crate) fn time(&self) -> Result<DateTime<Utc>, String> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| format!("Invalid timestamp {}: {e}", self.timestamp))
+    }
+
+    /// Reconstruct the changed properties as they were received. Values which could not be
+    /// recorded are omitted.
+    pub(crate) fn changed_properties(&self) -> HashMap<&str, Value<'static>> {
+        self.changed.iter()
+            .filter_map(|(k, v)| Some((k.as_str(), v.to_value()?)))
+            .collect()
+    }
+}
+
+/// Records received `PropertiesChanged` signals to a file, one JSON object per line.
+pub struct Recorder {
+    /// File to write recorded events to.
+    out: Mutex<File>
+}
+
+impl Recorder {
+    /// Create a new [`Recorder`] that appends events to the file at the given path.
+    pub(crate) fn new(path: &str) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            out: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)
+        })
+    }
+
+    /// Record the changed properties received for the given device.
+    pub(crate) async fn record(&self, path: &str, changed: &HashMap<&str, Value<'_>>)
+        -> Result<(), std::io::Error> {
+        let event = serde_json::to_string(&RecordedEvent::new(path, changed))?;
+        let mut out = self.out.lock().await;
+        writeln!(out, "{event}")
+    }
+}
+
+/// Read all events from a file previously written by a [`Recorder`].
+pub(crate) fn read_events(path: &str) -> Result<Vec<RecordedEvent>, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open {path}: {e}"))?;
+    let mut events = vec!();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Could not read {path}: {e}"))?;
+        if line.trim().is_empty() {
+            continue
+        }
+        events.push(serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid event on line {} of {path}: {e}", i + 1))?);
+    }
+    Ok(events)
+}
+
+/// Feed recorded events back through the given device configurations, writing any relevant
+/// changes. Events are replayed with the same spacing as when they were recorded, divided by
+/// `speed`; if `speed` is zero, they are replayed without any delay.
+pub(crate) async fn replay(
+    events: &[RecordedEvent],
+    paths: &[DeviceConfig],
+    writer: &impl Writer,
+    speed: f64
+) -> Result<(), ListenError> {
+    let mut prev_time: Option<DateTime<Utc>> = None;
+    for event in events {
+        let time = event.time().map_err(ListenError::Receive)?;
+        if let Some(prev) = prev_time {
+            if speed > 0.0 {
+                let delay = (time - prev).to_std().unwrap_or(Duration::ZERO);
+                sleep(delay.div_f64(speed)).await;
+            }
+        }
+        prev_time = Some(time);
+        let changed = event.changed_properties();
+        for p in paths.iter().filter(|p| p.is_for(&event.path)) {
+            p.handle_changes(&changed, writer).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use zbus::zvariant::Value::{self, Bool, F64, I64, U32, U64};
+    use crate::output::LineWriter;
+    use crate::record::{replay, RecordedEvent, RecordedValue};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::DeviceConfig;
+
+    /// Test that values survive a round trip through JSON.
+    #[test]
+    fn value_round_trip() {
+        let values: Vec<Value> = vec!(
+            U64(1707671976),
+            Bool(true),
+            I64(-12345),
+            F64(54.22),
+            U32(2),
+            Value::from("battery_BAT0")
+        );
+        for v in values {
+            let json = serde_json::to_string(&RecordedValue::from_value(&v)).unwrap();
+            let recorded: RecordedValue = serde_json::from_str(&json).unwrap();
+            assert_eq!(recorded.to_value(), Some(v));
+        }
+        let unsupported = RecordedValue::from_value(&Value::from(vec!(1u32, 2u32)));
+        assert_eq!(unsupported.to_value(), None);
+    }
+
+    /// Test that events survive a round trip through JSON.
+    #[test]
+    fn event_round_trip() {
+        let mut changed = HashMap::new();
+        changed.insert("Percentage", F64(80.0));
+        changed.insert("State", U32(2));
+        let event = RecordedEvent::new("/org/freedesktop/UPower/devices/DisplayDevice", &changed);
+        let json = serde_json::to_string(&event).unwrap();
+        let parsed: RecordedEvent = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, event);
+        assert!(parsed.time().is_ok());
+        assert_eq!(parsed.changed_properties(), changed);
+    }
+
+    /// Test that replayed events are filtered and written according to the device configuration.
+    #[test]
+    fn replay_events() {
+        let bat = "/org/freedesktop/UPower/devices/battery_BAT0";
+        let ac = "/org/freedesktop/UPower/devices/line_power_AC";
+        let events: Vec<RecordedEvent> = [
+            (bat, "Percentage", F64(80.0)),
+            (ac, "Online", Bool(true)),
+            (bat, "State", U32(1)),
+        ].into_iter().map(|(path, k, v)| {
+            let mut changed = HashMap::new();
+            changed.insert(k, v);
+            RecordedEvent::new(path, &changed)
+        }).collect();
+        let paths = DeviceConfig::from_args(
+            &[vec!(String::from(bat), String::from("State"))],
+            None
+        ).unwrap();
+        let buf = SharedBuffer::default();
+        let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        assert!(block_on(replay(&events, &paths, &writer, 0.0)).is_ok());
+        assert_eq!(buf.contents(), format!("{bat} State=Charging\n"));
+    }
+}
@@ -0,0 +1,153 @@
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::Serialize;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::Property;
+
+/// The value which replaces redacted properties in output.
+pub(crate) const REDACTED: &str = "<redacted>";
+
+/// A device property which identifies the device, and which can be redacted from output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Redacted {
+    /// The device's Serial property.
+    Serial,
+    /// The device's Vendor property.
+    Vendor,
+    /// The device's Model property.
+    Model,
+    /// The device's NativePath property (such as BAT0), which for some devices contains a serial
+    /// number or hardware address.
+    NativePath
+}
+
+impl Redacted {
+    /// The name of the redacted property.
+    pub(crate) fn property(&self) -> &'static str {
+        match self {
+            Redacted::Serial => "Serial",
+            Redacted::Vendor => "Vendor",
+            Redacted::Model => "Model",
+            Redacted::NativePath => "NativePath"
+        }
+    }
+}
+
+/// Split a marker into the words separated by spaces, treating a JSON string (as used for values
+/// containing whitespace) as a single word.
+fn words(marker: &str) -> Vec<&str> {
+    let mut words = vec!();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in marker.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' if !quoted => {
+                words.push(&marker[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    words.push(&marker[start..]);
+    words
+}
+
+/// A [`Writer`] which replaces the values of the given properties with [`REDACTED`] before passing
+/// changes to an inner [`Writer`], so that output sent off the machine does not identify its
+/// devices. `NAME=VALUE` pairs for the properties in markers (such as `DeviceInfo`) are redacted in
+/// the same way.
+pub struct RedactWriter<W: Writer> {
+    /// The writer to which redacted changes are passed.
+    inner: W,
+    /// The properties which are redacted.
+    redacted: Vec<Redacted>
+}
+
+impl<W: Writer> RedactWriter<W> {
+    /// Create a new [`RedactWriter`] which redacts the given properties.
+    pub(crate) fn new(inner: W, redacted: &[Redacted]) -> Self {
+        Self { inner, redacted: redacted.to_vec() }
+    }
+
+    /// Return whether the property named `name` is redacted.
+    fn is_redacted(&self, name: &str) -> bool {
+        self.redacted.iter().any(|r| r.property() == name)
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for RedactWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        if !event.changes.iter().any(|(k, _)| self.is_redacted(k.as_str())) {
+            return self.inner.write(event).await
+        }
+        let mut redacted = event.clone();
+        for (kind, property) in &mut redacted.changes {
+            if self.is_redacted(kind.as_str()) {
+                *property = Property::Other(Value::from(REDACTED).into());
+            }
+        }
+        self.inner.write(&redacted).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        if self.redacted.is_empty() {
+            return self.inner.write_marker(marker).await
+        }
+        let redacted = words(marker).into_iter()
+            .map(|w| match w.split_once('=') {
+                Some((name, _)) if self.is_redacted(name) => format!("{name}={REDACTED}"),
+                _ => String::from(w)
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.inner.write_marker(&redacted).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use zbus::zvariant::Value;
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::redact::{Redacted, RedactWriter};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Other, Percentage};
+    use crate::upower::PropertyKind;
+
+    /// Test that the chosen properties are redacted from changes and markers, including values
+    /// which are quoted because they contain spaces.
+    #[test]
+    fn redacted_output() {
+        block_on(async {
+            let buf = SharedBuffer::default();
+            let writer = RedactWriter::new(
+                LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false),
+                &[Redacted::Serial, Redacted::Vendor]
+            );
+            let other = |name: &str, value: &str| {
+                (PropertyKind::Other(String::from(name)), Other(Value::from(value).into()))
+            };
+            writer.write(&DeviceEvent::new("/bat", [
+                (PropertyKind::Percentage, Percentage(50.0)),
+                other("Serial", "1234"),
+                other("Model", "5B10W13930")
+            ])).await.unwrap();
+            writer.write_marker(concat!(
+                r#"DeviceInfo /bat Vendor="Sunwoda \"Electronics\"" Model=5B10W13930 Serial=1234 "#,
+                "Type=Battery"
+            )).await.unwrap();
+            assert_eq!(buf.contents(), concat!(
+                "/bat Percentage=50 Serial=<redacted> Model=5B10W13930\n",
+                "DeviceInfo /bat Vendor=<redacted> Model=5B10W13930 Serial=<redacted> ",
+                "Type=Battery\n"
+            ));
+        });
+    }
+}
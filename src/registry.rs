@@ -0,0 +1,205 @@
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use crate::locale::Locale;
+use crate::output::{FileOptions, JsonWriter, LineWriter, open_output, Writer};
+use crate::severity::SeverityColors;
+use crate::summary::{SummaryWriter, TagStyle};
+use crate::upower::UpdateTimeFormat;
+
+/// Options given on the command line which writers created by a [`WriterRegistry`] may use.
+#[derive(Clone, Copy, Debug)]
+pub struct WriterOptions<'a> {
+    /// The file to write to, or `None` for standard output.
+    pub output_file: Option<&'a str>,
+    /// How the file is written.
+    pub file: FileOptions,
+    /// String used to separate each property name from its value.
+    pub separator: &'a str,
+    /// String used to separate property-value pairs.
+    pub delimiter: &'a str,
+    /// Whether to include a timestamp in the output.
+    pub timestamp: bool,
+    /// The format in which to render `UpdateTime`.
+    pub update_time: UpdateTimeFormat,
+    /// The locale in which to format values, if any.
+    pub locale: Option<&'static Locale>,
+    /// The colours in which status bar formats show devices whose severity is warning or critical.
+    pub colors: &'a SeverityColors,
+    /// The version of the output format to write, where formats have changed between versions
+    /// (see [`crate::event::SCHEMA_VERSION`]).
+    pub output_version: u32
+}
+
+impl WriterOptions<'_> {
+    /// Open the file to write to (see [`open_output`]).
+    pub fn open(&self) -> Result<Box<dyn std::io::Write>, Error> {
+        open_output(self.output_file, &self.file)
+    }
+}
+
+/// An additional output, given on the command line in the form `FORMAT` or `FORMAT=PATH`, which is
+/// written in a format selected from a [`WriterRegistry`] alongside the main output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputSpec {
+    /// The name of the writer to create.
+    pub format: String,
+    /// The file to write to, or `None` for standard output.
+    pub output_file: Option<String>
+}
+
+impl FromStr for OutputSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, output_file) = match s.split_once('=') {
+            Some((f, p)) if p.trim().is_empty() => return Err(format!("No path given for {f}")),
+            Some((f, p)) => (f, Some(String::from(p.trim()))),
+            None => (s, None)
+        };
+        let format = format.trim();
+        if format.is_empty() {
+            return Err(format!("Expected FORMAT or FORMAT=PATH: {s}"))
+        }
+        Ok(Self { format: String::from(format), output_file })
+    }
+}
+
+/// A function which creates a [`Writer`] from the given options.
+pub type WriterFactory = fn(&WriterOptions) -> Result<Box<dyn Writer>, Error>;
+
+/// A set of [`Writer`]s which can be selected by name. The default registry provides `line`
+/// ([`LineWriter`]), `json` ([`JsonWriter`]), `summary` ([`SummaryWriter`]), and `polybar` and
+/// `lemonbar` (a [`SummaryWriter`] using the [`TagStyle`] of each bar); others can be added with
+/// [`WriterRegistry::register`].
+pub struct WriterRegistry {
+    /// The name of each writer and the function which creates it, in the order registered.
+    factories: Vec<(String, WriterFactory)>
+}
+
+impl WriterRegistry {
+    /// Create a registry with no writers.
+    pub fn empty() -> Self {
+        Self { factories: vec!() }
+    }
+
+    /// Register `factory` to create the writer with the given name, replacing any writer already
+    /// registered with that name.
+    pub fn register(&mut self, name: &str, factory: WriterFactory) {
+        match self.factories.iter_mut().find(|(n, _)| n == name) {
+            Some((_, f)) => *f = factory,
+            None => self.factories.push((String::from(name), factory))
+        }
+    }
+
+    /// Return the names of all registered writers.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.iter().map(|(n, _)| n.as_str())
+    }
+
+    /// Create the writer with the given name. Returns an error if no such writer is registered,
+    /// or if it cannot be created.
+    pub fn create(&self, name: &str, options: &WriterOptions) -> Result<Box<dyn Writer>, Error> {
+        match self.factories.iter().find(|(n, _)| n == name) {
+            Some((_, factory)) => factory(options),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Unknown writer: {name} (expected one of: {})",
+                    self.names().collect::<Vec<_>>().join(", ")
+                )
+            ))
+        }
+    }
+}
+
+impl Default for WriterRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("line", |o| Ok(Box::new(
+            LineWriter::from_writer(o.open()?, o.separator, o.delimiter, o.timestamp)
+                .with_update_time(o.update_time)
+                .with_locale(o.locale)
+        )));
+        registry.register("json", |o| Ok(Box::new(
+            JsonWriter::from_writer(o.open()?).with_version(o.output_version)
+        )));
+        registry.register("summary", |o| Ok(Box::new(
+            SummaryWriter::from_writer(o.open()?, o.separator, o.delimiter)
+        )));
+        registry.register("polybar", |o| Ok(Box::new(
+            SummaryWriter::from_writer(o.open()?, o.separator, o.delimiter)
+                .with_tags(TagStyle::Polybar, o.colors.clone())
+        )));
+        registry.register("lemonbar", |o| Ok(Box::new(
+            SummaryWriter::from_writer(o.open()?, o.separator, o.delimiter)
+                .with_tags(TagStyle::Lemonbar, o.colors.clone())
+        )));
+        registry
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::event::{DeviceEvent, SCHEMA_VERSION};
+    use crate::output::{FileOptions, LineWriter, Writer};
+    use crate::registry::{OutputSpec, WriterOptions, WriterRegistry};
+    use crate::rt::block_on;
+    use crate::severity::SeverityColors;
+    use crate::upower::{Property, PropertyKind, UpdateTimeFormat};
+
+    /// Test that writers are created by name, and can be replaced or added.
+    #[test]
+    fn registry() {
+        let options = WriterOptions {
+            output_file: Some("/dev/null"),
+            file: FileOptions::default(),
+            separator: "=",
+            delimiter: " ",
+            timestamp: false,
+            update_time: UpdateTimeFormat::Utc,
+            locale: None,
+            colors: &SeverityColors::default(),
+            output_version: SCHEMA_VERSION
+        };
+        let mut registry = WriterRegistry::default();
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            vec!("line", "json", "summary", "polybar", "lemonbar")
+        );
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Property::Percentage(50.0));
+        for name in ["line", "json", "summary", "polybar", "lemonbar"] {
+            let writer = registry.create(name, &options).unwrap();
+            block_on(writer.write(&DeviceEvent::new("/dev", changes.clone()))).unwrap();
+        }
+        assert!(registry.create("xml", &options).is_err());
+
+        registry.register("json", |_| Err(std::io::Error::other("replaced")));
+        registry.register("null", |o| Ok(Box::new(LineWriter::new(o.output_file, "", "", false)?)));
+        assert!(registry.create("json", &options).is_err());
+        assert!(registry.create("null", &options).is_ok());
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            vec!("line", "json", "summary", "polybar", "lemonbar", "null")
+        );
+    }
+
+    /// Test that additional outputs are parsed with and without a path.
+    #[test]
+    fn output_specs() {
+        assert_eq!(
+            "json=/var/log/upmon.json".parse::<OutputSpec>(),
+            Ok(OutputSpec {
+                format: String::from("json"),
+                output_file: Some(String::from("/var/log/upmon.json"))
+            })
+        );
+        assert_eq!(
+            "line".parse::<OutputSpec>(),
+            Ok(OutputSpec { format: String::from("line"), output_file: None })
+        );
+        assert!("json=".parse::<OutputSpec>().is_err());
+        assert!("=/tmp/out".parse::<OutputSpec>().is_err());
+    }
+}
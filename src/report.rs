@@ -0,0 +1,224 @@
+use std::time::Duration;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future::pending;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::rt::sleep;
+use crate::throttle::Throttle;
+use crate::upower::{Property, PropertyKind, STATE_CHARGING, STATE_DISCHARGING};
+
+/// The marker written at the end of each reporting period. The device path and its statistics for
+/// the period (see [`DeviceStats::report`]) are appended to the marker, separated by spaces.
+pub(crate) const REPORT_MARKER: &str = "Report";
+
+/// Statistics about a single device, accumulated over a reporting period.
+#[derive(Debug, Default)]
+struct DeviceStats {
+    /// The latest `State` of the device.
+    state: Option<u32>,
+    /// The latest `Percentage` of the device.
+    percentage: Option<f64>,
+    /// The lowest and highest `Percentage` seen during the period.
+    range: Option<(f64, f64)>,
+    /// The time spent discharging during the period, up to `discharging_since`.
+    on_battery: chrono::Duration,
+    /// When the device started discharging (or when the period started, if later), if it is
+    /// discharging.
+    discharging_since: Option<DateTime<Utc>>,
+    /// The number of times the device started charging after discharging during the period.
+    cycles: u32,
+    /// The sum and number of the values of `EnergyRate` seen while discharging during the period.
+    rates: (f64, u32)
+}
+
+impl DeviceStats {
+    /// Update the statistics with the changes described by `event`.
+    fn update(&mut self, event: &DeviceEvent) {
+        if let Some(Property::Percentage(p)) = event.get(&PropertyKind::Percentage) {
+            self.percentage = Some(*p);
+            self.range = Some(match self.range {
+                Some((min, max)) => (min.min(*p), max.max(*p)),
+                None => (*p, *p)
+            });
+        }
+        if let Some(Property::State(s)) = event.get(&PropertyKind::State) {
+            if self.state == Some(STATE_DISCHARGING) && *s == STATE_CHARGING {
+                self.cycles += 1;
+            }
+            match (self.discharging_since, *s == STATE_DISCHARGING) {
+                (None, true) => self.discharging_since = Some(event.timestamp),
+                (Some(since), false) => {
+                    self.on_battery += event.timestamp - since;
+                    self.discharging_since = None;
+                },
+                _ => {}
+            }
+            self.state = Some(*s);
+        }
+        if let Some(Property::EnergyRate(r)) = event.get(&PropertyKind::EnergyRate) {
+            if self.state == Some(STATE_DISCHARGING) {
+                self.rates.0 += r;
+                self.rates.1 += 1;
+            }
+        }
+    }
+
+    /// Describe the period ending at `now` as space-separated `key=value` pairs: `min` and `max`
+    /// (the lowest and highest percentage, if known), `on_battery` (the number of seconds spent
+    /// discharging), `cycles` (the number of times the device started charging after discharging)
+    /// and `rate` (the average EnergyRate while discharging, if known). The statistics are then
+    /// reset for the next period.
+    fn report(&mut self, now: DateTime<Utc>) -> String {
+        if let Some(since) = self.discharging_since {
+            self.on_battery += now - since;
+            self.discharging_since = Some(now);
+        }
+        let mut report = String::new();
+        if let Some((min, max)) = self.range {
+            report.push_str(&format!("min={min:.1} max={max:.1} "));
+        }
+        report.push_str(&format!(
+            "on_battery={} cycles={}",
+            self.on_battery.num_seconds(),
+            self.cycles
+        ));
+        if self.rates.1 > 0 {
+            report.push_str(&format!(" rate={:.2}", self.rates.0 / f64::from(self.rates.1)));
+        }
+        self.range = self.percentage.map(|p| (p, p));
+        self.on_battery = chrono::Duration::zero();
+        self.cycles = 0;
+        self.rates = (0.0, 0);
+        report
+    }
+}
+
+/// A [`Writer`] which passes all changes on to an inner [`Writer`], and accumulates statistics
+/// about each device which [`ReportWriter::run`] writes as a [`REPORT_MARKER`] at the end of each
+/// reporting period.
+pub struct ReportWriter<W: Writer> {
+    /// The writer to which changes and reports are passed.
+    inner: W,
+    /// The length of each reporting period, or `None` if no reports should be written.
+    period: Option<Duration>,
+    /// The statistics of each device, in the order in which each device first changed.
+    devices: Mutex<Vec<(String, DeviceStats)>>
+}
+
+impl<W: Writer> ReportWriter<W> {
+    /// Create a new [`ReportWriter`] which passes changes to `inner`, reporting on each device
+    /// once per `period`.
+    pub(crate) fn new(inner: W, period: Option<Duration>) -> Self {
+        Self {
+            inner,
+            period,
+            devices: Mutex::new(vec!())
+        }
+    }
+
+    /// Write a report for each device for the period ending at `now`, and start a new period.
+    async fn report(&self, now: DateTime<Utc>) -> Result<(), std::io::Error> {
+        let reports = self.devices.lock().await.iter_mut()
+            .map(|(path, stats)| format!("{REPORT_MARKER} {path} {}", stats.report(now)))
+            .collect::<Vec<_>>();
+        for report in reports {
+            self.inner.write_marker(&report).await?;
+        }
+        Ok(())
+    }
+
+    /// Write a report for each device at the end of each period, lengthened by `throttle` while on
+    /// battery. If no period is set, this never completes.
+    pub(crate) async fn run(&self, throttle: &Throttle) -> Result<(), std::io::Error> {
+        let Some(period) = self.period else {
+            return pending().await
+        };
+        loop {
+            sleep(throttle.interval(period)).await;
+            self.report(Utc::now()).await?;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for ReportWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        if self.period.is_some() {
+            let mut devices = self.devices.lock().await;
+            match devices.iter_mut().find(|(d, _)| *d == event.device) {
+                Some((_, stats)) => stats.update(event),
+                None => {
+                    let mut stats = DeviceStats::default();
+                    stats.update(event);
+                    devices.push((event.device.clone(), stats));
+                }
+            }
+        }
+        self.inner.write(event).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+    use chrono::{DateTime, Utc};
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::report::ReportWriter;
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{self, EnergyRate, Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that reports include the range of percentages, time on battery, cycles and average
+    /// discharge rate for the period, and that statistics are reset for the next period.
+    #[test]
+    fn reports() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = ReportWriter::new(inner, Some(Duration::from_secs(86400)));
+        let time = |t: &str| t.parse::<DateTime<Utc>>().unwrap();
+        let event = |t: &str, changes: Vec<(PropertyKind, Property)>| {
+            let mut event = DeviceEvent::new("/dev", changes);
+            event.timestamp = time(t);
+            event
+        };
+        block_on(async {
+            for e in [
+                event("2024-02-11T09:00:00Z", vec!(
+                    (PropertyKind::State, State(2)),
+                    (PropertyKind::Percentage, Percentage(90.0))
+                )),
+                event("2024-02-11T09:30:00Z", vec!((PropertyKind::EnergyRate, EnergyRate(10.0)))),
+                event("2024-02-11T10:00:00Z", vec!(
+                    (PropertyKind::EnergyRate, EnergyRate(8.0)),
+                    (PropertyKind::Percentage, Percentage(60.0))
+                )),
+                event("2024-02-11T10:00:00Z", vec!((PropertyKind::State, State(1)))),
+                event("2024-02-11T11:00:00Z", vec!(
+                    (PropertyKind::EnergyRate, EnergyRate(30.0)),
+                    (PropertyKind::Percentage, Percentage(95.0))
+                )),
+                event("2024-02-11T11:30:00Z", vec!((PropertyKind::State, State(2))))
+            ] {
+                writer.write(&e).await.unwrap();
+            }
+            writer.report(time("2024-02-11T12:00:00Z")).await.unwrap();
+            writer.report(time("2024-02-11T13:00:00Z")).await.unwrap();
+        });
+        let reports = buf.contents().lines()
+            .filter(|l| l.starts_with("Report"))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert_eq!(reports, vec!(
+            "Report /dev min=60.0 max=95.0 on_battery=5400 cycles=1 rate=9.00",
+            "Report /dev min=95.0 max=95.0 on_battery=3600 cycles=0"
+        ));
+    }
+}
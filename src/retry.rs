@@ -0,0 +1,235 @@
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use crate::rt::{sleep, timeout};
+use crate::stats::{increment, Counter};
+
+/// How long connecting to, writing to or reading from a server to which changes are sent may
+/// take before it is treated as having failed.
+pub(crate) const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait between attempts to connect (or reconnect) to something which has failed or
+/// been lost: the system bus and other buses while upmon is starting, plugins which exit, and the
+/// servers to which network writers send changes. Each delay is `multiplier` times longer than the
+/// last, up to `max_delay`, and is shortened by a random part of up to `jitter` of its length so
+/// that many instances of upmon which lose a connection at once do not all retry at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RetryPolicy {
+    /// How long to wait before the first retry.
+    pub(crate) initial_delay: Duration,
+    /// How many times longer to wait before each retry than before the last.
+    pub(crate) multiplier: u32,
+    /// The longest time to wait between retries.
+    pub(crate) max_delay: Duration,
+    /// The largest fraction (between 0 and 1) by which each delay is randomly shortened.
+    pub(crate) jitter: f64,
+    /// The number of attempts after which to give up, or `None` to keep trying.
+    pub(crate) max_attempts: Option<u32>
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2,
+            max_delay: Duration::from_secs(5),
+            jitter: 0.2,
+            max_attempts: None
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Return how long to wait after the given number of consecutive failed attempts before trying
+    /// again.
+    pub(crate) fn delay(&self, failures: u32) -> Duration {
+        let delay = self.initial_delay
+            .saturating_mul(self.multiplier.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_delay);
+        // A random number between 0 and 1, from the randomly keyed hasher used by `HashMap`.
+        let random = (RandomState::new().hash_one(failures) >> 11) as f64 / (1u64 << 53) as f64;
+        delay.mul_f64(1.0 - self.jitter * random)
+    }
+
+    /// Return whether no more attempts should be made after the given number of consecutive
+    /// failed attempts.
+    fn exhausted(&self, failures: u32) -> bool {
+        self.max_attempts.is_some_and(|max| failures >= max)
+    }
+
+    /// Call `attempt` until it succeeds, waiting between attempts as set out by the policy. Once
+    /// `timeout` has elapsed (or the maximum number of attempts has been made), the last error is
+    /// returned instead of retrying.
+    pub(crate) async fn retry<T, E>(
+        &self,
+        timeout: Duration,
+        attempt: impl AsyncFn() -> Result<T, E>
+    ) -> Result<T, E> {
+        let deadline = Instant::now() + timeout;
+        let mut failures = 0;
+        loop {
+            match attempt().await {
+                Ok(v) => return Ok(v),
+                Err(e) => {
+                    failures += 1;
+                    let now = Instant::now();
+                    if now >= deadline || self.exhausted(failures) {
+                        return Err(e)
+                    }
+                    sleep(self.delay(failures).min(deadline - now)).await;
+                }
+            }
+        }
+    }
+}
+
+/// The state of a connection which is remade when needed, such as when a change is to be written,
+/// rather than in a loop. Failed attempts are counted so that the next attempt can be put off for
+/// as long as the [`RetryPolicy`] says; changes written in the meantime are dropped with an error.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    /// The policy setting out how long to wait between attempts.
+    policy: RetryPolicy,
+    /// The number of attempts which have failed since the last one succeeded.
+    failures: u32,
+    /// When the next attempt may be made.
+    next_attempt: Instant
+}
+
+impl Backoff {
+    /// Create a new [`Backoff`], allowing the first attempt to be made immediately.
+    pub(crate) fn new(policy: RetryPolicy) -> Self {
+        Self { policy, failures: 0, next_attempt: Instant::now() }
+    }
+
+    /// Return whether an attempt may be made now, or a description of why not.
+    pub(crate) fn ready(&self) -> Result<(), String> {
+        if self.policy.exhausted(self.failures) {
+            return Err(format!("gave up after {} failed attempts", self.failures))
+        }
+        match self.next_attempt.checked_duration_since(Instant::now()) {
+            Some(wait) if !wait.is_zero() => Err(format!(
+                "retrying in {}ms after {} failed attempts",
+                wait.as_millis(),
+                self.failures
+            )),
+            _ => Ok(())
+        }
+    }
+
+    /// Record that an attempt failed, putting off the next one.
+    pub(crate) fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        self.next_attempt = Instant::now() + self.policy.delay(self.failures);
+    }
+
+    /// Record that an attempt succeeded, so that the next one (if the connection is lost again)
+    /// may be made immediately.
+    pub(crate) fn succeeded(&mut self) {
+        self.failures = 0;
+        self.next_attempt = Instant::now();
+    }
+}
+
+/// Log and count the error, if any, from writing to `sink`, which is remade as set out by a
+/// [`RetryPolicy`], rather than returning it. Such a sink is expected to fail while its server is
+/// away, and that must not stop changes being written to the primary output and the other sinks.
+pub(crate) fn tolerate(sink: &str, result: Result<(), Error>) -> Result<(), Error> {
+    if let Err(e) = result {
+        eprintln!("Error writing to {sink}: {e}");
+        increment(Counter::WriteErrors);
+    }
+    Ok(())
+}
+
+/// Wait for `io`, an exchange with a server to which changes are sent, failing with a
+/// [`ErrorKind::TimedOut`] error if it does not complete within `limit` (normally
+/// [`NETWORK_TIMEOUT`]), so that a server which stops responding cannot hold up a writer forever.
+pub(crate) async fn within<T>(limit: Duration, io: impl Future<Output = Result<T, Error>>)
+    -> Result<T, Error> {
+    timeout(limit, io).await.unwrap_or_else(|_| Err(Error::new(
+        ErrorKind::TimedOut,
+        format!("No response within {}ms", limit.as_millis())
+    )))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::ErrorKind;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use futures::future::pending;
+    use crate::retry::{within, Backoff, RetryPolicy};
+    use crate::rt::block_on;
+
+    /// Test that attempts are retried until they succeed, or until the timeout has elapsed.
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy { jitter: 0.0, ..RetryPolicy::default() };
+        block_on(async {
+            let attempts = AtomicUsize::new(0);
+            let attempt = async || match attempts.fetch_add(1, Ordering::SeqCst) {
+                n if n < 2 => Err(n),
+                n => Ok(n)
+            };
+            assert_eq!(policy.retry(Duration::from_secs(5), attempt).await, Ok(2));
+
+            let attempts = AtomicUsize::new(0);
+            let start = Instant::now();
+            let failed = policy.retry(
+                Duration::from_millis(250),
+                async || Err::<(), _>(attempts.fetch_add(1, Ordering::SeqCst))
+            ).await;
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(250) && elapsed < Duration::from_secs(1));
+            // Attempts are made after 0, 100 and 250ms (when the timeout has elapsed).
+            assert_eq!(failed, Err(2));
+
+            let failed = policy.retry(Duration::ZERO, async || Err::<(), _>("error")).await;
+            assert_eq!(failed, Err("error"));
+
+            let limited = RetryPolicy { max_attempts: Some(3), ..policy };
+            let attempts = AtomicUsize::new(0);
+            let failed = limited.retry(
+                Duration::from_secs(5),
+                async || Err::<(), _>(attempts.fetch_add(1, Ordering::SeqCst))
+            ).await;
+            assert_eq!(failed, Err(2));
+        })
+    }
+
+    /// Test that delays grow up to the maximum and are shortened by no more than the jitter, and
+    /// that attempts are put off after a failure until the delay has passed.
+    #[test]
+    fn delays() {
+        let policy = RetryPolicy::default();
+        for (failures, nominal) in [(1, 100), (2, 200), (6, 3200), (7, 5000), (40, 5000)] {
+            let delay = policy.delay(failures);
+            assert!(delay <= Duration::from_millis(nominal), "{failures}: {delay:?}");
+            assert!(delay >= Duration::from_millis(nominal * 4 / 5), "{failures}: {delay:?}");
+        }
+
+        let mut backoff = Backoff::new(RetryPolicy { max_attempts: Some(2), ..policy });
+        assert_eq!(backoff.ready(), Ok(()));
+        backoff.failed();
+        assert!(backoff.ready().is_err());
+        backoff.succeeded();
+        assert_eq!(backoff.ready(), Ok(()));
+        backoff.failed();
+        backoff.failed();
+        assert_eq!(backoff.ready(), Err(String::from("gave up after 2 failed attempts")));
+    }
+
+    /// Test that an exchange with a server which does not complete in time fails.
+    #[test]
+    fn time_limit() {
+        block_on(async {
+            let limit = Duration::from_millis(50);
+            let err = within(limit, pending::<Result<(), _>>()).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert_eq!(err.to_string(), "No response within 50ms");
+            assert_eq!(within(limit, async { Ok(1) }).await.unwrap(), 1);
+        })
+    }
+}
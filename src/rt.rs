@@ -0,0 +1,238 @@
+// The async runtime which upmon runs on: async-std by default, or tokio if the `tokio` feature
+// is enabled (in which case zbus also uses tokio). Everything that depends on the runtime is
+// accessed through this module, so the rest of upmon is runtime-independent. Network types have
+// the same API (and implement the same `futures` I/O traits) whichever runtime is used.
+
+#[cfg(not(any(feature = "async-std", feature = "tokio")))]
+compile_error!("Either the \"async-std\" or the \"tokio\" feature must be enabled");
+
+#[cfg(not(feature = "tokio"))]
+pub(crate) use async_std_rt::*;
+#[cfg(feature = "tokio")]
+pub(crate) use tokio_rt::*;
+
+/// Runtime-dependent functionality provided by async-std.
+#[cfg(not(feature = "tokio"))]
+mod async_std_rt {
+    use std::io::Error;
+    use std::os::fd::OwnedFd;
+    use async_io::Async;
+
+    pub(crate) use async_std::future::timeout;
+    pub(crate) use async_std::net::{TcpListener, TcpStream, UdpSocket};
+    pub(crate) use async_std::os::unix::net::{UnixListener, UnixStream};
+    pub(crate) use async_std::task::{block_on, sleep, spawn_blocking};
+
+    /// Return a Unix stream over which zbus can communicate, from a standard library stream.
+    pub(crate) fn bus_stream(stream: std::os::unix::net::UnixStream)
+        -> std::io::Result<std::os::unix::net::UnixStream> {
+        Ok(stream)
+    }
+
+    /// Return a connected pair of Unix streams over which zbus can communicate.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn bus_stream_pair()
+        -> std::io::Result<(std::os::unix::net::UnixStream, std::os::unix::net::UnixStream)> {
+        std::os::unix::net::UnixStream::pair()
+    }
+
+    /// A non-blocking file descriptor (such as a socket) which is read when it becomes readable,
+    /// rather than by blocking a thread.
+    pub(crate) struct AsyncFd(Async<OwnedFd>);
+
+    impl AsyncFd {
+        /// Wait for `fd`, which must be non-blocking, to become readable.
+        pub(crate) fn new(fd: OwnedFd) -> Result<Self, Error> {
+            Async::new(fd).map(Self)
+        }
+
+        /// Call `read` each time the file descriptor becomes readable, until it returns something
+        /// other than a [`std::io::ErrorKind::WouldBlock`] error, and return its result.
+        pub(crate) async fn read_with<T>(&self, read: impl FnMut(&OwnedFd) -> Result<T, Error>)
+            -> Result<T, Error> {
+            self.0.read_with(read).await
+        }
+    }
+}
+
+/// Runtime-dependent functionality provided by tokio.
+#[cfg(feature = "tokio")]
+mod tokio_rt {
+    use std::future::Future;
+    use std::io::Error;
+    use std::net::SocketAddr;
+    use std::os::fd::OwnedFd;
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use futures::io::{AsyncRead, AsyncWrite};
+    use futures::stream::{unfold, Stream};
+    use tokio::net::ToSocketAddrs;
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    pub(crate) use tokio::net::UdpSocket;
+    pub(crate) use tokio::time::sleep;
+    pub(crate) use tokio::time::timeout;
+
+    /// Run `future` to completion on a new multi-threaded runtime. Blocking tasks which are still
+    /// running (such as exchanging an event with a plugin) are not waited for.
+    pub(crate) fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Could not start tokio runtime");
+        let result = runtime.block_on(future);
+        runtime.shutdown_background();
+        result
+    }
+
+    /// Run the blocking function `f` on a thread where blocking is acceptable, and return its
+    /// result.
+    pub(crate) async fn spawn_blocking<T: Send + 'static>(
+        f: impl FnOnce() -> T + Send + 'static
+    ) -> T {
+        match tokio::task::spawn_blocking(f).await {
+            Ok(v) => v,
+            Err(e) => std::panic::resume_unwind(e.into_panic())
+        }
+    }
+
+    /// Return a connected pair of Unix streams over which zbus can communicate.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn bus_stream_pair()
+        -> Result<(tokio::net::UnixStream, tokio::net::UnixStream), Error> {
+        tokio::net::UnixStream::pair()
+    }
+
+    /// Return a Unix stream over which zbus can communicate, from a standard library stream.
+    pub(crate) fn bus_stream(stream: std::os::unix::net::UnixStream)
+        -> Result<tokio::net::UnixStream, Error> {
+        stream.set_nonblocking(true)?;
+        tokio::net::UnixStream::from_std(stream)
+    }
+
+    /// A non-blocking file descriptor (such as a socket) which is read when it becomes readable,
+    /// rather than by blocking a thread.
+    pub(crate) struct AsyncFd(tokio::io::unix::AsyncFd<OwnedFd>);
+
+    impl AsyncFd {
+        /// Wait for `fd`, which must be non-blocking, to become readable.
+        pub(crate) fn new(fd: OwnedFd) -> Result<Self, Error> {
+            tokio::io::unix::AsyncFd::new(fd).map(Self)
+        }
+
+        /// Call `read` each time the file descriptor becomes readable, until it returns something
+        /// other than a [`std::io::ErrorKind::WouldBlock`] error, and return its result.
+        pub(crate) async fn read_with<T>(&self, mut read: impl FnMut(&OwnedFd) -> Result<T, Error>)
+            -> Result<T, Error> {
+            loop {
+                let mut guard = self.0.readable().await?;
+                if let Ok(result) = guard.try_io(|fd| read(fd.get_ref())) {
+                    return result
+                }
+            }
+        }
+    }
+
+    /// Implement the `futures` I/O traits for a wrapper around a [`Compat`] stream.
+    macro_rules! impl_futures_io {
+        ($t:ty) => {
+            impl AsyncRead for $t {
+                fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+                    -> Poll<Result<usize, Error>> {
+                    Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+                }
+            }
+
+            impl AsyncWrite for $t {
+                fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                    -> Poll<Result<usize, Error>> {
+                    Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+                }
+
+                fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>)
+                    -> Poll<Result<(), Error>> {
+                    Pin::new(&mut self.get_mut().0).poll_flush(cx)
+                }
+
+                fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>)
+                    -> Poll<Result<(), Error>> {
+                    Pin::new(&mut self.get_mut().0).poll_close(cx)
+                }
+            }
+        };
+    }
+
+    /// A TCP connection.
+    #[derive(Debug)]
+    pub(crate) struct TcpStream(Compat<tokio::net::TcpStream>);
+
+    impl TcpStream {
+        /// Connect to `addr`.
+        pub(crate) async fn connect(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+            Ok(Self(tokio::net::TcpStream::connect(addr).await?.compat()))
+        }
+    }
+
+    impl_futures_io!(TcpStream);
+
+    /// A listener for TCP connections.
+    #[derive(Debug)]
+    pub(crate) struct TcpListener(tokio::net::TcpListener);
+
+    impl TcpListener {
+        /// Listen for connections on `addr`.
+        pub(crate) async fn bind(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+            tokio::net::TcpListener::bind(addr).await.map(Self)
+        }
+
+        /// Wait for and return the next connection, along with the address it is from.
+        pub(crate) async fn accept(&self) -> Result<(TcpStream, SocketAddr), Error> {
+            let (stream, addr) = self.0.accept().await?;
+            Ok((TcpStream(stream.compat()), addr))
+        }
+
+        /// Return the address on which connections are being listened for.
+        #[cfg(test)]
+        pub(crate) fn local_addr(&self) -> Result<SocketAddr, Error> {
+            self.0.local_addr()
+        }
+
+        /// Return a stream of incoming connections.
+        pub(crate) fn incoming(&self) -> impl Stream<Item = Result<TcpStream, Error>> + '_ {
+            unfold(self, |l| async move { Some((l.accept().await.map(|(s, _)| s), l)) })
+        }
+    }
+
+    /// A Unix socket connection.
+    #[derive(Debug)]
+    pub(crate) struct UnixStream(Compat<tokio::net::UnixStream>);
+
+    impl UnixStream {
+        /// Connect to the socket at `path`.
+        #[cfg(test)]
+        pub(crate) async fn connect(path: impl AsRef<Path>) -> Result<Self, Error> {
+            Ok(Self(tokio::net::UnixStream::connect(path).await?.compat()))
+        }
+    }
+
+    impl_futures_io!(UnixStream);
+
+    /// A listener for Unix socket connections.
+    #[derive(Debug)]
+    pub(crate) struct UnixListener(tokio::net::UnixListener);
+
+    impl UnixListener {
+        /// Listen for connections on the socket at `path`.
+        pub(crate) async fn bind(path: impl AsRef<Path>) -> Result<Self, Error> {
+            tokio::net::UnixListener::bind(path).map(Self)
+        }
+
+        /// Return a stream of incoming connections.
+        pub(crate) fn incoming(&self) -> impl Stream<Item = Result<UnixStream, Error>> + '_ {
+            unfold(self, |l| async move {
+                Some((l.0.accept().await.map(|(s, _)| UnixStream(s.compat())), l))
+            })
+        }
+    }
+}
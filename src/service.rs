@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::io::Error;
+use async_trait::async_trait;
+use zbus::{
+    dbus_interface, Connection, ConnectionBuilder, Result as zbus_Result, SignalContext,
+    zvariant::{OwnedValue, Value}
+};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+
+/// The default bus name claimed by upmon when running as a D-Bus service.
+pub(crate) const DEFAULT_SERVICE_NAME: &str = "io.github.bunburya.upmon";
+
+/// The object path at which upmon's interface is served.
+pub(crate) const SERVICE_PATH: &str = "/io/github/bunburya/upmon";
+
+/// Convert the JSON representation of a property value (see [`Property::to_json`]) to a D-Bus
+/// value, so that enumerated properties are given by name as they are in other output.
+fn to_variant(value: &serde_json::Value) -> OwnedValue {
+    match value {
+        serde_json::Value::Bool(b) => Value::from(*b).into(),
+        serde_json::Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => Value::from(i).into(),
+            (None, Some(f)) => Value::from(f).into(),
+            (None, None) => Value::from(n.to_string()).into()
+        },
+        serde_json::Value::String(s) => Value::from(s.clone()).into(),
+        other => Value::from(other.to_string()).into()
+    }
+}
+
+/// The `io.github.bunburya.upmon` interface, which caches the latest value of each property of
+/// each device so that it can be returned by `GetState`.
+#[derive(Debug, Default)]
+pub(crate) struct Service {
+    /// The latest value of each property of each device.
+    state: HashMap<String, HashMap<String, OwnedValue>>
+}
+
+#[dbus_interface(name = "io.github.bunburya.upmon")]
+impl Service {
+    /// Return the latest value of each property of each device, keyed by device path.
+    fn get_state(&self) -> HashMap<String, HashMap<String, OwnedValue>> {
+        self.state.clone()
+    }
+
+    /// Emitted when monitored properties of a device change.
+    #[dbus_interface(signal)]
+    async fn changed(
+        ctxt: &SignalContext<'_>,
+        device: &str,
+        changes: HashMap<String, OwnedValue>
+    ) -> zbus::Result<()>;
+
+    /// Emitted when a marker (such as "Resumed") is written.
+    #[dbus_interface(signal)]
+    async fn marker(ctxt: &SignalContext<'_>, marker: &str) -> zbus::Result<()>;
+}
+
+/// A [`Writer`] which re-emits changes and markers as signals of the `io.github.bunburya.upmon`
+/// interface, served at [`SERVICE_PATH`].
+pub struct ServiceWriter {
+    /// The connection on which the interface is served.
+    conn: Connection
+}
+
+impl ServiceWriter {
+    /// Create a new [`ServiceWriter`] which claims the given name on the session bus.
+    pub(crate) async fn new(name: &str) -> zbus_Result<Self> {
+        let conn = ConnectionBuilder::session()?
+            .name(name)?
+            .serve_at(SERVICE_PATH, Service::default())?
+            .build()
+            .await?;
+        Ok(Self { conn })
+    }
+
+    /// Create a new [`ServiceWriter`] which serves the interface on the given connection.
+    #[cfg(test)]
+    pub(crate) async fn from_connection(conn: Connection) -> zbus_Result<Self> {
+        conn.object_server().at(SERVICE_PATH, Service::default()).await?;
+        Ok(Self { conn })
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for ServiceWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        let iface_ref = self.conn.object_server()
+            .interface::<_, Service>(SERVICE_PATH)
+            .await
+            .map_err(Error::other)?;
+        let changes = event.iter()
+            .map(|(k, v)| (String::from(k.as_str()), to_variant(&v.to_json())))
+            .collect::<HashMap<_, _>>();
+        iface_ref.get_mut().await.state
+            .entry(event.device.clone())
+            .or_default()
+            .extend(changes.clone());
+        Service::changed(iface_ref.signal_context(), &event.device, changes).await
+            .map_err(Error::other)
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        let ctxt = SignalContext::new(&self.conn, SERVICE_PATH).map_err(Error::other)?;
+        Service::marker(&ctxt, marker).await.map_err(Error::other)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use futures::{StreamExt, try_join};
+    use zbus::{ConnectionBuilder, Guid, MessageStream};
+    use zbus::zvariant::{OwnedValue, Value};
+    use crate::event::DeviceEvent;
+    use crate::output::Writer;
+    use crate::rt::{block_on, bus_stream_pair};
+    use crate::service::{SERVICE_PATH, ServiceWriter};
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that changes are emitted as signals and returned by GetState.
+    #[test]
+    fn service_writer() {
+        block_on(async {
+            let (server_stream, client_stream) = bus_stream_pair().unwrap();
+            let guid = Guid::generate();
+            let server = ConnectionBuilder::unix_stream(server_stream)
+                .server(&guid)
+                .p2p()
+                .build();
+            let client = ConnectionBuilder::unix_stream(client_stream).p2p().build();
+            let (server, client) = try_join!(server, client).unwrap();
+            let writer = ServiceWriter::from_connection(server).await.unwrap();
+            let mut signals = MessageStream::from(&client);
+
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(80.0));
+            changes.insert(PropertyKind::State, State(2));
+            writer.write(&DeviceEvent::new("/dev/battery", changes)).await.unwrap();
+            writer.write_marker("Resumed").await.unwrap();
+
+            let signal = signals.next().await.unwrap().unwrap();
+            assert_eq!(signal.member().unwrap().as_str(), "Changed");
+            let (device, changes): (String, HashMap<String, OwnedValue>) =
+                signal.body().unwrap();
+            assert_eq!(device, "/dev/battery");
+            assert_eq!(&*changes["State"], &Value::from("Discharging"));
+            let signal = signals.next().await.unwrap().unwrap();
+            assert_eq!(signal.member().unwrap().as_str(), "Marker");
+            assert_eq!(signal.body::<String>().unwrap(), "Resumed");
+
+            let reply = client.call_method(
+                None::<&str>,
+                SERVICE_PATH,
+                Some("io.github.bunburya.upmon"),
+                "GetState",
+                &()
+            ).await.unwrap();
+            let state: HashMap<String, HashMap<String, OwnedValue>> = reply.body().unwrap();
+            assert_eq!(&*state["/dev/battery"]["Percentage"], &Value::from(80.0));
+        })
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind, STATE_CHARGING, STATE_DISCHARGING};
+
+/// The marker written when a charging or discharging session ends. The device path, the state of
+/// the session (`Charging` or `Discharging`) and its details (see [`Session::summary`]) are
+/// appended to the marker, separated by spaces.
+pub(crate) const SESSION_MARKER: &str = "Session";
+
+/// A period during which a device was continuously charging or discharging.
+#[derive(Debug)]
+struct Session {
+    /// The `State` of the device during the session.
+    state: u32,
+    /// When the session started.
+    start: DateTime<Utc>,
+    /// The device's `Percentage` when the session started, if known.
+    start_percentage: Option<f64>
+}
+
+impl Session {
+    /// Describe the session, which ended at `end` with the device at `end_percentage`, as
+    /// space-separated `key=value` pairs: `start` and `end` (in RFC 3339 format), `duration` (in
+    /// seconds) and, if the device's percentage is known, `from` and `to` (percentages). If the
+    /// device's `EnergyFull` is also known, `energy` gives the change in energy (in Wh, negative
+    /// while discharging) and `rate` the average rate at which it changed (in W).
+    fn summary(
+        &self,
+        end: DateTime<Utc>,
+        end_percentage: Option<f64>,
+        energy_full: Option<f64>
+    ) -> String {
+        let state = if self.state == STATE_CHARGING { "Charging" } else { "Discharging" };
+        let duration = (end - self.start).num_milliseconds() as f64 / 1000.0;
+        let mut summary = format!(
+            "{state} start={} end={} duration={duration:.0}",
+            self.start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            end.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        if let (Some(from), Some(to)) = (self.start_percentage, end_percentage) {
+            summary.push_str(&format!(" from={from:.1} to={to:.1}"));
+            if let Some(full) = energy_full {
+                let energy = (to - from) / 100.0 * full;
+                summary.push_str(&format!(" energy={energy:.2}"));
+                if duration > 0.0 {
+                    summary.push_str(&format!(" rate={:.2}", energy.abs() / (duration / 3600.0)));
+                }
+            }
+        }
+        summary
+    }
+}
+
+/// What is known about a single device.
+#[derive(Debug, Default)]
+struct DeviceSessions {
+    /// The latest `Percentage` of the device.
+    percentage: Option<f64>,
+    /// The latest `EnergyFull` of the device.
+    energy_full: Option<f64>,
+    /// The session in progress, if the device is charging or discharging.
+    current: Option<Session>
+}
+
+/// A [`Writer`] which passes all changes on to an inner [`Writer`], and additionally tracks the
+/// periods during which each device is charging or discharging, writing a [`SESSION_MARKER`]
+/// summarising each period when the device's `State` changes to end it. `State` and `Percentage`
+/// must be monitored, and `EnergyFull` should be to report the energy used.
+pub struct SessionWriter<W: Writer> {
+    /// The writer to which changes and session summaries are passed.
+    inner: W,
+    /// Whether sessions should be tracked.
+    enabled: bool,
+    /// What is known about each device.
+    devices: Mutex<HashMap<String, DeviceSessions>>
+}
+
+impl<W: Writer> SessionWriter<W> {
+    /// Create a new [`SessionWriter`] which passes changes to `inner`, tracking sessions if
+    /// `enabled` is true.
+    pub(crate) fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            devices: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for SessionWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        self.inner.write(event).await?;
+        if !self.enabled {
+            return Ok(())
+        }
+        let ended = {
+            let mut devices = self.devices.lock().await;
+            let device = devices.entry(event.device.clone()).or_default();
+            if let Some(Property::EnergyFull(e)) = event.get(&PropertyKind::EnergyFull) {
+                device.energy_full = Some(*e);
+            }
+            if let Some(Property::Percentage(p)) = event.get(&PropertyKind::Percentage) {
+                device.percentage = Some(*p);
+            }
+            match event.get(&PropertyKind::State) {
+                Some(Property::State(s))
+                    if device.current.as_ref().map(|c| c.state) != Some(*s) => {
+                    let ended = device.current.take().map(|c| {
+                        c.summary(event.timestamp, device.percentage, device.energy_full)
+                    });
+                    if *s == STATE_CHARGING || *s == STATE_DISCHARGING {
+                        device.current = Some(Session {
+                            state: *s,
+                            start: event.timestamp,
+                            start_percentage: device.percentage
+                        });
+                    }
+                    ended
+                },
+                _ => None
+            }
+        };
+        match ended {
+            Some(summary) => self.inner
+                .write_marker(&format!("{SESSION_MARKER} {} {summary}", event.device))
+                .await,
+            None => Ok(())
+        }
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chrono::{DateTime, Utc};
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::session::SessionWriter;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{self, EnergyFull, Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that a session summary is written when a device stops discharging, including the
+    /// energy used, and that a new session starts when it starts charging.
+    #[test]
+    fn sessions() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = SessionWriter::new(inner, true);
+        let event = |time: &str, changes: Vec<(PropertyKind, Property)>| {
+            let mut event = DeviceEvent::new("/dev", changes);
+            event.timestamp = time.parse::<DateTime<Utc>>().unwrap();
+            event
+        };
+        block_on(async {
+            for e in [
+                event("2024-02-11T09:00:00Z", vec!(
+                    (PropertyKind::State, State(2)),
+                    (PropertyKind::Percentage, Percentage(90.0)),
+                    (PropertyKind::EnergyFull, EnergyFull(50.0))
+                )),
+                event("2024-02-11T09:30:00Z", vec!((PropertyKind::Percentage, Percentage(80.0)))),
+                event("2024-02-11T10:00:00Z", vec!(
+                    (PropertyKind::Percentage, Percentage(70.0)),
+                    (PropertyKind::State, State(2))
+                )),
+                event("2024-02-11T10:00:05Z", vec!((PropertyKind::State, State(1)))),
+                event("2024-02-11T10:00:10Z", vec!((PropertyKind::State, State(0))))
+            ] {
+                writer.write(&e).await.unwrap();
+            }
+        });
+        let markers = buf.contents().lines()
+            .filter(|l| l.starts_with("Session"))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert_eq!(markers, vec!(
+            "Session /dev Discharging start=2024-02-11T09:00:00Z end=2024-02-11T10:00:05Z \
+                duration=3605 from=90.0 to=70.0 energy=-10.00 rate=9.99",
+            "Session /dev Charging start=2024-02-11T10:00:05Z end=2024-02-11T10:00:10Z \
+                duration=5 from=70.0 to=70.0 energy=0.00 rate=0.00"
+        ));
+    }
+}
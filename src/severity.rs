@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use strum::VariantNames;
+use zbus::zvariant::Value;
+use crate::cache::ValueCache;
+use crate::event::DeviceEvent;
+use crate::expr::Expr;
+use crate::filter::Sink;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+
+/// A classification of how urgently a device's state requires attention.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Critical
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical"
+        })
+    }
+}
+
+impl Severity {
+    /// Return the severity added to `event` by a [`SeverityWriter`], if any.
+    pub(crate) fn of(event: &DeviceEvent) -> Option<Self> {
+        match event.get(&PropertyKind::Severity)?.to_string().as_str() {
+            "ok" => Some(Severity::Ok),
+            "warning" => Some(Severity::Warning),
+            "critical" => Some(Severity::Critical),
+            _ => None
+        }
+    }
+}
+
+/// The colours in which status bar formats show devices whose [`Severity`] is warning or critical.
+/// Devices whose severity is ok (or not known) are shown in the bar's default colour.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeverityColors {
+    /// The colour of devices whose severity is warning, such as `#FFFF00`.
+    pub warning: String,
+    /// The colour of devices whose severity is critical, such as `#FF0000`.
+    pub critical: String
+}
+
+impl SeverityColors {
+    /// Return the colour in which to show a device with the given latest values, according to
+    /// its `Severity` pseudo-property.
+    pub(crate) fn color(&self, values: &HashMap<PropertyKind, Property>) -> Option<&str> {
+        match values.get(&PropertyKind::Severity)?.to_string().as_str() {
+            "warning" => Some(&self.warning),
+            "critical" => Some(&self.critical),
+            _ => None
+        }
+    }
+}
+
+impl Default for SeverityColors {
+    fn default() -> Self {
+        Self { warning: String::from("#FFFF00"), critical: String::from("#FF0000") }
+    }
+}
+
+/// The conditions under which a device's state is classified as each [`Severity`].
+#[derive(Debug)]
+pub struct SeverityBands {
+    /// The condition under which the severity is at least [`Severity::Warning`].
+    warning: Expr,
+    /// The condition under which the severity is [`Severity::Critical`].
+    critical: Expr
+}
+
+impl SeverityBands {
+    /// Create a new [`SeverityBands`] from the given conditions.
+    pub(crate) fn new(warning: Expr, critical: Expr) -> Self {
+        Self { warning, critical }
+    }
+
+    /// Return the names of all properties referred to by the conditions.
+    pub(crate) fn properties(&self) -> Vec<&PropertyKind> {
+        let mut props = self.warning.properties();
+        props.extend(self.critical.properties());
+        props
+    }
+
+    /// Classify a device's state given the latest values of its properties.
+    fn classify(&self, values: &HashMap<PropertyKind, Property>) -> Severity {
+        if self.critical.eval(values) {
+            Severity::Critical
+        } else if self.warning.eval(values) {
+            Severity::Warning
+        } else {
+            Severity::Ok
+        }
+    }
+}
+
+/// A [`Writer`] which adds a device's [`Severity`] to each change before passing it on to an inner
+/// [`Writer`].
+pub struct SeverityWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// The bands used to classify changes, or `None` if changes should not be classified.
+    bands: Mutex<Option<SeverityBands>>,
+    /// The latest value of each property of each device.
+    values: Mutex<ValueCache>
+}
+
+impl<W: Writer> SeverityWriter<W> {
+    /// Create a new [`SeverityWriter`] which passes changes to `inner`, classified using the given
+    /// bands.
+    pub(crate) fn new(inner: W, bands: Option<SeverityBands>) -> Self {
+        Self {
+            inner,
+            bands: Mutex::new(bands),
+            values: Mutex::new(ValueCache::default())
+        }
+    }
+
+    /// Replace the condition under which a device's severity is at least the given severity,
+    /// taking effect from the next change. Returns an error if changes are not being classified, or
+    /// if the severity is [`Severity::Ok`].
+    pub(crate) async fn set_condition(&self, severity: Severity, condition: Expr)
+        -> Result<(), String> {
+        let mut bands = self.bands.lock().await;
+        let Some(bands) = bands.as_mut() else {
+            return Err(String::from("Severity is not enabled"))
+        };
+        match severity {
+            Severity::Warning => bands.warning = condition,
+            Severity::Critical => bands.critical = condition,
+            Severity::Ok => return Err(String::from("Cannot set a condition for severity ok"))
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for SeverityWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let severity = match self.bands.lock().await.as_ref() {
+            Some(bands) => {
+                bands.classify(self.values.lock().await.update(event))
+            },
+            None => return self.inner.write(event).await
+        };
+        let mut classified = event.clone();
+        classified.insert(
+            PropertyKind::Severity,
+            Property::Other(Value::from(severity.to_string()).into())
+        );
+        self.inner.write(&classified).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+/// Something to which changes can be routed by their [`Severity`]: a [`Sink`], or the command run
+/// by `--exec`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteTarget {
+    /// A sink.
+    Sink(Sink),
+    /// The `--exec` command, which is only run for changes of the route's severity or above.
+    Exec
+}
+
+impl Display for RouteTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteTarget::Sink(sink) => write!(f, "{sink}"),
+            RouteTarget::Exec => write!(f, "exec")
+        }
+    }
+}
+
+/// The minimum [`Severity`] of the changes routed to a [`RouteTarget`], given on the command line
+/// in the form `TARGET=SEVERITY`. Changes to devices whose severity is lower are not written to
+/// the target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeverityRoute {
+    /// The target to which the route applies.
+    pub target: RouteTarget,
+    /// The minimum severity of changes written to the target.
+    pub severity: Severity
+}
+
+impl FromStr for SeverityRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, severity) = s.split_once('=')
+            .ok_or_else(|| format!("Expected TARGET=SEVERITY: {s}"))?;
+        let target = match target.trim() {
+            "exec" => RouteTarget::Exec,
+            t => RouteTarget::Sink(t.parse::<Sink>().map_err(|_| format!(
+                "Unknown route target: {t} (expected exec or one of: {})",
+                Sink::VARIANTS.join(", ")
+            ))?)
+        };
+        let severity = match severity.trim() {
+            "warning" => Severity::Warning,
+            "critical" => Severity::Critical,
+            s => return Err(format!("Expected warning or critical: {s}"))
+        };
+        Ok(Self { target, severity })
+    }
+}
+
+impl Display for SeverityRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.target, self.severity)
+    }
+}
+
+/// A [`Writer`] which only passes changes to devices of at least a minimum [`Severity`] on to an
+/// inner [`Writer`], so that each sink can be given only the changes which are urgent enough for
+/// it. Changes without a severity are treated as ok. Markers are always passed on.
+pub struct SeverityRouteWriter<W: Writer> {
+    /// The writer to which changes of at least the minimum severity are passed.
+    inner: W,
+    /// The minimum severity, or `None` if all changes are passed on.
+    severity: Option<Severity>
+}
+
+impl<W: Writer> SeverityRouteWriter<W> {
+    /// Create a new [`SeverityRouteWriter`] which passes changes of at least `severity` (or all
+    /// changes, if `None`) to `inner`.
+    pub(crate) fn new(inner: W, severity: Option<Severity>) -> Self {
+        Self { inner, severity }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for SeverityRouteWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        if self.severity.is_some_and(|s| Severity::of(event).unwrap_or(Severity::Ok) < s) {
+            return Ok(())
+        }
+        self.inner.write(event).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::event::DeviceEvent;
+    use crate::expr::Expr;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::filter::Sink;
+    use crate::severity::{
+        RouteTarget, Severity, SeverityBands, SeverityRoute, SeverityRouteWriter, SeverityWriter
+    };
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{self, Percentage, State, WarningLevel};
+    use crate::upower::PropertyKind;
+
+    /// Return the default severity bands.
+    fn bands() -> SeverityBands {
+        SeverityBands::new(
+            Expr::parse("Percentage <= 20 || WarningLevel >= Low").unwrap(),
+            Expr::parse("Percentage <= 5 || WarningLevel >= Critical").unwrap()
+        )
+    }
+
+    /// Test classification of device states.
+    #[test]
+    fn classify() {
+        let bands = bands();
+        let classify = |props: Vec<(&str, Property)>| bands.classify(
+            &props.into_iter().map(|(k, v)| (PropertyKind::from_name(k), v)).collect()
+        );
+        assert_eq!(classify(vec!()), Severity::Ok);
+        assert_eq!(classify(vec!(("Percentage", Percentage(50.0)))), Severity::Ok);
+        assert_eq!(classify(vec!(("Percentage", Percentage(20.0)))), Severity::Warning);
+        assert_eq!(classify(vec!(("Percentage", Percentage(5.0)))), Severity::Critical);
+        assert_eq!(
+            classify(vec!(("Percentage", Percentage(50.0)), ("WarningLevel", WarningLevel(4)))),
+            Severity::Critical
+        );
+    }
+
+    /// Test that the severity is added to each change, based on the latest values of the device's
+    /// properties.
+    #[test]
+    fn severity_writer() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", "\t", false);
+        let writer = SeverityWriter::new(inner, Some(bands()));
+        for (k, v) in [("Percentage", Percentage(10.0)), ("State", State(2))] {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::from_name(k), v);
+            block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        }
+        let lines = buf.contents().lines()
+            .map(|l| {
+                let mut fields = l.split(['\t', ' ']).collect::<Vec<_>>();
+                fields.sort();
+                fields.join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!(
+            "/dev Percentage=10 Severity=warning",
+            "/dev Severity=warning State=Discharging"
+        ));
+
+        let condition = |c| Expr::parse(c).unwrap();
+        block_on(writer.set_condition(Severity::Warning, condition("Percentage <= 5"))).unwrap();
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(9.0));
+        block_on(writer.write(&DeviceEvent::new("/dev", changes))).unwrap();
+        assert!(buf.contents().lines().last().unwrap().contains("Severity=ok"));
+        assert!(block_on(writer.set_condition(Severity::Ok, condition("Online"))).is_err());
+        let inner = LineWriter::from_writer(Box::new(buf), "=", " ", false);
+        let writer = SeverityWriter::new(inner, None);
+        assert!(block_on(writer.set_condition(Severity::Warning, condition("Online"))).is_err());
+    }
+
+    /// Test that routes are parsed, and that only changes of at least a route's severity are
+    /// passed on.
+    #[test]
+    fn severity_routes() {
+        assert_eq!(
+            "osd=critical".parse::<SeverityRoute>(),
+            Ok(SeverityRoute { target: RouteTarget::Sink(Sink::Osd), severity: Severity::Critical })
+        );
+        assert_eq!("exec=warning".parse::<SeverityRoute>().unwrap().to_string(), "exec=warning");
+        assert!("osd=ok".parse::<SeverityRoute>().is_err());
+        assert!("stdout=warning".parse::<SeverityRoute>().is_err());
+
+        let buf = SharedBuffer::default();
+        let writer = SeverityWriter::new(
+            SeverityRouteWriter::new(
+                LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false),
+                Some(Severity::Warning)
+            ),
+            Some(bands())
+        );
+        block_on(async {
+            for p in [50.0, 15.0, 3.0, 60.0] {
+                let changes = [(PropertyKind::Percentage, Percentage(p))];
+                writer.write(&DeviceEvent::new("/dev", changes)).await.unwrap();
+            }
+            writer.write_marker("Resumed").await.unwrap();
+        });
+        assert_eq!(
+            buf.contents(),
+            "/dev Percentage=15 Severity=warning\n/dev Percentage=3 Severity=critical\nResumed\n"
+        );
+    }
+}
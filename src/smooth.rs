@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+
+/// A [`Writer`] which replaces each change to a device's `EnergyRate` with an exponential moving
+/// average of the values received for that device, before passing it on to an inner [`Writer`].
+pub struct SmoothingWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// The weight given to each new value (between 0 and 1), or `None` if changes should not be
+    /// smoothed.
+    alpha: Option<f64>,
+    /// Whether to also write the raw value, as `EnergyRateRaw`.
+    raw: bool,
+    /// The current average of each device.
+    averages: Mutex<HashMap<String, f64>>
+}
+
+impl<W: Writer> SmoothingWriter<W> {
+    /// Create a new [`SmoothingWriter`] which passes changes to `inner`, smoothed using the given
+    /// weight.
+    pub(crate) fn new(inner: W, alpha: Option<f64>, raw: bool) -> Self {
+        Self {
+            inner,
+            alpha,
+            raw,
+            averages: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for SmoothingWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let (Some(alpha), Some(Property::EnergyRate(rate))) =
+            (self.alpha, event.get(&PropertyKind::EnergyRate)) else {
+            return self.inner.write(event).await
+        };
+        let average = {
+            let mut averages = self.averages.lock().await;
+            let average = averages.entry(event.device.clone()).or_insert(*rate);
+            *average += alpha * (rate - *average);
+            *average
+        };
+        let mut smoothed = event.clone();
+        smoothed.insert(PropertyKind::EnergyRate, Property::EnergyRate(average));
+        if self.raw {
+            let raw = Property::Other(Value::from(*rate).into());
+            smoothed.insert(PropertyKind::EnergyRateRaw, raw);
+        }
+        self.inner.write(&smoothed).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::smooth::SmoothingWriter;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{EnergyRate, State};
+    use crate::upower::PropertyKind;
+
+    /// Write each of the given changes to a new [`SmoothingWriter`], returning the lines written.
+    fn smooth(alpha: Option<f64>, raw: bool, changes: Vec<(&str, &str, crate::upower::Property)>)
+        -> Vec<String> {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", "\t", false);
+        let writer = SmoothingWriter::new(inner, alpha, raw);
+        for (device, k, v) in changes {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::from_name(k), v);
+            block_on(writer.write(&DeviceEvent::new(device, changes))).unwrap();
+        }
+        buf.contents().lines()
+            .map(|l| {
+                let mut fields = l.split(['\t', ' ']).collect::<Vec<_>>();
+                fields.sort();
+                fields.join(" ")
+            })
+            .collect()
+    }
+
+    /// Test that EnergyRate is smoothed separately for each device.
+    #[test]
+    fn smoothing() {
+        assert_eq!(
+            smooth(Some(0.5), false, vec!(
+                ("/a", "EnergyRate", EnergyRate(10.0)),
+                ("/a", "EnergyRate", EnergyRate(20.0)),
+                ("/b", "EnergyRate", EnergyRate(4.0)),
+                ("/a", "State", State(2)),
+                ("/a", "EnergyRate", EnergyRate(5.0))
+            )),
+            vec!(
+                "/a EnergyRate=10",
+                "/a EnergyRate=15",
+                "/b EnergyRate=4",
+                "/a State=Discharging",
+                "/a EnergyRate=10"
+            )
+        );
+    }
+
+    /// Test that the raw value is written alongside the smoothed value if requested, and that
+    /// changes are passed through unchanged if smoothing is disabled.
+    #[test]
+    fn raw() {
+        assert_eq!(
+            smooth(Some(0.25), true, vec!(
+                ("/a", "EnergyRate", EnergyRate(8.0)),
+                ("/a", "EnergyRate", EnergyRate(16.0))
+            )),
+            vec!("/a EnergyRate=8 EnergyRateRaw=8", "/a EnergyRate=10 EnergyRateRaw=16")
+        );
+        assert_eq!(
+            smooth(None, true, vec!(("/a", "EnergyRate", EnergyRate(8.0)))),
+            vec!("/a EnergyRate=8")
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::io::Write;
+use std::process::{Child, Command};
+use std::sync::Mutex;
+
+/// The command used to play sound files if none is given.
+pub(crate) const DEFAULT_SOUND_PLAYER: &str = "paplay";
+
+/// A sound played when an alert fires.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sound {
+    /// Ring the terminal bell.
+    Bell,
+    /// Play the sound file at the given path.
+    File(String)
+}
+
+/// Plays [`Sound`]s, running an external player command for sound files. At most one player runs
+/// at a time: a sound file is not played while the previous one is still playing.
+#[derive(Debug)]
+pub struct SoundPlayer {
+    /// The command used to play sound files, run using the shell with the path of the file
+    /// appended as an argument.
+    command: String,
+    /// The player most recently started, which may still be running.
+    child: Mutex<Option<Child>>
+}
+
+impl SoundPlayer {
+    /// Create a new [`SoundPlayer`] which plays sound files using `command`.
+    pub(crate) fn new(command: &str) -> Self {
+        Self {
+            command: String::from(command),
+            child: Mutex::new(None)
+        }
+    }
+
+    /// Play `sound`, returning whether it was started. The terminal bell is rung by writing to
+    /// standard error, so that it is not mixed with output. Sound files are played in the
+    /// background, and the previous player is reaped before a new one is started.
+    pub(crate) fn play(&self, sound: &Sound) -> Result<bool, std::io::Error> {
+        let path = match sound {
+            Sound::Bell => {
+                let mut stderr = std::io::stderr().lock();
+                stderr.write_all(b"\x07")?;
+                stderr.flush()?;
+                return Ok(true)
+            },
+            Sound::File(path) => path
+        };
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(c) = child.as_mut() {
+            if c.try_wait()?.is_none() {
+                return Ok(false)
+            }
+        }
+        // The path is passed in an environment variable so that it need not be quoted.
+        *child = Some(Command::new("/bin/sh")
+            .arg("-c")
+            .arg(format!("{} \"$UPMON_SOUND\"", self.command))
+            .env("UPMON_SOUND", path)
+            .spawn()?);
+        Ok(true)
+    }
+}
+
+impl Default for SoundPlayer {
+    fn default() -> Self {
+        Self::new(DEFAULT_SOUND_PLAYER)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::sound::{Sound, SoundPlayer};
+
+    /// Test that a sound file is not played while the previous one is still playing.
+    #[test]
+    fn one_player() {
+        let player = SoundPlayer::new("sleep 1; test -n");
+        let sound = Sound::File(String::from("/tmp/alert.wav"));
+        assert!(player.play(&sound).unwrap());
+        assert!(!player.play(&sound).unwrap());
+        let status = player.child.lock().unwrap().as_mut().unwrap().wait().unwrap();
+        assert!(status.success());
+        assert!(player.play(&sound).unwrap());
+    }
+}
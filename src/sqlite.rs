@@ -0,0 +1,133 @@
+use std::io::Error;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+
+/// The schema of the database. Each changed property is stored as a row of `events`, with its
+/// formatted value (as it would appear in line output) and, for numeric, boolean and enumerated
+/// properties, its numeric value. Markers are stored in `markers`. Timestamps are ISO 8601 strings
+/// in UTC.
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    device TEXT NOT NULL,
+    property TEXT NOT NULL,
+    value TEXT NOT NULL,
+    numeric_value REAL
+);
+CREATE INDEX IF NOT EXISTS events_device_property ON events (device, property, timestamp);
+CREATE TABLE IF NOT EXISTS markers (
+    id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    marker TEXT NOT NULL
+);
+";
+
+/// A [`Writer`] which appends changes to an SQLite database, as described by [`SCHEMA`].
+pub struct SqliteWriter {
+    /// The connection to the database.
+    conn: Mutex<Connection>
+}
+
+impl SqliteWriter {
+    /// Create a new [`SqliteWriter`] which writes to the database at `path`, creating it (and the
+    /// tables in [`SCHEMA`]) if necessary.
+    pub(crate) fn new(path: &str) -> Result<Self, rusqlite::Error> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Create a new [`SqliteWriter`] which writes to the given database connection.
+    fn from_connection(conn: Connection) -> Result<Self, rusqlite::Error> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    /// Return the current time, as stored in the database.
+    fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+        timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for SqliteWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        let mut conn = self.conn.lock().await;
+        let timestamp = Self::format_timestamp(event.timestamp);
+        let tx = conn.transaction().map_err(Error::other)?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO events (timestamp, device, property, value, numeric_value) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)"
+            ).map_err(Error::other)?;
+            for (k, v) in event.iter() {
+                let value = v.to_string();
+                stmt.execute(params!(timestamp, event.device, k.as_str(), value, v.as_f64()))
+                    .map_err(Error::other)?;
+            }
+        }
+        tx.commit().map_err(Error::other)
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO markers (timestamp, marker) VALUES (?1, ?2)",
+            params!(Self::format_timestamp(Utc::now()), marker)
+        ).map(|_| ()).map_err(Error::other)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use rusqlite::Connection;
+    use crate::event::DeviceEvent;
+    use crate::output::Writer;
+    use crate::rt::block_on;
+    use crate::sqlite::SqliteWriter;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that changes and markers are stored in the database.
+    #[test]
+    fn sqlite_writer() {
+        let writer = SqliteWriter::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::State, State(2));
+        changes.insert(PropertyKind::Percentage, Percentage(80.5));
+        block_on(writer.write(&DeviceEvent::new("/dev/battery", changes))).unwrap();
+        block_on(writer.write_marker("Resumed")).unwrap();
+
+        let conn = block_on(writer.conn.lock());
+        let mut stmt = conn.prepare(
+            "SELECT device, property, value, numeric_value FROM events ORDER BY property"
+        ).unwrap();
+        let rows = stmt.query_map([], |r| Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, String>(1)?,
+            r.get::<_, String>(2)?,
+            r.get::<_, Option<f64>>(3)?
+        ))).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows, vec!(
+            (
+                String::from("/dev/battery"),
+                String::from("Percentage"),
+                String::from("80.5"),
+                Some(80.5)
+            ),
+            (
+                String::from("/dev/battery"),
+                String::from("State"),
+                String::from("Discharging"),
+                Some(2.0)
+            )
+        ));
+        let marker: String = conn.query_row("SELECT marker FROM markers", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(marker, "Resumed");
+    }
+}
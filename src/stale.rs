@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::future::pending;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::rt::sleep;
+use crate::throttle::Throttle;
+use crate::upower::{Property, PropertyKind};
+
+/// The shortest and longest intervals at which devices are checked for staleness.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The last known update time of a device, and whether it was last reported as stale.
+#[derive(Debug)]
+struct DeviceUpdate {
+    update_time: u64,
+    stale: bool
+}
+
+/// A [`Writer`] which adds a `Stale` pseudo-property to each change to a device's `UpdateTime`,
+/// indicating whether the update time is older than a threshold, before passing it on to an inner
+/// [`Writer`]. [`StaleWriter::watch`] writes a change to `Stale` when a device becomes stale
+/// without its update time changing, which happens when the device's driver stops reporting.
+pub struct StaleWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// The age after which a device's update time is considered stale, or `None` if staleness
+    /// should not be detected.
+    threshold: Option<Duration>,
+    /// The last known update time of each device.
+    devices: Mutex<HashMap<String, DeviceUpdate>>
+}
+
+impl<W: Writer> StaleWriter<W> {
+    /// Create a new [`StaleWriter`] which passes changes to `inner`, considering update times older
+    /// than `threshold` to be stale.
+    pub(crate) fn new(inner: W, threshold: Option<Duration>) -> Self {
+        Self {
+            inner,
+            threshold,
+            devices: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Whether the given update time is stale at the given time.
+    fn is_stale(threshold: Duration, update_time: u64, now: i64) -> bool {
+        now - update_time as i64 > threshold.as_secs() as i64
+    }
+
+    /// Periodically check whether any device's update time has become stale, and write a change to
+    /// `Stale` for each device that has. Checks are made less often, as set out by `throttle`,
+    /// while on battery. If no threshold is set, this never completes.
+    pub(crate) async fn watch(&self, throttle: &Throttle) -> Result<(), std::io::Error> {
+        let Some(threshold) = self.threshold else {
+            return pending().await
+        };
+        let interval = (threshold / 4).clamp(MIN_CHECK_INTERVAL, MAX_CHECK_INTERVAL);
+        loop {
+            sleep(throttle.interval(interval)).await;
+            let now = Utc::now().timestamp();
+            let became_stale = {
+                let mut devices = self.devices.lock().await;
+                devices.iter_mut()
+                    .filter(|(_, d)| !d.stale && Self::is_stale(threshold, d.update_time, now))
+                    .map(|(path, d)| {
+                        d.stale = true;
+                        path.clone()
+                    })
+                    .collect::<Vec<_>>()
+            };
+            for path in became_stale {
+                let stale = Property::Other(Value::from(true).into());
+                let event = DeviceEvent::new(&path, vec!((PropertyKind::Stale, stale)));
+                self.inner.write(&event).await?;
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for StaleWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let (Some(threshold), Some(Property::UpdateTime(t))) =
+            (self.threshold, event.get(&PropertyKind::UpdateTime)) else {
+            return self.inner.write(event).await
+        };
+        let stale = Self::is_stale(threshold, *t, Utc::now().timestamp());
+        self.devices.lock().await.insert(
+            event.device.clone(),
+            DeviceUpdate { update_time: *t, stale }
+        );
+        let mut flagged = event.clone();
+        flagged.insert(PropertyKind::Stale, Property::Other(Value::from(stale).into()));
+        self.inner.write(&flagged).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::stale::StaleWriter;
+    use crate::testing::{eventually, run_until, SharedBuffer};
+    use crate::throttle::Throttle;
+    use crate::upower::Property::{Percentage, UpdateTime};
+    use crate::upower::PropertyKind;
+
+    /// Test that the Stale flag is added to changes to UpdateTime, and written when a device
+    /// becomes stale.
+    #[test]
+    fn stale_writer() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = StaleWriter::new(inner, Some(Duration::from_secs(1)));
+        let now = Utc::now().timestamp() as u64;
+        block_on(run_until(writer.watch(&Throttle::default()), async {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::UpdateTime, UpdateTime(now - 60));
+            writer.write(&DeviceEvent::new("/old", changes.clone())).await.unwrap();
+            changes.insert(PropertyKind::UpdateTime, UpdateTime(now));
+            writer.write(&DeviceEvent::new("/new", changes)).await.unwrap();
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(50.0));
+            writer.write(&DeviceEvent::new("/new", changes)).await.unwrap();
+            // Wait for the new device's update time to become stale.
+            assert!(eventually(|| buf.contents().lines().count() == 4).await);
+        }));
+        let lines = buf.contents().lines()
+            .map(|l| {
+                let mut fields = l.split(' ')
+                    .filter(|f| !f.starts_with("UpdateTime="))
+                    .collect::<Vec<_>>();
+                fields.sort();
+                fields.join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!(
+            "/old Stale=true",
+            "/new Stale=false",
+            "/new Percentage=50",
+            "/new Stale=true"
+        ));
+    }
+}
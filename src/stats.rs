@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+use strum::{EnumCount, IntoStaticStr, VariantArray};
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+
+/// Something upmon itself does, which is counted so that operators can tell whether it is healthy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumCount, IntoStaticStr, VariantArray)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum Counter {
+    /// A change was received from a device.
+    EventsReceived,
+    /// A change was written to the outputs.
+    EventsWritten,
+    /// A change or marker was dropped because the output queue was full.
+    EventsDropped,
+    /// A change to a sink was dropped or held back because it exceeded the sink's rate limit.
+    EventsRateLimited,
+    /// Writing a change or marker failed.
+    WriteErrors,
+    /// A connection was made again after failing or being lost: to the system bus while upmon is
+    /// starting, to a FIFO whose reader returned, or to a plugin which exited.
+    Reconnects
+}
+
+impl Counter {
+    /// A description of the counter, used as its help text in Prometheus output.
+    fn help(&self) -> &'static str {
+        match self {
+            Counter::EventsReceived => "Changes received from devices.",
+            Counter::EventsWritten => "Changes written to the outputs.",
+            Counter::EventsDropped => {
+                "Changes and markers dropped because the output queue was full."
+            },
+            Counter::EventsRateLimited => {
+                "Changes to a sink dropped or held back because they exceeded its rate limit."
+            },
+            Counter::WriteErrors => "Errors writing changes or markers.",
+            Counter::Reconnects => "Connections made again after failing or being lost."
+        }
+    }
+}
+
+/// The count of each [`Counter`] since upmon started, indexed by the counter's position.
+static COUNTS: [AtomicU64; Counter::COUNT] = [const { AtomicU64::new(0) }; Counter::COUNT];
+
+/// Add one to `counter`.
+pub(crate) fn increment(counter: Counter) {
+    COUNTS[counter as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Return the count of `counter`.
+pub(crate) fn get(counter: Counter) -> u64 {
+    COUNTS[counter as usize].load(Ordering::Relaxed)
+}
+
+/// Return each counter as a JSON object keyed by the counter's name.
+pub(crate) fn to_json() -> Value {
+    Value::Object(Counter::VARIANTS.iter()
+        .map(|c| (String::from(<&str>::from(c)), Value::from(get(*c))))
+        .collect::<Map<_, _>>())
+}
+
+/// Return each counter as `NAME=COUNT` pairs separated by spaces, as written to standard error.
+pub(crate) fn to_line() -> String {
+    Counter::VARIANTS.iter()
+        .map(|c| format!("{}={}", <&str>::from(c), get(*c)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Return each counter in the Prometheus text exposition format, named with an `upmon_` prefix and
+/// a `_total` suffix.
+pub(crate) fn to_prometheus() -> String {
+    Counter::VARIANTS.iter()
+        .map(|c| {
+            let name = format!("upmon_{}_total", <&str>::from(c));
+            format!("# HELP {name} {}\n# TYPE {name} counter\n{name} {}\n", c.help(), get(*c))
+        })
+        .collect()
+}
+
+/// A [`Writer`] which counts the changes passed to an inner [`Writer`] as received. Write errors
+/// are counted where changes are actually written (see [`crate::queue::QueueWriter::run`]), so
+/// that each is only counted once.
+pub struct StatsWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W
+}
+
+impl<W: Writer> StatsWriter<W> {
+    /// Create a new [`StatsWriter`] which passes changes to `inner`.
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for StatsWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        increment(Counter::EventsReceived);
+        self.inner.write(event).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::stats::{get, increment, to_json, to_line, to_prometheus, Counter};
+
+    /// Test that counters are written in each format. Counters are shared by all tests, which may
+    /// run at the same time, so they are not checked exactly.
+    #[test]
+    fn counters() {
+        let before = get(Counter::Reconnects);
+        increment(Counter::Reconnects);
+        assert!(get(Counter::Reconnects) > before);
+        let json = to_json();
+        assert_eq!(json.as_object().unwrap().len(), 6);
+        assert!(json["events_received"].is_u64());
+        assert!(to_line().starts_with("events_received="));
+        let prometheus = to_prometheus();
+        assert!(prometheus.starts_with(
+            "# HELP upmon_events_received_total Changes received from devices.\n\
+             # TYPE upmon_events_received_total counter\nupmon_events_received_total "
+        ));
+        assert!(prometheus.contains("\nupmon_reconnects_total "));
+    }
+}
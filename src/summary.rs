@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::io::{Error, Write};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use crate::cache::ValueCache;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::severity::SeverityColors;
+use crate::upower::{DeviceType, Property, PropertyKind, STATE_CHARGING, STATE_DISCHARGING};
+
+/// Return a short name for the device at the given path: the last component of the path, without
+/// the prefix UPower gives devices of its type (so `/org/freedesktop/UPower/devices/battery_BAT0`
+/// becomes `BAT0`).
+pub(crate) fn short_name(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    DeviceType::from_path(path)
+        .and_then(|t| name.strip_prefix(t.path_prefix()))
+        .and_then(|n| n.strip_prefix('_'))
+        .filter(|n| !n.is_empty())
+        .unwrap_or(name)
+}
+
+/// Format a number of seconds as hours and minutes, such as 2:05.
+fn format_hhmm(s: i64) -> String {
+    format!("{}:{:02}", s / 3600, s / 60 % 60)
+}
+
+/// Summarise the latest values of a single device: its `Icon` (if added), its `Online` status, its
+/// `Percentage` followed by an arrow while it is charging (`↑`) or discharging (`↓`), and the time
+/// until it is full or empty. Any other properties follow as name-value pairs, separated by
+/// `separator`.
+pub(crate) fn summarize(values: &HashMap<PropertyKind, Property>, separator: &str) -> String {
+    let mut parts = vec!();
+    if let Some(icon) = values.get(&PropertyKind::Icon) {
+        parts.push(icon.to_string());
+    }
+    if let Some(Property::Online(online)) = values.get(&PropertyKind::Online) {
+        parts.push(String::from(if *online { "online" } else { "offline" }));
+    }
+    let state = match values.get(&PropertyKind::State) {
+        Some(Property::State(s)) => Some(*s),
+        _ => None
+    };
+    match (values.get(&PropertyKind::Percentage), state) {
+        (Some(Property::Percentage(p)), _) => parts.push(format!(
+            "{p:.0}%{}",
+            match state {
+                Some(STATE_CHARGING) => "↑",
+                Some(STATE_DISCHARGING) => "↓",
+                _ => ""
+            }
+        )),
+        (_, Some(_)) => parts.push(values[&PropertyKind::State].to_string()),
+        _ => {}
+    }
+    let estimate = match state {
+        Some(STATE_CHARGING) => values.get(&PropertyKind::TimeToFull),
+        Some(STATE_DISCHARGING) => values.get(&PropertyKind::TimeToEmpty),
+        _ => None
+    };
+    if let Some(Property::TimeToFull(t) | Property::TimeToEmpty(t)) = estimate {
+        if *t > 0 {
+            parts.push(format_hhmm(*t));
+        }
+    }
+    let mut others = values.iter()
+        .filter(|(k, _)| !matches!(
+            k,
+            PropertyKind::Icon | PropertyKind::Online | PropertyKind::Percentage
+                | PropertyKind::State | PropertyKind::TimeToEmpty | PropertyKind::TimeToFull
+        ))
+        .map(|(k, v)| format!("{k}{separator}{v}"))
+        .collect::<Vec<_>>();
+    others.sort();
+    parts.extend(others);
+    parts.join(" ")
+}
+
+/// The formatting tags understood by a status bar, with which a [`SummaryWriter`] can colour each
+/// device's summary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TagStyle {
+    /// The formatting tags of [polybar](https://github.com/polybar/polybar), in which only `%{`
+    /// must be escaped.
+    Polybar,
+    /// The formatting tags of [lemonbar](https://github.com/LemonBoy/bar), in which every `%`
+    /// must be escaped.
+    Lemonbar
+}
+
+impl TagStyle {
+    /// Escape `text` so that the bar shows it as it is, rather than interpreting it as tags.
+    pub(crate) fn escape(&self, text: &str) -> String {
+        match self {
+            TagStyle::Polybar => text.replace("%{", "%%{"),
+            TagStyle::Lemonbar => text.replace('%', "%%")
+        }
+    }
+
+    /// Escape `text` and, if `color` is given, wrap it in tags which show it in that colour.
+    pub(crate) fn colored(&self, text: &str, color: Option<&str>) -> String {
+        match color {
+            Some(color) => format!("%{{F{color}}}{}%{{F-}}", self.escape(text)),
+            None => self.escape(text)
+        }
+    }
+}
+
+/// A [`Writer`] which keeps the latest values of every device and, on each change, writes a single
+/// line summarising all of them (as described by [`summarize`]), such as
+/// `AC=online BAT0=85%↓ 2:10`. If a [`TagStyle`] is given, each device's summary is instead
+/// escaped and coloured according to its `Severity`, for use by a status bar. Markers are not
+/// written.
+pub struct SummaryWriter {
+    /// File (or other struct implementing Write) to write to.
+    out: Mutex<Box<dyn Write>>,
+    /// The latest value of each property of each device.
+    cache: Mutex<ValueCache>,
+    /// String used to separate each device's name from its summary, and each other property's name
+    /// from its value.
+    separator: String,
+    /// String used to separate devices.
+    delimiter: String,
+    /// The status bar tags with which to colour each device's summary, and the colours to use.
+    tags: Option<(TagStyle, SeverityColors)>
+}
+
+impl SummaryWriter {
+    /// Create a new [`SummaryWriter`] which writes to the given output.
+    pub(crate) fn from_writer(out: Box<dyn Write>, separator: &str, delimiter: &str) -> Self {
+        Self {
+            out: Mutex::new(out),
+            cache: Mutex::new(ValueCache::default()),
+            separator: String::from(separator),
+            delimiter: String::from(delimiter),
+            tags: None
+        }
+    }
+
+    /// Colour each device's summary using the given tags, according to its `Severity`.
+    pub(crate) fn with_tags(mut self, style: TagStyle, colors: SeverityColors) -> Self {
+        self.tags = Some((style, colors));
+        self
+    }
+
+    /// Summarise a single device, escaping and colouring the summary if tags are used.
+    fn device_summary(&self, device: &str, values: &HashMap<PropertyKind, Property>) -> String {
+        let summary = format!(
+            "{}{}{}",
+            short_name(device),
+            self.separator,
+            summarize(values, &self.separator)
+        );
+        match &self.tags {
+            Some((style, colors)) => style.colored(&summary, colors.color(values)),
+            None => summary
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for SummaryWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        let line = {
+            let mut cache = self.cache.lock().await;
+            cache.update(event);
+            cache.iter()
+                .map(|(device, values)| self.device_summary(device, values))
+                .collect::<Vec<_>>()
+                .join(&self.delimiter)
+        };
+        let mut out = self.out.lock().await;
+        writeln!(out, "{line}")?;
+        out.flush()
+    }
+
+    async fn write_marker(&self, _marker: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::event::DeviceEvent;
+    use crate::output::Writer;
+    use zbus::zvariant::Value;
+    use crate::rt::block_on;
+    use crate::severity::SeverityColors;
+    use crate::summary::{short_name, SummaryWriter, TagStyle};
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{
+        Capacity, Online, Other, Percentage, State, TimeToEmpty, TimeToFull
+    };
+    use crate::upower::PropertyKind;
+
+    /// Test that devices are given short names based on their paths.
+    #[test]
+    fn summary_names() {
+        assert_eq!(short_name("/org/freedesktop/UPower/devices/battery_BAT0"), "BAT0");
+        assert_eq!(short_name("/org/freedesktop/UPower/devices/line_power_AC"), "AC");
+        assert_eq!(short_name("/org/freedesktop/UPower/devices/DisplayDevice"), "DisplayDevice");
+        assert_eq!(short_name("/org/bluez/hci0/dev_AA_BB"), "dev_AA_BB");
+    }
+
+    /// Test that a line summarising every device is written on each change.
+    #[test]
+    fn summary_writer() {
+        let buf = SharedBuffer::default();
+        let writer = SummaryWriter::from_writer(Box::new(buf.clone()), "=", " ");
+        let ac = "/org/freedesktop/UPower/devices/line_power_AC";
+        let bat = "/org/freedesktop/UPower/devices/battery_BAT0";
+        block_on(async {
+            writer.write(&DeviceEvent::new(ac, vec!((PropertyKind::Online, Online(true)))))
+                .await.unwrap();
+            writer.write(&DeviceEvent::new(bat, vec!(
+                (PropertyKind::Percentage, Percentage(85.0)),
+                (PropertyKind::State, State(1)),
+                (PropertyKind::TimeToFull, TimeToFull(1800)),
+                (PropertyKind::TimeToEmpty, TimeToEmpty(0))
+            ))).await.unwrap();
+            writer.write_marker("Resumed").await.unwrap();
+            writer.write(&DeviceEvent::new(ac, vec!((PropertyKind::Online, Online(false)))))
+                .await.unwrap();
+            writer.write(&DeviceEvent::new(bat, vec!(
+                (PropertyKind::State, State(2)),
+                (PropertyKind::TimeToEmpty, TimeToEmpty(7800)),
+                (PropertyKind::Capacity, Capacity(90.0))
+            ))).await.unwrap();
+        });
+        assert_eq!(
+            buf.contents(),
+            "AC=online\n\
+             AC=online BAT0=85%↑ 0:30\n\
+             AC=offline BAT0=85%↑ 0:30\n\
+             AC=offline BAT0=85%↓ 2:10 Capacity=90\n"
+        );
+    }
+    /// Test that text is escaped for each style of tags, and coloured if a colour is given.
+    #[test]
+    fn tag_styles() {
+        assert_eq!(TagStyle::Polybar.escape("50% %{F-}"), "50% %%{F-}");
+        assert_eq!(TagStyle::Lemonbar.escape("50% %{F-}"), "50%% %%{F-}");
+        assert_eq!(TagStyle::Polybar.colored("BAT0=4%", None), "BAT0=4%");
+        assert_eq!(
+            TagStyle::Lemonbar.colored("BAT0=4%", Some("#FF0000")),
+            "%{F#FF0000}BAT0=4%%%{F-}"
+        );
+    }
+
+    /// Test that, when tags are used, each device is coloured according to its severity.
+    #[test]
+    fn summary_writer_tags() {
+        let buf = SharedBuffer::default();
+        let writer = SummaryWriter::from_writer(Box::new(buf.clone()), "=", " | ")
+            .with_tags(TagStyle::Polybar, SeverityColors::default());
+        let ac = "/org/freedesktop/UPower/devices/line_power_AC";
+        let bat = "/org/freedesktop/UPower/devices/battery_BAT0";
+        block_on(async {
+            writer.write(&DeviceEvent::new(ac, vec!((PropertyKind::Online, Online(false)))))
+                .await.unwrap();
+            writer.write(&DeviceEvent::new(bat, vec!(
+                (PropertyKind::Percentage, Percentage(15.0)),
+                (PropertyKind::Severity, Other(Value::from("warning").into()))
+            ))).await.unwrap();
+        });
+        assert_eq!(
+            buf.contents(),
+            "AC=offline\n\
+             AC=offline | %{F#FFFF00}BAT0=15% Severity=warning%{F-}\n"
+        );
+    }
+}
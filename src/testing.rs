@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::future::{select, Future};
+use futures::{pin_mut, try_join};
+use zbus::{
+    dbus_interface, fdo, fdo::Properties, Connection, ConnectionBuilder, Guid,
+    Result as zbus_Result,
+    names::InterfaceName,
+    zvariant::{ObjectPath, Value::{self, Bool, F64, I64, U32, U64}}
+};
+use crate::rt::{bus_stream_pair, sleep};
+use crate::upower::{UPOWER_DEVICE_INTERFACE, UPOWER_PATH};
+
+/// The object path at which the mock device is served.
+pub(crate) const MOCK_DEVICE_PATH: &str = "/org/freedesktop/UPower/devices/battery_MOCK";
+
+/// How long [`eventually`] waits for a condition to hold.
+const EVENTUALLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// A mock implementation of the `org.freedesktop.UPower.Device` interface, exposing the properties
+/// that upmon supports, along with `Type` and `NativePath`.
+#[derive(Debug)]
+pub(crate) struct MockDevice {
+    update_time: u64,
+    online: bool,
+    time_to_empty: i64,
+    time_to_full: i64,
+    percentage: f64,
+    is_present: bool,
+    state: u32,
+    warning_level: u32,
+    battery_level: u32,
+    charge_start_threshold: u32,
+    charge_end_threshold: u32,
+    charge_threshold_enabled: bool,
+    charge_threshold_supported: bool,
+    energy_full: f64,
+    energy_full_design: f64,
+    capacity: f64,
+    energy_rate: f64,
+    device_type: u32,
+    native_path: String
+}
+
+impl Default for MockDevice {
+    fn default() -> Self {
+        Self {
+            update_time: 1707671976,
+            online: false,
+            time_to_empty: 7200,
+            time_to_full: 0,
+            percentage: 80.0,
+            is_present: true,
+            state: 2,
+            warning_level: 1,
+            battery_level: 1,
+            charge_start_threshold: 75,
+            charge_end_threshold: 80,
+            charge_threshold_enabled: false,
+            charge_threshold_supported: true,
+            energy_full: 45.0,
+            energy_full_design: 50.0,
+            capacity: 90.0,
+            energy_rate: 12.5,
+            device_type: 2,
+            native_path: String::from("MOCK")
+        }
+    }
+}
+
+impl MockDevice {
+    /// Set the property with the given name to the given value. Returns an error if the device
+    /// does not have a property with that name and type.
+    fn set(&mut self, name: &str, value: &Value) -> zbus_Result<()> {
+        match (name, value) {
+            ("UpdateTime", U64(t)) => self.update_time = *t,
+            ("Online", Bool(b)) => self.online = *b,
+            ("TimeToEmpty", I64(t)) => self.time_to_empty = *t,
+            ("TimeToFull", I64(t)) => self.time_to_full = *t,
+            ("Percentage", F64(p)) => self.percentage = *p,
+            ("IsPresent", Bool(b)) => self.is_present = *b,
+            ("State", U32(s)) => self.state = *s,
+            ("WarningLevel", U32(w)) => self.warning_level = *w,
+            ("BatteryLevel", U32(l)) => self.battery_level = *l,
+            ("ChargeStartThreshold", U32(t)) => self.charge_start_threshold = *t,
+            ("ChargeEndThreshold", U32(t)) => self.charge_end_threshold = *t,
+            ("ChargeThresholdEnabled", Bool(b)) => self.charge_threshold_enabled = *b,
+            ("ChargeThresholdSupported", Bool(b)) => self.charge_threshold_supported = *b,
+            ("EnergyFull", F64(e)) => self.energy_full = *e,
+            ("EnergyFullDesign", F64(e)) => self.energy_full_design = *e,
+            ("Capacity", F64(c)) => self.capacity = *c,
+            ("EnergyRate", F64(r)) => self.energy_rate = *r,
+            ("Type", U32(t)) => self.device_type = *t,
+            _ => return Err(fdo::Error::InvalidArgs(format!("Cannot set {name} to {value:?}")).into())
+        }
+        Ok(())
+    }
+}
+
+#[dbus_interface(name = "org.freedesktop.UPower.Device")]
+impl MockDevice {
+    #[dbus_interface(property)]
+    fn update_time(&self) -> u64 {
+        self.update_time
+    }
+
+    #[dbus_interface(property)]
+    fn online(&self) -> bool {
+        self.online
+    }
+
+    #[dbus_interface(property)]
+    fn time_to_empty(&self) -> i64 {
+        self.time_to_empty
+    }
+
+    #[dbus_interface(property)]
+    fn time_to_full(&self) -> i64 {
+        self.time_to_full
+    }
+
+    #[dbus_interface(property)]
+    fn percentage(&self) -> f64 {
+        self.percentage
+    }
+
+    #[dbus_interface(property)]
+    fn is_present(&self) -> bool {
+        self.is_present
+    }
+
+    #[dbus_interface(property)]
+    fn state(&self) -> u32 {
+        self.state
+    }
+
+    #[dbus_interface(property)]
+    fn warning_level(&self) -> u32 {
+        self.warning_level
+    }
+
+    #[dbus_interface(property)]
+    fn battery_level(&self) -> u32 {
+        self.battery_level
+    }
+
+    #[dbus_interface(property)]
+    fn charge_start_threshold(&self) -> u32 {
+        self.charge_start_threshold
+    }
+
+    #[dbus_interface(property)]
+    fn charge_end_threshold(&self) -> u32 {
+        self.charge_end_threshold
+    }
+
+    #[dbus_interface(property)]
+    fn charge_threshold_enabled(&self) -> bool {
+        self.charge_threshold_enabled
+    }
+
+    #[dbus_interface(property)]
+    fn charge_threshold_supported(&self) -> bool {
+        self.charge_threshold_supported
+    }
+
+    #[dbus_interface(property)]
+    fn energy_full(&self) -> f64 {
+        self.energy_full
+    }
+
+    #[dbus_interface(property)]
+    fn energy_full_design(&self) -> f64 {
+        self.energy_full_design
+    }
+
+    #[dbus_interface(property)]
+    fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    #[dbus_interface(property)]
+    fn energy_rate(&self) -> f64 {
+        self.energy_rate
+    }
+
+    #[dbus_interface(property, name = "Type")]
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    #[dbus_interface(property)]
+    fn native_path(&self) -> &str {
+        &self.native_path
+    }
+}
+
+/// A mock implementation of the `org.freedesktop.UPower` interface.
+#[derive(Debug)]
+pub(crate) struct MockManager {
+    critical_action: String,
+    on_battery: bool
+}
+
+impl Default for MockManager {
+    fn default() -> Self {
+        Self {
+            critical_action: String::from("HybridSleep"),
+            on_battery: false
+        }
+    }
+}
+
+#[dbus_interface(name = "org.freedesktop.UPower")]
+impl MockManager {
+    fn get_critical_action(&self) -> String {
+        self.critical_action.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn on_battery(&self) -> bool {
+        self.on_battery
+    }
+}
+
+/// A mock UPower service, serving a [`MockManager`] and a single [`MockDevice`] at
+/// [`MOCK_DEVICE_PATH`] over a private peer-to-peer connection.
+pub(crate) struct MockUPower {
+    /// The connection on which the mock device is served.
+    server: Connection,
+    /// The connection which should be used by the code under test.
+    pub(crate) client: Connection
+}
+
+impl MockUPower {
+    /// Start a new mock UPower service with a [`MockManager`] and [`MockDevice`] in their default
+    /// states.
+    pub(crate) async fn new() -> zbus_Result<Self> {
+        let (server_stream, client_stream) = bus_stream_pair()?;
+        let guid = Guid::generate();
+        let server = ConnectionBuilder::unix_stream(server_stream)
+            .server(&guid)
+            .p2p()
+            .serve_at(UPOWER_PATH, MockManager::default())?
+            .serve_at(MOCK_DEVICE_PATH, MockDevice::default())?
+            .build();
+        let client = ConnectionBuilder::unix_stream(client_stream)
+            .p2p()
+            .build();
+        let (server, client) = try_join!(server, client)?;
+        Ok(Self { server, client })
+    }
+
+    /// Update the given properties of the mock device and emit a single `PropertiesChanged` signal
+    /// describing the changes.
+    pub(crate) async fn set_properties(&self, changes: &[(&str, Value<'_>)]) -> zbus_Result<()> {
+        let iface_ref = self.server
+            .object_server()
+            .interface::<_, MockDevice>(MOCK_DEVICE_PATH)
+            .await?;
+        {
+            let mut device = iface_ref.get_mut().await;
+            for (k, v) in changes {
+                device.set(k, v)?;
+            }
+        }
+        let changed: HashMap<&str, &Value> = changes.iter()
+            .map(|(k, v)| (*k, v))
+            .collect();
+        Properties::properties_changed(
+            iface_ref.signal_context(),
+            InterfaceName::from_static_str_unchecked(UPOWER_DEVICE_INTERFACE),
+            &changed,
+            &[]
+        ).await
+    }
+
+    /// Set whether the mock manager reports that the system is on battery, and emit a
+    /// `PropertiesChanged` signal describing the change.
+    pub(crate) async fn set_on_battery(&self, on_battery: bool) -> zbus_Result<()> {
+        let iface_ref = self.server
+            .object_server()
+            .interface::<_, MockManager>(UPOWER_PATH)
+            .await?;
+        let mut manager = iface_ref.get_mut().await;
+        manager.on_battery = on_battery;
+        manager.on_battery_changed(iface_ref.signal_context()).await
+    }
+
+    /// Emit a `DeviceRemoved` signal for the device at `path`, as UPower does when a device goes
+    /// away. The device is still served.
+    pub(crate) async fn remove_device(&self, path: &str) -> zbus_Result<()> {
+        self.server.emit_signal(
+            None::<()>,
+            UPOWER_PATH,
+            "org.freedesktop.UPower",
+            "DeviceRemoved",
+            &ObjectPath::try_from(path)?
+        ).await
+    }
+
+    /// Emit a `PropertiesChanged` signal for the object at `path` whose body is not that of a
+    /// `PropertiesChanged` signal, as a misbehaving service might.
+    pub(crate) async fn emit_malformed_change(&self, path: &str) -> zbus_Result<()> {
+        self.server.emit_signal(
+            None::<()>,
+            path,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            &("malformed",)
+        ).await
+    }
+}
+
+/// An in-memory buffer implementing [`Write`], which can be cloned so that its contents can be
+/// inspected after it has been passed to a writer.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Return the contents of the buffer as a string.
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+
+    /// Return the contents of the buffer as bytes.
+    #[cfg(feature = "compress")]
+    pub(crate) fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Wait until the contents of the buffer are `expected`, failing the test if they are not
+    /// within [`EVENTUALLY_TIMEOUT`].
+    pub(crate) async fn wait_for(&self, expected: &str) {
+        eventually(|| self.contents() == expected).await;
+        assert_eq!(self.contents(), expected);
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Wait until `condition` holds, checking it every few milliseconds. Returns whether it held
+/// within [`EVENTUALLY_TIMEOUT`].
+pub(crate) async fn eventually(mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + EVENTUALLY_TIMEOUT;
+    while !condition() {
+        if Instant::now() > deadline {
+            return false
+        }
+        sleep(Duration::from_millis(5)).await;
+    }
+    true
+}
+
+/// Run `listener` until `actions` has completed. The listener is polled before the actions are
+/// started, so a listener which subscribes to signals before it first has to wait (as all of
+/// upmon's listeners do on the mock's peer-to-peer connection) sees every change they make.
+/// Actions should finish by waiting for the output they expect, rather than for a fixed time.
+pub(crate) async fn run_until(listener: impl Future, actions: impl Future) {
+    pin_mut!(listener, actions);
+    select(listener, actions).await;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use futures::future::join;
+    use zbus::zvariant::Value::{Bool, F64, U32};
+    use crate::output::LineWriter;
+    use crate::rt::block_on;
+    use crate::testing::{eventually, MOCK_DEVICE_PATH, MockUPower, run_until, SharedBuffer};
+    use crate::upower::{DeviceConfig, DeviceSet, Property, PropertyKind, UPOWER_SERVICE};
+
+    /// Test that changes to targeted properties are written, and changes to other properties (and
+    /// signals which cannot be parsed) are ignored.
+    #[test]
+    fn listen_writes_changes() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let conf = DeviceConfig::from_args(&[vec!(
+                String::from(MOCK_DEVICE_PATH),
+                String::from("Percentage,State")
+            )], None).unwrap();
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            run_until(conf[0].listen(&upower.client, &writer, None), async {
+                upower.emit_malformed_change(MOCK_DEVICE_PATH).await.unwrap();
+                upower.set_properties(&[("Percentage", F64(79.0))]).await.unwrap();
+                upower.set_properties(&[("Online", Bool(true))]).await.unwrap();
+                upower.set_properties(&[("State", U32(1))]).await.unwrap();
+                buf.wait_for(&format!(
+                    "{MOCK_DEVICE_PATH} Percentage=79\n{MOCK_DEVICE_PATH} State=Charging\n"
+                )).await;
+            }).await;
+        })
+    }
+
+    /// Test that all targeted properties changed in a single signal are written on a single line.
+    #[test]
+    fn listen_multiple_changes() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let conf = DeviceConfig::from_args(&[vec!(
+                String::from(MOCK_DEVICE_PATH),
+                String::from("Online,IsPresent")
+            )], None).unwrap();
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), ":", "|", false);
+            run_until(conf[0].listen(&upower.client, &writer, None), async {
+                upower.set_properties(&[
+                    ("Online", Bool(true)),
+                    ("IsPresent", Bool(false)),
+                    ("Percentage", F64(50.0))
+                ]).await.unwrap();
+                assert!(eventually(|| !buf.contents().is_empty()).await);
+            }).await;
+            let contents = buf.contents();
+            let (path, changes) = contents.trim_end().split_once(' ').unwrap();
+            assert_eq!(path, MOCK_DEVICE_PATH);
+            let mut changes = changes.split('|').collect::<Vec<&str>>();
+            changes.sort();
+            assert_eq!(changes, vec!("IsPresent:false", "Online:true"));
+        })
+    }
+
+    /// Test that signals for other interfaces are ignored when monitoring an arbitrary interface.
+    #[test]
+    fn listen_other_interface() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let conf = DeviceConfig::from_args(&[vec!(
+                String::from(MOCK_DEVICE_PATH),
+                String::from("Percentage")
+            )], Some("org.bluez.Battery1")).unwrap();
+            let device = DeviceConfig::new(MOCK_DEVICE_PATH, "Percentage", None).unwrap();
+            let (buf, device_buf) = (SharedBuffer::default(), SharedBuffer::default());
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            let device_writer =
+                LineWriter::from_writer(Box::new(device_buf.clone()), "=", " ", false);
+            let listen = join(
+                conf[0].listen(&upower.client, &writer, None),
+                device.listen(&upower.client, &device_writer, None)
+            );
+            run_until(listen, async {
+                upower.set_properties(&[("Percentage", F64(79.0))]).await.unwrap();
+                // Once the change is written for the device's own interface, it has been received.
+                device_buf.wait_for(&format!("{MOCK_DEVICE_PATH} Percentage=79\n")).await;
+            }).await;
+            assert_eq!(buf.contents(), "");
+        })
+    }
+
+    /// Test that devices added to a [`DeviceSet`] are listened to, and devices removed from it are
+    /// not.
+    #[test]
+    fn listen_device_set() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let devices = DeviceSet::default();
+            devices.extend([DeviceConfig::new(MOCK_DEVICE_PATH, "Percentage", None).unwrap()])
+                .await;
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            run_until(devices.listen(&upower.client, &HashMap::new(), &writer, None), async {
+                devices.subscribed().await;
+                upower.set_properties(&[("Percentage", F64(79.0))]).await.unwrap();
+                buf.wait_for(&format!("{MOCK_DEVICE_PATH} Percentage=79\n")).await;
+                devices.remove(MOCK_DEVICE_PATH).await.unwrap();
+                assert!(devices.remove(MOCK_DEVICE_PATH).await.is_err());
+                devices.applied().await;
+                upower.set_properties(&[("Percentage", F64(78.0))]).await.unwrap();
+                let config = DeviceConfig::new(MOCK_DEVICE_PATH, "State", None).unwrap();
+                devices.add(config.clone()).await.unwrap();
+                assert!(devices.add(config).await.is_err());
+                devices.applied().await;
+                upower.set_properties(&[("State", U32(1))]).await.unwrap();
+                buf.wait_for(&format!(
+                    "{MOCK_DEVICE_PATH} Percentage=79\n{MOCK_DEVICE_PATH} State=Charging\n"
+                )).await;
+            }).await;
+            assert_eq!(devices.configs().await.len(), 1);
+        })
+    }
+
+    /// Test that targeted properties which the device does not have, or which do not apply to its
+    /// type, are reported as unsupported.
+    #[test]
+    fn unsupported_targets() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let battery = DeviceConfig::new(MOCK_DEVICE_PATH, "Percentage,Online", None).unwrap();
+            assert_eq!(
+                battery.unsupported_targets(&upower.client).await.unwrap(),
+                vec!(&PropertyKind::Online)
+            );
+            upower.set_properties(&[("Type", U32(1))]).await.unwrap();
+            assert_eq!(
+                battery.unsupported_targets(&upower.client).await.unwrap(),
+                vec!(&PropertyKind::Percentage)
+            );
+
+            let other = DeviceConfig::new(
+                MOCK_DEVICE_PATH,
+                "Percentage,Online,Level",
+                Some("org.freedesktop.UPower.Device")
+            ).unwrap();
+            assert!(other.unsupported_targets(&upower.client).await.unwrap().is_empty());
+            let other = other.with_service(UPOWER_SERVICE);
+            assert_eq!(
+                other.unsupported_targets(&upower.client).await.unwrap(),
+                vec!(&PropertyKind::Other(String::from("Level")))
+            );
+        })
+    }
+
+    /// Test that every property of the device is discovered, sorted by name, and that changes to
+    /// every property are written when "*" is targeted.
+    #[test]
+    fn discover_properties() {
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let conf = DeviceConfig::new(MOCK_DEVICE_PATH, "*", None).unwrap();
+            let properties = conf.discover(&upower.client).await.unwrap().unwrap();
+            let names = properties.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+            assert_eq!(names.len(), 19);
+            assert!(names.is_sorted());
+            let percentage = properties.iter().find(|p| p.name == "Percentage").unwrap();
+            assert_eq!(percentage.dbus_type, "d");
+            assert_eq!(percentage.value, Property::Percentage(80.0));
+            let device_type = properties.iter().find(|p| p.name == "Type").unwrap();
+            assert_eq!(device_type.dbus_type, "u");
+            assert_eq!(device_type.value.to_string(), "2");
+
+            let buf = SharedBuffer::default();
+            let writer = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            run_until(conf.listen(&upower.client, &writer, None), async {
+                upower.set_properties(&[("Type", U32(1)), ("Online", Bool(true))]).await.unwrap();
+                buf.wait_for(&format!("{MOCK_DEVICE_PATH} Online=true Type=1\n")).await;
+            }).await;
+        })
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use zbus::zvariant::Value;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind, STATE_DISCHARGING};
+
+/// The latest values of a device's properties from which the time to the threshold is estimated.
+#[derive(Clone, Copy, Debug, Default)]
+struct Discharge {
+    percentage: Option<f64>,
+    state: Option<u32>,
+    energy_full: Option<f64>,
+    energy_rate: Option<f64>,
+    time_to_empty: Option<i64>
+}
+
+impl Discharge {
+    /// Estimate the number of seconds until the device reaches `threshold` percent, if it is
+    /// discharging. The estimate is based on the energy remaining above the threshold and the
+    /// (possibly smoothed) `EnergyRate` if both `EnergyFull` and `EnergyRate` are known, and on
+    /// UPower's own `TimeToEmpty` otherwise. Devices at or below the threshold give zero.
+    fn time_to(&self, threshold: f64) -> Option<i64> {
+        if self.state != Some(STATE_DISCHARGING) {
+            return None
+        }
+        let percentage = self.percentage?;
+        if percentage <= threshold {
+            return Some(0)
+        }
+        let above = percentage - threshold;
+        match (self.energy_full, self.energy_rate, self.time_to_empty) {
+            (Some(full), Some(rate), _) if full > 0.0 && rate > 0.0 =>
+                Some((above / 100.0 * full / rate * 3600.0).round() as i64),
+            (_, _, Some(t)) if t > 0 =>
+                Some((t as f64 * above / percentage).round() as i64),
+            _ => None
+        }
+    }
+}
+
+/// A [`Writer`] which adds a `TimeToThreshold` pseudo-property to each change, giving the
+/// estimated number of seconds until the device discharges to a given percentage, before passing
+/// it on to an inner [`Writer`]. The property is only added while the estimate can be made.
+pub struct ThresholdWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// The percentage to estimate the time to, or `None` if no estimate should be added.
+    threshold: Option<f64>,
+    /// The latest values of each device.
+    devices: Mutex<HashMap<String, Discharge>>
+}
+
+impl<W: Writer> ThresholdWriter<W> {
+    /// Create a new [`ThresholdWriter`] which passes changes to `inner`, adding the time until each
+    /// device reaches `threshold` percent.
+    pub(crate) fn new(inner: W, threshold: Option<f64>) -> Self {
+        Self {
+            inner,
+            threshold,
+            devices: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for ThresholdWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        let Some(threshold) = self.threshold else {
+            return self.inner.write(event).await
+        };
+        let discharge = {
+            let mut devices = self.devices.lock().await;
+            let device = devices.entry(event.device.clone()).or_default();
+            for (_, v) in event.iter() {
+                match v {
+                    Property::Percentage(p) => device.percentage = Some(*p),
+                    Property::State(s) => device.state = Some(*s),
+                    Property::EnergyFull(e) => device.energy_full = Some(*e),
+                    Property::EnergyRate(r) => device.energy_rate = Some(*r),
+                    Property::TimeToEmpty(t) => device.time_to_empty = Some(*t),
+                    _ => {}
+                }
+            }
+            *device
+        };
+        let Some(seconds) = discharge.time_to(threshold) else {
+            return self.inner.write(event).await
+        };
+        let mut estimated = event.clone();
+        estimated.insert(
+            PropertyKind::TimeToThreshold,
+            Property::Other(Value::from(seconds).into())
+        );
+        self.inner.write(&estimated).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::event::DeviceEvent;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::threshold::{Discharge, ThresholdWriter};
+    use crate::upower::Property::{EnergyFull, EnergyRate, Percentage, State, TimeToEmpty};
+    use crate::upower::PropertyKind;
+
+    /// Test that the time to the threshold is estimated from the energy rate if possible, and from
+    /// the time to empty otherwise.
+    #[test]
+    fn time_to() {
+        let mut discharge = Discharge {
+            percentage: Some(60.0),
+            state: Some(2),
+            energy_full: Some(50.0),
+            energy_rate: Some(10.0),
+            time_to_empty: Some(7200)
+        };
+        assert_eq!(discharge.time_to(20.0), Some(7200));
+        assert_eq!(discharge.time_to(60.0), Some(0));
+        discharge.energy_rate = None;
+        assert_eq!(discharge.time_to(20.0), Some(4800));
+        discharge.state = Some(1);
+        assert_eq!(discharge.time_to(20.0), None);
+    }
+
+    /// Test that the estimate is added to changes once it can be made.
+    #[test]
+    fn threshold_writer() {
+        let buf = SharedBuffer::default();
+        let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+        let writer = ThresholdWriter::new(inner, Some(20.0));
+        block_on(async {
+            writer.write(&DeviceEvent::new("/dev", vec!(
+                (PropertyKind::State, State(2)),
+                (PropertyKind::Percentage, Percentage(30.0))
+            ))).await.unwrap();
+            writer.write(&DeviceEvent::new("/dev", vec!(
+                (PropertyKind::EnergyFull, EnergyFull(40.0)),
+                (PropertyKind::EnergyRate, EnergyRate(8.0))
+            ))).await.unwrap();
+            writer.write(&DeviceEvent::new("/dev", vec!(
+                (PropertyKind::TimeToEmpty, TimeToEmpty(3600)),
+                (PropertyKind::State, State(1))
+            ))).await.unwrap();
+        });
+        assert_eq!(buf.contents(), "/dev State=Discharging Percentage=30\n\
+            /dev EnergyFull=40 EnergyRate=8 TimeToThreshold=1800\n\
+            /dev TimeToEmpty=01:00:00 State=Charging\n");
+    }
+}
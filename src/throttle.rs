@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use futures::future::pending;
+use zbus::{Connection, MessageStream, Result as zbus_Result, export::futures_util::TryStreamExt};
+use zbus::fdo::PropertiesChanged;
+use zbus::zvariant::{OwnedValue, Value};
+use crate::connect::with_method_timeout;
+use crate::upower::{properties_changed_rule, UPOWER_PATH, UPOWER_SERVICE};
+
+/// The interface of the UPower daemon itself, whose `OnBattery` property says whether the system
+/// is running on battery power.
+const UPOWER_INTERFACE: &str = "org.freedesktop.UPower";
+
+/// Lengthens upmon's own periodic work (checking for stale devices, writing reports and writing
+/// aggregated changes) while the system is on battery power, so that upmon wakes the system less
+/// often when power matters most.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    /// How many times longer intervals are made while on battery, or `None` if they are not.
+    factor: Option<u32>,
+    /// Whether the system is currently on battery power.
+    on_battery: AtomicBool
+}
+
+impl Throttle {
+    /// Create a new [`Throttle`] which makes intervals `factor` times longer while on battery.
+    pub(crate) fn new(factor: Option<u32>) -> Self {
+        Self { factor, on_battery: AtomicBool::new(false) }
+    }
+
+    /// Return how long to wait for `interval`, taking into account whether the system is on
+    /// battery.
+    pub(crate) fn interval(&self, interval: Duration) -> Duration {
+        match self.factor {
+            Some(factor) if self.on_battery.load(Ordering::Relaxed) => {
+                interval.saturating_mul(factor)
+            },
+            _ => interval
+        }
+    }
+
+    /// Record whether the system is on battery power.
+    fn set_on_battery(&self, on_battery: bool) {
+        self.on_battery.store(on_battery, Ordering::Relaxed);
+    }
+
+    /// Fetch UPower's `OnBattery` property and keep track of changes to it. If intervals are not
+    /// lengthened on battery, this never completes.
+    pub(crate) async fn watch(&self, conn: &Connection) -> zbus_Result<()> {
+        if self.factor.is_none() {
+            return pending().await
+        }
+        // Subscribe before fetching the current value, so that no change is missed in between.
+        let mut stream = MessageStream::for_match_rule(
+            properties_changed_rule(UPOWER_PATH, UPOWER_INTERFACE)?,
+            conn,
+            None
+        ).await?;
+        let on_battery: OwnedValue = with_method_timeout("Get", conn.call_method(
+            Some(UPOWER_SERVICE),
+            UPOWER_PATH,
+            Some("org.freedesktop.DBus.Properties"),
+            "Get",
+            &(UPOWER_INTERFACE, "OnBattery")
+        )).await?.body()?;
+        self.set_on_battery(matches!(&*on_battery, Value::Bool(true)));
+        loop {
+            let Some(msg) = stream.try_next().await? else {
+                return Ok(())
+            };
+            // Signals which cannot be parsed are skipped.
+            let Some(signal) = PropertiesChanged::from_message(msg) else {
+                continue
+            };
+            let Ok(args) = signal.args() else {
+                continue
+            };
+            if let Some(Value::Bool(on_battery)) = args.changed_properties.get("OnBattery") {
+                self.set_on_battery(*on_battery);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+    use crate::rt::block_on;
+    use crate::testing::{eventually, MockUPower, run_until};
+    use crate::throttle::Throttle;
+
+    /// Test that intervals are lengthened only while UPower reports that the system is on battery,
+    /// and only if asked.
+    #[test]
+    fn throttled() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(Throttle::default().interval(minute), minute);
+        block_on(async {
+            let upower = MockUPower::new().await.unwrap();
+            let throttle = Throttle::new(Some(4));
+            upower.set_on_battery(true).await.unwrap();
+            assert_eq!(throttle.interval(minute), minute);
+            run_until(throttle.watch(&upower.client), async {
+                // The current value is fetched on startup.
+                assert!(eventually(|| throttle.interval(minute) == minute * 4).await);
+                upower.set_on_battery(false).await.unwrap();
+                assert!(eventually(|| throttle.interval(minute) == minute).await);
+                upower.set_on_battery(true).await.unwrap();
+                assert!(eventually(|| throttle.interval(minute) == minute * 4).await);
+            }).await;
+        });
+    }
+}
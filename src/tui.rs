@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::Error;
+use std::time::Duration;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::Local;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, List, Row, Sparkline, Table};
+use ratatui::Frame;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::rt::sleep;
+use crate::upower::{Property, PropertyKind};
+
+/// The number of percentage samples kept for each device's sparkline.
+const HISTORY_SIZE: usize = 200;
+
+/// The number of lines kept in the event log.
+const LOG_SIZE: usize = 500;
+
+/// The interval at which the dashboard is redrawn and checked for key presses.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The state shown by the dashboard.
+#[derive(Debug, Default)]
+struct Dashboard {
+    /// The latest formatted value of each property of each device.
+    values: BTreeMap<String, BTreeMap<String, String>>,
+    /// Recent values of each device's `Percentage`.
+    history: HashMap<String, VecDeque<u64>>,
+    /// Recent events, most recent last.
+    log: VecDeque<String>
+}
+
+impl Dashboard {
+    /// Add a line to the event log.
+    fn log(&mut self, line: String) {
+        if self.log.len() == LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back(format!("{} {line}", Local::now().format("%H:%M:%S")));
+    }
+
+    /// Update the dashboard with the given changes.
+    fn update(&mut self, event: &DeviceEvent) {
+        let values = self.values.entry(event.device.clone()).or_default();
+        let mut changed = event.iter()
+            .map(|(k, v)| {
+                values.insert(String::from(k.as_str()), v.to_string());
+                format!("{k}={v}")
+            })
+            .collect::<Vec<_>>();
+        changed.sort();
+        if let Some(Property::Percentage(p)) = event.get(&PropertyKind::Percentage) {
+            let history = self.history.entry(event.device.clone()).or_default();
+            if history.len() == HISTORY_SIZE {
+                history.pop_front();
+            }
+            history.push_back(p.round() as u64);
+        }
+        self.log(format!("{} {}", event.device, changed.join(" ")));
+    }
+
+    /// Draw the dashboard: a table of current values, a sparkline of each device's percentage and
+    /// the event log.
+    fn draw(&self, frame: &mut Frame) {
+        let rows = self.values.iter()
+            .flat_map(|(device, values)| values.iter().map(move |(k, v)| {
+                Row::new(vec!(device.as_str(), k.as_str(), v.as_str()))
+            }))
+            .collect::<Vec<_>>();
+        let n_rows = rows.len() as u16;
+        let n_sparklines = self.history.len() as u16;
+        let [table_area, sparkline_area, log_area] = Layout::vertical([
+            Constraint::Length(n_rows + 3),
+            Constraint::Length(n_sparklines * 3),
+            Constraint::Min(3)
+        ]).areas(frame.area());
+
+        let table = Table::new(rows, [
+            Constraint::Percentage(60),
+            Constraint::Percentage(20),
+            Constraint::Percentage(20)
+        ])
+            .header(Row::new(vec!("Device", "Property", "Value")))
+            .block(Block::bordered().title("Devices (press q to quit)"));
+        frame.render_widget(table, table_area);
+
+        let mut devices = self.history.keys().collect::<Vec<_>>();
+        devices.sort();
+        let sparkline_areas = Layout::vertical(devices.iter().map(|_| Constraint::Length(3)))
+            .split(sparkline_area);
+        for (device, area) in devices.into_iter().zip(sparkline_areas.iter()) {
+            let data = self.history[device].iter().copied().collect::<Vec<_>>();
+            // Show the most recent values that fit.
+            let width = area.width.saturating_sub(2) as usize;
+            let sparkline = Sparkline::default()
+                .block(Block::bordered().title(format!("Percentage: {device}")))
+                .data(&data[data.len().saturating_sub(width)..])
+                .max(100);
+            frame.render_widget(sparkline, *area);
+        }
+
+        let shown = log_area.height.saturating_sub(2) as usize;
+        let log = List::new(self.log.iter().skip(self.log.len().saturating_sub(shown)).cloned())
+            .block(Block::bordered().title("Events"));
+        frame.render_widget(log, log_area);
+    }
+}
+
+/// A [`Writer`] which shows the latest value of each property of each device, a sparkline of each
+/// device's percentage and a log of changes and markers in a terminal dashboard, which is run by
+/// [`TuiWriter::run`].
+#[derive(Default)]
+pub struct TuiWriter {
+    /// The state shown by the dashboard.
+    dashboard: Mutex<Dashboard>
+}
+
+/// Restores the terminal when dropped, so that it is restored however the dashboard stops.
+struct RestoreTerminal;
+
+impl Drop for RestoreTerminal {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+impl TuiWriter {
+    /// Take over the terminal and show the dashboard until the user presses "q" or Esc.
+    pub(crate) async fn run(&self) -> Result<(), Error> {
+        let mut terminal = ratatui::try_init()?;
+        let _restore = RestoreTerminal;
+        loop {
+            {
+                let dashboard = self.dashboard.lock().await;
+                terminal.draw(|frame| dashboard.draw(frame))?;
+            }
+            while event::poll(Duration::ZERO)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press
+                        && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                        return Ok(())
+                    }
+                }
+            }
+            sleep(REFRESH_INTERVAL).await;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for TuiWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        self.dashboard.lock().await.update(event);
+        Ok(())
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        self.dashboard.lock().await.log(String::from(marker));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use crate::event::DeviceEvent;
+    use crate::output::Writer;
+    use crate::rt::block_on;
+    use crate::tui::TuiWriter;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+
+    /// Test that changes and markers are shown on the dashboard.
+    #[test]
+    fn dashboard() {
+        let writer = TuiWriter::default();
+        for p in [80.0, 79.0] {
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(p));
+            changes.insert(PropertyKind::State, State(2));
+            block_on(writer.write(&DeviceEvent::new("/dev/battery", changes))).unwrap();
+        }
+        block_on(writer.write_marker("Resumed")).unwrap();
+
+        let dashboard = block_on(writer.dashboard.lock());
+        assert_eq!(dashboard.values["/dev/battery"]["Percentage"], "79");
+        assert_eq!(dashboard.history["/dev/battery"], vec!(80, 79));
+        assert_eq!(dashboard.log.len(), 3);
+        assert!(dashboard.log[0].ends_with("/dev/battery Percentage=80 State=Discharging"));
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|frame| dashboard.draw(frame)).unwrap();
+        let screen = terminal.backend().buffer().content().iter()
+            .map(|c| c.symbol())
+            .collect::<String>();
+        assert!(screen.contains("Discharging"));
+        assert!(screen.contains("Percentage: /dev/battery"));
+        assert!(screen.contains("Resumed"));
+    }
+}
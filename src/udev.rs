@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use nix::sys::socket::{
+    bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType
+};
+use zbus::zvariant::Value::{self, Bool, F64, U32};
+use crate::output::Writer;
+use crate::record::Recorder;
+use crate::rt::AsyncFd;
+use crate::upower::{DeviceConfig, DeviceType, ListenError, UPOWER_DEVICES_PATH};
+
+/// The netlink multicast group to which the kernel sends uevents.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// Maximum size of a single uevent message.
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// Convert the value of a `POWER_SUPPLY_STATUS` uevent field to the equivalent value of the UPower
+/// `State` property, following the same mapping as UPower itself.
+fn status_to_state(status: &str) -> u32 {
+    match status.to_lowercase().as_str() {
+        "charging" => 1,
+        "discharging" => 2,
+        "empty" => 3,
+        "full" => 4,
+        "not charging" => 5,
+        _ => 0
+    }
+}
+
+/// Return the type UPower gives a device with the given `POWER_SUPPLY_TYPE`.
+fn supply_type_to_device_type(supply_type: &str) -> DeviceType {
+    match supply_type {
+        "Mains" | "USB" | "USB_C" | "USB_PD" | "USB_PD_DRP" => DeviceType::LinePower,
+        "UPS" => DeviceType::Ups,
+        _ => DeviceType::Battery
+    }
+}
+
+/// Parse a kernel uevent message. If the message relates to a device in the `power_supply`
+/// subsystem, return the UPower object path of the device (assuming UPower's naming scheme) and
+/// the UPower properties described by the message. Otherwise, return `None`.
+pub(crate) fn parse_uevent(msg: &[u8]) -> Option<(String, HashMap<&'static str, Value<'static>>)> {
+    let fields: HashMap<&str, &str> = msg.split(|b| *b == 0)
+        .filter_map(|f| std::str::from_utf8(f).ok())
+        .filter_map(|f| f.split_once('='))
+        .collect();
+    if fields.get("SUBSYSTEM") != Some(&"power_supply") {
+        return None
+    }
+    let name = fields.get("POWER_SUPPLY_NAME")?;
+    let prefix = supply_type_to_device_type(fields.get("POWER_SUPPLY_TYPE").unwrap_or(&"Battery"))
+        .path_prefix();
+    let mut changed = HashMap::new();
+    if let Some(c) = fields.get("POWER_SUPPLY_CAPACITY").and_then(|c| c.parse().ok()) {
+        changed.insert("Percentage", F64(c));
+    }
+    if let Some(s) = fields.get("POWER_SUPPLY_STATUS") {
+        changed.insert("State", U32(status_to_state(s)));
+    }
+    if let Some(o) = fields.get("POWER_SUPPLY_ONLINE") {
+        changed.insert("Online", Bool(*o == "1"));
+    }
+    if let Some(p) = fields.get("POWER_SUPPLY_PRESENT") {
+        changed.insert("IsPresent", Bool(*p == "1"));
+    }
+    Some((format!("{UPOWER_DEVICES_PATH}/{prefix}_{name}"), changed))
+}
+
+/// A non-blocking netlink socket listening for kernel uevents. The socket is closed when this is
+/// dropped, as nothing else holds it while waiting for a uevent.
+struct UeventSocket {
+    fd: AsyncFd
+}
+
+impl UeventSocket {
+    /// Open a new netlink socket subscribed to kernel uevents.
+    fn open() -> Result<Self, Error> {
+        let raw_fd = socket(
+            AddressFamily::Netlink,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+            SockProtocol::NetlinkKObjectUEvent
+        )?;
+        // SAFETY: `raw_fd` was just returned by `socket` and is not owned by anything else.
+        let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+        bind(fd.as_raw_fd(), &NetlinkAddr::new(0, KERNEL_UEVENT_GROUP))?;
+        Ok(Self { fd: AsyncFd::new(fd)? })
+    }
+
+    /// Wait for and return the next uevent message.
+    async fn recv(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; UEVENT_BUFFER_SIZE];
+        let n = self.fd.read_with(|fd| Ok(recv(fd.as_raw_fd(), &mut buf, MsgFlags::empty())?))
+            .await?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+}
+
+/// Listen for kernel uevents relating to power supply devices, and write any relevant changes to
+/// the properties of the specified devices. Devices are identified by the object path UPower would
+/// give them, so the same device configuration can be used with either backend. If a [`Recorder`]
+/// is given, all properties received for the specified devices are also recorded.
+///
+/// Unlike UPower, the kernel reports all of a device's properties in each uevent, so every
+/// targeted property will be written whenever the device changes.
+pub(crate) async fn listen_all(
+    paths: &[DeviceConfig],
+    writer: &impl Writer,
+    recorder: Option<&Recorder>
+) -> Result<(), ListenError> {
+    let socket = UeventSocket::open()
+        .map_err(|e| ListenError::Receive(format!("Could not open uevent socket: {e}")))?;
+    loop {
+        let msg = socket.recv().await
+            .map_err(|e| ListenError::Receive(format!("Could not receive uevent: {e}")))?;
+        let Some((path, changed)) = parse_uevent(&msg) else {
+            continue
+        };
+        let mut targets = paths.iter().filter(|p| p.is_for(&path)).peekable();
+        if targets.peek().is_none() {
+            continue
+        }
+        if let Some(r) = recorder {
+            r.record(&path, &changed).await?;
+        }
+        for p in targets {
+            p.handle_changes(&changed, writer).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::os::unix::net::UnixDatagram;
+    use nix::sys::socket::{recv, MsgFlags};
+    use std::time::Duration;
+    use zbus::zvariant::Value::{Bool, F64, U32};
+    use crate::rt::{block_on, timeout, AsyncFd};
+    use crate::udev::parse_uevent;
+
+    /// Build a uevent message from the given fields.
+    fn uevent(header: &str, fields: &[&str]) -> Vec<u8> {
+        let mut msg = header.as_bytes().to_vec();
+        for f in fields {
+            msg.push(0);
+            msg.extend_from_slice(f.as_bytes());
+        }
+        msg
+    }
+
+    /// Test parsing of uevents for batteries and line power devices.
+    #[test]
+    fn parse_power_supply_uevents() {
+        let bat = uevent("change@/devices/LNXSYSTM:00/PNP0C0A:00/power_supply/BAT0", &[
+            "ACTION=change",
+            "SUBSYSTEM=power_supply",
+            "POWER_SUPPLY_NAME=BAT0",
+            "POWER_SUPPLY_TYPE=Battery",
+            "POWER_SUPPLY_STATUS=Discharging",
+            "POWER_SUPPLY_PRESENT=1",
+            "POWER_SUPPLY_CAPACITY=81"
+        ]);
+        let (path, changed) = parse_uevent(&bat).unwrap();
+        assert_eq!(path, "/org/freedesktop/UPower/devices/battery_BAT0");
+        assert_eq!(changed.len(), 3);
+        assert_eq!(changed["State"], U32(2));
+        assert_eq!(changed["IsPresent"], Bool(true));
+        assert_eq!(changed["Percentage"], F64(81.0));
+
+        let ac = uevent("change@/devices/LNXSYSTM:00/ACPI0003:00/power_supply/AC", &[
+            "ACTION=change",
+            "SUBSYSTEM=power_supply",
+            "POWER_SUPPLY_NAME=AC",
+            "POWER_SUPPLY_TYPE=Mains",
+            "POWER_SUPPLY_ONLINE=0"
+        ]);
+        let (path, changed) = parse_uevent(&ac).unwrap();
+        assert_eq!(path, "/org/freedesktop/UPower/devices/line_power_AC");
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed["Online"], Bool(false));
+    }
+
+    /// Test that uevents from other subsystems are ignored.
+    #[test]
+    fn ignore_other_uevents() {
+        let usb = uevent("add@/devices/pci0000:00/usb1/1-1", &[
+            "ACTION=add",
+            "SUBSYSTEM=usb",
+            "DEVTYPE=usb_device"
+        ]);
+        assert!(parse_uevent(&usb).is_none());
+        assert!(parse_uevent(b"").is_none());
+    }
+
+    /// Test that a socket is read once it becomes readable, and that waiting for it can be
+    /// abandoned (as when upmon stops listening) without leaving anything blocked on it.
+    #[test]
+    fn read_when_readable() {
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let mut buf = [0; 16];
+        let mut read = |fd: &OwnedFd| Ok(recv(fd.as_raw_fd(), &mut buf, MsgFlags::empty())?);
+        block_on(async {
+            let fd = AsyncFd::new(OwnedFd::from(receiver)).unwrap();
+            sender.send(b"change").unwrap();
+            assert_eq!(fd.read_with(&mut read).await.unwrap(), 6);
+            assert!(timeout(Duration::from_millis(50), fd.read_with(&mut read)).await.is_err());
+        });
+        // The socket has been closed, so there is nothing left to receive this.
+        assert!(sender.send(b"change").is_err());
+    }
+}
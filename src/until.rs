@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use async_channel::{bounded, Receiver, Sender};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use futures::future::pending;
+use crate::event::DeviceEvent;
+use crate::expr::Expr;
+use crate::output::Writer;
+use crate::upower::{Property, PropertyKind};
+
+/// A [`Writer`] which passes all changes on to an inner [`Writer`], and signals when a condition
+/// holds for the latest property values of any device, so that upmon can stop monitoring.
+pub struct UntilWriter<W: Writer> {
+    /// The writer to which changes are passed.
+    inner: W,
+    /// The condition to check, if any.
+    condition: Option<Expr>,
+    /// The latest value of each property of each device.
+    values: Mutex<HashMap<String, HashMap<PropertyKind, Property>>>,
+    /// Used to signal that the condition holds.
+    sender: Sender<()>,
+    /// Used to wait for the condition to hold.
+    receiver: Receiver<()>
+}
+
+impl<W: Writer> UntilWriter<W> {
+    /// Create a new [`UntilWriter`] which passes changes to `inner` and checks the given
+    /// condition.
+    pub(crate) fn new(inner: W, condition: Option<Expr>) -> Self {
+        let (sender, receiver) = bounded(1);
+        Self {
+            inner,
+            condition,
+            values: Mutex::new(HashMap::new()),
+            sender,
+            receiver
+        }
+    }
+
+    /// Wait until the condition holds for some device after a change has been written. If no
+    /// condition is set, this never completes.
+    pub(crate) async fn met(&self) {
+        if self.condition.is_none() || self.receiver.recv().await.is_err() {
+            pending::<()>().await
+        }
+    }
+
+    /// Return whether the condition has held for some device and this has not yet been waited
+    /// for by [`UntilWriter::met`].
+    pub(crate) fn is_met(&self) -> bool {
+        !self.receiver.is_empty()
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Writer> Writer for UntilWriter<W> {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), std::io::Error> {
+        self.inner.write(event).await?;
+        let Some(condition) = &self.condition else {
+            return Ok(())
+        };
+        let mut values = self.values.lock().await;
+        let values = values.entry(event.device.clone()).or_default();
+        for (k, v) in event.iter() {
+            values.insert(k.clone(), v.clone());
+        }
+        if condition.eval(values) {
+            // If the channel is already full, the condition has already been signalled.
+            let _ = self.sender.try_send(());
+        }
+        Ok(())
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), std::io::Error> {
+        self.inner.write_marker(marker).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use crate::event::DeviceEvent;
+    use crate::expr::Expr;
+    use crate::output::{LineWriter, Writer};
+    use crate::rt::{block_on, timeout};
+    use crate::testing::SharedBuffer;
+    use crate::until::UntilWriter;
+    use crate::upower::Property::Percentage;
+    use crate::upower::PropertyKind;
+
+    /// Test that the condition is signalled only once it holds.
+    #[test]
+    fn until() {
+        block_on(async {
+            let buf = SharedBuffer::default();
+            let inner = LineWriter::from_writer(Box::new(buf.clone()), "=", " ", false);
+            let condition = Expr::parse("Percentage >= 80").unwrap();
+            let writer = UntilWriter::new(inner, Some(condition));
+            let mut changes = HashMap::new();
+            changes.insert(PropertyKind::Percentage, Percentage(79.0));
+            writer.write(&DeviceEvent::new("/dev", changes.clone())).await.unwrap();
+            assert!(timeout(Duration::from_millis(50), writer.met()).await.is_err());
+            assert!(!writer.is_met());
+            changes.insert(PropertyKind::Percentage, Percentage(80.0));
+            writer.write(&DeviceEvent::new("/dev", changes)).await.unwrap();
+            assert!(writer.is_met());
+            assert!(timeout(Duration::from_millis(50), writer.met()).await.is_ok());
+            assert_eq!(buf.contents(), "/dev Percentage=79\n/dev Percentage=80\n");
+        })
+    }
+}
@@ -1,22 +1,98 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
-use chrono::{NaiveDateTime, SecondsFormat};
-use futures::future::join_all;
+use std::sync::Arc;
+use async_channel::{bounded, unbounded, Receiver, Sender};
+use async_lock::Mutex;
+use chrono::{DateTime, Local, SecondsFormat};
+use clap::ValueEnum;
+use futures::future::{abortable, AbortHandle};
+use futures::stream::FuturesUnordered;
+use futures::{select, FutureExt, StreamExt};
 use zbus::{
     Connection, MatchRule, MessageStream, MessageType, Result as zbus_Result,
     export::futures_util::TryStreamExt,
-    fdo::PropertiesChanged,
-    zvariant::Value::{self, F64, I64, U32, U64, Bool}
+    fdo::{DBusProxy, PropertiesChanged, PropertiesProxy},
+    names::{BusName, InterfaceName},
+    zvariant::{ObjectPath, OwnedValue, Value::{self, F64, I64, U32, U64, Bool}}
 };
 
 use Property::*;
-use strum::VariantNames;
+use serde::{Deserialize, Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use strum::{Display, EnumString, IntoStaticStr, VariantNames};
+use crate::connect::with_method_timeout;
+use crate::event::DeviceEvent;
+use crate::lifecycle::{write_lifecycle, Lifecycle};
 use crate::output::Writer;
+use crate::record::Recorder;
 
-/// Convert seconds to a string in the format HH:MM:SS.
-fn secs_to_hhmmss(mut s: i64) -> String {
+/// The well-known bus name of the UPower service.
+pub(crate) const UPOWER_SERVICE: &str = "org.freedesktop.UPower";
+
+/// The object path of the UPower service itself.
+pub(crate) const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+
+/// The object path under which UPower exposes devices.
+pub(crate) const UPOWER_DEVICES_PATH: &str = "/org/freedesktop/UPower/devices";
+
+/// The object path of UPower's composite display device.
+pub(crate) const DISPLAY_DEVICE_PATH: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+
+/// The DBus interface implemented by UPower devices.
+pub(crate) const UPOWER_DEVICE_INTERFACE: &str = "org.freedesktop.UPower.Device";
+
+/// The value of a UPower device's `Type` property if its type is unknown.
+const DEVICE_TYPE_UNKNOWN: u32 = 0;
+
+/// The value of a UPower device's `Type` property if it is a line power supply.
+const DEVICE_TYPE_LINE_POWER: u32 = 1;
+
+/// Names of the possible values of the `State` property, indexed by their numeric value.
+const STATE_NAMES: [&str; 7] = [
+    "Unknown",
+    "Charging",
+    "Discharging",
+    "Empty",
+    "FullyCharged",
+    "PendingCharge",
+    "PendingDischarge"
+];
+
+/// The value of the `State` property while a device is charging.
+pub(crate) const STATE_CHARGING: u32 = 1;
+
+/// The value of the `State` property while a device is discharging.
+pub(crate) const STATE_DISCHARGING: u32 = 2;
+
+/// Names of the possible values of the `WarningLevel` property, indexed by their numeric value.
+const WARNING_LEVEL_NAMES: [&str; 6] = [
+    "Unknown",
+    "None",
+    "Discharging",
+    "Low",
+    "Critical",
+    "Action"
+];
+
+/// Names of the possible values of the `BatteryLevel` property, indexed by their numeric value.
+/// UPower uses the same values as for `WarningLevel`, with three more for devices which are not
+/// low on charge; `None` means that the device reports a percentage rather than a coarse level.
+const BATTERY_LEVEL_NAMES: [&str; 9] = [
+    "Unknown",
+    "None",
+    "Discharging",
+    "Low",
+    "Critical",
+    "Action",
+    "Normal",
+    "High",
+    "Full"
+];
+
+/// Write a number of seconds in the format HH:MM:SS.
+fn write_hhmmss(f: &mut Formatter<'_>, mut s: i64) -> std::fmt::Result {
     if s <= 0 {
-        return String::from("00:00:00")
+        return f.write_str("00:00:00")
     }
     let h = s / 3600;
     if h > 0 {
@@ -26,7 +102,169 @@ fn secs_to_hhmmss(mut s: i64) -> String {
     if m > 0 {
         s %= m * 60;
     }
-    format!("{h:02}:{m:02}:{s:02}")
+    write!(f, "{h:02}:{m:02}:{s:02}")
+}
+
+/// Formats in which the `UpdateTime` property can be rendered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateTimeFormat {
+    /// An ISO 8601 timestamp in UTC, such as 2024-02-11T17:19:36Z.
+    #[default]
+    Utc,
+    /// An ISO 8601 timestamp in the local time zone, such as 2024-02-11T18:19:36+01:00.
+    Local,
+    /// The number of seconds elapsed since the update time, followed by "s", such as 42s.
+    Age
+}
+
+/// Render an `UpdateTime` value (a Unix timestamp) in the given format. `now` is the current Unix
+/// timestamp, used to calculate the age of the value.
+pub(crate) fn format_update_time(t: u64, format: UpdateTimeFormat, now: i64) -> String {
+    let time = DateTime::from_timestamp(t as i64, 0)
+        .expect("Could not parse datetime from UpdateTime value.");
+    match format {
+        UpdateTimeFormat::Utc => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        UpdateTimeFormat::Local => time.with_timezone(&Local)
+            .to_rfc3339_opts(SecondsFormat::Secs, false),
+        UpdateTimeFormat::Age => format!("{}s", (now - t as i64).max(0))
+    }
+}
+
+/// Render an arbitrary DBus value as a string, for properties which upmon does not specifically
+/// know how to format.
+fn format_value(v: &Value) -> String {
+    let join = |values: &[Value]| values.iter()
+        .map(format_value)
+        .collect::<Vec<String>>()
+        .join(",");
+    match v {
+        Value::U8(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::I16(n) => n.to_string(),
+        Value::U16(n) => n.to_string(),
+        Value::I32(n) => n.to_string(),
+        Value::U32(n) => n.to_string(),
+        Value::I64(n) => n.to_string(),
+        Value::U64(n) => n.to_string(),
+        Value::F64(n) => n.to_string(),
+        Value::Str(s) => s.to_string(),
+        Value::Signature(s) => s.to_string(),
+        Value::ObjectPath(p) => p.to_string(),
+        Value::Value(v) => format_value(v),
+        Value::Array(a) => format!("[{}]", join(a.get())),
+        Value::Structure(s) => format!("({})", join(s.fields())),
+        _ => format!("{v:?}")
+    }
+}
+
+/// The name of a property, by which changes to it are keyed. UPower device properties which upmon
+/// supports and the pseudo-properties which upmon adds to changes have their own variants, so that
+/// they can be matched exhaustively and compared without comparing strings; the names of other
+/// properties (when monitoring arbitrary interfaces) are held by `Other`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Display, EnumString, IntoStaticStr, VariantNames)]
+pub enum PropertyKind {
+    UpdateTime,
+    Online,
+    TimeToEmpty,
+    TimeToFull,
+    Percentage,
+    IsPresent,
+    State,
+    WarningLevel,
+    BatteryLevel,
+    ChargeStartThreshold,
+    ChargeEndThreshold,
+    ChargeThresholdEnabled,
+    ChargeThresholdSupported,
+    EnergyFull,
+    EnergyFullDesign,
+    Capacity,
+    EnergyRate,
+    /// The pseudo-property giving a device's [`crate::severity::Severity`], which is added to
+    /// each change written by a [`crate::severity::SeverityWriter`].
+    Severity,
+    /// The pseudo-property giving the raw (unsmoothed) value of `EnergyRate`, which is added to
+    /// each change to `EnergyRate` written by a [`crate::smooth::SmoothingWriter`] if requested.
+    EnergyRateRaw,
+    /// The pseudo-property indicating whether a device's `UpdateTime` is older than the
+    /// configured threshold, which is written by a [`crate::stale::StaleWriter`].
+    Stale,
+    /// The pseudo-property giving a glyph representing a device's charge level.
+    Icon,
+    /// The pseudo-property giving a bar (such as `[###--]`) representing a device's charge level.
+    Bar,
+    /// The pseudo-property giving the estimated number of seconds until a discharging device
+    /// reaches the configured percentage, which is added by a
+    /// [`crate::threshold::ThresholdWriter`].
+    TimeToThreshold,
+    #[strum(default)]
+    Other(String)
+}
+
+impl PropertyKind {
+    /// Return the kind of the property with the given name. Names are matched case-insensitively
+    /// and may be given in snake_case (such as `time_to_empty`), but are canonicalised to the
+    /// names used by UPower. Names which are not otherwise recognised are parsed as `Other`, so
+    /// this cannot fail.
+    pub(crate) fn from_name(name: &str) -> Self {
+        let normalised = name.replace('_', "").to_lowercase();
+        PropertyKind::VARIANTS.iter()
+            .filter(|v| **v != "Other")
+            .find(|v| v.to_lowercase() == normalised)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| PropertyKind::Other(String::from(name)))
+    }
+
+    /// Return the name of the supported UPower device property closest to `name`, if any is close
+    /// enough that `name` is likely to be a misspelling of it. Names are compared as they are
+    /// matched by [`PropertyKind::from_name`], ignoring case and underscores.
+    pub(crate) fn suggest(name: &str) -> Option<&'static str> {
+        let normalised = name.replace('_', "").to_lowercase();
+        let max_distance = (normalised.chars().count() / 3).max(1);
+        Property::names()
+            .map(|n| (edit_distance(&normalised, &n.to_lowercase()), n))
+            .filter(|(d, _)| *d <= max_distance)
+            .min_by_key(|(d, _)| *d)
+            .map(|(_, n)| n)
+    }
+
+    /// Return the name of the property.
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            PropertyKind::Other(name) => name,
+            kind => kind.into()
+        }
+    }
+
+    /// Whether this is a UPower device property which upmon supports (rather than a
+    /// pseudo-property or a property of some other interface).
+    pub(crate) fn is_upower(&self) -> bool {
+        !matches!(
+            self,
+            PropertyKind::Severity | PropertyKind::EnergyRateRaw | PropertyKind::Stale
+                | PropertyKind::Icon | PropertyKind::Bar | PropertyKind::TimeToThreshold
+                | PropertyKind::Other(_)
+        )
+    }
+
+    /// Whether this property is meaningful for UPower devices of the given `Type`. `Online` only
+    /// applies to line power, and properties describing a battery only apply to other devices;
+    /// every property is assumed to apply to devices of unknown type.
+    pub(crate) fn applies_to(&self, device_type: u32) -> bool {
+        match (self, device_type) {
+            (_, DEVICE_TYPE_UNKNOWN) => true,
+            (PropertyKind::Online, t) => t == DEVICE_TYPE_LINE_POWER,
+            (PropertyKind::UpdateTime | PropertyKind::WarningLevel, _) => true,
+            (kind, t) => !kind.is_upower() || t != DEVICE_TYPE_LINE_POWER
+        }
+    }
+}
+
+impl Serialize for PropertyKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
 /// Properties of the `org.freedesktop.UPower.Device` interface which can be monitored.
@@ -36,7 +274,10 @@ fn secs_to_hhmmss(mut s: i64) -> String {
 ///
 /// See https://upower.freedesktop.org/docs/Device.html#id-1.2.4.8.2 for all available properties
 /// and their descriptions.
-#[derive(Debug, PartialEq, VariantNames)]
+///
+/// The `Other` variant holds the value of a property of some other interface, when monitoring
+/// arbitrary interfaces; it is not itself a property name.
+#[derive(Clone, Debug, PartialEq, VariantNames)]
 pub enum Property {
     UpdateTime(u64),
     Online(bool),
@@ -44,175 +285,1550 @@ pub enum Property {
     TimeToFull(i64),
     Percentage(f64),
     IsPresent(bool),
-    State(u32)
+    State(u32),
+    WarningLevel(u32),
+    BatteryLevel(u32),
+    ChargeStartThreshold(u32),
+    ChargeEndThreshold(u32),
+    ChargeThresholdEnabled(bool),
+    ChargeThresholdSupported(bool),
+    EnergyFull(f64),
+    EnergyFullDesign(f64),
+    Capacity(f64),
+    EnergyRate(f64),
+    Other(OwnedValue)
 }
 
 impl Property {
+    /// Return the names of the UPower device properties that upmon supports.
+    pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+        Property::VARIANTS.iter()
+            .copied()
+            .filter(|v| *v != "Other")
+    }
+
     /// Create a ['Property'] variant from a key and value which may be returned from
-    /// [`zbus::fdo::PropertiesChangedArgs::changed_properties`].
-    fn from_key_value(k: &str, v: &Value) -> Result<Self, ()> {
-        match (k, v) {
-            ("UpdateTime", U64(t)) => Ok(UpdateTime(*t)),
-            ("Online", Bool(b)) => Ok(Online(*b)),
-            ("TimeToEmpty", I64(t)) => Ok(TimeToEmpty(*t)),
-            ("TimeToFull", I64(t)) => Ok(TimeToFull(*t)),
-            ("Percentage", F64(p)) => Ok(Percentage(*p)),
-            ("IsPresent", Bool(b)) => Ok(IsPresent(*b)),
-            ("State", U32(s)) => Ok(State(*s)),
+    /// [`zbus::fdo::PropertiesChangedArgs::changed_properties`]. Values wrapped in one or more
+    /// variants, as some D-Bus stacks deliver them, are unwrapped first.
+    fn from_key_value(k: &PropertyKind, v: &Value) -> Result<Self, ()> {
+        match (k, unwrap_variant(v)) {
+            (PropertyKind::UpdateTime, U64(t)) => Ok(UpdateTime(*t)),
+            (PropertyKind::Online, Bool(b)) => Ok(Online(*b)),
+            (PropertyKind::TimeToEmpty, I64(t)) => Ok(TimeToEmpty(*t)),
+            (PropertyKind::TimeToFull, I64(t)) => Ok(TimeToFull(*t)),
+            (PropertyKind::Percentage, F64(p)) => Ok(Percentage(*p)),
+            (PropertyKind::IsPresent, Bool(b)) => Ok(IsPresent(*b)),
+            (PropertyKind::State, U32(s)) => Ok(State(*s)),
+            (PropertyKind::WarningLevel, U32(w)) => Ok(WarningLevel(*w)),
+            (PropertyKind::BatteryLevel, U32(l)) => Ok(BatteryLevel(*l)),
+            (PropertyKind::ChargeStartThreshold, U32(t)) => Ok(ChargeStartThreshold(*t)),
+            (PropertyKind::ChargeEndThreshold, U32(t)) => Ok(ChargeEndThreshold(*t)),
+            (PropertyKind::ChargeThresholdEnabled, Bool(b)) => Ok(ChargeThresholdEnabled(*b)),
+            (PropertyKind::ChargeThresholdSupported, Bool(b)) => Ok(ChargeThresholdSupported(*b)),
+            (PropertyKind::EnergyFull, F64(e)) => Ok(EnergyFull(*e)),
+            (PropertyKind::EnergyFullDesign, F64(e)) => Ok(EnergyFullDesign(*e)),
+            (PropertyKind::Capacity, F64(c)) => Ok(Capacity(*c)),
+            (PropertyKind::EnergyRate, F64(r)) => Ok(EnergyRate(*r)),
             _ => Err(())
         }
     }
+
+    /// Create a [`Property`] variant from a key and a value whose type is not the one expected,
+    /// where this can be done without losing information: integers of any type (or floats with no
+    /// fractional part) are converted to the expected numeric type if they are in range, numbers
+    /// to floats, and 0 or 1 to booleans. Values wrapped in variants are unwrapped first.
+    fn coerce(k: &PropertyKind, v: &Value) -> Option<Self> {
+        let integer = value_integer(v);
+        let float = value_float(v);
+        let uint = || integer.and_then(|n| u32::try_from(n).ok());
+        let seconds = || integer.and_then(|n| i64::try_from(n).ok());
+        let boolean = || match integer {
+            Some(0) => Some(false),
+            Some(1) => Some(true),
+            _ => None
+        };
+        match k {
+            PropertyKind::UpdateTime => integer.and_then(|n| u64::try_from(n).ok()).map(UpdateTime),
+            PropertyKind::Online => boolean().map(Online),
+            PropertyKind::TimeToEmpty => seconds().map(TimeToEmpty),
+            PropertyKind::TimeToFull => seconds().map(TimeToFull),
+            PropertyKind::Percentage => float.map(Percentage),
+            PropertyKind::IsPresent => boolean().map(IsPresent),
+            PropertyKind::State => uint().filter(|s| (*s as usize) < STATE_NAMES.len()).map(State),
+            PropertyKind::WarningLevel => uint()
+                .filter(|w| (*w as usize) < WARNING_LEVEL_NAMES.len())
+                .map(WarningLevel),
+            PropertyKind::BatteryLevel => uint()
+                .filter(|l| (*l as usize) < BATTERY_LEVEL_NAMES.len())
+                .map(BatteryLevel),
+            PropertyKind::ChargeStartThreshold => uint().map(ChargeStartThreshold),
+            PropertyKind::ChargeEndThreshold => uint().map(ChargeEndThreshold),
+            PropertyKind::ChargeThresholdEnabled => boolean().map(ChargeThresholdEnabled),
+            PropertyKind::ChargeThresholdSupported => boolean().map(ChargeThresholdSupported),
+            PropertyKind::EnergyFull => float.map(EnergyFull),
+            PropertyKind::EnergyFullDesign => float.map(EnergyFullDesign),
+            PropertyKind::Capacity => float.map(Capacity),
+            PropertyKind::EnergyRate => float.map(EnergyRate),
+            _ => None
+        }
+    }
+
+    /// Return the value of the property as a number, if it has a numeric (or boolean) value.
+    /// Enumerated properties such as `State` return their numeric value, and booleans are
+    /// converted to 1 or 0.
+    pub(crate) fn as_f64(&self) -> Option<f64> {
+        Some(match self {
+            UpdateTime(t) => *t as f64,
+            TimeToEmpty(t) | TimeToFull(t) => *t as f64,
+            State(n) | WarningLevel(n) | BatteryLevel(n) | ChargeStartThreshold(n)
+                | ChargeEndThreshold(n) => *n as f64,
+            Online(b) | IsPresent(b) | ChargeThresholdEnabled(b) | ChargeThresholdSupported(b) =>
+                if *b { 1.0 } else { 0.0 },
+            Percentage(p) | EnergyFull(p) | EnergyFullDesign(p) | Capacity(p) | EnergyRate(p) =>
+                *p,
+            Other(v) => match &**v {
+                Value::U8(n) => *n as f64,
+                Value::Bool(b) => if *b { 1.0 } else { 0.0 },
+                Value::I16(n) => *n as f64,
+                Value::U16(n) => *n as f64,
+                Value::I32(n) => *n as f64,
+                Value::U32(n) => *n as f64,
+                Value::I64(n) => *n as f64,
+                Value::U64(n) => *n as f64,
+                Value::F64(n) => *n,
+                _ => return None
+            }
+        })
+    }
+
+    /// Return the value of the property as JSON, as it is serialized (see the [`Serialize`]
+    /// implementation).
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("Properties can always be serialized")
+    }
+
+    /// Create a [`Property`] of the given kind from its value as JSON, as produced by
+    /// [`Property::to_json`]. Enumerated properties may also be given as numbers, and `UpdateTime`
+    /// as a Unix timestamp. Values of pseudo-properties and properties of other interfaces are
+    /// returned as [`Property::Other`].
+    pub(crate) fn from_json(kind: &PropertyKind, value: &serde_json::Value)
+        -> Result<Self, String> {
+        let enumerated = |names: &[&str]| match value {
+            serde_json::Value::String(s) => names.iter().position(|n| n == s).map(|n| n as u32),
+            _ => value.as_u64().and_then(|n| u32::try_from(n).ok())
+        };
+        let uint = || value.as_u64().and_then(|n| u32::try_from(n).ok());
+        let property = match kind {
+            PropertyKind::UpdateTime => match value {
+                serde_json::Value::String(s) => DateTime::parse_from_rfc3339(s).ok()
+                    .and_then(|t| u64::try_from(t.timestamp()).ok()),
+                _ => value.as_u64()
+            }.map(UpdateTime),
+            PropertyKind::Online => value.as_bool().map(Online),
+            PropertyKind::TimeToEmpty => value.as_i64().map(TimeToEmpty),
+            PropertyKind::TimeToFull => value.as_i64().map(TimeToFull),
+            PropertyKind::Percentage => value.as_f64().map(Percentage),
+            PropertyKind::IsPresent => value.as_bool().map(IsPresent),
+            PropertyKind::State => enumerated(&STATE_NAMES).map(State),
+            PropertyKind::WarningLevel => enumerated(&WARNING_LEVEL_NAMES).map(WarningLevel),
+            PropertyKind::BatteryLevel => enumerated(&BATTERY_LEVEL_NAMES).map(BatteryLevel),
+            PropertyKind::ChargeStartThreshold => uint().map(ChargeStartThreshold),
+            PropertyKind::ChargeEndThreshold => uint().map(ChargeEndThreshold),
+            PropertyKind::ChargeThresholdEnabled => value.as_bool().map(ChargeThresholdEnabled),
+            PropertyKind::ChargeThresholdSupported => value.as_bool().map(ChargeThresholdSupported),
+            PropertyKind::EnergyFull => value.as_f64().map(EnergyFull),
+            PropertyKind::EnergyFullDesign => value.as_f64().map(EnergyFullDesign),
+            PropertyKind::Capacity => value.as_f64().map(Capacity),
+            PropertyKind::EnergyRate => value.as_f64().map(EnergyRate),
+            _ => match value {
+                serde_json::Value::Bool(b) => Some(Other(Value::from(*b).into())),
+                serde_json::Value::Number(n) => match n.as_i64() {
+                    Some(i) => Some(Other(Value::from(i).into())),
+                    None => n.as_f64().map(|f| Other(Value::from(f).into()))
+                },
+                serde_json::Value::String(s) => Some(Other(Value::from(s.as_str()).into())),
+                _ => None
+            }
+        };
+        property.ok_or_else(|| format!("Invalid value for {kind}: {value}"))
+    }
+
+    /// Return a description of the property with the given name, or `None` if upmon does not
+    /// support a property with that name.
+    pub(crate) fn info(name: &str) -> Option<PropertyInfo> {
+        let (dbus_type, units, values, description): (_, _, &[&str], _) = match name {
+            "UpdateTime" => ("t", Some("ISO 8601"), &[],
+                             "The point in time at which data was last read from the device."),
+            "Online" => ("b", None, &[], "Whether power is currently being provided."),
+            "TimeToEmpty" => ("x", Some("HH:MM:SS"), &[],
+                              "Number of seconds until the device is considered empty."),
+            "TimeToFull" => ("x", Some("HH:MM:SS"), &[],
+                             "Number of seconds until the device is considered full."),
+            "Percentage" => ("d", Some("%"), &[], "The amount of energy left in the device."),
+            "IsPresent" => ("b", None, &[], "Whether a battery is present in the bay."),
+            "State" => ("u", None, &STATE_NAMES, "The battery power state."),
+            "WarningLevel" => ("u", None, &WARNING_LEVEL_NAMES, "The warning level of the device."),
+            "BatteryLevel" => ("u", None, &BATTERY_LEVEL_NAMES,
+                               "The coarse charge level of a device which does not report a \
+                               percentage."),
+            "ChargeStartThreshold" => ("u", Some("%"), &[],
+                                       "The battery level below which charging starts."),
+            "ChargeEndThreshold" => ("u", Some("%"), &[],
+                                     "The battery level above which charging stops."),
+            "ChargeThresholdEnabled" => ("b", None, &[],
+                                         "Whether the charge thresholds are being applied."),
+            "ChargeThresholdSupported" => ("b", None, &[],
+                                           "Whether the device supports charge thresholds."),
+            "EnergyFull" => ("d", Some("Wh"), &[],
+                             "The amount of energy in the device when it is fully charged."),
+            "EnergyFullDesign" => ("d", Some("Wh"), &[],
+                                   "The amount of energy in the device when it is fully charged, \
+                                   by design."),
+            "Capacity" => ("d", Some("%"), &[],
+                           "The capacity of the device relative to its design capacity."),
+            "EnergyRate" => ("d", Some("W"), &[],
+                             "The rate at which energy is being drained from or supplied to the \
+                             device."),
+            _ => return None
+        };
+        Some(PropertyInfo {
+            name: String::from(name),
+            dbus_type,
+            units,
+            values: values.to_vec(),
+            formatted: matches!(
+                name,
+                "UpdateTime" | "TimeToEmpty" | "TimeToFull" | "State" | "WarningLevel"
+                    | "BatteryLevel"
+            ),
+            description
+        })
+    }
+}
+
+/// Static information about a property that upmon can monitor, as printed by
+/// `--list-properties`.
+#[derive(Debug, Serialize)]
+pub struct PropertyInfo {
+    /// The name of the property.
+    pub name: String,
+    /// The DBus type signature of the property's value.
+    pub dbus_type: &'static str,
+    /// The units (or format) in which upmon outputs the property's value, if any.
+    pub units: Option<&'static str>,
+    /// The names of the possible values of the property, for enumerated properties (in order of
+    /// their numeric value). Empty for properties which are not enumerated.
+    pub values: Vec<&'static str>,
+    /// Whether upmon formats the value before outputting it, rather than outputting the raw value.
+    pub formatted: bool,
+    /// A short description of the property.
+    pub description: &'static str
 }
 
 impl Display for Property {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", match self {
-            UpdateTime(t) => NaiveDateTime::from_timestamp_opt(*t as i64, 0)
-                .expect("Could not parse datetime from UpdateTime value.")
-                .and_utc()
-                .to_rfc3339_opts(SecondsFormat::Secs, true),
-            State(n) => match n {
-                0 => String::from("Unknown"),
-                1 => String::from("Charging"),
-                2 => String::from("Discharging"),
-                3 => String::from("Empty"),
-                4 => String::from("FullyCharged"),
-                5 => String::from("PendingCharge"),
-                6 => String::from("PendingDischarge"),
-                _ => panic!("Unexpected value for State: {n}")
+        // Values are written directly, rather than via intermediate strings, as this is done for
+        // every change written. Values of enumerated properties which upmon does not know (as a
+        // newer UPower might send) are written as the raw number.
+        match self {
+            UpdateTime(t) => f.write_str(&format_update_time(*t, UpdateTimeFormat::Utc, 0)),
+            State(n) => match STATE_NAMES.get(*n as usize) {
+                Some(s) => f.write_str(s),
+                None => write!(f, "{n}")
+            },
+            WarningLevel(n) => match WARNING_LEVEL_NAMES.get(*n as usize) {
+                Some(s) => f.write_str(s),
+                None => write!(f, "{n}")
+            },
+            BatteryLevel(n) => match BATTERY_LEVEL_NAMES.get(*n as usize) {
+                Some(s) => f.write_str(s),
+                None => write!(f, "{n}")
+            },
+            TimeToEmpty(t) | TimeToFull(t) => write_hhmmss(f, *t),
+            Online(b) | IsPresent(b) | ChargeThresholdEnabled(b) | ChargeThresholdSupported(b) =>
+                write!(f, "{b}"),
+            Percentage(p) | EnergyFull(p) | EnergyFullDesign(p) | Capacity(p) | EnergyRate(p) =>
+                write!(f, "{p}"),
+            ChargeStartThreshold(t) | ChargeEndThreshold(t) => write!(f, "{t}"),
+            Other(v) => f.write_str(&format_value(v))
+        }
+    }
+}
+
+impl Serialize for Property {
+    /// Serialize the value of the property. Enumerated properties and `UpdateTime` are given as
+    /// formatted strings (as in line output), except for unknown values of enumerated properties,
+    /// which are given as numbers. Time estimates are given as a number of seconds, and other
+    /// properties as numbers or booleans where possible.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            State(n) if STATE_NAMES.get(*n as usize).is_none() => serializer.serialize_u32(*n),
+            WarningLevel(n) if WARNING_LEVEL_NAMES.get(*n as usize).is_none() => {
+                serializer.serialize_u32(*n)
             },
-            TimeToEmpty(t) | TimeToFull(t) => secs_to_hhmmss(*t),
-            Online(b) | IsPresent(b) => b.to_string(),
-            Percentage(p) => p.to_string()
+            BatteryLevel(n) if BATTERY_LEVEL_NAMES.get(*n as usize).is_none() => {
+                serializer.serialize_u32(*n)
+            },
+            UpdateTime(_) | State(_) | WarningLevel(_) | BatteryLevel(_) => {
+                serializer.collect_str(self)
+            },
+            Online(b) | IsPresent(b) | ChargeThresholdEnabled(b) | ChargeThresholdSupported(b) =>
+                serializer.serialize_bool(*b),
+            TimeToEmpty(t) | TimeToFull(t) => serializer.serialize_i64(*t),
+            ChargeStartThreshold(t) | ChargeEndThreshold(t) => serializer.serialize_u32(*t),
+            Percentage(p) | EnergyFull(p) | EnergyFullDesign(p) | Capacity(p) | EnergyRate(p) =>
+                serializer.serialize_f64(*p),
+            Other(v) => match &**v {
+                Value::Bool(b) => serializer.serialize_bool(*b),
+                Value::F64(n) => serializer.serialize_f64(*n),
+                Value::Str(_) | Value::ObjectPath(_) | Value::Signature(_) | Value::Value(_)
+                | Value::Array(_) | Value::Structure(_) => serializer.collect_str(self),
+                // The remaining basic types are all integers.
+                _ => match self.as_f64() {
+                    Some(n) => serializer.serialize_i64(n as i64),
+                    None => serializer.collect_str(self)
+                }
+            }
+        }
+    }
+}
+
+/// Build and return a `MatchRule` object matching `PropertiesChanged` signals for the given path
+/// and interface. The interface is matched against the signal's first argument, so that the bus
+/// does not send upmon changes to other interfaces on the same path.
+pub(crate) fn properties_changed_rule<'a>(
+    path: &'a str,
+    interface: &'a str
+) -> zbus_Result<MatchRule<'a>> {
+    Ok(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(path)?
+        .arg(0, interface)?
+        .build())
+}
+
+/// Forms in which the match rules for configured devices can be printed: as the rules themselves,
+/// or as command lines which print the signals that upmon would receive using common D-Bus tools.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum RulesFormat {
+    /// The match rule.
+    Rule,
+    /// A `busctl monitor` command line using the match rule.
+    Busctl,
+    /// A `dbus-monitor` command line using the match rule.
+    DbusMonitor,
+    /// A `gdbus monitor` command line for the device's service and path. gdbus does not accept
+    /// match rules, so this also prints signals other than `PropertiesChanged`.
+    Gdbus
+}
+
+/// Split a device given as `LABEL@PATH` into the label of the bus it is on (see
+/// [`crate::connect::Bus`]) and its path. Devices on the system bus have no label.
+pub(crate) fn split_bus(device: &str) -> (Option<&str>, &str) {
+    match device.split_once('@') {
+        Some((bus, path)) => (Some(bus), path),
+        None => (None, device)
+    }
+}
+
+/// Return the DBus object path of the device given on the command line as `name`. Object paths
+/// are returned unchanged, once validated; anything else is taken as the name of a UPower device
+/// (such as `battery_BAT0` or `DisplayDevice`), and the path of that device returned. If `name` is
+/// prefixed with the label of a bus and `@`, the path is returned with the same prefix.
+pub(crate) fn expand_device_path(name: &str) -> Result<String, ConfigError> {
+    if let (Some(bus), path) = split_bus(name) {
+        if bus.is_empty() {
+            return Err(ConfigError::InvalidPath(String::from(name)))
+        }
+        return expand_device_path(path).map(|p| format!("{bus}@{p}"))
+    }
+    let path = match name.strip_prefix('/') {
+        Some(_) => String::from(name),
+        None if !name.contains('/') => format!("{UPOWER_DEVICES_PATH}/{name}"),
+        None => return Err(ConfigError::InvalidPath(String::from(name)))
+    };
+    match ObjectPath::try_from(path.as_str()) {
+        Ok(_) => Ok(path),
+        Err(_) => Err(ConfigError::InvalidPath(String::from(name)))
+    }
+}
+
+/// Return whether `name` is the name of a UPower device of a recognised [`DeviceType`], rather
+/// than (for example) a list of properties.
+fn is_device_name(name: &str) -> bool {
+    expand_device_path(name).is_ok_and(|p| DeviceType::from_path(split_bus(&p).1).is_some())
+}
+
+/// Return the Levenshtein distance between `a` and `b`: the number of characters which must be
+/// inserted, deleted or substituted to turn one into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Return the value inside any variants wrapping `v`, or `v` itself if it is not a variant.
+fn unwrap_variant<'a, 'b>(v: &'a Value<'b>) -> &'a Value<'b> {
+    match v {
+        Value::Value(inner) => unwrap_variant(inner),
+        v => v
+    }
+}
+
+/// Return the value of `v` (unwrapped from any variants) as an integer, if it is an integer of
+/// any type or a float with no fractional part.
+fn value_integer(v: &Value) -> Option<i128> {
+    match unwrap_variant(v) {
+        Value::U8(n) => Some(i128::from(*n)),
+        Value::I16(n) => Some(i128::from(*n)),
+        Value::U16(n) => Some(i128::from(*n)),
+        Value::I32(n) => Some(i128::from(*n)),
+        Value::U32(n) => Some(i128::from(*n)),
+        Value::I64(n) => Some(i128::from(*n)),
+        Value::U64(n) => Some(i128::from(*n)),
+        F64(f) if f.fract() == 0.0 && f.abs() < 2f64.powi(63) => Some(*f as i128),
+        _ => None
+    }
+}
+
+/// Return the value of `v` (unwrapped from any variants) as a float, if it is a number of any
+/// type.
+fn value_float(v: &Value) -> Option<f64> {
+    match unwrap_variant(v) {
+        F64(f) => Some(*f),
+        v => value_integer(v).map(|n| n as f64)
+    }
+}
+
+/// Return whether UPower is running on (or can be activated over) the bus of `conn`.
+pub(crate) async fn upower_available(conn: &Connection) -> zbus_Result<bool> {
+    with_method_timeout("NameHasOwner", async {
+        let proxy = DBusProxy::new(conn).await?;
+        let name = BusName::try_from(UPOWER_SERVICE)?;
+        Ok(proxy.name_has_owner(name).await?
+            || proxy.list_activatable_names().await?.iter().any(|n| n.as_str() == UPOWER_SERVICE))
+    }).await
+}
+
+/// The version of the UPower daemon in which each device property which has not always been
+/// available was added, for properties which can be monitored.
+const PROPERTY_VERSIONS: [(&str, &str); 6] = [
+    ("WarningLevel", "0.99.0"),
+    ("BatteryLevel", "0.99.5"),
+    ("ChargeStartThreshold", "1.90.5"),
+    ("ChargeEndThreshold", "1.90.5"),
+    ("ChargeThresholdEnabled", "1.90.5"),
+    ("ChargeThresholdSupported", "1.90.5")
+];
+
+/// Return the numeric components of a version such as `1.90.2`, ignoring anything after the digits
+/// of each component (such as a `-rc1` suffix).
+fn version_components(version: &str) -> Vec<u32> {
+    version.split('.')
+        .map_while(|c| {
+            let digits = c.split(|ch: char| !ch.is_ascii_digit()).next()?;
+            digits.parse().ok()
         })
+        .collect()
+}
+
+/// Return the version of UPower which added the property `kind`, if it was added after the given
+/// version of the daemon.
+pub(crate) fn required_version(kind: &PropertyKind, daemon_version: &str) -> Option<&'static str> {
+    let (_, since) = PROPERTY_VERSIONS.iter().find(|(name, _)| *name == kind.as_str())?;
+    (version_components(daemon_version) < version_components(since)).then_some(*since)
+}
+
+/// Ask UPower for its version, from its `DaemonVersion` property. Returns `None` if the version is
+/// not a string.
+pub(crate) async fn daemon_version(conn: &Connection) -> zbus_Result<Option<String>> {
+    let version: OwnedValue = with_method_timeout("Get", conn.call_method(
+        Some(UPOWER_SERVICE),
+        UPOWER_PATH,
+        Some("org.freedesktop.DBus.Properties"),
+        "Get",
+        &("org.freedesktop.UPower", "DaemonVersion")
+    )).await?.body()?;
+    Ok(match &*version {
+        Value::Str(s) => Some(s.to_string()),
+        _ => None
+    })
+}
+
+/// Types of UPower device for which upmon has a default set of properties to monitor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    LinePower,
+    Battery,
+    Ups
+}
+
+impl DeviceType {
+    /// Return the type of the UPower device at the given path, according to the prefix UPower
+    /// gives the object paths of devices of each type. The display device is treated as a battery.
+    pub(crate) fn from_path(path: &str) -> Option<Self> {
+        if path == DISPLAY_DEVICE_PATH {
+            return Some(DeviceType::Battery)
+        }
+        let name = path.strip_prefix(UPOWER_DEVICES_PATH)?.strip_prefix('/')?;
+        [DeviceType::LinePower, DeviceType::Battery, DeviceType::Ups].into_iter()
+            .find(|t| name.strip_prefix(t.path_prefix()).is_some_and(|n| n.starts_with('_')))
+    }
+
+    /// Return the prefix UPower uses in the object paths of devices of this type.
+    pub(crate) fn path_prefix(&self) -> &'static str {
+        match self {
+            DeviceType::LinePower => "line_power",
+            DeviceType::Battery => "battery",
+            DeviceType::Ups => "ups"
+        }
+    }
+
+    /// Return the properties monitored by default for devices of this type.
+    pub(crate) fn default_properties(&self) -> &'static [PropertyKind] {
+        match self {
+            DeviceType::LinePower => &[PropertyKind::Online],
+            DeviceType::Battery => &[
+                PropertyKind::Percentage,
+                PropertyKind::State,
+                PropertyKind::TimeToEmpty,
+                PropertyKind::TimeToFull
+            ],
+            DeviceType::Ups => &[
+                PropertyKind::Percentage,
+                PropertyKind::State,
+                PropertyKind::TimeToEmpty
+            ]
+        }
     }
 }
 
-/// A single configured device path.
+/// A property exposed by a device, as found by [`DeviceConfig::discover`].
 #[derive(Debug)]
+pub struct DiscoveredProperty {
+    /// The name of the property.
+    pub name: String,
+    /// The DBus type signature of the property's value.
+    pub dbus_type: String,
+    /// The property's current value.
+    pub value: Property
+}
+
+/// An error in the configuration of the devices and properties to monitor.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// No properties were given for a device.
+    NoTargets,
+    /// The given device path is not a valid DBus object path, or the given device name is not a
+    /// valid element of one.
+    InvalidPath(String),
+    /// `default` properties were requested for the device at the given path, whose type is not
+    /// recognised.
+    NoDefaults(String),
+    /// A property name was given which is not valid where it was used.
+    UnknownProperty {
+        /// The property name, as given.
+        name: String,
+        /// Where the property was given, such as "for device /org/freedesktop/UPower/devices/x" or
+        /// "in condition".
+        context: String,
+        /// The supported property whose name is closest to the one given, if any is close.
+        suggestion: Option<&'static str>
+    }
+}
+
+impl ConfigError {
+    /// Create a [`ConfigError::UnknownProperty`] for the property `name` given in `context`,
+    /// suggesting the closest supported property.
+    pub(crate) fn unknown_property(name: &str, context: &str) -> Self {
+        ConfigError::UnknownProperty {
+            name: String::from(name),
+            context: String::from(context),
+            suggestion: PropertyKind::suggest(name)
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NoTargets => {
+                write!(f, "Must specify one or more target properties to monitor.")
+            },
+            ConfigError::InvalidPath(path) => write!(f, "Invalid device path: {path}"),
+            ConfigError::NoDefaults(path) => {
+                write!(f, "No default properties for device {path}; specify them instead")
+            },
+            ConfigError::UnknownProperty { name, context, suggestion } => {
+                write!(f, "Unexpected property {context}: {name}")?;
+                match suggestion {
+                    Some(s) => write!(f, " (did you mean `{s}`?)"),
+                    None => Ok(())
+                }
+            }
+        }
+    }
+}
+
+impl From<ConfigError> for String {
+    fn from(e: ConfigError) -> Self {
+        e.to_string()
+    }
+}
+
+/// A single configured device path.
+#[derive(Clone, Debug)]
 pub struct DeviceConfig {
     /// The device's DBus object path.
     path: String,
+    /// The label of the bus the device is on, if it is not on the system bus.
+    bus: Option<String>,
     /// A list of properties that should be monitored for this device.
-    targets: Vec<String>
+    targets: Vec<PropertyKind>,
+    /// Whether every property exposed by the device is monitored, rather than only `targets`.
+    all: bool,
+    /// The DBus interface whose properties should be monitored, if not a UPower device.
+    interface: Option<String>,
+    /// The bus name of the service exposing the device, if known.
+    service: Option<String>,
+    /// Whether every signal received for the device is logged to standard error.
+    debug_signals: bool,
+    /// The properties whose values have been received with an unexpected type, and for which a
+    /// warning has therefore been written. This is shared between clones of the configuration.
+    mismatched: Arc<std::sync::Mutex<HashSet<PropertyKind>>>
 }
 
 impl DeviceConfig {
     /// Produce a single [`DeviceConfig`] from two string arguments. `path` should be the device
-    /// path and `targets` should be a comma-delimited list of properties to target.
-    fn new(path: &str, targets: &str) -> Result<Self, String> {
+    /// path and `targets` should be a comma-delimited list of properties to target. If `interface`
+    /// is given, properties of that interface are monitored instead of the UPower device
+    /// properties, and any property names are accepted. If `targets` is `*`, every property the
+    /// device exposes is monitored, including any which upmon does not otherwise support. If
+    /// `targets` is `default`, the default properties for the device's [`DeviceType`] are
+    /// monitored. `path` may also be the name of a UPower device, and may be prefixed with the
+    /// label of the bus the device is on (see [`expand_device_path`]).
+    pub(crate) fn new(path: &str, targets: &str, interface: Option<&str>)
+        -> Result<Self, ConfigError> {
+        let device = &expand_device_path(path)?;
+        let (bus, path) = split_bus(device);
         if targets.is_empty() {
-            return Err(String::from("Must specify one or more target properties to monitor."))
+            return Err(ConfigError::NoTargets)
+        }
+        if targets == "default" && interface.is_none() {
+            let Some(device_type) = DeviceType::from_path(path) else {
+                return Err(ConfigError::NoDefaults(String::from(path)))
+            };
+            let defaults = device_type.default_properties().iter()
+                .map(PropertyKind::as_str)
+                .collect::<Vec<_>>()
+                .join(",");
+            return Self::new(device, &defaults, interface)
         }
+        let all = targets == "*";
         let targs = targets.split(",")
+            .filter(|_| !all)
             .map(|s| {
-                if Property::VARIANTS.contains(&s) {
-                    Ok(String::from(s))
+                let kind = PropertyKind::from_name(s);
+                if interface.is_some() || kind.is_upower() {
+                    Ok(kind)
                 } else {
-                    Err(format!("Unexpected target property: {}", s))
+                    Err(ConfigError::unknown_property(s, &format!("for device {path}")))
                 }
             })
-            .collect::<Result<Vec<String>, String>>()?;
+            .collect::<Result<Vec<PropertyKind>, ConfigError>>()?;
         Ok(DeviceConfig {
             path: String::from(path),
-            targets: targs
+            bus: bus.map(String::from),
+            targets: targs,
+            all,
+            interface: interface.map(String::from),
+            service: match interface {
+                Some(_) => None,
+                None => Some(String::from(UPOWER_SERVICE))
+            },
+            debug_signals: false,
+            mismatched: Arc::default()
         })
     }
 
-    /// Produce a vector of [`DeviceConfig`] structs from a vector of string arguments. The vector
-    /// must have an even number of items. Each pair of items will be passed to
-    /// [`DeviceConfig::new`].
-    pub(crate) fn from_varargs(args: &[String]) -> Result<Vec<DeviceConfig>, String> {
-        let n_args = args.len();
-        if n_args % 2 != 0 {
-            return Err(format!("Invalid aggregate number of path arguments: {n_args}"))
-        }
+    /// Set the bus name of the service exposing the device.
+    pub(crate) fn with_service(mut self, service: &str) -> Self {
+        self.service = Some(String::from(service));
+        self
+    }
+
+    /// Set whether every signal received for the device is logged to standard error, along with
+    /// what is done with each changed property (see [`DeviceConfig::explain_changes`]).
+    pub(crate) fn with_debug_signals(mut self, debug_signals: bool) -> Self {
+        self.debug_signals = debug_signals;
+        self
+    }
+
+    /// Return the name of the DBus interface whose properties are monitored.
+    fn interface_name(&self) -> &str {
+        self.interface.as_deref().unwrap_or(UPOWER_DEVICE_INTERFACE)
+    }
+
+    /// Produce a vector of [`DeviceConfig`] structs from the arguments given to each occurrence of
+    /// `--path`. A pair of arguments is passed to [`DeviceConfig::new`] as the path and targets,
+    /// along with `interface`. A single argument is split into the path and targets at the first
+    /// colon (which cannot appear in a DBus object path); if there is no colon, an object path or
+    /// the name of a UPower device of a recognised [`DeviceType`] is taken as the path and the
+    /// `default` targets are monitored, and anything else is taken as the targets of the display
+    /// device.
+    pub(crate) fn from_args(args: &[Vec<String>], interface: Option<&str>)
+        -> Result<Vec<DeviceConfig>, ConfigError> {
         let mut v: Vec<DeviceConfig> = vec!();
-        let iter = args.chunks(2);
-        for c in iter {
-            v.push(DeviceConfig::new(&c[0], &c[1])?)
+        for arg in args {
+            let (path, targets) = match arg.as_slice() {
+                [path, targets] => (path.as_str(), targets.as_str()),
+                [arg] => match arg.split_once(':') {
+                    Some(split) => split,
+                    None if split_bus(arg).1.starts_with('/') || is_device_name(arg) => {
+                        (arg.as_str(), "default")
+                    },
+                    None => (DISPLAY_DEVICE_PATH, arg.as_str())
+                },
+                _ => unreachable!("--path takes one or two arguments")
+            };
+            v.push(DeviceConfig::new(path, targets, interface)?)
         }
         Ok(v)
     }
 
-    /// Collect the relevant changes into a `HashMap`.
-    fn collect_changes(&self, properties: &HashMap<&str, Value>) -> HashMap<&str, Property> {
-        let mut changes: HashMap<&str, Property> = HashMap::new();
+    /// Return the value of a changed property. Properties of interfaces other than UPower's, and
+    /// UPower properties which upmon does not otherwise support, are returned as `Other`. If the
+    /// value's type is not the one expected, it is converted if possible (see
+    /// [`Property::coerce`]), and a warning is written the first time this happens for each
+    /// property.
+    fn to_property(&self, k: &PropertyKind, v: &Value) -> Option<Property> {
+        if self.interface.is_some() || !k.is_upower() {
+            return Some(Other(unwrap_variant(v).into()))
+        }
+        Property::from_key_value(k, v).ok().or_else(|| {
+            let coerced = Property::coerce(k, v);
+            let mut mismatched = self.mismatched.lock().unwrap_or_else(|e| e.into_inner());
+            if mismatched.insert(k.clone()) {
+                let expected = Property::info(k.as_str()).map(|i| i.dbus_type).unwrap_or_default();
+                let outcome = match &coerced {
+                    Some(p) => format!("converting it to {p}"),
+                    None => String::from("it could not be converted, so changes will be ignored")
+                };
+                eprintln!(
+                    "Warning: {k} of {} has type {} rather than {expected}; {outcome}",
+                    self.path,
+                    v.value_signature()
+                );
+            }
+            coerced
+        })
+    }
+
+    /// Collect the relevant changes, in the order in which the targeted properties were given (or
+    /// by name, if every property is monitored). No memory is allocated if there are none.
+    fn collect_changes(&self, properties: &HashMap<&str, Value>) -> Vec<(PropertyKind, Property)> {
+        if self.all {
+            let mut names = properties.keys().collect::<Vec<_>>();
+            names.sort();
+            return names.into_iter()
+                .filter_map(|k| {
+                    let kind = PropertyKind::from_name(k);
+                    self.to_property(&kind, &properties[k]).map(|p| (kind, p))
+                })
+                .collect()
+        }
+        let mut changes = vec!();
+        if self.targets.iter().any(|k| properties.contains_key(k.as_str())) {
+            // At most this many changes can be collected, so the vector never needs to grow.
+            changes.reserve(self.targets.len().min(properties.len()));
+        }
         for k in &self.targets {
-            if let Some(v) = properties.get(k.as_str()) {
-                if let Ok(p) = Property::from_key_value(k, v) {
-                    changes.insert(k, p);
-                }
+            if let Some(p) = properties.get(k.as_str()).and_then(|v| self.to_property(k, v)) {
+                changes.push((k.clone(), p));
             }
         }
         changes
     }
 
+    /// Describe what is done with each of the given changed properties of `interface`, one line
+    /// per property (in order of name): the property's name, value and DBus type, followed by
+    /// `written` if the change is written (noting the expected type if the value had to be
+    /// converted), `not targeted` if the property is not monitored, or the expected type if the
+    /// value's type is not the one upmon expects and cannot be converted. If `interface` is not
+    /// monitored, no changes are written and a single line saying so is returned.
+    fn explain_changes(&self, interface: &str, properties: &HashMap<&str, Value>) -> Vec<String> {
+        if self.interface.as_deref().is_some_and(|i| i != interface) {
+            return vec!(format!("interface {interface} is not monitored"))
+        }
+        let mut names = properties.keys().collect::<Vec<_>>();
+        names.sort();
+        names.into_iter()
+            .map(|name| {
+                let v = &properties[name];
+                let kind = PropertyKind::from_name(name);
+                let reason = if !self.all && !self.targets.contains(&kind) {
+                    String::from("not targeted")
+                } else {
+                    let expected = Property::info(name).map(|i| i.dbus_type).unwrap_or_default();
+                    match self.to_property(&kind, v) {
+                        None => format!("type mismatch (expected {expected})"),
+                        Some(Other(_)) => String::from("written"),
+                        Some(_) if Property::from_key_value(&kind, v).is_ok() =>
+                            String::from("written"),
+                        Some(_) => format!("written after conversion (expected {expected})")
+                    }
+                };
+                format!("{name}={} ({}) {reason}", format_value(v), v.value_signature())
+            })
+            .collect()
+    }
+
     /// Build and return a `MatchRule` object for this path.
     pub(crate) fn rule(&self) -> zbus_Result<MatchRule<'_>> {
-        Ok(MatchRule::builder()
-            .msg_type(MessageType::Signal)
-            .interface("org.freedesktop.DBus.Properties")?
-            .member("PropertiesChanged")?
-            .path(self.path.as_str())?
-            .build())
+        properties_changed_rule(&self.path, self.interface_name())
+    }
+
+    /// Return the match rule for this path in the given format. This fails if the rule cannot be
+    /// built, or if the format needs the bus name of the device's service and it is not known.
+    pub(crate) fn rule_in(&self, format: RulesFormat) -> Result<String, String> {
+        let rule = self.rule().map_err(|e| e.to_string())?;
+        Ok(match format {
+            RulesFormat::Rule => rule.to_string(),
+            RulesFormat::Busctl => format!("busctl monitor --system --match \"{rule}\""),
+            RulesFormat::DbusMonitor => format!("dbus-monitor --system \"{rule}\""),
+            RulesFormat::Gdbus => {
+                let Some(service) = &self.service else {
+                    return Err(format!("Service exposing {} is not known", self.path))
+                };
+                format!("gdbus monitor --system --dest {service} --object-path {}", self.path)
+            }
+        })
+    }
+
+    /// Write any relevant changes from the given changed properties.
+    pub(crate) async fn handle_changes(
+        &self,
+        properties: &HashMap<&str, Value<'_>>,
+        writer: &impl Writer
+    ) -> Result<(), std::io::Error> {
+        let changes = self.collect_changes(properties);
+        if !changes.is_empty() {
+            writer.write(&DeviceEvent::new(&self.device(), changes)).await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to changes to properties for this device, returning a stream of the signals
+    /// describing them to be passed to [`DeviceConfig::listen_stream`].
+    pub(crate) async fn subscribe(&self, conn: &Connection) -> zbus_Result<MessageStream> {
+        MessageStream::for_match_rule(self.rule()?, conn, None).await
     }
 
     /// Listen for relevant changes to properties for this device, and write any detected changes.
-    async fn listen(&self, conn: &Connection, writer: &impl Writer) -> zbus_Result<()> {
-        let rule = self.rule()?;
-        let mut stream = MessageStream::for_match_rule(
-            rule,
-            conn,
-            None
-        ).await?;
+    /// If a [`Recorder`] is given, all changed properties received are also recorded.
+    #[cfg(test)]
+    pub(crate) async fn listen(
+        &self,
+        conn: &Connection,
+        writer: &impl Writer,
+        recorder: Option<&Recorder>
+    ) -> Result<(), ListenError> {
+        let stream = self.subscribe(conn).await?;
+        self.listen_stream(stream, writer, recorder).await
+    }
+
+    /// Write any relevant changes described by signals received from `stream`, as returned by
+    /// [`DeviceConfig::subscribe`]. If a [`Recorder`] is given, all changed properties received
+    /// are also recorded.
+    pub(crate) async fn listen_stream(
+        &self,
+        mut stream: MessageStream,
+        writer: &impl Writer,
+        recorder: Option<&Recorder>
+    ) -> Result<(), ListenError> {
         loop {
-            let msg = stream.try_next().await?.unwrap();
-            let signal = PropertiesChanged::from_message(msg).unwrap();
-            let args = signal.args()?;
-            let changes = self.collect_changes(&args.changed_properties);
-            if !changes.is_empty() {
-                writer.write(&self.path, &changes).await?;
+            let Some(msg) = stream.try_next().await? else {
+                return Ok(())
+            };
+            // Signals which cannot be parsed are skipped.
+            let Some(signal) = PropertiesChanged::from_message(msg) else {
+                continue
+            };
+            let Ok(args) = signal.args() else {
+                continue
+            };
+            if self.debug_signals {
+                let interface = args.interface_name().as_str();
+                for line in self.explain_changes(interface, &args.changed_properties) {
+                    eprintln!("PropertiesChanged {} {interface}: {line}", self.path);
+                }
             }
+            if let Some(i) = &self.interface {
+                if args.interface_name().as_str() != i {
+                    continue
+                }
+            }
+            if let Some(r) = recorder {
+                r.record(&self.path, &args.changed_properties).await?;
+            }
+            self.handle_changes(&args.changed_properties, writer).await?;
+        }
+    }
+
+    /// Fetch the current values of all properties of the monitored interface. Returns `None` if the
+    /// device's service is not known.
+    pub(crate) async fn fetch_properties(&self, conn: &Connection)
+        -> zbus_Result<Option<HashMap<String, OwnedValue>>> {
+        let Some(service) = &self.service else {
+            return Ok(None)
+        };
+        with_method_timeout("GetAll", async {
+            let proxy = PropertiesProxy::builder(conn)
+                .destination(service.as_str())?
+                .path(self.path.as_str())?
+                .build()
+                .await?;
+            Ok(Some(proxy.get_all(InterfaceName::try_from(self.interface_name())?).await?))
+        }).await
+    }
+
+    /// Fetch every property exposed by the device on the monitored interface, sorted by name.
+    /// Returns `None` if the device's service is not known.
+    pub(crate) async fn discover(&self, conn: &Connection)
+        -> zbus_Result<Option<Vec<DiscoveredProperty>>> {
+        let Some(all) = self.fetch_properties(conn).await? else {
+            return Ok(None)
+        };
+        let mut properties = all.iter()
+            .map(|(name, v)| DiscoveredProperty {
+                dbus_type: v.value_signature().to_string(),
+                value: self.to_property(&PropertyKind::from_name(name), v)
+                    .unwrap_or_else(|| Other(v.clone())),
+                name: name.clone()
+            })
+            .collect::<Vec<_>>();
+        properties.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Some(properties))
+    }
+
+    /// Return the targeted properties which will never change for this device, because they are
+    /// not returned by `GetAll` or (for UPower devices) do not apply to the device's `Type`.
+    /// Returns an empty vector if the device's service is not known.
+    pub(crate) async fn unsupported_targets(&self, conn: &Connection)
+        -> zbus_Result<Vec<&PropertyKind>> {
+        let Some(all) = self.fetch_properties(conn).await? else {
+            return Ok(vec!())
+        };
+        let device_type = match all.get("Type").map(|v| &**v) {
+            Some(Value::U32(t)) if self.is_upower() => *t,
+            _ => DEVICE_TYPE_UNKNOWN
+        };
+        Ok(self.targets.iter()
+            .filter(|k| !all.contains_key(k.as_str()) || !k.applies_to(device_type))
+            .collect())
+    }
+
+    /// Fetch the current values of all targeted properties and write them, as if they had all just
+    /// changed. Devices whose service is not known are skipped.
+    pub(crate) async fn refresh(&self, conn: &Connection, writer: &impl Writer) -> zbus_Result<()> {
+        let Some(all) = self.fetch_properties(conn).await? else {
+            return Ok(())
+        };
+        let properties: HashMap<&str, Value> = all.iter()
+            .map(|(k, v)| (k.as_str(), Value::clone(v)))
+            .collect();
+        self.handle_changes(&properties, writer).await?;
+        Ok(())
+    }
+
+    /// Return the device's DBus object path.
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether this configuration is for a UPower device.
+    pub(crate) fn is_upower(&self) -> bool {
+        self.interface.is_none()
+    }
+
+    /// Return the label of the bus the device is on, if it is not on the system bus.
+    pub(crate) fn bus(&self) -> Option<&str> {
+        self.bus.as_deref()
+    }
+
+    /// Return how the device is identified in changes: its path, prefixed with the label of its
+    /// bus and `@` if it is not on the system bus.
+    pub(crate) fn device(&self) -> String {
+        match &self.bus {
+            Some(bus) => format!("{bus}@{}", self.path),
+            None => self.path.clone()
+        }
+    }
+
+    /// Whether this configuration is for the device identified by `device` (see
+    /// [`DeviceConfig::device`]).
+    pub(crate) fn is_for(&self, device: &str) -> bool {
+        let (bus, path) = split_bus(device);
+        self.bus.as_deref() == bus && self.path == path
+    }
+}
+
+impl Serialize for DeviceConfig {
+    /// Serialize the device configuration, including the DBus match rule that will be used to
+    /// listen for changes to the device.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rule = self.rule().map_err(serde::ser::Error::custom)?;
+        let mut state = serializer.serialize_struct("DeviceConfig", 6)?;
+        state.serialize_field("path", &self.path)?;
+        state.serialize_field("bus", &self.bus)?;
+        state.serialize_field("service", &self.service)?;
+        state.serialize_field("interface", &self.interface)?;
+        if self.all {
+            state.serialize_field("properties", "*")?;
+        } else {
+            state.serialize_field("properties", &self.targets)?;
+        }
+        state.serialize_field("rule", &rule.to_string())?;
+        state.end()
+    }
+}
+
+/// An error which stopped upmon listening for changes to devices' properties.
+#[derive(Debug)]
+pub enum ListenError {
+    /// Changes could not be received.
+    Receive(String),
+    /// Changes could not be written or recorded.
+    Write(std::io::Error)
+}
+
+impl Display for ListenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenError::Receive(e) => write!(f, "{e}"),
+            ListenError::Write(e) => write!(f, "Error writing output: {e}")
+        }
+    }
+}
+
+impl From<zbus::Error> for ListenError {
+    fn from(e: zbus::Error) -> Self {
+        ListenError::Receive(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for ListenError {
+    fn from(e: std::io::Error) -> Self {
+        ListenError::Write(e)
+    }
+}
+
+/// A change to the devices in a [`DeviceSet`].
+enum DeviceUpdate {
+    /// A device has been added.
+    Added(Arc<DeviceConfig>),
+    /// All devices with the given path have been removed.
+    Removed(String)
+}
+
+/// A set of devices to monitor, to which devices can be added (and from which they can be removed)
+/// while they are being monitored by [`DeviceSet::listen`].
+pub struct DeviceSet {
+    /// The devices currently in the set.
+    configs: Mutex<Vec<Arc<DeviceConfig>>>,
+    /// Used to notify the listener of changes to the set.
+    sender: Sender<DeviceUpdate>,
+    /// Used to receive changes to the set.
+    receiver: Receiver<DeviceUpdate>,
+    /// Used to signal that the listener has subscribed to changes for the initial devices.
+    subscribed_sender: Sender<()>,
+    /// Used to wait for the listener to subscribe to changes for the initial devices.
+    subscribed_receiver: Receiver<()>,
+    /// Used to signal that the listener has applied a change to the set.
+    applied_sender: Sender<()>,
+    /// Used to wait for the listener to apply a change to the set.
+    #[cfg_attr(not(test), allow(dead_code))]
+    applied_receiver: Receiver<()>,
+    /// Whether a lifecycle marker is written when each device is subscribed to or lost.
+    lifecycle: bool
+}
+
+impl Default for DeviceSet {
+    fn default() -> Self {
+        let (sender, receiver) = unbounded();
+        let (subscribed_sender, subscribed_receiver) = bounded(1);
+        let (applied_sender, applied_receiver) = bounded(1);
+        Self {
+            configs: Mutex::new(vec!()),
+            sender,
+            receiver,
+            subscribed_sender,
+            subscribed_receiver,
+            applied_sender,
+            applied_receiver,
+            lifecycle: false
         }
     }
 }
 
-/// Listen for relevant changes to properties for all specified devices, and write any detected
-/// changes.
-pub async fn listen_all(conn: &Connection, paths: &[DeviceConfig], writer: &impl Writer) {
-    let mut futures = vec!();
-    for p in paths {
-        futures.push(p.listen(conn, writer));
+impl DeviceSet {
+    /// Set whether a [`crate::lifecycle::LIFECYCLE_MARKER`] is written when each device is
+    /// subscribed to, and when changes to it can no longer be received.
+    pub(crate) fn with_lifecycle(mut self, lifecycle: bool) -> Self {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    /// Add the given devices to the set, even if devices with the same paths are already present.
+    pub(crate) async fn extend(&self, configs: impl IntoIterator<Item = DeviceConfig>) {
+        let mut current = self.configs.lock().await;
+        for c in configs {
+            let c = Arc::new(c);
+            current.push(c.clone());
+            // The receiver is owned by the set, so the channel cannot be closed.
+            let _ = self.sender.try_send(DeviceUpdate::Added(c));
+        }
+    }
+
+    /// Add a device to the set. Returns an error if the device is already present.
+    pub(crate) async fn add(&self, config: DeviceConfig) -> Result<(), String> {
+        if self.configs.lock().await.iter().any(|c| c.is_for(&config.device())) {
+            return Err(format!("Device is already monitored: {}", config.device()))
+        }
+        self.extend([config]).await;
+        Ok(())
+    }
+
+    /// Remove all devices identified by `path` (see [`DeviceConfig::device`]) from the set. Returns
+    /// an error if there are none.
+    pub(crate) async fn remove(&self, path: &str) -> Result<(), String> {
+        let mut current = self.configs.lock().await;
+        if !current.iter().any(|c| c.is_for(path)) {
+            return Err(format!("Device is not monitored: {path}"))
+        }
+        current.retain(|c| !c.is_for(path));
+        let _ = self.sender.try_send(DeviceUpdate::Removed(String::from(path)));
+        Ok(())
+    }
+
+    /// Wait until [`DeviceSet::listen`] has subscribed to changes for the devices which were in the
+    /// set when it started, so that no changes to them will be missed.
+    pub(crate) async fn subscribed(&self) {
+        let _ = self.subscribed_receiver.recv().await;
+    }
+
+    /// Wait until [`DeviceSet::listen`] has applied a change made to the set, that is, until a
+    /// removed device is no longer listened to or an added device has been subscribed to. This
+    /// should be called after each change whose effect must be observed.
+    #[cfg(test)]
+    pub(crate) async fn applied(&self) {
+        let _ = self.applied_receiver.recv().await;
+    }
+
+    /// Return the devices currently in the set.
+    pub(crate) async fn configs(&self) -> Vec<Arc<DeviceConfig>> {
+        self.configs.lock().await.clone()
+    }
+
+    /// Listen for relevant changes to properties for all devices in the set, and write any detected
+    /// changes. Devices added to the set are listened to as soon as they are added, and devices
+    /// removed from the set are no longer listened to. Only one listener should run at a time.
+    /// Devices whose changes cannot be received are no longer monitored, but if changes cannot be
+    /// written, listening stops and the error is returned. Devices are listened to on `conn`, or
+    /// on the connection in `buses` with the label of their bus, if they are not on the system bus.
+    pub(crate) async fn listen(
+        &self,
+        conn: &Connection,
+        buses: &HashMap<String, Connection>,
+        writer: &impl Writer,
+        recorder: Option<&Recorder>
+    ) -> Result<(), std::io::Error> {
+        let mut listeners = FuturesUnordered::new();
+        let mut handles: HashMap<String, Vec<AbortHandle>> = HashMap::new();
+        let start = |
+            c: Arc<DeviceConfig>,
+            stream: Option<MessageStream>,
+            listeners: &mut FuturesUnordered<_>,
+            handles: &mut HashMap<String, Vec<AbortHandle>>
+        | {
+            let path = c.device();
+            let conn = c.bus().and_then(|b| buses.get(b)).unwrap_or(conn);
+            let lifecycle = self.lifecycle;
+            let applied = &self.applied_sender;
+            let (listener, handle) = abortable(async move {
+                let stream = match stream {
+                    Some(stream) => stream,
+                    None => {
+                        let stream = c.subscribe(conn).await;
+                        let _ = applied.try_send(());
+                        match stream {
+                            Ok(stream) => stream,
+                            Err(_) => return Ok(())
+                        }
+                    }
+                };
+                if lifecycle {
+                    write_lifecycle(writer, Lifecycle::DeviceSubscribed, Some(&c.device())).await?;
+                }
+                match c.listen_stream(stream, writer, recorder).await {
+                    Err(ListenError::Write(e)) => Err(e),
+                    _ if lifecycle => {
+                        write_lifecycle(writer, Lifecycle::DeviceLost, Some(&c.device())).await
+                    },
+                    _ => Ok(())
+                }
+            });
+            handles.entry(path).or_default().push(handle);
+            listeners.push(listener);
+        };
+        {
+            // Start listening to the current devices, discarding the updates which added them.
+            let current = self.configs.lock().await;
+            while self.receiver.try_recv().is_ok() {}
+            for c in current.iter() {
+                let conn = c.bus().and_then(|b| buses.get(b)).unwrap_or(conn);
+                if let Ok(stream) = c.subscribe(conn).await {
+                    start(c.clone(), Some(stream), &mut listeners, &mut handles);
+                }
+            }
+        }
+        let _ = self.subscribed_sender.try_send(());
+        loop {
+            select! {
+                update = self.receiver.recv().fuse() => match update {
+                    Ok(DeviceUpdate::Added(c)) => start(c, None, &mut listeners, &mut handles),
+                    Ok(DeviceUpdate::Removed(path)) => {
+                        for h in handles.remove(&path).unwrap_or_default() {
+                            h.abort();
+                        }
+                        let _ = self.applied_sender.try_send(());
+                    },
+                    Err(_) => return Ok(())
+                },
+                result = listeners.select_next_some() => if let Ok(Err(e)) = result {
+                    return Err(e)
+                }
+            }
+        }
     }
-    join_all(futures).await;
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
-    use zbus::zvariant::Value::{Bool, F64, I64, U32, U64};
-    use crate::upower::{DeviceConfig, Property};
+    use std::collections::HashMap;
+    use zbus::zvariant::Value::{self, Bool, F64, I64, U32, U64, U8};
+    use crate::upower::{
+        ConfigError, DeviceConfig, DeviceType, DISPLAY_DEVICE_PATH, edit_distance,
+        expand_device_path, format_update_time, Property, PropertyKind, required_version,
+        RulesFormat, UPOWER_DEVICES_PATH, UpdateTimeFormat
+    };
     use crate::upower::Property::{IsPresent, Online, Percentage, State, TimeToEmpty, TimeToFull,
-                                  UpdateTime};
+                                  UpdateTime, WarningLevel, BatteryLevel, ChargeStartThreshold,
+                                  ChargeEndThreshold, ChargeThresholdEnabled,
+                                  ChargeThresholdSupported, EnergyFull, EnergyFullDesign,
+                                  Capacity, EnergyRate, Other};
 
     /// Test creation of [`Property`] structs.
     #[test]
     fn create_property() {
+        let from = |k: &str, v| Property::from_key_value(&PropertyKind::from_name(k), v);
         let to_test = vec!(
-            (Property::from_key_value("UpdateTime", &U64(1707671976)), UpdateTime(1707671976)),
-            (Property::from_key_value("Online", &Bool(true)), Online(true)),
-            (Property::from_key_value("TimeToEmpty", &I64(12345)), TimeToEmpty(12345)),
-            (Property::from_key_value("TimeToFull", &I64(54321)), TimeToFull(54321)),
-            (Property::from_key_value("Percentage", &F64(54.22)), Percentage(54.22)),
-            (Property::from_key_value("IsPresent", &Bool(false)), IsPresent(false)),
-            (Property::from_key_value("State", &U32(2)), State(2))
+            (from("UpdateTime", &U64(1707671976)), UpdateTime(1707671976)),
+            (from("Online", &Bool(true)), Online(true)),
+            (from("TimeToEmpty", &I64(12345)), TimeToEmpty(12345)),
+            (from("TimeToFull", &I64(54321)), TimeToFull(54321)),
+            (from("Percentage", &F64(54.22)), Percentage(54.22)),
+            (from("IsPresent", &Bool(false)), IsPresent(false)),
+            (from("State", &U32(2)), State(2)),
+            (from("WarningLevel", &U32(3)), WarningLevel(3)),
+            (from("BatteryLevel", &U32(6)), BatteryLevel(6)),
+            (from("ChargeStartThreshold", &U32(40)), ChargeStartThreshold(40)),
+            (from("ChargeEndThreshold", &U32(80)), ChargeEndThreshold(80)),
+            (
+                from("ChargeThresholdEnabled", &Bool(true)),
+                ChargeThresholdEnabled(true)
+            ),
+            (
+                from("ChargeThresholdSupported", &Bool(true)),
+                ChargeThresholdSupported(true)
+            ),
+            (from("EnergyFull", &F64(45.5)), EnergyFull(45.5)),
+            (from("EnergyFullDesign", &F64(50.0)), EnergyFullDesign(50.0)),
+            (from("Capacity", &F64(91.0)), Capacity(91.0)),
+            (from("EnergyRate", &F64(12.5)), EnergyRate(12.5))
         );
         for (actual, expected) in to_test {
             assert!(actual.is_ok());
             assert_eq!(actual.unwrap(), expected);
         }
-        assert!(Property::from_key_value("SomeBadKey", &U32(2)).is_err());
-        assert!(Property::from_key_value("UpdateTime", &Bool(true)).is_err());
+        assert!(from("SomeBadKey", &U32(2)).is_err());
+        let wrapped = Value::Value(Box::new(F64(42.0)));
+        let nested = Value::Value(Box::new(wrapped.clone()));
+        assert_eq!(from("Percentage", &wrapped), Ok(Percentage(42.0)));
+        assert_eq!(from("Percentage", &nested), Ok(Percentage(42.0)));
+        assert!(from("State", &nested).is_err());
+        assert!(from("UpdateTime", &Bool(true)).is_err());
+    }
+
+    /// Test conversion of property names to and from [`PropertyKind`]s.
+    #[test]
+    fn property_kind() {
+        assert_eq!(PropertyKind::from_name("Percentage"), PropertyKind::Percentage);
+        assert_eq!(PropertyKind::from_name("Stale"), PropertyKind::Stale);
+        assert_eq!(PropertyKind::from_name("Source"), PropertyKind::Other(String::from("Source")));
+        assert_eq!(PropertyKind::from_name("percentage"), PropertyKind::Percentage);
+        assert_eq!(PropertyKind::from_name("time_to_empty"), PropertyKind::TimeToEmpty);
+        assert_eq!(PropertyKind::from_name("ENERGY_RATE_RAW"), PropertyKind::EnergyRateRaw);
+        assert_eq!(PropertyKind::from_name("other"), PropertyKind::Other(String::from("other")));
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(PropertyKind::suggest("Percentag"), Some("Percentage"));
+        assert_eq!(PropertyKind::suggest("time_to_emtpy"), Some("TimeToEmpty"));
+        assert_eq!(PropertyKind::suggest("Source"), None);
+        for name in ["Percentage", "EnergyRateRaw", "Source"] {
+            assert_eq!(PropertyKind::from_name(name).as_str(), name);
+            assert_eq!(PropertyKind::from_name(name).to_string(), name);
+        }
+        assert!(Property::names().all(|n| PropertyKind::from_name(n).is_upower()));
+        assert!(!PropertyKind::Severity.is_upower());
+        assert!(!PropertyKind::from_name("Source").is_upower());
+    }
+
+    /// Test that every supported property has associated [`crate::upower::PropertyInfo`].
+    #[test]
+    fn property_info() {
+        for p in Property::names() {
+            let info = Property::info(p);
+            assert!(info.is_some());
+            assert_eq!(info.unwrap().name, p);
+        }
+        let state = Property::info("State").unwrap();
+        assert_eq!(state.dbus_type, "u");
+        assert_eq!(state.values[2], "Discharging");
+        assert_eq!(state.values[2], State(2).to_string());
+        assert_eq!(Property::info("BatteryLevel").unwrap().values[8], BatteryLevel(8).to_string());
+        assert!(Property::info("Percentage").unwrap().values.is_empty());
+        assert!(Property::info("SomeBadKey").is_none());
+    }
+
+    /// Test rendering of UpdateTime in each format.
+    #[test]
+    fn update_time_formats() {
+        let t = 1707671976;
+        assert_eq!(format_update_time(t, UpdateTimeFormat::Utc, 0), "2024-02-11T17:19:36Z");
+        let local = chrono::DateTime::parse_from_rfc3339(
+            &format_update_time(t, UpdateTimeFormat::Local, 0)
+        ).unwrap();
+        assert_eq!(local.timestamp(), t as i64);
+        assert_eq!(format_update_time(t, UpdateTimeFormat::Age, t as i64 + 42), "42s");
+        assert_eq!(format_update_time(t, UpdateTimeFormat::Age, t as i64 - 5), "0s");
+    }
+
+    /// Test conversion of property values to JSON.
+    #[test]
+    fn property_json() {
+        assert_eq!(State(2).to_json(), serde_json::json!("Discharging"));
+        assert_eq!(State(9).to_json(), serde_json::json!(9));
+        assert_eq!(WarningLevel(12).to_json(), serde_json::json!(12));
+        assert_eq!(WarningLevel(12).to_string(), "12");
+        assert_eq!(TimeToEmpty(3600).to_json(), serde_json::json!(3600));
+        assert_eq!(Percentage(80.5).to_json(), serde_json::json!(80.5));
+        assert_eq!(Online(true).to_json(), serde_json::json!(true));
+        assert_eq!(UpdateTime(1707671976).to_json(), serde_json::json!("2024-02-11T17:19:36Z"));
+    }
+
+    /// Test collection and formatting of properties of arbitrary interfaces.
+    #[test]
+    fn generic_properties() {
+        let dev_conf = DeviceConfig::new(
+            "/org/bluez/hci0/dev_00_11_22_33_44_55",
+            "Percentage,Source,Sources",
+            Some("org.bluez.Battery1")
+        ).unwrap();
+        let mut properties = HashMap::new();
+        properties.insert("Percentage", U8(80));
+        properties.insert("Source", Value::from("HFP"));
+        properties.insert("Sources", Value::from(vec!("HFP", "GATT")));
+        properties.insert("Untargeted", Bool(true));
+        let changes = dev_conf.collect_changes(&properties);
+        // Changes are collected in the order in which the targets were given.
+        assert_eq!(
+            changes.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(),
+            vec!("Percentage", "Source", "Sources")
+        );
+        assert_eq!(changes[0].1.to_string(), "80");
+        assert_eq!(changes[1].1.to_string(), "HFP");
+        assert_eq!(changes[2].1.to_string(), "[HFP,GATT]");
+        assert_eq!(changes[0].1.to_json(), serde_json::json!(80));
+        assert_eq!(changes[1].1.to_json(), serde_json::json!("HFP"));
+
+        // Without an interface, properties are validated and parsed as UPower properties.
+        assert_eq!(
+            DeviceConfig::new("/org/bluez/hci0", "Source", None).unwrap_err(),
+            ConfigError::UnknownProperty {
+                name: String::from("Source"),
+                context: String::from("for device /org/bluez/hci0"),
+                suggestion: None
+            }
+        );
+        assert_eq!(
+            DeviceConfig::new("/org/bluez/hci0", "Percentage,TimeToEmtpy", None)
+                .unwrap_err()
+                .to_string(),
+            "Unexpected property for device /org/bluez/hci0: TimeToEmtpy \
+             (did you mean `TimeToEmpty`?)"
+        );
+    }
+
+    /// Test that every changed property is collected, sorted by name, when "*" is targeted, and
+    /// that properties upmon does not otherwise support are collected as `Other`.
+    #[test]
+    fn collect_all_properties() {
+        let dev_conf = DeviceConfig::new(DISPLAY_DEVICE_PATH, "*", None).unwrap();
+        let mut properties = HashMap::new();
+        properties.insert("State", U32(1));
+        properties.insert("Percentage", F64(80.0));
+        properties.insert("Vendor", Value::from("ACME"));
+        let changes = dev_conf.collect_changes(&properties);
+        assert_eq!(
+            changes,
+            vec!(
+                (PropertyKind::Percentage, Percentage(80.0)),
+                (PropertyKind::State, State(1)),
+                (PropertyKind::Other(String::from("Vendor")), Other(Value::from("ACME").into()))
+            )
+        );
+        assert_eq!(changes[2].1.to_string(), "ACME");
+        assert_eq!(serde_json::to_value(&dev_conf).unwrap()["properties"], "*");
+    }
+
+    /// Test that device names are expanded to UPower object paths, and that invalid paths and names
+    /// are rejected.
+    #[test]
+    fn device_names() {
+        let battery = format!("{UPOWER_DEVICES_PATH}/battery_BAT0");
+        assert_eq!(expand_device_path("battery_BAT0"), Ok(battery.clone()));
+        assert_eq!(expand_device_path(&battery), Ok(battery));
+        assert_eq!(expand_device_path("DisplayDevice").unwrap(), DISPLAY_DEVICE_PATH);
+        assert_eq!(expand_device_path("/org/bluez/hci0").unwrap(), "/org/bluez/hci0");
+        for name in ["battery-BAT0", "devices/battery_BAT0", "/org/bluez/", ""] {
+            assert_eq!(
+                expand_device_path(name),
+                Err(ConfigError::InvalidPath(String::from(name)))
+            );
+        }
+        assert!(DeviceConfig::new("battery BAT0", "Percentage", None).is_err());
+    }
+
+    /// Test that device types are recognised from UPower's object paths, and that `default` selects
+    /// the default properties for the device's type.
+    #[test]
+    fn default_properties() {
+        let battery = format!("{UPOWER_DEVICES_PATH}/battery_BAT0");
+        assert_eq!(DeviceType::from_path(&battery), Some(DeviceType::Battery));
+        assert_eq!(DeviceType::from_path(DISPLAY_DEVICE_PATH), Some(DeviceType::Battery));
+        let ac = format!("{UPOWER_DEVICES_PATH}/line_power_AC");
+        assert_eq!(DeviceType::from_path(&ac), Some(DeviceType::LinePower));
+        let ups = format!("{UPOWER_DEVICES_PATH}/ups_hiddev0");
+        assert_eq!(DeviceType::from_path(&ups), Some(DeviceType::Ups));
+        assert_eq!(DeviceType::from_path(&format!("{UPOWER_DEVICES_PATH}/mouse_0")), None);
+        assert_eq!(DeviceType::from_path(&format!("{UPOWER_DEVICES_PATH}/batteryX")), None);
+        assert_eq!(DeviceType::from_path("/org/bluez/hci0/battery_0"), None);
+
+        assert_eq!(
+            DeviceConfig::new(&battery, "default", None).unwrap().targets,
+            vec!(
+                PropertyKind::Percentage,
+                PropertyKind::State,
+                PropertyKind::TimeToEmpty,
+                PropertyKind::TimeToFull
+            )
+        );
+        assert_eq!(
+            DeviceConfig::new(&ac, "default", None).unwrap().targets,
+            vec!(PropertyKind::Online)
+        );
+        assert_eq!(
+            DeviceConfig::new(&ups, "default", None).unwrap().targets,
+            vec!(PropertyKind::Percentage, PropertyKind::State, PropertyKind::TimeToEmpty)
+        );
+        assert!(DeviceConfig::new(&format!("{UPOWER_DEVICES_PATH}/mouse_0"), "default", None)
+            .is_err());
+    }
+
+    /// Test that values of unexpected types are converted where this loses no information.
+    #[test]
+    fn coerce_property() {
+        let coerce = |k: &str, v: Value| Property::coerce(&PropertyKind::from_name(k), &v);
+        assert_eq!(coerce("Percentage", U32(42)), Some(Percentage(42.0)));
+        assert_eq!(coerce("Percentage", Value::Value(Box::new(F64(42.5)))), Some(Percentage(42.5)));
+        assert_eq!(coerce("State", I64(2)), Some(State(2)));
+        assert_eq!(coerce("State", F64(2.0)), Some(State(2)));
+        assert_eq!(coerce("State", F64(2.5)), None);
+        assert_eq!(coerce("State", U32(99)), None);
+        assert_eq!(coerce("TimeToEmpty", U32(3600)), Some(TimeToEmpty(3600)));
+        assert_eq!(coerce("UpdateTime", I64(-1)), None);
+        assert_eq!(coerce("Online", U8(1)), Some(Online(true)));
+        assert_eq!(coerce("Online", U8(2)), None);
+        assert_eq!(coerce("Online", Value::from("true")), None);
+    }
+
+    /// Test that the fate of each changed property is explained for --debug-signals.
+    #[test]
+    fn explain_changes() {
+        let battery = DeviceConfig::new(DISPLAY_DEVICE_PATH, "IsPresent,Percentage,State", None)
+            .unwrap();
+        let mut changes = HashMap::new();
+        changes.insert("IsPresent", Value::from("yes"));
+        changes.insert("Percentage", F64(42.0));
+        changes.insert("State", F64(2.0));
+        changes.insert("Voltage", F64(12.1));
+        assert_eq!(battery.explain_changes("org.freedesktop.UPower.Device", &changes), vec!(
+            "IsPresent=yes (s) type mismatch (expected b)",
+            "Percentage=42 (d) written",
+            "State=2 (d) written after conversion (expected u)",
+            "Voltage=12.1 (d) not targeted"
+        ));
+        let other = DeviceConfig::new("/org/bluez/hci0", "Percentage", Some("org.bluez.Battery1"))
+            .unwrap();
+        assert_eq!(
+            other.explain_changes("org.bluez.Device1", &changes),
+            vec!("interface org.bluez.Device1 is not monitored")
+        );
+    }
+
+    /// Test that match rules can be printed as command lines for D-Bus tools.
+    #[test]
+    fn rules_formats() {
+        let battery = DeviceConfig::new(DISPLAY_DEVICE_PATH, "Percentage", None).unwrap();
+        let rule = battery.rule_in(RulesFormat::Rule).unwrap();
+        assert_eq!(
+            battery.rule_in(RulesFormat::Busctl).unwrap(),
+            format!("busctl monitor --system --match \"{rule}\"")
+        );
+        assert_eq!(
+            battery.rule_in(RulesFormat::DbusMonitor).unwrap(),
+            format!("dbus-monitor --system \"{rule}\"")
+        );
+        assert_eq!(
+            battery.rule_in(RulesFormat::Gdbus).unwrap(),
+            format!("gdbus monitor --system --dest org.freedesktop.UPower \
+                --object-path {DISPLAY_DEVICE_PATH}")
+        );
+        let other = DeviceConfig::new("/org/bluez/hci0", "Percentage", Some("org.bluez.Battery1"))
+            .unwrap();
+        assert!(other.rule_in(RulesFormat::Gdbus).is_err());
     }
 
     /// Test creation of single [`DeviceConfig`] structs.
@@ -220,59 +1836,72 @@ pub(crate) mod tests {
     fn create_device_config() {
         let dev_path = "/org/freedesktop/UPower/devices/DisplayDevice";
 
-        let single_r = DeviceConfig::new(dev_path, "TimeToFull");
+        let single_r = DeviceConfig::new(dev_path, "TimeToFull", None);
         assert!(single_r.is_ok());
         let single = single_r.unwrap();
         assert_eq!(single.path, dev_path);
-        assert_eq!(single.targets, vec!(String::from("TimeToFull")));
+        assert_eq!(single.targets, vec!(PropertyKind::TimeToFull));
 
-        let multi_r = DeviceConfig::new(dev_path, "Online,State,Percentage");
+        let multi_r = DeviceConfig::new(dev_path, "Online,State,Percentage", None);
         assert!(multi_r.is_ok());
         let multi = multi_r.unwrap();
         assert_eq!(multi.path, dev_path);
         assert_eq!(
             multi.targets,
-            vec!(
-                String::from("Online"),
-                String::from("State"),
-                String::from("Percentage")
-            )
+            vec!(PropertyKind::Online, PropertyKind::State, PropertyKind::Percentage)
         );
 
-        let zero_r = DeviceConfig::new(dev_path, "");
+        let zero_r = DeviceConfig::new(dev_path, "", None);
         println!("{zero_r:?}");
         assert!(zero_r.is_err());
 
-        let invalid = DeviceConfig::new(dev_path, "Online,BadTarget");
+        let invalid = DeviceConfig::new(dev_path, "Online,BadTarget", None);
         assert!(invalid.is_err());
     }
 
     /// Test creation of multiple [`DeviceConfig`] structures using the
-    /// [`DeviceConfig::from_varargs`] function.
+    /// [`DeviceConfig::from_args`] function, including the single-argument forms.
     #[test]
     fn multi_device_configs() {
-        let good_args: Vec<String> = [
-            "/org/freedesktop/UPower/devices/DisplayDevice", "IsPresent,Percentage",
-            "/org/freedesktop/UPower/devices/line_power_AC", "Online"
-        ].iter().map(|s| String::from(*s)).collect();
-        let confs_r = DeviceConfig::from_varargs(&good_args);
+        let args = |a: &[&[&str]]| a.iter()
+            .map(|v| v.iter().map(|s| String::from(*s)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let good_args = args(&[
+            &["/org/freedesktop/UPower/devices/DisplayDevice", "IsPresent,Percentage"],
+            &["/org/freedesktop/UPower/devices/line_power_AC", "Online"]
+        ]);
+        let confs_r = DeviceConfig::from_args(&good_args, None);
         assert!(confs_r.is_ok());
         let confs = confs_r.unwrap();
         assert_eq!(confs.len(), 2);
 
-        let bad_number_args: Vec<String> = [
-            "/org/freedesktop/UPower/devices/DisplayDevice", "IsPresent,Percentage",
-            "/org/freedesktop/UPower/devices/line_power_AC"
-        ].iter().map(|s| String::from(*s)).collect();
-        let confs_r = DeviceConfig::from_varargs(&bad_number_args);
-        assert!(confs_r.is_err());
+        let short_args = args(&[
+            &["/org/freedesktop/UPower/devices/DisplayDevice:IsPresent,Percentage"],
+            &["/org/freedesktop/UPower/devices/line_power_AC"]
+        ]);
+        let confs = DeviceConfig::from_args(&short_args, None).unwrap();
+        assert_eq!(confs[0].path, "/org/freedesktop/UPower/devices/DisplayDevice");
+        assert_eq!(confs[0].targets, vec!(PropertyKind::IsPresent, PropertyKind::Percentage));
+        assert_eq!(confs[1].path, "/org/freedesktop/UPower/devices/line_power_AC");
+        assert_eq!(confs[1].targets, vec!(PropertyKind::Online));
 
-        let invalid_args: Vec<String> = [
-            "/org/freedesktop/UPower/devices/DisplayDevice", "IsPresent,BadTarget",
-            "/org/freedesktop/UPower/devices/line_power_AC", "Online"
-        ].iter().map(|s| String::from(*s)).collect();
-        let confs_r = DeviceConfig::from_varargs(&invalid_args);
+        let invalid_args = args(&[
+            &["/org/freedesktop/UPower/devices/DisplayDevice", "IsPresent,BadTarget"],
+            &["/org/freedesktop/UPower/devices/line_power_AC", "Online"]
+        ]);
+        let confs_r = DeviceConfig::from_args(&invalid_args, None);
         assert!(confs_r.is_err());
+        assert!(DeviceConfig::from_args(&args(&[&["/org/bluez/hci0"]]), None).is_err());
+
+        let confs = DeviceConfig::from_args(&args(&[&["battery_BAT0"], &["DisplayDevice"]]), None)
+            .unwrap();
+        assert_eq!(confs[0].path, format!("{UPOWER_DEVICES_PATH}/battery_BAT0"));
+        assert_eq!(confs[1].path, DISPLAY_DEVICE_PATH);
+        assert_eq!(confs[1].targets, DeviceType::Battery.default_properties());
+
+        let confs = DeviceConfig::from_args(&args(&[&["Percentage,State"]]), None).unwrap();
+        assert_eq!(confs[0].path, DISPLAY_DEVICE_PATH);
+        assert_eq!(confs[0].targets, vec!(PropertyKind::Percentage, PropertyKind::State));
     }
 
     /// Test creation of [`zbus::MatchRule`] structs.
@@ -280,14 +1909,68 @@ pub(crate) mod tests {
     fn rules() {
         let dev_conf = DeviceConfig::new(
             "/org/freedesktop/UPower/devices/DisplayDevice",
-            "TimeToFull"
+            "TimeToFull",
+            None
         ).unwrap();
         let rule_r = dev_conf.rule();
         assert!(rule_r.is_ok());
         let rule = rule_r.unwrap();
         let rule_str = "type='signal',interface='org.freedesktop.DBus.Properties',\
                             member='PropertiesChanged',\
-                            path='/org/freedesktop/UPower/devices/DisplayDevice'";
+                            path='/org/freedesktop/UPower/devices/DisplayDevice',\
+                            arg0='org.freedesktop.UPower.Device'";
         assert_eq!(rule.to_string(), rule_str);
+
+        let other = DeviceConfig::new(
+            "/org/bluez/hci0/dev_X",
+            "Percentage",
+            Some("org.bluez.Battery1")
+        ).unwrap();
+        assert!(other.rule().unwrap().to_string().ends_with(",arg0='org.bluez.Battery1'"));
     }
-}
\ No newline at end of file
+
+    /// Test serialization of [`DeviceConfig`] structs.
+    #[test]
+    fn serialize_device_config() {
+        let dev_conf = DeviceConfig::new(
+            "/org/freedesktop/UPower/devices/line_power_AC",
+            "Online",
+            None
+        ).unwrap();
+        let json = serde_json::to_value(&dev_conf).unwrap();
+        assert_eq!(json["path"], "/org/freedesktop/UPower/devices/line_power_AC");
+        assert_eq!(json["properties"], serde_json::json!(["Online"]));
+        assert_eq!(json["rule"], dev_conf.rule().unwrap().to_string());
+    }
+
+    /// Test that properties added after the daemon's version are detected.
+    #[test]
+    fn required_versions() {
+        let thresholds = PropertyKind::ChargeStartThreshold;
+        assert_eq!(required_version(&thresholds, "1.90.2"), Some("1.90.5"));
+        assert_eq!(required_version(&thresholds, "1.90.5"), None);
+        assert_eq!(required_version(&thresholds, "1.91.0-rc1"), None);
+        assert_eq!(required_version(&PropertyKind::BatteryLevel, "0.99.4"), Some("0.99.5"));
+        assert_eq!(required_version(&PropertyKind::BatteryLevel, "0.99.5"), None);
+        assert_eq!(required_version(&PropertyKind::Percentage, "0.9.23"), None);
+    }
+
+    /// Test that devices on other buses are given and identified with the label of their bus.
+    #[test]
+    fn bus_labels() {
+        let confs = DeviceConfig::from_args(&[
+            vec!(String::from("container@battery_BAT0")),
+            vec!(String::from("container@/org/freedesktop/UPower/devices/line_power_AC:Online"))
+        ], None).unwrap();
+        assert_eq!(confs[0].bus(), Some("container"));
+        assert_eq!(confs[0].path(), format!("{UPOWER_DEVICES_PATH}/battery_BAT0"));
+        assert_eq!(confs[0].device(), format!("container@{UPOWER_DEVICES_PATH}/battery_BAT0"));
+        assert!(confs[0].is_for(&confs[0].device()));
+        assert!(!confs[0].is_for(confs[0].path()));
+        assert_eq!(confs[1].targets, vec!(PropertyKind::Online));
+        assert_eq!(
+            expand_device_path("@battery_BAT0"),
+            Err(ConfigError::InvalidPath(String::from("@battery_BAT0")))
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use std::io::{Error, Write};
+use std::rc::Rc;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use serde_json::Value;
+use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
+use crate::event::DeviceEvent;
+use crate::http::{change_event, marker_event};
+use crate::output::Writer;
+
+/// The loaded instance of a [`WasmModule`], and its exports.
+struct Instance {
+    /// The store holding the instance's state.
+    store: Store<()>,
+    /// The instance's linear memory, in which events and formatted output are passed.
+    memory: Memory,
+    /// `alloc(len) -> ptr`, which returns a buffer of `len` bytes in which to write an event.
+    alloc: TypedFunc<i32, i32>,
+    /// `filter(ptr, len) -> keep`, if exported.
+    filter: Option<TypedFunc<(i32, i32), i32>>,
+    /// `format(ptr, len) -> (out_ptr << 32) | out_len`, if exported.
+    format: Option<TypedFunc<(i32, i32), i64>>
+}
+
+impl Instance {
+    /// Write `event` (as JSON) to a buffer allocated in the instance's memory, returning the
+    /// buffer's address and length.
+    fn pass(&mut self, event: &Value) -> Result<(i32, i32), wasmi::Error> {
+        let json = event.to_string();
+        let len = i32::try_from(json.len()).map_err(|_| wasmi::Error::new("Event too large"))?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, json.as_bytes())?;
+        Ok((ptr, len))
+    }
+}
+
+/// A WebAssembly module which can filter and format events. Events are passed to the module as
+/// JSON, in the same form as the events served over HTTP. The module must export its `memory` and
+/// an `alloc(len: i32) -> i32` function, which returns the address of a buffer of `len` bytes in
+/// which `upmon` writes each event. It may then export either or both of:
+///
+/// * `filter(ptr: i32, len: i32) -> i32`, which is given the event in the buffer and returns
+///   non-zero if it should be written.
+/// * `format(ptr: i32, len: i32) -> i64`, which is given the event in the buffer and returns the
+///   address of the UTF-8 text to write in its high 32 bits and the length of the text in its low
+///   32 bits.
+///
+/// `upmon` never frees buffers, so the module is free to reuse them once a call has returned.
+pub struct WasmModule {
+    /// The instance, which is only used by one call at a time.
+    instance: Mutex<Instance>,
+    /// Whether the module exports `filter`.
+    has_filter: bool,
+    /// Whether the module exports `format`.
+    has_format: bool
+}
+
+impl WasmModule {
+    /// Load the module (in binary format) from the file at `path`.
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Could not read WASM module {path}: {e}"))?;
+        Self::from_bytes(&bytes).map_err(|e| format!("Invalid WASM module {path}: {e}"))
+    }
+
+    /// Load the module from the given bytes (in binary format).
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, bytes).map_err(|e| e.to_string())?;
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| e.to_string())?;
+        let memory = instance.get_memory(&store, "memory")
+            .ok_or("Module does not export memory")?;
+        let alloc = instance.get_typed_func(&store, "alloc")
+            .map_err(|e| format!("Module does not export alloc(i32) -> i32: {e}"))?;
+        let filter = match instance.get_export(&store, "filter") {
+            Some(_) => Some(instance.get_typed_func(&store, "filter")
+                .map_err(|e| format!("filter must be filter(i32, i32) -> i32: {e}"))?),
+            None => None
+        };
+        let format = match instance.get_export(&store, "format") {
+            Some(_) => Some(instance.get_typed_func(&store, "format")
+                .map_err(|e| format!("format must be format(i32, i32) -> i64: {e}"))?),
+            None => None
+        };
+        if filter.is_none() && format.is_none() {
+            return Err(String::from("Module exports neither filter nor format"))
+        }
+        Ok(Self {
+            has_filter: filter.is_some(),
+            has_format: format.is_some(),
+            instance: Mutex::new(Instance { store, memory, alloc, filter, format })
+        })
+    }
+
+    /// Whether the module exports `filter`.
+    pub(crate) fn has_filter(&self) -> bool {
+        self.has_filter
+    }
+
+    /// Whether the module exports `format`.
+    pub(crate) fn has_format(&self) -> bool {
+        self.has_format
+    }
+
+    /// Return whether `event` should be written, according to the module's `filter` function.
+    /// Every event is written if the module does not export `filter`.
+    pub(crate) async fn filter(&self, event: &Value) -> Result<bool, Error> {
+        let mut instance = self.instance.lock().await;
+        let Some(filter) = instance.filter else {
+            return Ok(true)
+        };
+        let (ptr, len) = instance.pass(event).map_err(Error::other)?;
+        filter.call(&mut instance.store, (ptr, len)).map(|keep| keep != 0).map_err(Error::other)
+    }
+
+    /// Return `event` as formatted by the module's `format` function, or `None` if the module
+    /// does not export `format`.
+    pub(crate) async fn format(&self, event: &Value) -> Result<Option<String>, Error> {
+        let mut instance = self.instance.lock().await;
+        let Some(format) = instance.format else {
+            return Ok(None)
+        };
+        let (ptr, len) = instance.pass(event).map_err(Error::other)?;
+        let out = format.call(&mut instance.store, (ptr, len)).map_err(Error::other)?;
+        let (out_ptr, out_len) = ((out >> 32) as u32 as usize, out as u32 as usize);
+        let text = instance.memory.data(&instance.store)
+            .get(out_ptr..out_ptr + out_len)
+            .ok_or_else(|| Error::other("format returned text outside the module's memory"))?;
+        String::from_utf8(text.to_vec()).map(Some).map_err(Error::other)
+    }
+}
+
+/// A [`Writer`] which writes each change or marker as formatted by a [`WasmModule`], followed by
+/// a newline. Nothing is written for events which the module formats as an empty string.
+pub struct WasmWriter {
+    /// The module which formats events.
+    module: Rc<WasmModule>,
+    /// File (or other struct implementing Write) to write to.
+    out: Mutex<Box<dyn Write>>
+}
+
+impl WasmWriter {
+    /// Create a new [`WasmWriter`] which writes events formatted by `module` to `out`.
+    pub(crate) fn new(module: Rc<WasmModule>, out: Box<dyn Write>) -> Self {
+        Self { module, out: Mutex::new(out) }
+    }
+
+    /// Format `event` and write it.
+    async fn write_event(&self, event: &Value) -> Result<(), Error> {
+        match self.module.format(event).await? {
+            Some(text) if !text.is_empty() => writeln!(self.out.lock().await, "{text}"),
+            _ => Ok(())
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for WasmWriter {
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        self.write_event(&change_event(event)).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        self.write_event(&marker_event(marker)).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use serde_json::{json, Value};
+    use crate::event::DeviceEvent;
+    use crate::http::change_event;
+    use crate::output::Writer;
+    use crate::rt::block_on;
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::Percentage;
+    use crate::upower::PropertyKind;
+    use crate::wasm::{WasmModule, WasmWriter};
+
+    /// A module which keeps events whose JSON is longer than 40 bytes, and formats each event as
+    /// the event itself. In text format:
+    ///
+    /// ```text
+    /// (module
+    ///     (memory (export "memory") 1)
+    ///     (func (export "alloc") (param i32) (result i32) i32.const 16)
+    ///     (func (export "filter") (param i32 i32) (result i32)
+    ///         local.get 1
+    ///         i32.const 40
+    ///         i32.gt_s)
+    ///     (func (export "format") (param i32 i32) (result i64)
+    ///         local.get 0
+    ///         i64.extend_i32_u
+    ///         i64.const 32
+    ///         i64.shl
+    ///         local.get 1
+    ///         i64.extend_i32_u
+    ///         i64.or))
+    /// ```
+    const TEST_MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+        // Types: (i32) -> i32, (i32, i32) -> i32 and (i32, i32) -> i64.
+        0x01, 0x12, 0x03,
+        0x60, 0x01, 0x7f, 0x01, 0x7f,
+        0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f,
+        0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e,
+        // Functions: alloc, filter and format.
+        0x03, 0x04, 0x03, 0x00, 0x01, 0x02,
+        // Memory: one page.
+        0x05, 0x03, 0x01, 0x00, 0x01,
+        // Exports.
+        0x07, 0x24, 0x04,
+        0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+        0x05, b'a', b'l', b'l', b'o', b'c', 0x00, 0x00,
+        0x06, b'f', b'i', b'l', b't', b'e', b'r', 0x00, 0x01,
+        0x06, b'f', b'o', b'r', b'm', b'a', b't', 0x00, 0x02,
+        // Code.
+        0x0a, 0x1b, 0x03,
+        0x04, 0x00, 0x41, 0x10, 0x0b,
+        0x07, 0x00, 0x20, 0x01, 0x41, 0x28, 0x4a, 0x0b,
+        0x0c, 0x00, 0x20, 0x00, 0xad, 0x42, 0x20, 0x86, 0x20, 0x01, 0xad, 0x84, 0x0b
+    ];
+
+    /// A module which exports only its memory and `alloc`, which returns 0.
+    const ALLOC_ONLY_MODULE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01, 0x7f,
+        0x03, 0x02, 0x01, 0x00,
+        0x05, 0x03, 0x01, 0x00, 0x01,
+        0x07, 0x12, 0x02,
+        0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00,
+        0x05, b'a', b'l', b'l', b'o', b'c', 0x00, 0x00,
+        0x0a, 0x06, 0x01, 0x04, 0x00, 0x41, 0x00, 0x0b
+    ];
+
+    /// Test that events are filtered and formatted by the module.
+    #[test]
+    fn wasm_hooks() {
+        let module = WasmModule::from_bytes(TEST_MODULE).unwrap();
+        assert!(module.has_filter() && module.has_format());
+        let long = json!({"marker": "Resumed", "timestamp": "2024-02-11T20:41:02.113Z"});
+        assert!(block_on(module.filter(&long)).unwrap());
+        assert!(!block_on(module.filter(&json!({"marker": "Resumed"}))).unwrap());
+
+        let buf = SharedBuffer::default();
+        let writer = WasmWriter::new(Rc::new(module), Box::new(buf.clone()));
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::Percentage, Percentage(50.0));
+        let event = DeviceEvent::new("/dev", changes);
+        block_on(writer.write(&event)).unwrap();
+        let written: Value = serde_json::from_str(&buf.contents()).unwrap();
+        assert_eq!(written, change_event(&event));
+    }
+
+    /// Test that modules without the required exports are rejected.
+    #[test]
+    fn wasm_invalid() {
+        assert!(WasmModule::from_bytes(b"not a module").is_err());
+        assert!(WasmModule::from_bytes(&ALLOC_ONLY_MODULE[..8]).is_err());
+        assert!(WasmModule::from_bytes(ALLOC_ONLY_MODULE).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+use std::io::{Error, ErrorKind, Write};
+use async_lock::Mutex;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use serde_json::json;
+use crate::event::DeviceEvent;
+use crate::output::Writer;
+use crate::retry::{tolerate, within, Backoff, RetryPolicy, NETWORK_TIMEOUT};
+use crate::rt::TcpStream;
+
+/// The key under which markers (such as "Resumed") are reported.
+const MARKER_KEY: &str = "event";
+
+/// The header which begins each message sent to or received from a Zabbix server or proxy.
+const ZABBIX_HEADER: &[u8] = b"ZBXD\x01";
+
+/// The largest response accepted from a Zabbix server or proxy, excluding the header and length.
+const MAX_RESPONSE_LENGTH: u64 = 64 * 1024;
+
+/// A single value to be reported to Zabbix.
+#[derive(Debug, PartialEq)]
+struct Item {
+    /// The item key, such as `upmon.Percentage[battery_BAT0]`.
+    key: String,
+    /// The value, formatted as a string.
+    value: String,
+    /// The time at which the value was received, as a Unix timestamp.
+    clock: i64
+}
+
+/// Quote a value for the input format of `zabbix_sender`, if it contains whitespace or quotes.
+fn quote(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        String::from(value)
+    }
+}
+
+/// Build a message to send to a Zabbix server or proxy using the sender protocol.
+fn sender_message(host: &str, items: &[Item]) -> Vec<u8> {
+    let data = items.iter()
+        .map(|i| json!({
+            "host": host,
+            "key": i.key,
+            "value": i.value,
+            "clock": i.clock
+        }))
+        .collect::<Vec<_>>();
+    let body = json!({ "request": "sender data", "data": data }).to_string().into_bytes();
+    let mut msg = Vec::from(ZABBIX_HEADER);
+    msg.extend((body.len() as u64).to_le_bytes());
+    msg.extend(body);
+    msg
+}
+
+/// A [`Writer`] which reports changes as Zabbix items, either in the input format accepted by
+/// `zabbix_sender` (one `host key timestamp value` line per item) or by sending them directly to a
+/// Zabbix server or proxy.
+pub struct ZabbixWriter {
+    /// File (or other struct implementing Write) to write to, if not sending to a server.
+    out: Mutex<Box<dyn Write>>,
+    /// The address (`host:port`) of the Zabbix server or proxy to send items to, if any.
+    server: Option<String>,
+    /// The name of the monitored host, as configured in Zabbix.
+    host: String,
+    /// The prefix of each item key.
+    prefix: String,
+    /// Failed attempts to send items to the server.
+    connects: Mutex<Backoff>
+}
+
+impl ZabbixWriter {
+    /// Create a new [`ZabbixWriter`] which writes to the given output, or sends items to `server`
+    /// if given.
+    pub(crate) fn new(out: Box<dyn Write>, server: Option<&str>, host: &str, prefix: &str) -> Self {
+        Self {
+            out: Mutex::new(out),
+            server: server.map(String::from),
+            host: String::from(host),
+            prefix: String::from(prefix),
+            connects: Mutex::new(Backoff::new(RetryPolicy::default()))
+        }
+    }
+
+    /// Put off sending items to the server after a failure as set out by `policy`, rather than the
+    /// default [`RetryPolicy`].
+    pub(crate) fn with_retry(self, policy: RetryPolicy) -> Self {
+        Self { connects: Mutex::new(Backoff::new(policy)), ..self }
+    }
+
+    /// Return the item key for the given property of the device at `device_path`. The device is
+    /// identified by the last element of its path (for example, `battery_BAT0`).
+    fn key(&self, property: &str, device_path: &str) -> String {
+        let device = device_path.rsplit('/').next().unwrap_or(device_path);
+        format!("{}.{property}[{device}]", self.prefix)
+    }
+
+    /// Return an item for each property changed by `event`, sorted by key. Numeric, boolean and
+    /// enumerated properties are reported as numbers (see [`Property::as_f64`]), so that Zabbix can
+    /// graph them.
+    fn items(&self, event: &DeviceEvent) -> Vec<Item> {
+        let clock = event.timestamp.timestamp();
+        let mut items = event.iter()
+            .map(|(k, v)| Item {
+                key: self.key(k.as_str(), &event.device),
+                value: v.as_f64().map(|n| n.to_string()).unwrap_or_else(|| v.to_string()),
+                clock
+            })
+            .collect::<Vec<_>>();
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+        items
+    }
+
+    /// Report the given items. Failures to send them to the server are logged rather than
+    /// returned, as the server is retried.
+    async fn send(&self, items: &[Item]) -> Result<(), Error> {
+        let Some(server) = &self.server else {
+            let mut out = self.out.lock().await;
+            for i in items {
+                writeln!(out, "{} {} {} {}", quote(&self.host), i.key, i.clock, quote(&i.value))?;
+            }
+            return Ok(())
+        };
+        tolerate(&format!("Zabbix server {server}"), self.send_to(server, items).await)
+    }
+
+    /// Send the given items to the Zabbix server or proxy at `server`.
+    async fn send_to(&self, server: &str, items: &[Item]) -> Result<(), Error> {
+        let mut connects = self.connects.lock().await;
+        connects.ready().map_err(|e| Error::other(format!("Not connecting to Zabbix: {e}")))?;
+        let response = within(NETWORK_TIMEOUT, async {
+            let mut stream = TcpStream::connect(server).await?;
+            stream.write_all(&sender_message(&self.host, items)).await?;
+            // The server need not close the connection, so only the declared length is read.
+            let mut header = [0; ZABBIX_HEADER.len() + 8];
+            stream.read_exact(&mut header).await?;
+            let (magic, length) = header.split_at(ZABBIX_HEADER.len());
+            let length = u64::from_le_bytes(length.try_into().unwrap());
+            if magic != ZABBIX_HEADER || length > MAX_RESPONSE_LENGTH {
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid response from Zabbix"))
+            }
+            let mut response = vec!(0; length as usize);
+            stream.read_exact(&mut response).await?;
+            Ok(response)
+        }).await;
+        match response {
+            Ok(_) => connects.succeeded(),
+            Err(_) => connects.failed()
+        }
+        let body: serde_json::Value = serde_json::from_slice(&response?)?;
+        if body["response"] != "success" {
+            return Err(Error::other(format!("Zabbix rejected items: {body}")))
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Writer for ZabbixWriter {
+    /// Report each changed property as a separate item.
+    async fn write(&self, event: &DeviceEvent) -> Result<(), Error> {
+        self.send(&self.items(event)).await
+    }
+
+    async fn write_marker(&self, marker: &str) -> Result<(), Error> {
+        self.send(&[Item {
+            key: format!("{}.{MARKER_KEY}", self.prefix),
+            value: String::from(marker),
+            clock: Utc::now().timestamp()
+        }]).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::io::ErrorKind;
+    use std::time::Duration;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::join;
+    use crate::event::DeviceEvent;
+    use crate::output::Writer;
+    use crate::rt::{block_on, timeout, TcpListener};
+    use crate::testing::SharedBuffer;
+    use crate::upower::Property::{Percentage, State};
+    use crate::upower::PropertyKind;
+    use crate::zabbix::{Item, quote, sender_message, ZABBIX_HEADER, ZabbixWriter};
+
+    /// Test quoting of values.
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("80"), "80");
+        assert_eq!(quote("Resumed"), "Resumed");
+        assert_eq!(quote("CriticalAction PowerOff"), "\"CriticalAction PowerOff\"");
+        assert_eq!(quote("a\"b"), "\"a\\\"b\"");
+        assert_eq!(quote(""), "\"\"");
+    }
+
+    /// Test building of sender protocol messages.
+    #[test]
+    fn message() {
+        let msg = sender_message("laptop", &[Item {
+            key: String::from("upmon.Percentage[battery_BAT0]"),
+            value: String::from("80"),
+            clock: 1707671976
+        }]);
+        let body = "{\"data\":[{\"clock\":1707671976,\"host\":\"laptop\",\
+                    \"key\":\"upmon.Percentage[battery_BAT0]\",\"value\":\"80\"}],\
+                    \"request\":\"sender data\"}";
+        assert_eq!(&msg[..5], b"ZBXD\x01");
+        assert_eq!(msg[5..13], (body.len() as u64).to_le_bytes());
+        assert_eq!(&msg[13..], body.as_bytes());
+    }
+
+    /// Test writing of items in the input format of `zabbix_sender`.
+    #[test]
+    fn sender_lines() {
+        let buf = SharedBuffer::default();
+        let writer = ZabbixWriter::new(Box::new(buf.clone()), None, "-", "upmon");
+        let mut changes = HashMap::new();
+        changes.insert(PropertyKind::State, State(2));
+        changes.insert(PropertyKind::Percentage, Percentage(80.5));
+        let event = DeviceEvent::new("/org/freedesktop/UPower/devices/battery_BAT0", changes);
+        block_on(writer.write(&event)).unwrap();
+        block_on(writer.write_marker("CriticalAction PowerOff")).unwrap();
+        let lines = buf.contents().lines()
+            .map(|l| {
+                let fields = l.splitn(4, ' ').collect::<Vec<_>>();
+                assert!(fields[2].parse::<i64>().is_ok());
+                format!("{} {} {}", fields[0], fields[1], fields[3])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!(
+            "- upmon.Percentage[battery_BAT0] 80.5",
+            "- upmon.State[battery_BAT0] 2",
+            "- upmon.event \"CriticalAction PowerOff\""
+        ));
+    }
+
+    /// Test sending items directly to a server, which need not close the connection after
+    /// replying.
+    #[test]
+    fn send_to_server() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let writer = ZabbixWriter::new(Box::new(SharedBuffer::default()), Some(&addr), "h", "u");
+            let server = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut header = [0; 13];
+                stream.read_exact(&mut header).await.unwrap();
+                let len = u64::from_le_bytes(header[5..].try_into().unwrap());
+                let mut body = vec!(0; len as usize);
+                stream.read_exact(&mut body).await.unwrap();
+                let reply = b"{\"response\":\"success\",\"info\":\"processed: 1\"}";
+                stream.write_all(ZABBIX_HEADER).await.unwrap();
+                stream.write_all(&(reply.len() as u64).to_le_bytes()).await.unwrap();
+                stream.write_all(reply).await.unwrap();
+                (String::from_utf8(body).unwrap(), stream)
+            };
+            let changes = vec!((PropertyKind::Percentage, Percentage(80.0)));
+            let event = DeviceEvent::new("/dev/battery", changes);
+            let items = writer.items(&event);
+            let sent = async { join!(server, writer.send_to(&addr, &items)) };
+            let ((body, _stream), result) = timeout(Duration::from_secs(5), sent).await.unwrap();
+            result.unwrap();
+            assert!(body.contains("\"key\":\"u.Percentage[battery]\""));
+            assert!(body.contains("\"value\":\"80\""));
+        })
+    }
+
+    /// Test that a reply which is not in the Zabbix protocol is rejected.
+    #[test]
+    fn invalid_response() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let out = Box::new(SharedBuffer::default());
+            let writer = ZabbixWriter::new(out, Some(&addr), "h", "u");
+            let server = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await.unwrap();
+            };
+            let (_, result) = join!(server, writer.send_to(&addr, &[]));
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        })
+    }
+}
@@ -0,0 +1,65 @@
+//! Tests which use upmon as a library, as another crate would.
+
+use std::fs::{read_to_string, remove_file};
+use std::io::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use async_trait::async_trait;
+use futures::executor::block_on;
+use upmon::{
+    DeviceEvent, FileOptions, Property, PropertyKind, SeverityColors, UpdateTimeFormat, Writer,
+    WriterOptions, WriterRegistry
+};
+
+/// The number of events and markers written to every [`CountingWriter`].
+static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+/// A writer which counts the events and markers written to it in [`WRITTEN`].
+struct CountingWriter;
+
+#[async_trait(?Send)]
+impl Writer for CountingWriter {
+    async fn write(&self, _event: &DeviceEvent) -> Result<(), Error> {
+        WRITTEN.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn write_marker(&self, _marker: &str) -> Result<(), Error> {
+        WRITTEN.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Test that a writer implemented outside upmon can be registered alongside upmon's own writers,
+/// and that both can be created by name and written to.
+#[test]
+fn register_writer() {
+    let mut registry = WriterRegistry::default();
+    registry.register("counting", |_| Ok(Box::new(CountingWriter)));
+    assert!(registry.names().any(|n| n == "line"));
+    assert!(registry.names().any(|n| n == "counting"));
+    let path = std::env::temp_dir().join(format!("upmon-library-{}", std::process::id()));
+    let colors = SeverityColors::default();
+    let options = WriterOptions {
+        output_file: path.to_str(),
+        file: FileOptions::default(),
+        separator: "=",
+        delimiter: " ",
+        timestamp: false,
+        update_time: UpdateTimeFormat::Utc,
+        locale: None,
+        colors: &colors,
+        output_version: 2
+    };
+    let event = DeviceEvent::new("/bat", [(PropertyKind::Percentage, Property::Percentage(50.0))]);
+    block_on(async {
+        for name in ["counting", "line"] {
+            let writer = registry.create(name, &options).unwrap();
+            writer.write(&event).await.unwrap();
+            writer.write_marker("Marker").await.unwrap();
+        }
+    });
+    assert_eq!(WRITTEN.load(Ordering::SeqCst), 2);
+    assert_eq!(read_to_string(&path).unwrap(), "/bat Percentage=50\nMarker\n");
+    remove_file(&path).unwrap();
+    assert!(registry.create("unknown", &options).is_err());
+}
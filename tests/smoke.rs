@@ -0,0 +1,31 @@
+//! Smoke tests which run the upmon binary itself.
+
+use std::process::Command;
+
+/// Test that upmon starts, prints the information asked for and exits successfully.
+#[test]
+fn prints_information() {
+    for arg in ["--help-exit-codes", "--list-properties"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_upmon")).arg(arg).output().unwrap();
+        assert!(output.status.success(), "upmon {arg} failed: {output:?}");
+        assert!(!output.stdout.is_empty());
+    }
+}
+
+/// Test that the PID file is removed when upmon fails to start after writing it.
+#[test]
+fn removes_pid_file_on_failure() {
+    let dir = std::env::temp_dir();
+    let pid_file = dir.join(format!("upmon-smoke-{}.pid", std::process::id()));
+    let socket = dir.join("upmon-smoke-missing").join("control.sock");
+    let output = Command::new(env!("CARGO_BIN_EXE_upmon"))
+        .arg("--pid-file")
+        .arg(&pid_file)
+        .arg("--control-socket")
+        .arg(&socket)
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "upmon started: {output:?}");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Error when binding control socket"));
+    assert!(!pid_file.exists());
+}